@@ -0,0 +1,51 @@
+//! Throughput of the lexer on a realistic document and on pathological inputs.
+//!
+//! Run with `cargo bench --bench lexer`, which reports the throughput in bytes per second.
+//! To catch regressions, save a baseline with `-- --save-baseline main` before a change,
+//! and compare to it with `-- --baseline main` after.
+
+use std::hint::black_box;
+
+use criterion::{criterion_group, criterion_main, Criterion, Throughput};
+use texfmt::lexer::lex_tokens;
+
+/// Size of the inputs, in bytes.
+const SIZE: usize = 100_000;
+
+/// `src` repeated to at least [`SIZE`] bytes.
+fn repeat(src: &str) -> String {
+    src.repeat(SIZE.div_ceil(src.len()))
+}
+
+fn lexer(c: &mut Criterion) {
+    let document = [
+        include_str!("../assets/src_01.tex"),
+        include_str!("../tests/assets/math.tex"),
+        include_str!("../tests/assets/nested.tex"),
+        include_str!("../tests/assets/tables.tex"),
+        include_str!("../fuzz/corpus/format/document.tex"),
+    ]
+    .concat();
+    let inputs = [
+        ("document", repeat(&document)),
+        (
+            "text",
+            repeat("Lorem ipsum dolor sit amet, consectetur adipiscing elit.\n"),
+        ),
+        ("commands", repeat(r"\alpha\beta\gamma\delta")),
+        (
+            "nested braces",
+            "{".repeat(SIZE / 2) + &"}".repeat(SIZE / 2),
+        ),
+    ];
+
+    let mut group = c.benchmark_group("lex_tokens");
+    for (name, input) in &inputs {
+        group.throughput(Throughput::Bytes(input.len() as u64));
+        group.bench_function(*name, |b| b.iter(|| lex_tokens(black_box(input))));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, lexer);
+criterion_main!(benches);
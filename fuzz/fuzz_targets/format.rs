@@ -0,0 +1,24 @@
+//! The formatter never panics, whatever the options, even if it fails to format the input.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use texfmt::format::{MathDelimiters, PunctuationSpacing};
+use texfmt::{format_lines, format_str, FormatOptions};
+
+fuzz_target!(|input: &str| {
+    let _ = format_str(input, &FormatOptions::default());
+    let options = FormatOptions::builder()
+        .width(10)
+        .align_tables(true)
+        .comment_space(true)
+        .math_delimiters(Some(MathDelimiters::Amsmath))
+        .punctuation_spacing(Some(PunctuationSpacing::Fr))
+        .split_packages(true)
+        .sort_packages(true)
+        .build()
+        .unwrap();
+    let _ = format_str(input, &options);
+    let _ = format_lines(input, &[2..=3], &options);
+    let _ = texfmt::bib::format_bib(input, &options);
+});
@@ -0,0 +1,25 @@
+//! The lexer never panics, and the tokens it writes are tokenized again into the same tokens.
+
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use texfmt::lexer::{lex_tokens, Lexer, TokenIterator};
+use texfmt::writer::write_tokens;
+
+fuzz_target!(|input: &str| {
+    let mut lexer = Lexer::new(input);
+    let tokens: Vec<_> = lexer.by_ref().collect();
+    let rest = lexer.rest();
+    assert_eq!(lex_tokens(input).is_ok(), rest.is_empty());
+    let written = write_tokens(&tokens);
+    assert_eq!(lex_tokens(&written), Ok(tokens.clone()));
+
+    let streamed: Result<Vec<_>, _> = TokenIterator::new(input.as_bytes()).collect();
+    match streamed {
+        Ok(streamed) => {
+            assert!(rest.is_empty());
+            assert!(streamed.iter().map(|token| token.as_ref()).eq(tokens));
+        }
+        Err(_) => assert!(!rest.is_empty()),
+    }
+});
@@ -0,0 +1,359 @@
+//! Format a BibTeX database.
+//!
+//! BibTeX is not (La)TeX, so it is not tokenized by the [`lexer`](crate::lexer): the
+//! entries are parsed by this module, and only their layout is changed.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use texfmt::{bib::format_bib, FormatOptions};
+//! let src = "@book{knuth, title={The \\TeX book}, year = 1984}\n";
+//! assert_eq!(
+//!     format_bib(src, &FormatOptions::default()).unwrap(),
+//!     "@book{knuth,\n  title = {The \\TeX book},\n  year  = 1984\n}\n"
+//! );
+//! ```
+
+use crate::lexer::NewlineKind;
+use crate::{formatter, FormatOptions};
+
+/// Entries whose content is not made of fields, and which are left untouched.
+const VERBATIM_ENTRIES: &[&str] = &["comment", "preamble", "string"];
+
+/// Error returned by [`format_bib`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct BibError {
+    /// What the parser expected.
+    pub expected: &'static str,
+    /// Byte offset where it was expected.
+    pub byte: usize,
+}
+
+impl std::fmt::Display for BibError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "expected {} at byte {}", self.expected, self.byte)
+    }
+}
+
+impl std::error::Error for BibError {}
+
+/// `@kind{key, name = value, ...}`
+struct Entry<'a> {
+    kind: &'a str,
+    /// `{` or `(`
+    open: char,
+    key: &'a str,
+    /// Names and values, each value made of the pieces concatenated by `#`.
+    fields: Vec<(&'a str, Vec<&'a str>)>,
+}
+
+/// Part of a database.
+enum Item<'a> {
+    /// Text between the entries, which BibTeX ignores.
+    Text(&'a str),
+    /// An entry that is copied as it is.
+    Verbatim(&'a str),
+    Entry(Entry<'a>),
+}
+
+struct Parser<'a> {
+    input: &'a str,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn rest(&self) -> &'a str {
+        &self.input[self.pos..]
+    }
+
+    fn peek(&self) -> Option<char> {
+        self.rest().chars().next()
+    }
+
+    fn error(&self, expected: &'static str) -> BibError {
+        BibError {
+            expected,
+            byte: self.pos,
+        }
+    }
+
+    fn skip_whitespace(&mut self) {
+        let rest = self.rest();
+        self.pos += rest.len() - rest.trim_start().len();
+    }
+
+    /// Consume `c`, after any whitespace.
+    fn expect(&mut self, c: char, expected: &'static str) -> Result<(), BibError> {
+        self.skip_whitespace();
+        if self.peek() == Some(c) {
+            self.pos += c.len_utf8();
+            Ok(())
+        } else {
+            Err(self.error(expected))
+        }
+    }
+
+    /// Consume the characters satisfying `pred`.
+    fn take_while(&mut self, pred: impl Fn(char) -> bool) -> &'a str {
+        let rest = self.rest();
+        let len = rest.find(|c| !pred(c)).unwrap_or(rest.len());
+        self.pos += len;
+        &rest[..len]
+    }
+
+    /// Consume up to the `close` delimiter outside of braces, included.
+    fn skip_delimited(&mut self, close: char) -> Result<(), BibError> {
+        let mut depth = 0usize;
+        for (i, c) in self.rest().char_indices() {
+            match c {
+                _ if c == close && depth == 0 => {
+                    self.pos += i + c.len_utf8();
+                    return Ok(());
+                }
+                '{' => depth += 1,
+                '}' => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+        }
+        self.pos = self.input.len();
+        Err(self.error(if close == '}' { "`}`" } else { "`)`" }))
+    }
+
+    fn item(&mut self) -> Result<Item<'a>, BibError> {
+        if self.peek() != Some('@') {
+            return Ok(Item::Text(self.take_while(|c| c != '@')));
+        }
+        let start = self.pos;
+        self.pos += 1;
+        self.skip_whitespace();
+        let kind = self.take_while(|c| c.is_ascii_alphanumeric() || c == '_');
+        if kind.is_empty() {
+            return Err(self.error("an entry type"));
+        }
+        self.skip_whitespace();
+        let open = match self.peek() {
+            Some(c @ ('{' | '(')) => c,
+            _ => return Err(self.error("`{` or `(`")),
+        };
+        self.pos += 1;
+        let close = if open == '{' { '}' } else { ')' };
+
+        if VERBATIM_ENTRIES.contains(&kind.to_ascii_lowercase().as_str()) {
+            self.skip_delimited(close)?;
+            return Ok(Item::Verbatim(&self.input[start..self.pos]));
+        }
+
+        self.skip_whitespace();
+        let key = self
+            .take_while(|c| c != ',' && c != close && !c.is_whitespace())
+            .trim();
+        let mut fields = Vec::new();
+        loop {
+            self.skip_whitespace();
+            match self.peek() {
+                Some(c) if c == close => {
+                    self.pos += 1;
+                    break;
+                }
+                Some(',') => self.pos += 1,
+                _ => return Err(self.error("`,` or the end of the entry")),
+            }
+            self.skip_whitespace();
+            if self.peek() == Some(close) {
+                continue;
+            }
+            let name = self.take_while(|c| {
+                !c.is_whitespace() && !matches!(c, '=' | ',' | '{' | '}' | '(' | ')' | '"' | '#')
+            });
+            if name.is_empty() {
+                return Err(self.error("a field name"));
+            }
+            self.expect('=', "`=`")?;
+            fields.push((name, self.value(close)?));
+        }
+        Ok(Item::Entry(Entry {
+            kind,
+            open,
+            key,
+            fields,
+        }))
+    }
+
+    /// Parse the pieces of a value, concatenated by `#`.
+    fn value(&mut self, close: char) -> Result<Vec<&'a str>, BibError> {
+        let mut pieces = Vec::new();
+        loop {
+            self.skip_whitespace();
+            let start = self.pos;
+            match self.peek() {
+                Some('{') => {
+                    self.pos += 1;
+                    self.skip_delimited('}')?;
+                }
+                Some('"') => {
+                    self.pos += 1;
+                    self.skip_delimited('"')?;
+                }
+                _ => {
+                    self.take_while(|c| !c.is_whitespace() && c != ',' && c != '#' && c != close);
+                }
+            }
+            if self.pos == start {
+                return Err(self.error("a value"));
+            }
+            pieces.push(&self.input[start..self.pos]);
+            self.skip_whitespace();
+            if self.peek() != Some('#') {
+                return Ok(pieces);
+            }
+            self.pos += 1;
+        }
+    }
+}
+
+/// Write `entry` with one field per line, aligning the `=` of the fields.
+fn write_entry(out: &mut String, entry: &Entry, options: &FormatOptions) {
+    let close = if entry.open == '{' { '}' } else { ')' };
+    out.push('@');
+    out.push_str(entry.kind);
+    out.push(entry.open);
+    out.push_str(entry.key);
+    if entry.fields.is_empty() {
+        out.push(close);
+        return;
+    }
+    out.push(',');
+
+    let indent = if options.use_tabs {
+        "\t".to_owned()
+    } else {
+        " ".repeat(options.indent_width)
+    };
+    let width = entry
+        .fields
+        .iter()
+        .map(|(name, _)| name.chars().count())
+        .max()
+        .unwrap_or(0);
+    for (i, (name, pieces)) in entry.fields.iter().enumerate() {
+        let padding = width - name.chars().count();
+        out.push('\n');
+        out.push_str(&indent);
+        out.push_str(name);
+        out.push_str(&" ".repeat(padding));
+        out.push_str(" = ");
+        out.push_str(&pieces.join(" # "));
+        if i + 1 < entry.fields.len() || options.bib_trailing_comma {
+            out.push(',');
+        }
+    }
+    out.push('\n');
+    out.push(close);
+}
+
+/// Format the BibTeX database `input` according to `options`.
+///
+/// Every entry is written with one field per line, indented by one level, and with the
+/// `=` of its fields aligned. The last field is followed by a comma only if
+/// [`FormatOptions::bib_trailing_comma`] is set. The entry types, the citation keys and
+/// the values are copied as they are, as well as the `@comment`, `@preamble` and `@string`
+/// entries, while the text between the entries loses its trailing whitespace. The output
+/// ends as chosen by [`FormatOptions::final_newline`].
+pub fn format_bib(input: &str, options: &FormatOptions) -> Result<String, BibError> {
+    let mut parser = Parser { input, pos: 0 };
+    let mut result = String::with_capacity(input.len());
+    while parser.pos < input.len() {
+        match parser.item()? {
+            Item::Text(text) => {
+                for line in text.split_inclusive('\n') {
+                    let content = line.trim_end();
+                    result.push_str(content);
+                    if line.ends_with("\r\n") {
+                        result.push_str("\r\n");
+                    } else if line.ends_with('\n') {
+                        result.push('\n');
+                    }
+                }
+            }
+            Item::Verbatim(text) => result.push_str(text),
+            Item::Entry(entry) => write_entry(&mut result, &entry, options),
+        }
+    }
+    let detected = input.find('\n').map(|i| {
+        if input[..i].ends_with('\r') {
+            NewlineKind::CrLf
+        } else {
+            NewlineKind::Lf
+        }
+    });
+    let newline = formatter::newline_kind(options.line_ending, detected);
+    formatter::end_with_newline(&mut result, options.final_newline, newline);
+    Ok(result)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn format(input: &str) -> String {
+        format_bib(input, &FormatOptions::default()).unwrap()
+    }
+
+    #[test]
+    fn book() {
+        let formatted =
+            "@book{knuth84,\n  author = {Donald E. Knuth},\n  title  = \"The {\\TeX}book\"\n}\n";
+        assert_eq!(
+            format("@book{knuth84, author={Donald E. Knuth},title = \"The {\\TeX}book\",}\n"),
+            formatted
+        );
+        assert_eq!(format(formatted), formatted);
+        let options = FormatOptions::builder()
+            .use_tabs(true)
+            .bib_trailing_comma(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            format_bib(formatted, &options).unwrap(),
+            "@book{knuth84,\n\tauthor = {Donald E. Knuth},\n\ttitle  = \"The {\\TeX}book\",\n}\n"
+        );
+    }
+
+    #[test]
+    fn values() {
+        assert_eq!(
+            format("@misc(key:1,\n  note = {a, {b}, c}#\" d\"  #  jan, year=2000)"),
+            "@misc(key:1,\n  note = {a, {b}, c} # \" d\" # jan,\n  year = 2000\n)\n"
+        );
+        assert_eq!(format("@misc{empty}\n\n"), "@misc{empty}\n");
+    }
+
+    #[test]
+    fn untouched() {
+        for src in [
+            "Some text   \n",
+            "@string{ tug = \"TeX Users Group\" }\n",
+            "@Comment{ not, = formatted }\n",
+            "@preamble{ \"\\newcommand{\\x}{y}\" }\n",
+        ] {
+            assert_eq!(format(src), src.replace("   \n", "\n"));
+        }
+    }
+
+    #[test]
+    fn errors() {
+        let error = |input| format_bib(input, &FormatOptions::default()).unwrap_err();
+        assert_eq!(
+            error("@book{key, title = {open}"),
+            BibError {
+                expected: "`,` or the end of the entry",
+                byte: 25
+            }
+        );
+        assert_eq!(error("@book{key, title {x}}").byte, 17);
+        assert_eq!(
+            error("@book key").to_string(),
+            "expected `{` or `(` at byte 6"
+        );
+    }
+}
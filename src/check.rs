@@ -0,0 +1,324 @@
+//! Check the token stream for errors that would make formatting unreliable.
+
+use crate::lexer::{Span, Token};
+
+/// Error returned by [`check_braces`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BraceError {
+    /// `}` without a matching `{`.
+    Unmatched(Span),
+    /// `{` without a matching `}`.
+    Unclosed(Span),
+    /// `\endgroup` closing a `\bgroup`, or `\egroup` closing a `\begingroup`.
+    Mismatched(Span),
+}
+
+impl std::fmt::Display for BraceError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (what, span) = match self {
+            BraceError::Unmatched(span) => ("unmatched `}`", span),
+            BraceError::Unclosed(span) => ("unclosed `{`", span),
+            BraceError::Mismatched(span) => ("mismatched end of group", span),
+        };
+        write!(
+            f,
+            "{what} at line {}, column {} (byte {})",
+            span.line, span.col, span.byte
+        )
+    }
+}
+
+impl std::error::Error for BraceError {}
+
+/// Group open at some point of [`check_braces`].
+enum Group {
+    /// Opened by `{`, and possibly already closed by `\egroup`.
+    Brace { span: Span, ended: bool },
+    /// Opened by `\bgroup`.
+    Implicit,
+    /// Opened by `\begingroup`.
+    Semisimple,
+}
+
+/// Check that every `{` is matched by a `}`.
+///
+/// Returns the position of the first unmatched `}`, or else of the first `{` still open
+/// at the end of the input. Braces inside verbatim content are not tokens, so they are ignored.
+///
+/// The groups of `\begingroup` and `\bgroup` are never reported as unclosed, since they may
+/// be closed in another argument, as in `\newenvironment{a}{\begingroup}{\endgroup}`.
+/// As in TeX, `\bgroup` and `\egroup` can match the braces: `\egroup` closes a `{`, and `}`
+/// closes a `\bgroup` if no `{` is open. A `\begingroup` is only closed by `\endgroup`, so
+/// `}` without a `{` or a `\bgroup` is unmatched, and `\endgroup` closing a `\bgroup` or
+/// `\egroup` closing a `\begingroup` are mismatched.
+pub fn check_braces<S: AsRef<str>>(tokens: &[(Token<S>, Span)]) -> Result<(), BraceError> {
+    let mut open = Vec::new();
+    for (token, span) in tokens {
+        match token {
+            Token::LBrace => open.push(Group::Brace {
+                span: *span,
+                ended: false,
+            }),
+            Token::RBrace => {
+                // the groups opened inside the braces are closed with them
+                let start = open
+                    .iter()
+                    .rposition(|g| matches!(g, Group::Brace { .. }))
+                    .or_else(|| open.iter().rposition(|g| matches!(g, Group::Implicit)));
+                match start {
+                    Some(start) => open.truncate(start),
+                    None => return Err(BraceError::Unmatched(*span)),
+                }
+            }
+            token if token.begins_implicit_group() => open.push(Group::Implicit),
+            token if token.begins_semisimple_group() => open.push(Group::Semisimple),
+            token if token.ends_implicit_group() => match open.last_mut() {
+                Some(Group::Implicit) => {
+                    open.pop();
+                }
+                Some(Group::Brace { ended, .. }) => *ended = true,
+                Some(Group::Semisimple) => return Err(BraceError::Mismatched(*span)),
+                None => {}
+            },
+            // an `\endgroup` inside braces may close a group opened in another argument
+            token if token.ends_semisimple_group() => match open.last() {
+                Some(Group::Semisimple) => {
+                    open.pop();
+                }
+                Some(Group::Implicit) => return Err(BraceError::Mismatched(*span)),
+                Some(Group::Brace { .. }) | None => {}
+            },
+            _ => {}
+        }
+    }
+    let unclosed = open.iter().find_map(|group| match group {
+        Group::Brace { span, ended: false } => Some(*span),
+        _ => None,
+    });
+    match unclosed {
+        Some(span) => Err(BraceError::Unclosed(span)),
+        None => Ok(()),
+    }
+}
+
+/// Commands referring to labels, whose argument is a list of keys separated by commas.
+pub const REF_COMMANDS: &[&str] = &[
+    "ref", "eqref", "pageref", "autoref", "nameref", "vref", "cref", "Cref", "cpageref",
+];
+
+/// Inconsistency between the labels and the references, found by [`check_refs`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RefError {
+    /// Reference to a key which is not defined by any `\label`.
+    Undefined { key: String, span: Span },
+    /// `\label` whose key is never referenced.
+    Unused { key: String, span: Span },
+}
+
+impl std::fmt::Display for RefError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let (what, key, span) = match self {
+            RefError::Undefined { key, span } => ("undefined reference", key, span),
+            RefError::Unused { key, span } => ("unused label", key, span),
+        };
+        write!(
+            f,
+            "{what} `{key}` at line {}, column {} (byte {})",
+            span.line, span.col, span.byte
+        )
+    }
+}
+
+impl std::error::Error for RefError {}
+
+/// The text of the required argument at the start of `tokens`, which follow a command.
+pub(crate) fn argument<'t, S: AsRef<str> + 't>(
+    tokens: impl IntoIterator<Item = &'t Token<S>>,
+) -> Option<String> {
+    let mut rest = tokens
+        .into_iter()
+        .skip_while(|token| matches!(token, Token::Whitespace(_)));
+    // the starred variants, such as `\cref*`
+    let mut token = rest.next()?;
+    if matches!(token, Token::Text(text) if text.as_ref() == "*") {
+        token = rest.next()?;
+    }
+    if !matches!(token, Token::LBrace) {
+        return None;
+    }
+    let mut depth = 0;
+    let mut text = String::new();
+    for token in rest {
+        match token {
+            Token::LBrace => depth += 1,
+            Token::RBrace if depth == 0 => return Some(text),
+            Token::RBrace => depth -= 1,
+            // the parameter of a macro, such as `\ref{#1}`, is not a key
+            Token::Param(_) => return None,
+            _ => {}
+        }
+        text.push_str(&token.to_string());
+    }
+    None
+}
+
+/// Check that every key referenced by the [`REF_COMMANDS`] is defined by a `\label`,
+/// and that every label is referenced.
+///
+/// Returns the problems in order of position. Only the labels in `tokens` are known, so
+/// the labels of a document split into several files are checked on its main file.
+pub fn check_refs<S: AsRef<str>>(tokens: &[(Token<S>, Span)]) -> Vec<RefError> {
+    let mut labels = Vec::new();
+    let mut refs = Vec::new();
+    for (i, (token, span)) in tokens.iter().enumerate() {
+        let Token::Command(name) = token else {
+            continue;
+        };
+        let name = name.as_ref();
+        if name == "label" {
+            if let Some(key) = argument(tokens[i + 1..].iter().map(|(t, _)| t)) {
+                labels.push((key.trim().to_owned(), *span));
+            }
+        } else if REF_COMMANDS.contains(&name) {
+            if let Some(keys) = argument(tokens[i + 1..].iter().map(|(t, _)| t)) {
+                let keys = keys.split(',').map(str::trim).filter(|key| !key.is_empty());
+                refs.extend(keys.map(|key| (key.to_owned(), *span)));
+            }
+        }
+    }
+
+    let defined: std::collections::HashSet<_> = labels.iter().map(|(key, _)| key).collect();
+    let used: std::collections::HashSet<_> = refs.iter().map(|(key, _)| key).collect();
+    let mut errors: Vec<_> = refs
+        .iter()
+        .filter(|(key, _)| !defined.contains(key))
+        .map(|(key, span)| RefError::Undefined {
+            key: key.clone(),
+            span: *span,
+        })
+        .chain(
+            labels
+                .iter()
+                .filter(|(key, _)| !used.contains(key))
+                .map(|(key, span)| RefError::Unused {
+                    key: key.clone(),
+                    span: *span,
+                }),
+        )
+        .collect();
+    errors.sort_by_key(|error| match error {
+        RefError::Undefined { span, .. } | RefError::Unused { span, .. } => span.byte,
+    });
+    errors
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_tokens_spanned;
+
+    fn check(input: &str) -> Result<(), BraceError> {
+        let tokens = lex_tokens_spanned(input).unwrap();
+        check_braces(&tokens)
+    }
+
+    #[test]
+    fn braces() {
+        assert_eq!(check("\\begin{a}\n{\\x{y}}\n\\end{a}"), Ok(()));
+        assert_eq!(
+            check("{a{b}"),
+            Err(BraceError::Unclosed(Span {
+                line: 1,
+                col: 1,
+                byte: 0
+            }))
+        );
+        assert_eq!(
+            check("x\na}b"),
+            Err(BraceError::Unmatched(Span {
+                line: 2,
+                col: 2,
+                byte: 3
+            }))
+        );
+        assert_eq!(check("\\begin{verbatim}}\\end{verbatim}"), Ok(()));
+    }
+
+    #[test]
+    fn implicit_groups() {
+        assert_eq!(check("\\begingroup a {b} \\endgroup"), Ok(()));
+        assert_eq!(
+            check("\\newenvironment{a}{\\begingroup}{\\endgroup}"),
+            Ok(())
+        );
+        assert_eq!(
+            check("\\def\\x{\\bgroup\\itshape}\\def\\y{\\egroup}"),
+            Ok(())
+        );
+        assert_eq!(check("\\hbox\\bgroup a}"), Ok(()));
+        assert_eq!(check("\\hbox{a\\egroup"), Ok(()));
+        assert_eq!(
+            check("\\begingroup{"),
+            Err(BraceError::Unclosed(Span {
+                line: 1,
+                col: 12,
+                byte: 11
+            }))
+        );
+        assert_eq!(
+            check("\\begingroup\nx}").unwrap_err().to_string(),
+            "unmatched `}` at line 2, column 2 (byte 13)"
+        );
+        assert_eq!(
+            check("{\nx\\endgroup").unwrap_err().to_string(),
+            "unclosed `{` at line 1, column 1 (byte 0)"
+        );
+        assert_eq!(
+            check("\\begingroup\\egroup").unwrap_err().to_string(),
+            "mismatched end of group at line 1, column 12 (byte 11)"
+        );
+        assert_eq!(
+            check("\\bgroup\\endgroup").unwrap_err().to_string(),
+            "mismatched end of group at line 1, column 8 (byte 7)"
+        );
+        assert_eq!(check("\\begingroup\\bgroup}\\endgroup"), Ok(()));
+        assert_eq!(check("\\begingroup\\def\\x{\\endgroup}"), Ok(()));
+        assert_eq!(
+            check("\\bgroup}}").unwrap_err().to_string(),
+            "unmatched `}` at line 1, column 9 (byte 8)"
+        );
+        assert_eq!(
+            check("a}b").unwrap_err().to_string(),
+            "unmatched `}` at line 1, column 2 (byte 1)"
+        );
+    }
+
+    fn refs(input: &str) -> Vec<String> {
+        let tokens = lex_tokens_spanned(input).unwrap();
+        check_refs(&tokens).iter().map(|e| e.to_string()).collect()
+    }
+
+    #[test]
+    fn undefined_ref() {
+        assert_eq!(
+            refs("\\section{A}\\label{sec:a}\nSee \\ref{sec:a} and \\eqref{ eq:b }.\n"),
+            ["undefined reference `eq:b` at line 2, column 21 (byte 45)"]
+        );
+        assert_eq!(
+            refs("\\label{a}\\label{b}\\cref{a, b,c}"),
+            ["undefined reference `c` at line 1, column 19 (byte 18)"]
+        );
+        assert_eq!(
+            refs("\\newcommand\\fref[1]{\\ref{#1}}"),
+            Vec::<String>::new()
+        );
+    }
+
+    #[test]
+    fn unused_label() {
+        assert_eq!(
+            refs("\\label{a}\n\\label{b_c}\n\\ref*{a}\n\\begin{verbatim}\\ref{b_c}\\end{verbatim}"),
+            ["unused label `b_c` at line 2, column 1 (byte 10)"]
+        );
+    }
+}
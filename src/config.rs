@@ -0,0 +1,314 @@
+//! Load the formatting configuration from a `.texfmt.toml` file.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use texfmt::{config::Config, FormatOptions};
+//! let config: Config = "width = 100\nuse-tabs = true\n".parse().unwrap();
+//! let options = config.apply(FormatOptions::builder()).build().unwrap();
+//! assert_eq!(options.width, 100);
+//! assert!(options.use_tabs);
+//! ```
+
+use std::collections::BTreeMap;
+use std::path::{Path, PathBuf};
+
+use crate::format::{IndentStyle, MathDelimiters, PunctuationSpacing};
+use crate::formatter::{FinalNewline, FormatOptions, FormatOptionsBuilder, Lang, LineEnding};
+
+/// Name of the configuration file.
+pub const FILE_NAME: &str = ".texfmt.toml";
+
+/// Content of a configuration file.
+///
+/// The keys are the same as the long command line options, and the missing ones keep
+/// their default value.
+#[derive(Clone, Debug, Default, PartialEq, Eq, serde::Deserialize, serde::Serialize)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+pub struct Config {
+    pub width: Option<usize>,
+    pub indent_width: Option<usize>,
+    pub use_tabs: Option<bool>,
+    pub indent_style: Option<IndentStyle>,
+    pub tab_width: Option<usize>,
+    pub sentence_wrap: Option<bool>,
+    pub align_tables: Option<bool>,
+    pub comment_space: Option<bool>,
+    pub math_delimiters: Option<MathDelimiters>,
+    pub punctuation_spacing: Option<PunctuationSpacing>,
+    pub split_packages: Option<bool>,
+    pub sort_packages: Option<bool>,
+    pub remove_redundant_braces: Option<bool>,
+    pub normalize_quotes: Option<bool>,
+    pub max_consecutive_newlines: Option<usize>,
+    pub line_ending: Option<LineEnding>,
+    pub final_newline: Option<FinalNewline>,
+    pub bib_trailing_comma: Option<bool>,
+    pub lang: Option<Lang>,
+    pub plain_tex: Option<bool>,
+    pub verbatim_environments: Option<Vec<String>>,
+    pub opaque_environments: Option<Vec<String>>,
+    pub citation_commands: Option<Vec<String>>,
+    pub command_args: Option<BTreeMap<String, usize>>,
+}
+
+/// Error returned when loading a [`Config`].
+#[derive(Debug)]
+pub enum ConfigError {
+    /// The file cannot be read.
+    Io(std::io::Error),
+    /// The file is not a valid configuration.
+    Parse(toml::de::Error),
+}
+
+impl std::fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ConfigError::Io(e) => e.fmt(f),
+            ConfigError::Parse(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for ConfigError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            ConfigError::Io(e) => Some(e),
+            ConfigError::Parse(e) => Some(e),
+        }
+    }
+}
+
+impl std::str::FromStr for Config {
+    type Err = ConfigError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        toml::from_str(s).map_err(ConfigError::Parse)
+    }
+}
+
+impl Config {
+    /// Load the configuration file at `path`.
+    pub fn load(path: &Path) -> Result<Self, ConfigError> {
+        std::fs::read_to_string(path)
+            .map_err(ConfigError::Io)?
+            .parse()
+    }
+
+    /// Find the nearest configuration file in `dir` or in one of its ancestors.
+    pub fn discover(dir: &Path) -> Option<PathBuf> {
+        let dir = std::path::absolute(dir).ok()?;
+        dir.ancestors()
+            .map(|dir| dir.join(FILE_NAME))
+            .find(|path| path.is_file())
+    }
+
+    /// Set the options that are present in the configuration.
+    pub fn apply(&self, mut builder: FormatOptionsBuilder) -> FormatOptionsBuilder {
+        if let Some(width) = self.width {
+            builder = builder.width(width);
+        }
+        if let Some(indent_width) = self.indent_width {
+            builder = builder.indent_width(indent_width);
+        }
+        if let Some(use_tabs) = self.use_tabs {
+            builder = builder.use_tabs(use_tabs);
+        }
+        if let Some(indent_style) = self.indent_style {
+            builder = builder.indent_style(Some(indent_style));
+        }
+        if let Some(tab_width) = self.tab_width {
+            builder = builder.tab_width(tab_width);
+        }
+        if let Some(sentence_wrap) = self.sentence_wrap {
+            builder = builder.sentence_wrap(sentence_wrap);
+        }
+        if let Some(align_tables) = self.align_tables {
+            builder = builder.align_tables(align_tables);
+        }
+        if let Some(comment_space) = self.comment_space {
+            builder = builder.comment_space(comment_space);
+        }
+        if let Some(math_delimiters) = self.math_delimiters {
+            builder = builder.math_delimiters(Some(math_delimiters));
+        }
+        if let Some(punctuation_spacing) = self.punctuation_spacing {
+            builder = builder.punctuation_spacing(Some(punctuation_spacing));
+        }
+        if let Some(split_packages) = self.split_packages {
+            builder = builder.split_packages(split_packages);
+        }
+        if let Some(sort_packages) = self.sort_packages {
+            builder = builder.sort_packages(sort_packages);
+        }
+        if let Some(remove_redundant_braces) = self.remove_redundant_braces {
+            builder = builder.remove_redundant_braces(remove_redundant_braces);
+        }
+        if let Some(normalize_quotes) = self.normalize_quotes {
+            builder = builder.normalize_quotes(normalize_quotes);
+        }
+        if let Some(max_consecutive_newlines) = self.max_consecutive_newlines {
+            builder = builder.max_consecutive_newlines(max_consecutive_newlines);
+        }
+        if let Some(line_ending) = self.line_ending {
+            builder = builder.line_ending(line_ending);
+        }
+        if let Some(final_newline) = self.final_newline {
+            builder = builder.final_newline(final_newline);
+        }
+        if let Some(bib_trailing_comma) = self.bib_trailing_comma {
+            builder = builder.bib_trailing_comma(bib_trailing_comma);
+        }
+        if let Some(lang) = self.lang {
+            builder = builder.lang(Some(lang));
+        }
+        if let Some(plain_tex) = self.plain_tex {
+            builder = builder.plain_tex(plain_tex);
+        }
+        if let Some(ref verbatim_environments) = self.verbatim_environments {
+            builder = builder.verbatim_environments(verbatim_environments.clone());
+        }
+        if let Some(ref opaque_environments) = self.opaque_environments {
+            builder = builder.opaque_environments(opaque_environments.clone());
+        }
+        if let Some(ref citation_commands) = self.citation_commands {
+            builder = builder.citation_commands(citation_commands.clone());
+        }
+        if let Some(ref command_args) = self.command_args {
+            builder = builder.command_args(command_args.clone());
+        }
+        builder
+    }
+}
+
+impl From<&FormatOptions> for Config {
+    /// The configuration setting all the options to their value in `options`.
+    fn from(options: &FormatOptions) -> Self {
+        Config {
+            width: Some(options.width),
+            indent_width: Some(options.indent_width),
+            use_tabs: Some(options.use_tabs),
+            indent_style: options.indent_style,
+            tab_width: Some(options.tab_width),
+            sentence_wrap: Some(options.sentence_wrap),
+            align_tables: Some(options.align_tables),
+            comment_space: Some(options.comment_space),
+            math_delimiters: options.math_delimiters,
+            punctuation_spacing: options.punctuation_spacing,
+            split_packages: Some(options.split_packages),
+            sort_packages: Some(options.sort_packages),
+            remove_redundant_braces: Some(options.remove_redundant_braces),
+            normalize_quotes: Some(options.normalize_quotes),
+            max_consecutive_newlines: Some(options.max_consecutive_newlines),
+            line_ending: Some(options.line_ending),
+            final_newline: Some(options.final_newline),
+            bib_trailing_comma: Some(options.bib_trailing_comma),
+            lang: options.lang,
+            plain_tex: Some(options.plain_tex),
+            verbatim_environments: Some(options.verbatim_environments.clone()),
+            opaque_environments: Some(options.opaque_environments.clone()),
+            citation_commands: Some(options.citation_commands.clone()),
+            command_args: Some(options.command_args.clone()),
+        }
+    }
+}
+
+impl std::fmt::Display for Config {
+    /// Write the configuration as the content of a configuration file.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(&toml::to_string(self).map_err(|_| std::fmt::Error)?)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse() {
+        assert_eq!("".parse::<Config>().unwrap(), Config::default());
+        assert_eq!(
+            "width = 60\nalign-tables = true".parse::<Config>().unwrap(),
+            Config {
+                width: Some(60),
+                align_tables: Some(true),
+                ..Config::default()
+            }
+        );
+        assert_eq!(
+            "math-delimiters = \"amsmath\"".parse::<Config>().unwrap(),
+            Config {
+                math_delimiters: Some(MathDelimiters::Amsmath),
+                ..Config::default()
+            }
+        );
+        assert_eq!(
+            "verbatim-environments = [\"code\"]"
+                .parse::<Config>()
+                .unwrap()
+                .verbatim_environments,
+            Some(vec!["code".to_owned()])
+        );
+        assert_eq!(
+            "opaque-environments = [\"axis\"]"
+                .parse::<Config>()
+                .unwrap()
+                .opaque_environments,
+            Some(vec!["axis".to_owned()])
+        );
+        assert_eq!(
+            "citation-commands = [\"cite\", \"mycite\"]"
+                .parse::<Config>()
+                .unwrap()
+                .citation_commands,
+            Some(vec!["cite".to_owned(), "mycite".to_owned()])
+        );
+        assert_eq!(
+            "command-args = { href = 2 }"
+                .parse::<Config>()
+                .unwrap()
+                .command_args,
+            Some(BTreeMap::from([("href".to_owned(), 2)]))
+        );
+        assert!("widht = 60".parse::<Config>().is_err());
+        assert!("width = \"wide\"".parse::<Config>().is_err());
+    }
+
+    #[test]
+    fn roundtrip() {
+        let options = FormatOptions::builder()
+            .width(60)
+            .math_delimiters(Some(MathDelimiters::Amsmath))
+            .lang(Some(Lang::Tex))
+            .build()
+            .unwrap();
+        let config = Config::from(&options);
+        let text = config.to_string();
+        assert!(text.contains("width = 60\n"), "{text}");
+        assert!(text.contains("math-delimiters = \"amsmath\"\n"), "{text}");
+        assert!(text.contains("lang = \"tex\"\n"), "{text}");
+        assert!(text.contains("plain-tex = true\n"), "{text}");
+        assert!(!text.contains("punctuation-spacing"), "{text}");
+        assert_eq!(text.parse::<Config>().unwrap(), config);
+        let builder = config.apply(FormatOptions::builder());
+        assert_eq!(builder.build().unwrap(), options);
+    }
+
+    #[test]
+    fn discover() {
+        let root = tempfile::tempdir().unwrap();
+        let root = root.path();
+        let nested = root.join("a").join("b");
+        std::fs::create_dir_all(&nested).unwrap();
+        // ignore the configuration files outside of the temporary directory
+        let discover = |dir: &Path| Config::discover(dir).filter(|path| path.starts_with(root));
+        assert_eq!(discover(&nested), None);
+
+        std::fs::write(root.join(FILE_NAME), "width = 60").unwrap();
+        assert_eq!(discover(&nested), Some(root.join(FILE_NAME)));
+
+        std::fs::write(nested.join(FILE_NAME), "width = 40").unwrap();
+        assert_eq!(discover(&nested), Some(nested.join(FILE_NAME)));
+        assert_eq!(discover(&root.join("a")), Some(root.join(FILE_NAME)));
+    }
+}
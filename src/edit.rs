@@ -0,0 +1,174 @@
+//! Describe the formatting of a text as a list of small edits.
+//!
+//! Editors apply the edits instead of replacing the whole text, which keeps the cursor and
+//! the undo history of the parts of the text which did not change.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use texfmt::edit::{diff_edits, Position, TextEdit};
+//! assert_eq!(
+//!     diff_edits("a\nb  c\nd\n", "a\nb c\nd\n"),
+//!     [TextEdit {
+//!         start: Position { line: 1, character: 2 },
+//!         end: Position { line: 1, character: 3 },
+//!         text: String::new(),
+//!     }]
+//! );
+//! ```
+
+/// Position in a text, between two characters.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, serde::Serialize)]
+pub struct Position {
+    /// Line, counted from 0.
+    pub line: usize,
+    /// Offset in the line, in UTF-16 code units as in the Language Server Protocol.
+    pub character: usize,
+}
+
+impl Position {
+    /// The position of the byte offset `offset` of `text`.
+    pub fn of_offset(text: &str, offset: usize) -> Self {
+        let before = &text[..offset];
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        Position {
+            line: before.matches('\n').count(),
+            character: before[line_start..].encode_utf16().count(),
+        }
+    }
+}
+
+/// Replacement of the text from `start` to `end` with `text`.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct TextEdit {
+    /// Start of the replaced text.
+    pub start: Position,
+    /// End of the replaced text, excluded.
+    pub end: Position,
+    /// Replacement.
+    pub text: String,
+}
+
+/// The edits turning `original` into `formatted`.
+///
+/// The lines which differ are found by a diff, and the adjacent changed lines make a single
+/// edit, which is then shrunk to the characters which differ. The edits are sorted, they
+/// do not overlap, and their positions refer to `original`, so they can be applied together.
+pub fn diff_edits(original: &str, formatted: &str) -> Vec<TextEdit> {
+    let diff = similar::TextDiff::from_lines(original, formatted);
+    // the byte offsets of the starts of the lines, and of the end of the text
+    let line_starts = |text: &str| -> Vec<usize> {
+        std::iter::once(0)
+            .chain(text.split_inclusive('\n').scan(0, |end, line| {
+                *end += line.len();
+                Some(*end)
+            }))
+            .collect()
+    };
+    let (old_starts, new_starts) = (line_starts(original), line_starts(formatted));
+
+    let mut edits = Vec::new();
+    // the ranges of lines of the current edit, which merges the adjacent changes
+    let mut edit: Option<(std::ops::Range<usize>, std::ops::Range<usize>)> = None;
+    for op in diff.ops().iter().map(Some).chain([None]) {
+        match op {
+            Some(op) if op.tag() != similar::DiffTag::Equal => {
+                let (old, new) = (op.old_range(), op.new_range());
+                edit = Some(match edit {
+                    Some((o, n)) => (o.start..old.end, n.start..new.end),
+                    None => (old, new),
+                });
+            }
+            _ => {
+                if let Some((o, n)) = edit.take() {
+                    let old = old_starts[o.start]..old_starts[o.end];
+                    let new = new_starts[n.start]..new_starts[n.end];
+                    edits.push(shrink(original, old, &formatted[new]));
+                }
+            }
+        }
+    }
+    edits
+}
+
+/// The edit replacing the range `old` of `original` with `new`, without the characters
+/// which they have in common at the start and at the end.
+fn shrink(original: &str, old: std::ops::Range<usize>, new: &str) -> TextEdit {
+    let replaced = &original[old.clone()];
+    let mut prefix = replaced
+        .char_indices()
+        .zip(new.chars())
+        .find(|&((_, a), b)| a != b)
+        .map_or(replaced.len().min(new.len()), |((i, _), _)| i);
+    // the common prefix is made of whole characters, so it is a boundary of `new` as well
+    let mut suffix = replaced[prefix..]
+        .chars()
+        .rev()
+        .zip(new[prefix..].chars().rev())
+        .take_while(|(a, b)| a == b)
+        .map(|(a, _)| a.len_utf8())
+        .sum::<usize>();
+    // a line ending `\r\n` is never split
+    if replaced[..prefix].ends_with('\r') {
+        prefix -= 1;
+    }
+    if replaced[..replaced.len() - suffix].ends_with('\r') && suffix > 0 {
+        suffix -= 1;
+    }
+    let (start, end) = (old.start + prefix, old.end - suffix);
+    TextEdit {
+        start: Position::of_offset(original, start),
+        end: Position::of_offset(original, end),
+        text: new[prefix..new.len() - suffix].to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn edit(start: (usize, usize), end: (usize, usize), text: &str) -> TextEdit {
+        let position = |(line, character)| Position { line, character };
+        TextEdit {
+            start: position(start),
+            end: position(end),
+            text: text.to_owned(),
+        }
+    }
+
+    #[test]
+    fn one_line() {
+        let original = "First line.\nSecond  line,  changed.\nThird line.\n";
+        let formatted = "First line.\nSecond line, changed.\nThird line.\n";
+        assert_eq!(
+            diff_edits(original, formatted),
+            [edit((1, 7), (1, 14), "line,")]
+        );
+        assert_eq!(diff_edits(original, original), []);
+        assert_eq!(diff_edits("a\nb", "a\nb\n"), [edit((1, 1), (1, 1), "\n")]);
+    }
+
+    #[test]
+    fn several_lines() {
+        let original = "a\n  b\nc\nd\ne  f\n";
+        let formatted = "a\nb c\nd\ne f\ng\n";
+        assert_eq!(
+            diff_edits(original, formatted),
+            [edit((1, 0), (2, 0), "b "), edit((4, 2), (4, 4), "f\ng"),]
+        );
+    }
+
+    #[test]
+    fn positions() {
+        let original = "\u{e9}\u{1f600}  x\r\ny\r\n";
+        assert_eq!(
+            diff_edits(original, "\u{e9}\u{1f600} x\r\ny\r\n"),
+            [edit((0, 4), (0, 5), "")]
+        );
+        assert_eq!(
+            diff_edits(original, "\u{e9}\u{1f600}  x\ny\r\n"),
+            [edit((0, 6), (1, 0), "\n")]
+        );
+        assert_eq!(Position::of_offset(original, original.len()).line, 2);
+    }
+}
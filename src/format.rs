@@ -0,0 +1,415 @@
+//! Format a (La)TeX source.
+//!
+//! This module provides formatting passes that rewrite a stream of [`Token`]s.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use texfmt::{format::strip_trailing_whitespace, lexer::lex_tokens, writer::write_tokens};
+//! let mut tokens = lex_tokens("some text \t\n").unwrap();
+//! strip_trailing_whitespace(&mut tokens);
+//! assert_eq!(write_tokens(&tokens), "some text\n");
+//! ```
+
+use crate::lexer::{NewlineKind, Token};
+
+mod align;
+mod braces;
+mod citations;
+mod display;
+mod indent;
+mod items;
+mod math;
+mod packages;
+mod punctuation;
+mod quotes;
+mod reflow;
+
+pub use align::align_tables;
+pub use braces::remove_redundant_braces;
+pub(crate) use braces::remove_redundant_braces_after;
+pub use citations::{normalize_citations, CITATION_COMMANDS};
+pub use display::break_display_math;
+pub(crate) use display::break_display_math_after;
+pub use indent::indent;
+pub(crate) use indent::indent_after;
+pub use items::break_items;
+pub(crate) use items::break_items_after;
+pub use math::{convert_math_delimiters, is_math_size_command, MathDelimiters};
+pub use packages::{one_package_per_line, sort_packages};
+pub(crate) use packages::{one_package_per_line_after, sort_packages_after};
+pub use punctuation::{punctuation_spacing, PunctuationSpacing};
+pub use quotes::normalize_quotes;
+pub use reflow::{reflow, sentence_wrap};
+
+/// Math and tabular environments, whose content is laid out by hand and never reflowed.
+const NO_REFLOW_ENVIRONMENTS: &[&str] = &[
+    "align",
+    "align*",
+    "alignat",
+    "alignat*",
+    "array",
+    "displaymath",
+    "eqnarray",
+    "eqnarray*",
+    "equation",
+    "equation*",
+    "flalign",
+    "flalign*",
+    "gather",
+    "gather*",
+    "math",
+    "multline",
+    "multline*",
+    "tabular",
+    "tabular*",
+    "tabularx",
+];
+
+/// Open environments, tracking whether any of them belongs to a given list.
+#[derive(Default)]
+struct Environments {
+    open: Vec<bool>,
+}
+
+impl Environments {
+    fn update<S: AsRef<str>>(&mut self, token: &Token<S>, names: &[&str]) {
+        match token {
+            Token::BeginEnv(name, _) => self.open.push(names.contains(&name.as_ref())),
+            Token::EndEnv(..) => {
+                self.open.pop();
+            }
+            _ => {}
+        }
+    }
+
+    /// Whether any of the open environments belongs to the list.
+    fn inside(&self) -> bool {
+        self.open.contains(&true)
+    }
+}
+
+/// Whether the end of `context` is inside a math or tabular environment.
+pub(crate) fn inside_no_reflow_environment<S: AsRef<str>>(context: &[Token<S>]) -> bool {
+    let mut environments = Environments::default();
+    for token in context {
+        environments.update(token, NO_REFLOW_ENVIRONMENTS);
+    }
+    environments.inside()
+}
+
+/// End the current line of `result`, unless it is empty.
+///
+/// The indentation of the line is kept, while the spaces at its end are dropped.
+fn break_line<S>(result: &mut Vec<Token<S>>, newline: NewlineKind) {
+    let end = result
+        .iter()
+        .rposition(|t| !matches!(t, Token::Whitespace(_)))
+        .map_or(0, |i| i + 1);
+    if !matches!(result[..end].last(), None | Some(Token::Newline(_))) {
+        result.truncate(end);
+        result.push(Token::Newline(newline));
+    }
+}
+
+/// Character used for the indentation.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum IndentStyle {
+    /// Spaces
+    Space,
+    /// Tabs
+    Tab,
+}
+
+/// Rewrite the whitespace at the start of every line in `style`, keeping its width, where a
+/// tab stop is every `width` columns.
+///
+/// Unlike [`indent`], this does not change the depth of the lines: with spaces, a tab
+/// becomes up to `width` spaces, and with tabs, every `width` columns become a tab, while
+/// the columns left over stay spaces. The whitespace elsewhere in the lines, and the
+/// content of verbatim tokens, are left alone, and so is everything if `width` is 0.
+pub fn convert_indentation<S>(tokens: &mut [Token<S>], style: IndentStyle, width: usize)
+where
+    S: AsRef<str> + From<String>,
+{
+    if width == 0 {
+        return;
+    }
+    let mut line_start = true;
+    for token in tokens {
+        match token {
+            Token::Whitespace(ws) if line_start => {
+                let columns = ws.as_ref().chars().fold(0, |column, c| match c {
+                    '\t' => column + width - column % width,
+                    _ => column + 1,
+                });
+                let converted = match style {
+                    IndentStyle::Space => " ".repeat(columns),
+                    IndentStyle::Tab => "\t".repeat(columns / width) + &" ".repeat(columns % width),
+                };
+                if converted != ws.as_ref() {
+                    *ws = converted.into();
+                }
+            }
+            _ => {}
+        }
+        line_start = matches!(token, Token::Newline(_));
+    }
+}
+
+/// Write every `\begin{env}` and `\end{env}` without spaces around the name of the
+/// environment, so that `\begin { env }` becomes `\begin{env}`.
+///
+/// The case of the names is kept, since they are case-sensitive.
+pub fn normalize_environments<S>(tokens: &mut [Token<S>]) {
+    for token in tokens {
+        if let Token::BeginEnv(_, source) | Token::EndEnv(_, source) = token {
+            *source = None;
+        }
+    }
+}
+
+/// Remove the whitespace at the end of every line.
+pub fn strip_trailing_whitespace<S>(tokens: &mut Vec<Token<S>>) {
+    let trailing: Vec<bool> = (0..tokens.len())
+        .map(|i| {
+            matches!(tokens[i], Token::Whitespace(_))
+                && matches!(tokens.get(i + 1), None | Some(Token::Newline(_)))
+        })
+        .collect();
+    let mut trailing = trailing.into_iter();
+    tokens.retain(|_| !trailing.next().unwrap());
+}
+
+/// Replace the whitespace between words with a single space.
+///
+/// The indentation at the start of a line, the whitespace before a comment, and the
+/// whitespace inside math and tabular environments are preserved.
+pub fn normalize_spaces<'a, S>(tokens: &mut [Token<S>])
+where
+    S: AsRef<str> + From<&'a str>,
+{
+    let mut environments = Environments::default();
+    let mut line_start = true;
+    for i in 0..tokens.len() {
+        if matches!(tokens[i], Token::Whitespace(_))
+            && !line_start
+            && !environments.inside()
+            && !matches!(tokens.get(i + 1), Some(Token::Comment(_)))
+        {
+            tokens[i] = Token::Whitespace(S::from(" "));
+        }
+        line_start = matches!(tokens[i], Token::Newline(_));
+        environments.update(&tokens[i], NO_REFLOW_ENVIRONMENTS);
+    }
+}
+
+/// Whether the comment with this `text` is a magic comment read by editors, such as
+/// `%!TeX program = lualatex` or `% !TeX root = main.tex`.
+///
+/// The `TeX` keyword is case-insensitive, and any spaces may precede the `!`.
+fn is_magic_comment(text: &str) -> bool {
+    text.trim_start_matches([' ', '\t'])
+        .strip_prefix('!')
+        .and_then(|rest| rest.get(..3))
+        .is_some_and(|keyword| keyword.eq_ignore_ascii_case("tex"))
+}
+
+/// Normalize the space between `%` and the text of every comment.
+///
+/// The text is preceded by exactly one space if `single_space` is true, and by none otherwise.
+/// Magic comments such as `%!TeX root=main.tex` or `% !TeX program = lualatex`, other
+/// comments starting with `!` or `%`, and rules made only of `%`, `=` and `-` characters
+/// are left untouched.
+pub fn normalize_comments<S>(tokens: &mut [Token<S>], single_space: bool)
+where
+    S: AsRef<str> + From<String>,
+{
+    for token in tokens {
+        if let Token::Comment(text) = token {
+            let s = text.as_ref();
+            let content = s.trim_start_matches([' ', '\t']);
+            // also true for empty comments, which are left alone
+            let is_rule = content
+                .trim_end()
+                .chars()
+                .all(|c| matches!(c, '%' | '=' | '-'));
+            if s.starts_with(['!', '%']) || is_rule || is_magic_comment(s) {
+                continue;
+            }
+            let normalized = if single_space {
+                format!(" {content}")
+            } else {
+                content.to_owned()
+            };
+            if normalized != s {
+                *text = normalized.into();
+            }
+        }
+    }
+}
+
+/// Replace every line ending with `kind`.
+pub fn convert_newlines<S>(tokens: &mut [Token<S>], kind: NewlineKind) {
+    for token in tokens {
+        if let Token::Newline(k) = token {
+            *k = kind;
+        }
+    }
+}
+
+/// Reduce every run of blank lines to at most `max` blank lines.
+///
+/// Lines containing only whitespace count as blank, and so do the lines at the
+/// start of the input.
+pub fn collapse_blank_lines<S>(tokens: &mut Vec<Token<S>>, max: usize) {
+    let mut result = Vec::with_capacity(tokens.len());
+    // the start of the input counts as the end of a line
+    let mut newlines = 1;
+    for token in tokens.drain(..) {
+        match token {
+            Token::Newline(_) => {
+                newlines += 1;
+                if newlines > max + 1 {
+                    while let Some(Token::Whitespace(_)) = result.last() {
+                        result.pop();
+                    }
+                    continue;
+                }
+            }
+            Token::Whitespace(_) => {}
+            _ => newlines = 0,
+        }
+        result.push(token);
+    }
+    *tokens = result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_tokens;
+
+    fn format<'a>(input: &'a str, pass: impl FnOnce(&mut Vec<Token<&'a str>>)) -> String {
+        let mut tokens = lex_tokens(input).unwrap();
+        pass(&mut tokens);
+        tokens.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn environments() {
+        let normalize = |input| format(input, |tokens| normalize_environments(tokens));
+        assert_eq!(
+            normalize("\\begin { itemize }\n\\item a\n\\end  {itemize }\n"),
+            "\\begin{itemize}\n\\item a\n\\end{itemize}\n"
+        );
+        // the names are case-sensitive
+        assert_eq!(
+            normalize("\\begin{ Equation\t}x\\end{Equation}"),
+            "\\begin{Equation}x\\end{Equation}"
+        );
+        assert_eq!(normalize("\\begin {x}"), "\\begin{x}");
+    }
+
+    #[test]
+    fn trailing_whitespace() {
+        let strip = |input| format(input, strip_trailing_whitespace);
+        assert_eq!(strip("a  \nb\t\nc \t \n"), "a\nb\nc\n");
+        assert_eq!(strip("a b\n  \n\tc"), "a b\n\n\tc");
+        assert_eq!(strip("last line \t"), "last line");
+        assert_eq!(strip("\\cmd{x} \n\\cmd  "), "\\cmd{x}\n\\cmd");
+        assert_eq!(
+            strip("\\begin{verbatim}  \ncode  \n\\end{verbatim}  \n"),
+            "\\begin{verbatim}  \ncode  \n\\end{verbatim}\n"
+        );
+    }
+
+    #[test]
+    fn indentation() {
+        let convert = |input: &str, style| {
+            let mut tokens = crate::lexer::lex_tokens_owned(input).unwrap();
+            convert_indentation(&mut tokens, style, 4);
+            tokens.iter().map(|t| t.to_string()).collect::<String>()
+        };
+        let tabs = "\\begin{a}\n\tx\t y\n\t\t{z}\n  \tw\n\\end{a}\n";
+        let spaces = "\\begin{a}\n    x\t y\n        {z}\n    w\n\\end{a}\n";
+        assert_eq!(convert(tabs, IndentStyle::Space), spaces);
+        assert_eq!(
+            convert(spaces, IndentStyle::Tab),
+            tabs.replace("  \tw", "\tw")
+        );
+        assert_eq!(convert("      a\n", IndentStyle::Tab), "\t  a\n");
+        let verbatim = "\\begin{verbatim}\n\tcode\n\\end{verbatim}\n";
+        assert_eq!(convert(verbatim, IndentStyle::Space), verbatim);
+    }
+
+    #[test]
+    fn blank_lines() {
+        let collapse = |input, max| format(input, |tokens| collapse_blank_lines(tokens, max));
+        assert_eq!(collapse("a\nb\n", 1), "a\nb\n");
+        assert_eq!(collapse("a\n\nb\n", 1), "a\n\nb\n");
+        assert_eq!(collapse("a\n\n\n\nb\n", 1), "a\n\nb\n");
+        assert_eq!(collapse("a\n\n\n\n\nb\n", 1), "a\n\nb\n");
+        assert_eq!(collapse("a\n\n\n\n\n\nb\n", 1), "a\n\nb\n");
+        assert_eq!(collapse("a\n  \n\t\n \n  b\n", 1), "a\n  \n  b\n");
+        assert_eq!(collapse("\n\n\na\n\n\n", 1), "\na\n\n");
+        assert_eq!(collapse("a\n\n\nb", 0), "a\nb");
+        assert_eq!(collapse("a\n\n\n\nb", 2), "a\n\n\nb");
+        assert_eq!(
+            collapse("\\begin{verbatim}\n\n\n\n\\end{verbatim}", 1),
+            "\\begin{verbatim}\n\n\n\n\\end{verbatim}"
+        );
+    }
+
+    #[test]
+    fn spaces() {
+        let normalize = |input| format(input, |tokens| normalize_spaces(tokens));
+        assert_eq!(normalize("word1    word2\tword3\n"), "word1 word2 word3\n");
+        assert_eq!(
+            normalize("\t  a  \\cmd  {b  c}\n  d"),
+            "\t  a \\cmd {b c}\n  d"
+        );
+        assert_eq!(normalize("a  % comment\n"), "a  % comment\n");
+        assert_eq!(
+            normalize("\\begin{tabular}{ll}\na  & b\n\\end{tabular}  x"),
+            "\\begin{tabular}{ll}\na  & b\n\\end{tabular} x"
+        );
+        assert_eq!(
+            normalize("\\begin{verbatim}a   b\\end{verbatim}"),
+            "\\begin{verbatim}a   b\\end{verbatim}"
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let normalize = |input: &str, single_space| {
+            let mut tokens = crate::lexer::lex_tokens_owned(input).unwrap();
+            normalize_comments(&mut tokens, single_space);
+            tokens.iter().map(|t| t.to_string()).collect::<String>()
+        };
+        assert_eq!(normalize("%hello\n", true), "% hello\n");
+        assert_eq!(normalize("%   hello\n", true), "% hello\n");
+        assert_eq!(normalize("a %\thello", true), "a % hello");
+        assert_eq!(normalize("% hello", false), "%hello");
+        assert_eq!(
+            normalize("%!TeX root=main.tex", true),
+            "%!TeX root=main.tex"
+        );
+        for magic in [
+            "%!TeX program = lualatex\n",
+            "% !TeX root = main.tex\n",
+            "%   !tex spellcheck = en_US\n",
+            "%\t!TEX encoding = UTF-8\n",
+        ] {
+            assert_eq!(normalize(magic, true), magic);
+            assert_eq!(normalize(magic, false), magic);
+        }
+        assert_eq!(normalize("%  !important\n", true), "% !important\n");
+        assert_eq!(normalize("%%% Title %%%", true), "%%% Title %%%");
+        assert_eq!(normalize("%=====\n%  ---", true), "%=====\n%  ---");
+        assert_eq!(normalize("%\n%  \n", true), "%\n%  \n");
+    }
+}
@@ -0,0 +1,379 @@
+//! Align the columns of tables on the `&` separators.
+
+use super::reflow::width;
+use crate::lexer::Token;
+
+/// Environments whose rows are aligned on `&`.
+const ALIGN_ENVIRONMENTS: &[&str] = &[
+    "align", "align*", "alignat", "alignat*", "array", "bmatrix", "matrix", "pmatrix", "tabular",
+    "tabular*", "tabularx",
+];
+
+/// Horizontal rules of tables, which go on lines of their own.
+const RULE_COMMANDS: &[&str] = &[
+    "bottomrule",
+    "cline",
+    "cmidrule",
+    "hhline",
+    "hline",
+    "midrule",
+    "specialrule",
+    "toprule",
+];
+
+/// Row of a table, split at the top-level `&`.
+struct Row<S> {
+    /// Whitespace at the start of the line.
+    indent: Vec<Token<S>>,
+    /// Cells without the surrounding whitespace, and whether they were preceded by whitespace.
+    cells: Vec<(Vec<Token<S>>, bool)>,
+    /// `\\` ending the row, with everything following it.
+    tail: Vec<Token<S>>,
+}
+
+impl<S: AsRef<str> + From<String>> Row<S> {
+    /// Split a line into cells, or give it back if it contains no top-level `&`.
+    fn new(mut line: Vec<Token<S>>) -> Result<Self, Vec<Token<S>>> {
+        let mut depth = 0usize;
+        let mut last_separator = None;
+        let mut tail_start = None;
+        for (i, token) in line.iter().enumerate() {
+            match token {
+                Token::LBrace => depth += 1,
+                Token::RBrace => depth = depth.saturating_sub(1),
+                Token::Ampersand if depth == 0 => last_separator = Some(i),
+                Token::Endline | Token::EndlineSpaced(_) if depth == 0 => {
+                    tail_start = tail_start.or(Some(i))
+                }
+                _ => {}
+            }
+        }
+        let Some(last_separator) = last_separator else {
+            return Err(line);
+        };
+
+        let tail = match tail_start {
+            Some(i) if i > last_separator => line.split_off(i),
+            _ => Vec::new(),
+        };
+        let content = line
+            .iter()
+            .position(|t| !matches!(t, Token::Whitespace(_)))
+            .unwrap_or(line.len());
+        let indent = line.drain(..content).collect();
+
+        let mut depth = 0usize;
+        let mut cells = vec![(Vec::new(), false)];
+        for token in line {
+            let (cell, spaced) = cells.last_mut().unwrap();
+            match token {
+                Token::Ampersand if depth == 0 => cells.push((Vec::new(), false)),
+                Token::Whitespace(_) if cell.is_empty() => *spaced = true,
+                _ => {
+                    match token {
+                        Token::LBrace => depth += 1,
+                        Token::RBrace => depth = depth.saturating_sub(1),
+                        _ => {}
+                    }
+                    cell.push(token);
+                }
+            }
+        }
+        for (cell, _) in &mut cells {
+            while let Some(Token::Whitespace(_)) = cell.last() {
+                cell.pop();
+            }
+        }
+
+        Ok(Row {
+            indent,
+            cells,
+            tail,
+        })
+    }
+
+    /// Whether the cell in column `i` is followed by a separator, so that it must be padded.
+    fn is_padded(&self, i: usize) -> bool {
+        i + 1 < self.cells.len() || !self.tail.is_empty()
+    }
+
+    /// Join the cells, padding them to the width of their column.
+    ///
+    /// The cells in the columns marked in `spaced` are preceded by a space.
+    fn into_tokens(self, widths: &[usize], spaced: &[bool], tab_width: usize) -> Vec<Token<S>> {
+        let padded: Vec<_> = (0..self.cells.len()).map(|i| self.is_padded(i)).collect();
+        let mut tokens = self.indent;
+        for (i, (cell, _)) in self.cells.into_iter().enumerate() {
+            if i > 0 {
+                tokens.push(Token::Ampersand);
+                if spaced[i] && (padded[i] || !cell.is_empty()) {
+                    tokens.push(Token::Whitespace(" ".to_owned().into()));
+                }
+            }
+            let cell_width: usize = cell.iter().map(|token| width(token, tab_width)).sum();
+            tokens.extend(cell);
+            if padded[i] {
+                let padding = widths[i] - cell_width + 1;
+                tokens.push(Token::Whitespace(" ".repeat(padding).into()));
+            }
+        }
+        tokens.extend(self.tail);
+        tokens
+    }
+}
+
+/// Find the lines strictly between `\begin{env}` at the start of `tokens` and `\end{env}`.
+///
+/// Returns `None` if the environment is not closed or contains nested environments.
+fn body_lines<S>(tokens: &[Token<S>]) -> Option<std::ops::Range<usize>> {
+    let mut first_newline = None;
+    let mut last_newline = None;
+    for (i, token) in tokens.iter().enumerate().skip(1) {
+        match token {
+            Token::BeginEnv(..) => return None,
+            Token::EndEnv(..) => {
+                let start = first_newline? + 1;
+                let end = last_newline?;
+                return (start < end).then_some(start..end);
+            }
+            Token::Newline(_) => {
+                first_newline.get_or_insert(i);
+                last_newline = Some(i);
+            }
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Put the horizontal rules at the top level of `lines` on lines of their own, with the
+/// indentation of the line where they were.
+///
+/// A rule keeps its arguments, as in `\cline{1-2}` or `\cmidrule(lr){1-2}`, and a comment
+/// following it.
+fn split_rules<S>(lines: Vec<Token<S>>) -> Vec<Token<S>>
+where
+    S: AsRef<str> + Clone,
+{
+    let Some(newline) = lines
+        .iter()
+        .find(|t| matches!(t, Token::Newline(_)))
+        .cloned()
+    else {
+        return lines;
+    };
+    let is_argument = |token: &Token<S>| match token {
+        Token::LBrace | Token::LBracket => true,
+        Token::Text(text) => text.as_ref().starts_with('('),
+        _ => false,
+    };
+
+    let mut result: Vec<Token<S>> = Vec::with_capacity(lines.len());
+    // the start of the current line in `result`
+    let mut line_start = 0;
+    let mut depth = 0usize;
+    let mut tokens = lines.into_iter().peekable();
+    while let Some(token) = tokens.next() {
+        match &token {
+            Token::Command(name) if depth == 0 && RULE_COMMANDS.contains(&name.as_ref()) => {
+                let indent: Vec<_> = result[line_start..]
+                    .iter()
+                    .take_while(|t| matches!(t, Token::Whitespace(_)))
+                    .cloned()
+                    .collect();
+                if indent.len() < result.len() - line_start {
+                    while let Some(Token::Whitespace(_)) = result.last() {
+                        result.pop();
+                    }
+                    result.push(newline.clone());
+                    line_start = result.len();
+                    result.extend(indent.iter().cloned());
+                }
+                result.push(token);
+                // the arguments, within which the depth returns to zero
+                let mut nesting = 0usize;
+                while let Some(next) = tokens.next_if(|t| nesting > 0 || is_argument(t)) {
+                    match next {
+                        Token::LBrace | Token::LBracket => nesting += 1,
+                        Token::RBrace | Token::RBracket => nesting = nesting.saturating_sub(1),
+                        _ => {}
+                    }
+                    result.push(next);
+                }
+                let mut spaces = Vec::new();
+                while let Some(space) = tokens.next_if(|t| matches!(t, Token::Whitespace(_))) {
+                    spaces.push(space);
+                }
+                match tokens.peek() {
+                    None | Some(Token::Newline(_) | Token::Comment(_)) => result.extend(spaces),
+                    Some(_) => {
+                        result.push(newline.clone());
+                        line_start = result.len();
+                        result.extend(indent);
+                    }
+                }
+                continue;
+            }
+            Token::LBrace => depth += 1,
+            Token::RBrace => depth = depth.saturating_sub(1),
+            Token::Newline(_) => line_start = result.len() + 1,
+            _ => {}
+        }
+        result.push(token);
+    }
+    result
+}
+
+/// Align the cells of the rows in `lines`, which are separated by newlines.
+///
+/// The lines are returned unchanged if a group spans multiple lines.
+fn align_rows<S>(lines: Vec<Token<S>>, tab_width: usize) -> Vec<Token<S>>
+where
+    S: AsRef<str> + From<String>,
+{
+    let mut depth = 0usize;
+    for token in &lines {
+        match token {
+            Token::LBrace => depth += 1,
+            Token::RBrace => depth = depth.saturating_sub(1),
+            Token::Newline(_) if depth > 0 => return lines,
+            _ => {}
+        }
+    }
+
+    // rows to align, and lines without separators to leave alone
+    let mut rows = Vec::new();
+    let mut newlines = Vec::new();
+    let mut line = Vec::new();
+    for token in lines {
+        if matches!(token, Token::Newline(_)) {
+            rows.push(Row::new(std::mem::take(&mut line)));
+            newlines.push(token);
+        } else {
+            line.push(token);
+        }
+    }
+    rows.push(Row::new(line));
+
+    let columns = rows.iter().flatten().map(|row| row.cells.len()).max();
+    let mut widths = vec![0; columns.unwrap_or(0)];
+    let mut spaced = vec![false; columns.unwrap_or(0)];
+    for row in rows.iter().flatten() {
+        for (i, (cell, cell_spaced)) in row.cells.iter().enumerate() {
+            if row.is_padded(i) {
+                let cell_width = cell.iter().map(|token| width(token, tab_width)).sum();
+                widths[i] = widths[i].max(cell_width);
+            }
+            spaced[i] |= *cell_spaced;
+        }
+    }
+
+    let mut result = Vec::new();
+    let mut newlines = newlines.into_iter();
+    for (i, row) in rows.into_iter().enumerate() {
+        if i > 0 {
+            result.extend(newlines.next());
+        }
+        match row {
+            Ok(row) => result.extend(row.into_tokens(&widths, &spaced, tab_width)),
+            Err(line) => result.extend(line),
+        }
+    }
+    result
+}
+
+/// Pad the cells of tables so that the `&` separators line up in columns.
+///
+/// The rows of `tabular`, `array`, `align` and matrix environments are aligned, except
+/// for the lines containing `\begin{env}` and `\end{env}`. Cells are left-aligned, and
+/// rows with fewer cells are padded as far as they go. The horizontal rules such as
+/// `\hline` and `\midrule` are put on lines of their own. Environments containing
+/// other environments, or groups spanning multiple lines, are left untouched.
+/// The widths of the cells are measured with a tab taking `tab_width` columns.
+pub fn align_tables<S>(tokens: &mut Vec<Token<S>>, tab_width: usize)
+where
+    S: AsRef<str> + Clone + From<String>,
+{
+    let mut i = 0;
+    while i < tokens.len() {
+        if let Token::BeginEnv(name, _) = &tokens[i] {
+            if ALIGN_ENVIRONMENTS.contains(&name.as_ref()) {
+                if let Some(body) = body_lines(&tokens[i..]) {
+                    let start = i + body.start;
+                    let lines: Vec<_> = tokens.drain(start..i + body.end).collect();
+                    let aligned = align_rows(split_rules(lines), tab_width);
+                    i = start + aligned.len();
+                    tokens.splice(start..start, aligned);
+                    continue;
+                }
+            }
+        }
+        i += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::lex_tokens_owned;
+
+    fn align(input: &str) -> String {
+        let mut tokens = lex_tokens_owned(input).unwrap();
+        super::align_tables(&mut tokens, 8);
+        tokens.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn table() {
+        assert_eq!(
+            align("\\begin{tabular}{ll}\n  a & bb \\\\\n  ccc & d\\\\ \\hline\n  ee  &   f\n\\end{tabular}\n"),
+            "\\begin{tabular}{ll}\n  a   & bb \\\\\n  ccc & d  \\\\\n  \\hline\n  ee  & f\n\\end{tabular}\n"
+        );
+    }
+
+    #[test]
+    fn rules() {
+        assert_eq!(
+            align("\\begin{tabular}{ll}\n  \\toprule a & bb \\\\ \\midrule\n  ccc & d \\\\\\cline{1-2}  \\cmidrule(lr){1-1} ee & f\\\\  \\bottomrule % end\n\\end{tabular}\n"),
+            "\\begin{tabular}{ll}\n  \\toprule\n  a   & bb \\\\\n  \\midrule\n  ccc & d  \\\\\n  \\cline{1-2}\n  \\cmidrule(lr){1-1}\n  ee  & f  \\\\\n  \\bottomrule % end\n\\end{tabular}\n"
+        );
+        // not at the top level
+        let src =
+            "\\begin{tabular}{ll}\na & \\multicolumn{1}{c}{b \\hline} \\\\\nc & d\n\\end{tabular}";
+        assert_eq!(align(src), src);
+    }
+
+    #[test]
+    fn equations() {
+        assert_eq!(
+            align("\\begin{align}\nx &= 1 \\\\\nyy &= 22\n\\end{align}"),
+            "\\begin{align}\nx  &= 1 \\\\\nyy &= 22\n\\end{align}"
+        );
+    }
+
+    #[test]
+    fn unicode() {
+        assert_eq!(
+            align("\\begin{tabular}{ll}\nété & a \\\\\n京 & b \\\\\ne\u{301} & c\n\\end{tabular}"),
+            "\\begin{tabular}{ll}\nété & a \\\\\n京  & b \\\\\ne\u{301}   & c\n\\end{tabular}"
+        );
+    }
+
+    #[test]
+    fn ragged() {
+        assert_eq!(
+            align("\\begin{tabular}{lll}\na & b & c \\\\\n\\hline\nlong & {x & y} \\\\\n& \\\\\n\\end{tabular}"),
+            "\\begin{tabular}{lll}\na    & b       & c \\\\\n\\hline\nlong & {x & y} \\\\\n     &         \\\\\n\\end{tabular}"
+        );
+    }
+
+    #[test]
+    fn untouched() {
+        for src in [
+            "\\begin{tabular}{ll}\na & {b\nc} \\\\\nd & e\n\\end{tabular}",
+            "\\begin{tabular}{ll}\na & b \\begin{x}\\end{x}\nc & dd\n\\end{tabular}",
+            "\\begin{itemize}\na & b\nccc & d\n\\end{itemize}",
+        ] {
+            assert_eq!(align(src), src);
+        }
+    }
+}
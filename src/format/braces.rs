@@ -0,0 +1,202 @@
+//! Remove the braces around single words of text.
+
+use crate::lexer::Token;
+
+use super::{Environments, NO_REFLOW_ENVIRONMENTS};
+
+/// Whether TeX skips `token` when looking for the arguments of a command.
+fn is_blank<S>(token: &Token<S>) -> bool {
+    matches!(
+        token,
+        Token::Whitespace(_) | Token::Newline(_) | Token::Comment(_)
+    )
+}
+
+/// The last token of `tokens` which is not blank.
+fn last_non_blank<'a, 'b>(tokens: &'a [Token<&'b str>]) -> Option<&'a Token<&'b str>> {
+    tokens.iter().rev().find(|token| !is_blank(token))
+}
+
+/// Whether the last character of `before` and the first character of `after` would form a
+/// ligature, such as `fi`, if the braces between them were removed.
+fn forms_ligature(before: Option<char>, after: Option<char>) -> bool {
+    before == Some('f') && matches!(after, Some('f' | 'i' | 'l'))
+}
+
+/// Whether `text` ends with a control symbol, such as the accent `\~`, which would take the
+/// word after it as its argument.
+fn ends_with_control_symbol(text: &str) -> bool {
+    let mut chars = text.chars().rev();
+    chars.next().is_some() && chars.next() == Some('\\')
+}
+
+/// Whether the group of `tokens` opening at `index`, which is not protected, only wraps a
+/// word which can do without it.
+fn is_redundant(tokens: &[Token<&str>], index: usize) -> bool {
+    let word = match tokens.get(index..index + 3) {
+        Some([Token::LBrace, Token::Text(word), Token::RBrace]) => *word,
+        _ => return false,
+    };
+    if !word.chars().all(char::is_alphanumeric) {
+        return false;
+    }
+    let before = &tokens[..index];
+    // after a command, even with spaces in between, the group is an argument
+    let last = match last_non_blank(before) {
+        None => None,
+        Some(Token::Text(text)) => Some(*text),
+        Some(_) => return false,
+    };
+    if let Some(text) = last {
+        let rest = &before[..before
+            .iter()
+            .rposition(|t| matches!(t, Token::Text(_)))
+            .unwrap()];
+        if ends_with_control_symbol(text) || matches!(last_non_blank(rest), Some(Token::Command(_)))
+        {
+            return false;
+        }
+    }
+    let previous = match before.last() {
+        Some(Token::Text(text)) => text.chars().last(),
+        _ => None,
+    };
+    let next = match tokens.get(index + 3) {
+        Some(Token::Text(text)) => text.chars().next(),
+        _ => None,
+    };
+    !forms_ligature(previous, word.chars().next()) && !forms_ligature(word.chars().last(), next)
+}
+
+/// The indices of the braces of `tokens` to remove.
+fn redundant_braces(tokens: &[Token<&str>]) -> Vec<usize> {
+    let mut environments = Environments::default();
+    let mut math = 0usize;
+    let mut brackets = 0usize;
+    // whether each open group is an argument, or inside one
+    let mut groups: Vec<bool> = Vec::new();
+    let mut braces = Vec::new();
+    let mut index = 0;
+    while index < tokens.len() {
+        let token = &tokens[index];
+        environments.update(token, NO_REFLOW_ENVIRONMENTS);
+        match token {
+            Token::InlineMath | Token::TDisplayMath if math > 0 => math -= 1,
+            Token::InlineMath | Token::TDisplayMath | Token::BInlineMath | Token::BDisplayMath => {
+                math += 1
+            }
+            Token::EInlineMath | Token::EDisplayMath => math = math.saturating_sub(1),
+            Token::LBracket => brackets += 1,
+            Token::RBracket => brackets = brackets.saturating_sub(1),
+            Token::LBrace => {
+                let protected = groups.last() == Some(&true) || brackets > 0;
+                if math == 0 && !environments.inside() && !protected && is_redundant(tokens, index)
+                {
+                    braces.extend([index, index + 2]);
+                    index += 3;
+                    continue;
+                }
+                let argument = !matches!(
+                    last_non_blank(&tokens[..index]),
+                    None | Some(Token::Text(_))
+                );
+                groups.push(protected || argument);
+            }
+            Token::RBrace => {
+                groups.pop();
+            }
+            _ => {}
+        }
+        index += 1;
+    }
+    braces
+}
+
+/// Remove the braces around single words of the text, as in `a{b}c`, which become `abc`.
+///
+/// This is very conservative, and keeps the braces which might change anything: those after
+/// commands, even if they are not their arguments, and inside them, those in math and
+/// tabular environments, around anything but letters and digits, and those preventing
+/// ligatures, as in `f{f}`.
+pub fn remove_redundant_braces<S>(tokens: &mut Vec<Token<S>>)
+where
+    S: AsRef<str> + From<String>,
+{
+    remove_redundant_braces_after::<S, &str>(tokens, &[]);
+}
+
+/// Remove the braces around single words of the text in `tokens`, as if they followed
+/// `context`.
+pub(crate) fn remove_redundant_braces_after<S, T>(tokens: &mut Vec<Token<S>>, context: &[Token<T>])
+where
+    S: AsRef<str> + From<String>,
+    T: AsRef<str>,
+{
+    let all: Vec<Token<&str>> = context
+        .iter()
+        .map(Token::as_ref)
+        .chain(tokens.iter().map(Token::as_ref))
+        .collect();
+    let braces = redundant_braces(&all);
+    if braces.is_empty() {
+        return;
+    }
+    let mut braces = braces
+        .into_iter()
+        .map(|index| index - context.len())
+        .peekable();
+    let mut result: Vec<Token<S>> = Vec::with_capacity(tokens.len());
+    for (index, token) in tokens.drain(..).enumerate() {
+        if braces.next_if_eq(&index).is_some() {
+            continue;
+        }
+        match (result.last_mut(), token) {
+            // the word is joined to the text around it
+            (Some(Token::Text(last)), Token::Text(text)) => {
+                *last = S::from(format!("{}{}", last.as_ref(), text.as_ref()));
+            }
+            (_, token) => result.push(token),
+        }
+    }
+    *tokens = result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_tokens_owned;
+    use crate::writer::write_tokens;
+
+    fn remove(input: &str) -> String {
+        let mut tokens = lex_tokens_owned(input).unwrap();
+        remove_redundant_braces(&mut tokens);
+        write_tokens(&tokens)
+    }
+
+    #[test]
+    fn braces() {
+        assert_eq!(remove("a{b}c"), "abc");
+        assert_eq!(remove("{word} and {x1}."), "word and x1.");
+        assert_eq!(remove("\\textbf{b}"), "\\textbf{b}");
+        assert_eq!(remove("\\textbf {b}"), "\\textbf {b}");
+        assert_eq!(remove("\\frac{a}{b}"), "\\frac{a}{b}");
+        assert_eq!(remove("\\emph{a{b}}"), "\\emph{a{b}}");
+        assert_eq!(remove("\\section[a{b}]{c}"), "\\section[a{b}]{c}");
+        assert_eq!(remove("\\def\\x a{b}"), "\\def\\x a{b}");
+        assert_eq!(remove("{\\bfseries b}"), "{\\bfseries b}");
+        assert_eq!(remove("{a b}"), "{a b}");
+        assert_eq!(remove("{}"), "{}");
+        assert_eq!(remove("\\~{n}"), "\\~{n}");
+        assert_eq!(remove("f{f}i o{f} {f}ine"), "f{f}i of {f}ine");
+    }
+
+    #[test]
+    fn math() {
+        assert_eq!(remove("$a{b}c$ d{e}"), "$a{b}c$ de");
+        assert_eq!(remove("\\[a{b}\\]"), "\\[a{b}\\]");
+        assert_eq!(
+            remove("\\begin{tabular}{l}\na{b}\n\\end{tabular}"),
+            "\\begin{tabular}{l}\na{b}\n\\end{tabular}"
+        );
+    }
+}
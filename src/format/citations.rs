@@ -0,0 +1,144 @@
+//! Normalize the lists of keys of the citation commands.
+
+use crate::lexer::Token;
+
+use super::packages::group_end;
+
+/// Commands citing a list of keys, of LaTeX, natbib and biblatex.
+pub const CITATION_COMMANDS: &[&str] = &[
+    "Autocite",
+    "Citeauthor",
+    "Citep",
+    "Citet",
+    "Parencite",
+    "Textcite",
+    "autocite",
+    "cite",
+    "citealp",
+    "citealt",
+    "citeauthor",
+    "citep",
+    "citet",
+    "citeyear",
+    "citeyearpar",
+    "footcite",
+    "nocite",
+    "parencite",
+    "textcite",
+];
+
+/// The normalized list of keys of the required argument `{a, b}`, if it is plain text and
+/// it changes.
+///
+/// The empty keys are dropped, unless they are all empty, and so is the list if a key
+/// contains a space.
+fn key_list<S: AsRef<str>>(argument: &[Token<S>]) -> Option<String> {
+    let mut list = String::new();
+    for token in &argument[1..argument.len() - 1] {
+        match token {
+            Token::Text(text) => list.push_str(text.as_ref()),
+            Token::Whitespace(_) | Token::Newline(_) => list.push(' '),
+            _ => return None,
+        }
+    }
+    let keys: Vec<&str> = list
+        .split(',')
+        .map(str::trim)
+        .filter(|key| !key.is_empty())
+        .collect();
+    if keys.is_empty() || keys.iter().any(|key| key.contains(' ')) {
+        return None;
+    }
+    let keys = keys.join(",");
+    (argument.len() != 3 || keys != list).then_some(keys)
+}
+
+/// Remove the spaces and the empty keys in the lists of keys of the citation `commands`, so
+/// that `\cite{a, b ,c}` becomes `\cite{a,b,c}`.
+///
+/// The optional arguments, such as the notes of `\citep[see][p.~5]{key}`, are untouched,
+/// and so are the lists with anything but text, such as comments or commands.
+pub fn normalize_citations<S>(tokens: &mut Vec<Token<S>>, commands: &[String])
+where
+    S: AsRef<str> + From<String>,
+{
+    let mut i = 0;
+    while i < tokens.len() {
+        let cites = matches!(&tokens[i], Token::Command(name) if commands.iter().any(|c| c == name.as_ref()));
+        i += 1;
+        if !cites {
+            continue;
+        }
+        // the starred variant, and the optional arguments
+        if matches!(&tokens.get(i), Some(Token::Text(text)) if text.as_ref() == "*") {
+            i += 1;
+        }
+        let mut start = i;
+        loop {
+            while matches!(tokens.get(start), Some(Token::Whitespace(_))) {
+                start += 1;
+            }
+            match tokens.get(start) {
+                Some(Token::LBracket) => match group_end(tokens, start) {
+                    Some(end) => start = end,
+                    None => break,
+                },
+                _ => break,
+            }
+        }
+        if !matches!(tokens.get(start), Some(Token::LBrace)) {
+            continue;
+        }
+        let Some(end) = group_end(tokens, start) else {
+            continue;
+        };
+        if let Some(keys) = key_list(&tokens[start..end]) {
+            tokens.splice(start + 1..end - 1, [Token::Text(keys.into())]);
+            i = start + 3;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_tokens_owned;
+    use crate::writer::write_tokens;
+
+    fn normalize(input: &str) -> String {
+        let commands: Vec<String> = CITATION_COMMANDS.iter().map(|&c| c.to_owned()).collect();
+        let mut tokens = lex_tokens_owned(input).unwrap();
+        normalize_citations(&mut tokens, &commands);
+        write_tokens(&tokens)
+    }
+
+    #[test]
+    fn keys() {
+        assert_eq!(normalize("\\cite{a, b ,c}"), "\\cite{a,b,c}");
+        assert_eq!(normalize("\\cite{a,\n  b,}."), "\\cite{a,b}.");
+        assert_eq!(normalize("\\cite*{ a }"), "\\cite*{a}");
+        assert_eq!(normalize("\\cite {a, b}"), "\\cite {a,b}");
+        assert_eq!(normalize("\\nocite{*}"), "\\nocite{*}");
+        assert_eq!(normalize("\\cite{a b, c}"), "\\cite{a b, c}");
+        assert_eq!(normalize("\\cite{a, % b\n c}"), "\\cite{a, % b\n c}");
+        assert_eq!(normalize("\\cite{a, \\x}"), "\\cite{a, \\x}");
+        assert_eq!(normalize("\\ref{a, b}"), "\\ref{a, b}");
+        assert_eq!(normalize("\\cite{ , }"), "\\cite{ , }");
+    }
+
+    #[test]
+    fn optional_arguments() {
+        assert_eq!(
+            normalize("\\citep[see][p.~5]{a, b}"),
+            "\\citep[see][p.~5]{a,b}"
+        );
+        assert_eq!(
+            normalize("\\citep[see, e.g.,][]{key}"),
+            "\\citep[see, e.g.,][]{key}"
+        );
+        assert_eq!(
+            normalize("\\cite[{p, 5}]{a, b} \\cite{c, d}"),
+            "\\cite[{p, 5}]{a,b} \\cite{c,d}"
+        );
+    }
+}
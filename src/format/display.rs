@@ -0,0 +1,205 @@
+//! Put the display math on its own lines.
+
+use crate::lexer::{NewlineKind, Token};
+
+use super::break_line;
+
+/// Environments typesetting their body as display math.
+const DISPLAY_MATH_ENVIRONMENTS: &[&str] = &[
+    "align",
+    "align*",
+    "alignat",
+    "alignat*",
+    "displaymath",
+    "eqnarray",
+    "eqnarray*",
+    "equation",
+    "equation*",
+    "flalign",
+    "flalign*",
+    "gather",
+    "gather*",
+    "multline",
+    "multline*",
+];
+
+/// Delimiters of display math, tracking whether `$$` opens or closes it.
+#[derive(Default)]
+struct Delimiters {
+    groups: usize,
+    double_dollar: bool,
+}
+
+impl Delimiters {
+    /// Whether `token` opens display math, closes it, or neither, and update the state.
+    fn update<S: AsRef<str>>(&mut self, token: &Token<S>) -> Option<bool> {
+        let top_level = self.groups == 0;
+        let opens = match token {
+            Token::LBrace => {
+                self.groups += 1;
+                return None;
+            }
+            Token::RBrace => {
+                self.groups = self.groups.saturating_sub(1);
+                return None;
+            }
+            Token::TDisplayMath => {
+                self.double_dollar = !self.double_dollar;
+                self.double_dollar
+            }
+            Token::BDisplayMath => true,
+            Token::EDisplayMath => false,
+            Token::BeginEnv(name, _) if DISPLAY_MATH_ENVIRONMENTS.contains(&name.as_ref()) => true,
+            Token::EndEnv(name, _) if DISPLAY_MATH_ENVIRONMENTS.contains(&name.as_ref()) => false,
+            _ => return None,
+        };
+        top_level.then_some(opens)
+    }
+}
+
+/// What follows a delimiter of display math on its line.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum After {
+    /// Not after a delimiter.
+    Nothing,
+    /// After an opening delimiter, followed by its arguments and its `\label`, with the
+    /// number of open braces.
+    Opening(usize),
+    /// After the arguments and the label of an opening delimiter, or after a closing one.
+    Break,
+}
+
+/// Put every delimiter of display math on its own line: `\[`, `\]`, `$$`, and the `\begin`
+/// and `\end` of display math environments such as `equation` and `align`.
+///
+/// The arguments of an environment, as in `\begin{alignat}{2}`, a `\label` and a comment
+/// stay on the line of the opening delimiter, and a comment on the line of a closing one.
+/// The delimiters inside groups are left alone, and the math itself is not changed:
+/// [`indent`](super::indent) indents it, and [`reflow`](super::reflow) never wraps it.
+pub fn break_display_math<S>(tokens: &mut Vec<Token<S>>, newline: NewlineKind)
+where
+    S: AsRef<str>,
+{
+    break_display_math_after::<S, &str>(tokens, newline, &[]);
+}
+
+/// Put the display math in `tokens` on its own lines, as if they followed `context`,
+/// which ends with a newline.
+pub(crate) fn break_display_math_after<S, T>(
+    tokens: &mut Vec<Token<S>>,
+    newline: NewlineKind,
+    context: &[Token<T>],
+) where
+    S: AsRef<str>,
+    T: AsRef<str>,
+{
+    let mut delimiters = Delimiters::default();
+    for token in context {
+        delimiters.update(token);
+    }
+    let mut after = After::Nothing;
+    // the whitespace after a delimiter, dropped if the line is broken
+    let mut space = None;
+    let mut result = Vec::with_capacity(tokens.len());
+    for token in tokens.drain(..) {
+        match (after, &token) {
+            (After::Nothing, _) => {}
+            (_, Token::Whitespace(_)) if !matches!(after, After::Opening(1..)) => {
+                space = Some(token);
+                continue;
+            }
+            (_, Token::Newline(_) | Token::Comment(_)) if !matches!(after, After::Opening(1..)) => {
+                result.extend(space.take());
+                after = After::Nothing;
+            }
+            (After::Opening(braces), _) => {
+                let keep = match &token {
+                    Token::LBrace => Some(braces + 1),
+                    Token::RBrace if braces > 0 => Some(braces - 1),
+                    Token::Command(name) if braces == 0 => (name.as_ref() == "label").then_some(0),
+                    _ if braces > 0 => Some(braces),
+                    _ => None,
+                };
+                if let Some(braces) = keep {
+                    result.extend(space.take());
+                    after = After::Opening(braces);
+                    delimiters.update(&token);
+                    result.push(token);
+                    continue;
+                }
+                space = None;
+                result.push(Token::Newline(newline));
+                after = After::Nothing;
+            }
+            (After::Break, _) => {
+                space = None;
+                result.push(Token::Newline(newline));
+                after = After::Nothing;
+            }
+        }
+
+        if let Some(opens) = delimiters.update(&token) {
+            break_line(&mut result, newline);
+            after = if opens {
+                After::Opening(0)
+            } else {
+                After::Break
+            };
+        }
+        result.push(token);
+    }
+    result.extend(space);
+    *tokens = result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_tokens_owned;
+    use crate::writer::write_tokens;
+
+    fn break_display_math(input: &str) -> String {
+        let mut tokens = lex_tokens_owned(input).unwrap();
+        super::break_display_math(&mut tokens, NewlineKind::Lf);
+        write_tokens(&tokens)
+    }
+
+    #[test]
+    fn inline() {
+        assert_eq!(
+            break_display_math("text \\[ a+b \\] text"),
+            "text\n\\[\na+b\n\\]\ntext"
+        );
+        assert_eq!(
+            break_display_math("a $$x$$, b $$y$$"),
+            "a\n$$\nx\n$$\n, b\n$$\ny\n$$"
+        );
+        assert_eq!(
+            break_display_math("a \\begin{equation} x \\end{equation} b"),
+            "a\n\\begin{equation}\nx\n\\end{equation}\nb"
+        );
+    }
+
+    #[test]
+    fn headers() {
+        assert_eq!(
+            break_display_math("\\begin{alignat}{2} \\label{eq:a} a &= b \\end{alignat} % c"),
+            "\\begin{alignat}{2} \\label{eq:a}\na &= b\n\\end{alignat} % c"
+        );
+        assert_eq!(
+            break_display_math("\\[ % note\n  x\n\\]\n"),
+            "\\[ % note\n  x\n\\]\n"
+        );
+    }
+
+    #[test]
+    fn untouched() {
+        for src in [
+            "text\n\\[\n  a+b\n\\]\ntext\n",
+            "a $x$ b \\(y\\) \\begin{math}z\\end{math}",
+            "\\fbox{\\[x\\]} \\begin{tabular}{l}a\\end{tabular}",
+        ] {
+            assert_eq!(break_display_math(src), src);
+        }
+    }
+}
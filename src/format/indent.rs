@@ -0,0 +1,253 @@
+//! Indent lines according to their nesting depth.
+
+use crate::lexer::Token;
+
+/// Environments whose body is not indented.
+const NO_INDENT_ENVIRONMENTS: &[&str] = &["document"];
+
+/// Group open at some point of the indentation.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Group {
+    /// Opened by `{`, or by display math.
+    Brace,
+    /// Opened by `\bgroup`.
+    Implicit,
+    /// Opened by `\begingroup`.
+    Semisimple,
+}
+
+/// Nesting depth of environments and groups.
+#[derive(Default)]
+struct Depth {
+    /// Open environments, and whether they indent their body.
+    environments: Vec<bool>,
+    /// Open braces, display math and groups of `\begingroup` or `\bgroup`.
+    groups: Vec<Group>,
+    /// Whether `$$` display math is open.
+    double_dollar: bool,
+}
+
+impl Depth {
+    fn update<S: AsRef<str>>(&mut self, token: &Token<S>) {
+        match token {
+            Token::BeginEnv(name, _) => self
+                .environments
+                .push(!NO_INDENT_ENVIRONMENTS.contains(&name.as_ref())),
+            Token::EndEnv(..) => {
+                self.environments.pop();
+            }
+            Token::LBrace | Token::BDisplayMath => self.groups.push(Group::Brace),
+            Token::RBrace | Token::EDisplayMath => {
+                // a brace also closes the groups opened inside it without a brace, such as
+                // the `\begingroup` in `\newenvironment{a}{\begingroup}{\endgroup}`, and
+                // a `\bgroup` if no brace is open, but never a `\begingroup` alone
+                let open = self
+                    .groups
+                    .iter()
+                    .rposition(|&group| group == Group::Brace)
+                    .or_else(|| {
+                        self.groups
+                            .iter()
+                            .rposition(|&group| group == Group::Implicit)
+                    });
+                if let Some(open) = open {
+                    self.groups.truncate(open);
+                }
+            }
+            Token::TDisplayMath => self.double_dollar = !self.double_dollar,
+            token if token.begins_implicit_group() => self.groups.push(Group::Implicit),
+            token if token.begins_semisimple_group() => self.groups.push(Group::Semisimple),
+            // a group opened by a brace is only closed by a brace
+            token
+                if token.ends_implicit_group() && self.groups.last() == Some(&Group::Implicit) =>
+            {
+                self.groups.pop();
+            }
+            token
+                if token.ends_semisimple_group()
+                    && self.groups.last() == Some(&Group::Semisimple) =>
+            {
+                self.groups.pop();
+            }
+            _ => {}
+        }
+    }
+
+    fn get(&self) -> usize {
+        self.environments.iter().filter(|&&indent| indent).count()
+            + self.groups.len()
+            + usize::from(self.double_dollar)
+    }
+}
+
+/// Indent every line by `unit` repeated as many times as its nesting depth.
+///
+/// The depth increases inside environments, except `document`, and inside groups and
+/// `\[...\]` or `$$...$$` display math spanning multiple lines. The groups are opened by
+/// `{`, `\begingroup` or `\bgroup`, and closed by `}`, `\endgroup` or `\egroup`, with
+/// `\begingroup` only closed by `\endgroup`.
+/// A line starting with `\end{env}`, `}`, `\endgroup`, `\egroup`, `\]` or a closing `$$`
+/// is dedented to match the line that opened them. Blank lines are left empty.
+pub fn indent<S>(tokens: &mut Vec<Token<S>>, unit: &str)
+where
+    S: AsRef<str> + From<String>,
+{
+    indent_after::<S, &str>(tokens, unit, &[]);
+}
+
+/// Indent the lines of `tokens` as if they followed `context`, which ends with a newline.
+pub(crate) fn indent_after<S, T>(tokens: &mut Vec<Token<S>>, unit: &str, context: &[Token<T>])
+where
+    S: AsRef<str> + From<String>,
+    T: AsRef<str>,
+{
+    let mut result = Vec::with_capacity(tokens.len());
+    let mut depth = Depth::default();
+    for token in context {
+        depth.update(token);
+    }
+    let mut line_start = true;
+    // closing tokens at the start of the line, which affect its indentation
+    let mut closing = Vec::new();
+
+    let indentation = |depth: &Depth| {
+        let depth = depth.get();
+        (depth > 0).then(|| Token::Whitespace(unit.repeat(depth).into()))
+    };
+
+    for token in std::mem::take(tokens) {
+        if line_start {
+            match token {
+                Token::Whitespace(_) if closing.is_empty() => continue,
+                Token::Newline(_) if closing.is_empty() => {
+                    result.push(token);
+                    continue;
+                }
+                Token::EndEnv(..) | Token::RBrace | Token::EDisplayMath => {
+                    depth.update(&token);
+                    closing.push(token);
+                    continue;
+                }
+                Token::Command(_)
+                    if token.ends_implicit_group() || token.ends_semisimple_group() =>
+                {
+                    depth.update(&token);
+                    closing.push(token);
+                    continue;
+                }
+                Token::TDisplayMath if depth.double_dollar => {
+                    depth.update(&token);
+                    closing.push(token);
+                    continue;
+                }
+                _ => {
+                    result.extend(indentation(&depth));
+                    result.append(&mut closing);
+                    line_start = false;
+                }
+            }
+        }
+        if matches!(token, Token::Newline(_)) {
+            line_start = true;
+        } else {
+            depth.update(&token);
+        }
+        result.push(token);
+    }
+    if !closing.is_empty() {
+        result.extend(indentation(&depth));
+        result.append(&mut closing);
+    }
+
+    *tokens = result;
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::lexer::lex_tokens_owned;
+
+    fn indent(input: &str, unit: &str) -> String {
+        let mut tokens = lex_tokens_owned(input).unwrap();
+        super::indent(&mut tokens, unit);
+        tokens.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn nested_lists() {
+        assert_eq!(
+            indent(
+                "\\begin{enumerate}\n\\item a\n\t\\begin{itemize}\n      \\item b\n  \\end{itemize}\n\\end{enumerate}\n",
+                "  "
+            ),
+            "\\begin{enumerate}\n  \\item a\n  \\begin{itemize}\n    \\item b\n  \\end{itemize}\n\\end{enumerate}\n"
+        );
+        assert_eq!(
+            indent("\\begin{a}\n\\begin{b}\nx\n\\end{b}\\end{a}\n", "\t"),
+            "\\begin{a}\n\t\\begin{b}\n\t\tx\n\\end{b}\\end{a}\n"
+        );
+    }
+
+    #[test]
+    fn document() {
+        assert_eq!(
+            indent(
+                "\\begin{document}\n  text\n\\begin{abstract}\nx\n\n\\end{abstract}\n\\end{document}",
+                "  "
+            ),
+            "\\begin{document}\ntext\n\\begin{abstract}\n  x\n\n\\end{abstract}\n\\end{document}"
+        );
+    }
+
+    #[test]
+    fn groups() {
+        assert_eq!(
+            indent("\\newcommand{\\x}{%\nx\n    }\n{a}\n{\n} {\nb\n}", "  "),
+            "\\newcommand{\\x}{%\n  x\n}\n{a}\n{\n} {\n  b\n}"
+        );
+        assert_eq!(
+            indent("\\begin{a}\n\\[\nx\n\\]\n\\end{a}", "  "),
+            "\\begin{a}\n  \\[\n    x\n  \\]\n\\end{a}"
+        );
+        assert_eq!(
+            indent("$$\nx\n$$\n$$ y $$\n", "  "),
+            "$$\n  x\n$$\n$$ y $$\n"
+        );
+    }
+
+    #[test]
+    fn implicit_groups() {
+        assert_eq!(
+            indent(
+                "\\begingroup\nx\n{\ny\n}\n\\endgroup\n\\bgroup\nz\n  \\egroup\n",
+                "  "
+            ),
+            "\\begingroup\n  x\n  {\n    y\n  }\n\\endgroup\n\\bgroup\n  z\n\\egroup\n"
+        );
+        // `\endgroup` only closes `\begingroup`, and `}` only closes a brace or `\bgroup`
+        assert_eq!(
+            indent(
+                "\\begingroup\n\\bgroup\nx\n\\endgroup\ny\n}\nz\n\\endgroup\n",
+                "  "
+            ),
+            "\\begingroup\n  \\bgroup\n    x\n    \\endgroup\n    y\n  }\n  z\n\\endgroup\n"
+        );
+        // the groups opened in the arguments of definitions do not leak out of them
+        assert_eq!(
+            indent(
+                "\\newenvironment{a}{\\begingroup}{\\endgroup}\nx\n\\def\\y{\\bgroup\n\\egroup}\nz\n",
+                "  "
+            ),
+            "\\newenvironment{a}{\\begingroup}{\\endgroup}\nx\n\\def\\y{\\bgroup\n\\egroup}\nz\n"
+        );
+    }
+
+    #[test]
+    fn verbatim() {
+        let src =
+            "\\begin{itemize}\n\\begin{verbatim}\n  {\ncode\n\\end{verbatim}\n\\end{itemize}\n";
+        assert_eq!(
+            indent(src, "  "),
+            "\\begin{itemize}\n  \\begin{verbatim}\n  {\ncode\n\\end{verbatim}\n\\end{itemize}\n"
+        );
+    }
+}
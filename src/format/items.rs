@@ -0,0 +1,186 @@
+//! Put the items of lists on their own lines.
+
+use crate::lexer::{NewlineKind, Token};
+
+use super::break_line;
+
+/// Environments whose body is a list of `\item`s.
+const LIST_ENVIRONMENTS: &[&str] = &["description", "enumerate", "itemize"];
+
+/// Open environments, tracking whether the tokens are directly in the body of a list.
+#[derive(Default)]
+struct Lists {
+    /// For each open environment, the number of open groups at its start if it is a list.
+    environments: Vec<Option<usize>>,
+    groups: usize,
+}
+
+impl Lists {
+    fn update<S: AsRef<str>>(&mut self, token: &Token<S>) {
+        match token {
+            Token::BeginEnv(name, _) => self.environments.push(
+                LIST_ENVIRONMENTS
+                    .contains(&name.as_ref())
+                    .then_some(self.groups),
+            ),
+            Token::EndEnv(..) => {
+                self.environments.pop();
+            }
+            Token::LBrace => self.groups += 1,
+            Token::RBrace => self.groups = self.groups.saturating_sub(1),
+            _ => {}
+        }
+    }
+
+    /// Whether the innermost environment is a list, outside of any group inside it.
+    fn directly_inside(&self) -> bool {
+        self.environments.last() == Some(&Some(self.groups))
+    }
+}
+
+/// Progress through an item marker, `\item` or `\item[label]`.
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Marker {
+    /// Not in a marker.
+    None,
+    /// After `\item` or the closing `]` of its label.
+    End,
+    /// Inside the label, with the number of open brackets and braces.
+    Label(usize, usize),
+}
+
+/// Put every `\item` of the lists, the nested lists and the `\end` of the lists at the start
+/// of a line, with a single space after the item markers.
+///
+/// The lists are the `itemize`, `enumerate` and `description` environments, and the items
+/// inside groups, such as the ones in the definitions of macros, are left alone.
+/// A label such as `\item[Foo]` is part of the marker, and it is copied as it is.
+/// The body of the items is indented by [`indent`](super::indent) like the rest of the list.
+pub fn break_items<S>(tokens: &mut Vec<Token<S>>, newline: NewlineKind)
+where
+    S: AsRef<str> + From<String>,
+{
+    break_items_after::<S, &str>(tokens, newline, &[]);
+}
+
+/// Put the items of the lists in `tokens` on their own lines, as if they followed `context`,
+/// which ends with a newline.
+pub(crate) fn break_items_after<S, T>(
+    tokens: &mut Vec<Token<S>>,
+    newline: NewlineKind,
+    context: &[Token<T>],
+) where
+    S: AsRef<str> + From<String>,
+    T: AsRef<str>,
+{
+    let mut lists = Lists::default();
+    for token in context {
+        lists.update(token);
+    }
+    let mut marker = Marker::None;
+    let mut result = Vec::with_capacity(tokens.len());
+    for token in tokens.drain(..) {
+        let breaks = lists.directly_inside()
+            && match &token {
+                Token::Command(name) => name.as_ref() == "item",
+                Token::BeginEnv(name, _) | Token::EndEnv(name, _) => {
+                    LIST_ENVIRONMENTS.contains(&name.as_ref())
+                }
+                _ => false,
+            };
+        if breaks {
+            break_line(&mut result, newline);
+        }
+
+        let mut token = token;
+        match marker {
+            Marker::End => {
+                marker = Marker::None;
+                match token {
+                    Token::LBracket => marker = Marker::Label(1, 0),
+                    Token::Whitespace(_) => token = Token::Whitespace(" ".to_owned().into()),
+                    Token::Newline(_) | Token::Comment(_) | Token::EndEnv(..) => {}
+                    _ => result.push(Token::Whitespace(" ".to_owned().into())),
+                }
+            }
+            Marker::Label(brackets, braces) => {
+                marker = match token {
+                    Token::LBracket if braces == 0 => Marker::Label(brackets + 1, braces),
+                    Token::RBracket if braces == 0 && brackets == 1 => Marker::End,
+                    Token::RBracket if braces == 0 => Marker::Label(brackets - 1, braces),
+                    Token::LBrace => Marker::Label(brackets, braces + 1),
+                    Token::RBrace => Marker::Label(brackets, braces.saturating_sub(1)),
+                    _ => marker,
+                }
+            }
+            Marker::None => {}
+        }
+        if breaks && matches!(token, Token::Command(_)) {
+            marker = Marker::End;
+        }
+
+        lists.update(&token);
+        result.push(token);
+    }
+    *tokens = result;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_tokens_owned;
+    use crate::writer::write_tokens;
+
+    fn break_items(input: &str) -> String {
+        let mut tokens = lex_tokens_owned(input).unwrap();
+        super::break_items(&mut tokens, NewlineKind::Lf);
+        write_tokens(&tokens)
+    }
+
+    #[test]
+    fn items() {
+        assert_eq!(
+            break_items("\\begin{itemize}\\item a\\item b\\end{itemize}"),
+            "\\begin{itemize}\n\\item a\n\\item b\n\\end{itemize}"
+        );
+        assert_eq!(
+            break_items(
+                "\\begin{enumerate}[label=(\\alph*)]  \\item   a  \\item{b}\n\\end{enumerate}"
+            ),
+            "\\begin{enumerate}[label=(\\alph*)]\n\\item a\n\\item {b}\n\\end{enumerate}"
+        );
+        let formatted = "\\begin{itemize}\n  \\item a\n  \\item b\n\\end{itemize}\n";
+        assert_eq!(break_items(formatted), formatted);
+    }
+
+    #[test]
+    fn nested() {
+        assert_eq!(
+            break_items("\\begin{itemize}\\item a \\begin{enumerate}\\item b\\end{enumerate}\\end{itemize}"),
+            "\\begin{itemize}\n\\item a\n\\begin{enumerate}\n\\item b\n\\end{enumerate}\n\\end{itemize}"
+        );
+    }
+
+    #[test]
+    fn labels() {
+        assert_eq!(
+            break_items("\\begin{description}\\item[Foo]bar \\item[{[x]}]   baz\\end{description}"),
+            "\\begin{description}\n\\item[Foo] bar\n\\item[{[x]}] baz\n\\end{description}"
+        );
+        assert_eq!(
+            break_items("\\begin{description}\\item[Foo]\n  bar\\end{description}"),
+            "\\begin{description}\n\\item[Foo]\n  bar\n\\end{description}"
+        );
+    }
+
+    #[test]
+    fn untouched() {
+        for src in [
+            "text \\item a \\item b",
+            "\\begin{theorem}a \\item b\\end{theorem}",
+            "\\begin{itemize}\\newcommand{\\x}{\\item y}",
+        ] {
+            assert_eq!(break_items(src), src);
+        }
+    }
+}
@@ -0,0 +1,165 @@
+//! Rewrite the delimiters of math formulas.
+
+use crate::lexer::Token;
+
+/// Style of the delimiters of math formulas.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum MathDelimiters {
+    /// `\(...\)` and `\[...\]`
+    Amsmath,
+    /// `$...$` and `$$...$$`
+    Dollar,
+}
+
+/// Rewrite the delimiters of every formula in the given `style`, leaving the bodies untouched.
+///
+/// Since the opening and the closing `$` are the same token, they are paired in order of
+/// appearance: a `$` without a partner at the end of the input is left alone, and so is a
+/// `$` inside a display formula. Escaped dollars `\$` are text, so they are not affected.
+///
+/// A formula is only written with dollars if they would not touch another `$`, since two
+/// adjacent dollars are read as `$$`: in `\(a\)\(b\)` the second formula is left alone.
+/// The same goes for an opening delimiter without its closing one.
+pub fn convert_math_delimiters<S>(tokens: &mut [Token<S>], style: MathDelimiters) {
+    match style {
+        MathDelimiters::Amsmath => {
+            // the index of the open `$` or `$$`, if any
+            let mut open: Option<usize> = None;
+            for i in 0..tokens.len() {
+                let (begin, end) = match tokens[i] {
+                    Token::InlineMath => (Token::BInlineMath, Token::EInlineMath),
+                    Token::TDisplayMath => (Token::BDisplayMath, Token::EDisplayMath),
+                    _ => continue,
+                };
+                match open {
+                    None => open = Some(i),
+                    Some(j)
+                        if std::mem::discriminant(&tokens[j])
+                            == std::mem::discriminant(&tokens[i]) =>
+                    {
+                        tokens[j] = begin;
+                        tokens[i] = end;
+                        open = None;
+                    }
+                    // a `$` inside `$$...$$`, or the other way round
+                    Some(_) => {}
+                }
+            }
+        }
+        MathDelimiters::Dollar => {
+            let is_dollar = |token: Option<&Token<S>>| {
+                matches!(token, Some(Token::InlineMath | Token::TDisplayMath))
+            };
+            let mut i = 0;
+            while i < tokens.len() {
+                let (dollar, end): (fn() -> Token<S>, _) = match tokens[i] {
+                    Token::BInlineMath => (|| Token::InlineMath, Token::EInlineMath),
+                    Token::BDisplayMath => (|| Token::TDisplayMath, Token::EDisplayMath),
+                    _ => {
+                        i += 1;
+                        continue;
+                    }
+                };
+                let Some(j) = tokens[i + 1..]
+                    .iter()
+                    .position(|token| std::mem::discriminant(token) == std::mem::discriminant(&end))
+                    .map(|j| i + 1 + j)
+                else {
+                    break;
+                };
+                // a `$` next to another one would merge with it into `$$`
+                let touches =
+                    (i > 0 && is_dollar(tokens.get(i - 1))) || is_dollar(tokens.get(j + 1));
+                if !touches {
+                    tokens[i] = dollar();
+                    tokens[j] = dollar();
+                }
+                i = j + 1;
+            }
+        }
+    }
+}
+
+/// Commands which size the delimiter after them, as `\left` in `\left(`.
+const SIZE_COMMANDS: &[&str] = &[
+    "left", "middle", "right", "big", "Big", "bigg", "Bigg", "bigl", "Bigl", "biggl", "Biggl",
+    "bigm", "Bigm", "biggm", "Biggm", "bigr", "Bigr", "biggr", "Biggr",
+];
+
+/// Whether `\name` sizes the delimiter after it, so that the two are never separated.
+///
+/// These are `\left`, `\middle` and `\right`, and the variants of `\big`, such as `\Bigl`.
+pub fn is_math_size_command(name: &str) -> bool {
+    SIZE_COMMANDS.contains(&name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_tokens;
+
+    fn convert(input: &str, style: MathDelimiters) -> String {
+        let mut tokens = lex_tokens(input).unwrap();
+        convert_math_delimiters(&mut tokens, style);
+        tokens.iter().map(|t| t.to_string()).collect()
+    }
+
+    #[test]
+    fn amsmath() {
+        let amsmath = |input| convert(input, MathDelimiters::Amsmath);
+        assert_eq!(amsmath("$a$"), "\\(a\\)");
+        assert_eq!(amsmath("$$a$$"), "\\[a\\]");
+        assert_eq!(
+            amsmath("$a$ and $$b$$ or $c$"),
+            "\\(a\\) and \\[b\\] or \\(c\\)"
+        );
+        assert_eq!(amsmath("costs \\$5, $x$"), "costs \\$5, \\(x\\)");
+        assert_eq!(amsmath("$a$ $b"), "\\(a\\) $b");
+        assert_eq!(amsmath("\\(a\\)"), "\\(a\\)");
+    }
+
+    #[test]
+    fn dollar() {
+        let dollar = |input| convert(input, MathDelimiters::Dollar);
+        assert_eq!(dollar("\\(a\\)"), "$a$");
+        assert_eq!(dollar("\\[a\\]"), "$$a$$");
+        assert_eq!(dollar("$a$ \\$"), "$a$ \\$");
+        assert_eq!(dollar("\\(a\\)\\(b\\)"), "$a$\\(b\\)");
+        assert_eq!(dollar("\\(a\\)$b$"), "\\(a\\)$b$");
+        assert_eq!(dollar("$a$\\[b\\] \\[c\\]"), "$a$\\[b\\] $$c$$");
+        assert_eq!(dollar("\\(a\\) \\(b\\)"), "$a$ $b$");
+        assert_eq!(dollar("\\(a"), "\\(a");
+    }
+
+    #[test]
+    fn round_trip() {
+        for input in [
+            "\\(a\\)\\(b\\)",
+            "\\(a\\)$b$",
+            "$a$\\[b\\]\\(c\\)",
+            "\\(a\\) \\[b\\]",
+        ] {
+            let dollar = convert(input, MathDelimiters::Dollar);
+            assert_eq!(convert(&dollar, MathDelimiters::Dollar), dollar, "{input}");
+            let amsmath = convert(input, MathDelimiters::Amsmath);
+            assert_eq!(
+                convert(&dollar, MathDelimiters::Amsmath),
+                amsmath,
+                "{input}"
+            );
+        }
+    }
+
+    #[test]
+    fn size_commands() {
+        for name in ["left", "right", "middle", "big", "Bigl", "biggr", "Biggm"] {
+            assert!(is_math_size_command(name), "{name}");
+        }
+        for name in ["lefteqn", "bigskip", "l", "Big l", "frac", ""] {
+            assert!(!is_math_size_command(name), "{name}");
+        }
+    }
+}
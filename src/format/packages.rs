@@ -0,0 +1,435 @@
+//! Put the packages loaded in the preamble on their own lines.
+
+use crate::lexer::{NewlineKind, Token};
+
+use super::break_line;
+
+/// Commands loading packages.
+const PACKAGE_COMMANDS: &[&str] = &["usepackage", "RequirePackage"];
+
+/// Index of the first `\begin{document}` outside of groups, if any.
+fn document_start<S: AsRef<str>>(tokens: &[Token<S>], mut depth: usize) -> Option<usize> {
+    for (i, token) in tokens.iter().enumerate() {
+        match token {
+            Token::BeginEnv(name, _) if depth == 0 && name.as_ref() == "document" => {
+                return Some(i)
+            }
+            Token::LBrace => depth += 1,
+            Token::RBrace => depth = depth.saturating_sub(1),
+            _ => {}
+        }
+    }
+    None
+}
+
+/// Number of `{` not yet closed at the end of `tokens`.
+fn open_braces<S>(tokens: &[Token<S>]) -> usize {
+    open_braces_from(0, tokens)
+}
+
+/// Index after the group opened at `start`, by `[` or `{`, if it is closed.
+pub(super) fn group_end<S>(tokens: &[Token<S>], start: usize) -> Option<usize> {
+    let bracket = matches!(tokens[start], Token::LBracket);
+    let (mut braces, mut brackets) = (0usize, 0usize);
+    for (i, token) in tokens.iter().enumerate().skip(start) {
+        match token {
+            Token::LBrace => braces += 1,
+            Token::RBrace => braces = braces.checked_sub(1)?,
+            Token::LBracket if braces == 0 => brackets += 1,
+            Token::RBracket if braces == 0 && brackets > 0 => brackets -= 1,
+            _ => {}
+        }
+        if braces == 0 && (!bracket || brackets == 0) {
+            return Some(i + 1);
+        }
+    }
+    None
+}
+
+/// The arguments of the command at `command`: the range of its required argument, if any,
+/// and the index after its last argument.
+fn arguments<S>(tokens: &[Token<S>], command: usize) -> (Option<(usize, usize)>, usize) {
+    let mut required = None;
+    let mut end = command + 1;
+    loop {
+        let start = (end..tokens.len())
+            .find(|&i| !matches!(tokens[i], Token::Whitespace(_)))
+            .unwrap_or(tokens.len());
+        if !matches!(tokens.get(start), Some(Token::LBrace | Token::LBracket)) {
+            return (required, end);
+        }
+        let Some(group_end) = group_end(tokens, start) else {
+            return (required, end);
+        };
+        if required.is_none() && matches!(tokens[start], Token::LBrace) {
+            required = Some((start, group_end));
+        }
+        end = group_end;
+    }
+}
+
+/// The names of the packages in the required argument `{a, b}`, if there are several and
+/// they are plain text.
+fn package_list<S: AsRef<str>>(argument: &[Token<S>]) -> Option<Vec<String>> {
+    let mut list = String::new();
+    for token in &argument[1..argument.len() - 1] {
+        match token {
+            Token::Text(text) => list.push_str(text.as_ref()),
+            Token::Whitespace(_) | Token::Newline(_) => list.push(' '),
+            _ => return None,
+        }
+    }
+    let names: Vec<String> = list
+        .split(',')
+        .map(str::trim)
+        .filter(|name| !name.is_empty())
+        .map(str::to_owned)
+        .collect();
+    let valid = names.len() > 1 && names.iter().all(|name| !name.contains(' '));
+    valid.then_some(names)
+}
+
+/// A copy of `token`.
+fn copy<S: AsRef<str> + From<String>>(token: &Token<S>) -> Token<S> {
+    token.as_ref().map(|s| s.to_owned().into())
+}
+
+/// Put every `\usepackage` and `\RequirePackage` of the preamble on its own line.
+///
+/// The preamble is what precedes `\begin{document}`, so a fragment without it is left
+/// untouched. The optional arguments, such as `[utf8]`, are preserved, and the comments
+/// after the commands stay on their lines. If `split_lists` is true, a command loading a
+/// list of packages, such as `\usepackage[opt]{a,b}`, is split into one command per
+/// package, each with the same optional arguments.
+pub fn one_package_per_line<S>(tokens: &mut Vec<Token<S>>, newline: NewlineKind, split_lists: bool)
+where
+    S: AsRef<str> + From<String>,
+{
+    one_package_per_line_after::<S, &str>(tokens, newline, split_lists, &[]);
+}
+
+/// Put the packages loaded in `tokens` on their own lines, as if they followed `context`,
+/// which ends with a newline.
+pub(crate) fn one_package_per_line_after<S, T>(
+    tokens: &mut Vec<Token<S>>,
+    newline: NewlineKind,
+    split_lists: bool,
+    context: &[Token<T>],
+) where
+    S: AsRef<str> + From<String>,
+    T: AsRef<str>,
+{
+    if document_start(context, 0).is_some() {
+        return;
+    }
+    let mut depth = open_braces(context);
+    let Some(preamble) = document_start(tokens, depth) else {
+        return;
+    };
+
+    let mut result = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+    while i < preamble {
+        let token = &tokens[i];
+        let loads_package =
+            matches!(token, Token::Command(name) if PACKAGE_COMMANDS.contains(&name.as_ref()));
+        if !(depth == 0 && loads_package) {
+            match token {
+                Token::LBrace => depth += 1,
+                Token::RBrace => depth = depth.saturating_sub(1),
+                _ => {}
+            }
+            result.push(copy(token));
+            i += 1;
+            continue;
+        }
+
+        let (required, end) = arguments(&tokens[..preamble], i);
+        break_line(&mut result, newline);
+        let names = required
+            .filter(|_| split_lists)
+            .and_then(|(start, group_end)| {
+                Some((start, group_end, package_list(&tokens[start..group_end])?))
+            });
+        match names {
+            Some((start, group_end, names)) => {
+                for (n, name) in names.into_iter().enumerate() {
+                    if n > 0 {
+                        result.push(Token::Newline(newline));
+                    }
+                    result.extend(tokens[i..start].iter().map(copy));
+                    result.push(Token::LBrace);
+                    result.push(Token::Text(name.into()));
+                    result.push(Token::RBrace);
+                    result.extend(tokens[group_end..end].iter().map(copy));
+                }
+            }
+            None => result.extend(tokens[i..end].iter().map(copy)),
+        }
+
+        // what follows the command on its line starts a new line, except for a comment
+        let next = (end..preamble)
+            .find(|&j| !matches!(tokens[j], Token::Whitespace(_)))
+            .unwrap_or(preamble);
+        match tokens[next] {
+            Token::Newline(_) | Token::Comment(_) if next < preamble => i = end,
+            _ => {
+                result.push(Token::Newline(newline));
+                i = next;
+            }
+        }
+    }
+    result.extend(tokens.drain(preamble..));
+    *tokens = result;
+}
+
+/// The sort key of a line loading packages: the text of its required argument.
+///
+/// A line loads packages if it is made of `\usepackage` or `\RequirePackage` with its
+/// arguments, with an optional indentation and an optional comment at its end.
+fn package_key<S: AsRef<str>>(line: &[Token<S>]) -> Option<String> {
+    let command = line
+        .iter()
+        .position(|t| !matches!(t, Token::Whitespace(_)))?;
+    if !matches!(&line[command], Token::Command(name) if PACKAGE_COMMANDS.contains(&name.as_ref()))
+    {
+        return None;
+    }
+    let (required, end) = arguments(line, command);
+    let (start, group_end) = required?;
+    let rest_is_blank = line[end..].iter().enumerate().all(|(i, t)| match t {
+        Token::Whitespace(_) => true,
+        Token::Comment(_) => end + i + 1 == line.len(),
+        _ => false,
+    });
+    if !rest_is_blank {
+        return None;
+    }
+    let mut key = String::new();
+    for token in &line[start + 1..group_end - 1] {
+        match token {
+            Token::Text(text) => key.push_str(&text.as_ref().to_lowercase()),
+            Token::Whitespace(_) | Token::Newline(_) => {}
+            _ => return None,
+        }
+    }
+    // the key of a list is the key of the sorted list
+    let mut names: Vec<_> = key.split(',').collect();
+    names.sort_unstable();
+    Some(names.join(","))
+}
+
+/// A copy of the package line `line`, with the list of packages of its required argument
+/// sorted, if any.
+fn sorted_list<S: AsRef<str> + From<String>>(line: &[Token<S>]) -> Vec<Token<S>> {
+    let command = line
+        .iter()
+        .position(|t| !matches!(t, Token::Whitespace(_)))
+        .unwrap_or(0);
+    let (required, _) = arguments(line, command);
+    let Some((start, group_end)) = required else {
+        return line.iter().map(copy).collect();
+    };
+    let Some(mut names) = package_list(&line[start..group_end]) else {
+        return line.iter().map(copy).collect();
+    };
+    names.sort_by_key(|name| name.to_lowercase());
+    let spaced = line[start..group_end]
+        .iter()
+        .any(|t| matches!(t, Token::Whitespace(_) | Token::Newline(_)));
+    let mut result: Vec<_> = line[..start].iter().map(copy).collect();
+    result.push(Token::LBrace);
+    result.push(Token::Text(
+        names.join(if spaced { ", " } else { "," }).into(),
+    ));
+    result.push(Token::RBrace);
+    result.extend(line[group_end..].iter().map(copy));
+    result
+}
+
+/// Sort alphabetically the consecutive lines of the preamble loading packages, and the
+/// lists of packages loaded by a single command, such as `\usepackage{b,a}`.
+///
+/// The lines are sorted by the names of their packages, ignoring the case, and the lines
+/// loading the same packages keep their order. A blank line, a comment or any other line
+/// separates the groups of lines which are sorted, and a comment at the end of a line moves
+/// with it. As in [`one_package_per_line`], a fragment without `\begin{document}` is left
+/// untouched.
+pub fn sort_packages<S>(tokens: &mut Vec<Token<S>>)
+where
+    S: AsRef<str> + From<String>,
+{
+    sort_packages_after::<S, &str>(tokens, &[]);
+}
+
+/// Sort the packages loaded in `tokens`, as if they followed `context`, which ends with a
+/// newline.
+pub(crate) fn sort_packages_after<S, T>(tokens: &mut Vec<Token<S>>, context: &[Token<T>])
+where
+    S: AsRef<str> + From<String>,
+    T: AsRef<str>,
+{
+    if document_start(context, 0).is_some() {
+        return;
+    }
+    let mut depth = open_braces(context);
+    let Some(preamble) = document_start(tokens, depth) else {
+        return;
+    };
+
+    // the lines of the preamble ended by a newline, and whether they load packages
+    let mut lines = Vec::new();
+    let mut start = 0;
+    for (i, token) in tokens[..preamble].iter().enumerate() {
+        if let Token::Newline(_) = token {
+            let line = &tokens[start..i];
+            let key = (depth == 0).then(|| package_key(line)).flatten();
+            lines.push((start..i, key));
+            depth = open_braces_from(depth, line);
+            start = i + 1;
+        }
+    }
+
+    let mut result = Vec::with_capacity(tokens.len());
+    let mut i = 0;
+    while i < lines.len() {
+        let group = lines[i..]
+            .iter()
+            .take_while(|(_, key)| key.is_some())
+            .count();
+        if group == 0 {
+            let (range, _) = &lines[i];
+            result.extend(tokens[range.start..=range.end].iter().map(copy));
+            i += 1;
+            continue;
+        }
+        let mut sorted: Vec<_> = lines[i..i + group]
+            .iter()
+            .map(|(range, key)| (key.clone(), range.clone()))
+            .collect();
+        sorted.sort_by(|(a, _), (b, _)| a.cmp(b));
+        for (_, range) in sorted {
+            result.extend(sorted_list(&tokens[range.clone()]));
+            result.push(copy(&tokens[range.end]));
+        }
+        i += group;
+    }
+    result.extend(tokens.drain(start..));
+    *tokens = result;
+}
+
+/// Number of `{` not yet closed after `tokens`, which follow `depth` open braces.
+fn open_braces_from<S>(depth: usize, tokens: &[Token<S>]) -> usize {
+    tokens.iter().fold(depth, |depth, token| match token {
+        Token::LBrace => depth + 1,
+        Token::RBrace => depth.saturating_sub(1),
+        _ => depth,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_tokens_owned;
+    use crate::writer::write_tokens;
+
+    fn packages(preamble: &str, split_lists: bool) -> String {
+        let src = format!("{preamble}\\begin{{document}}\\usepackage{{a}}\\usepackage{{b}}");
+        let mut tokens = lex_tokens_owned(&src).unwrap();
+        one_package_per_line(&mut tokens, NewlineKind::Lf, split_lists);
+        let body = "\\begin{document}\\usepackage{a}\\usepackage{b}";
+        let formatted = write_tokens(&tokens);
+        assert!(formatted.ends_with(body), "{formatted:?}");
+        formatted[..formatted.len() - body.len()].to_owned()
+    }
+
+    #[test]
+    fn lines() {
+        assert_eq!(
+            packages(
+                "\\documentclass{article}\\usepackage{a}\\usepackage[utf8]{inputenc}\n",
+                false
+            ),
+            "\\documentclass{article}\n\\usepackage{a}\n\\usepackage[utf8]{inputenc}\n"
+        );
+        assert_eq!(
+            packages(
+                "\\usepackage{a}  % why\n\\RequirePackage{b}[2020/01/01] \\x\n",
+                false
+            ),
+            "\\usepackage{a}  % why\n\\RequirePackage{b}[2020/01/01]\n\\x\n"
+        );
+        assert_eq!(packages("\\usepackage{a,b}", false), "\\usepackage{a,b}\n");
+        let formatted = "\\usepackage{a}\n\\usepackage{b}\n";
+        assert_eq!(packages(formatted, false), formatted);
+        assert_eq!(
+            packages("\\x{\\usepackage{a}\\usepackage{b}}", false),
+            "\\x{\\usepackage{a}\\usepackage{b}}"
+        );
+    }
+
+    #[test]
+    fn lists() {
+        assert_eq!(
+            packages("\\usepackage[opt]{amsmath, amssymb,\n  amsthm}\n", true),
+            "\\usepackage[opt]{amsmath}\n\\usepackage[opt]{amssymb}\n\\usepackage[opt]{amsthm}\n"
+        );
+        assert_eq!(packages("\\usepackage{a}", true), "\\usepackage{a}\n");
+        assert_eq!(
+            packages("\\usepackage{a,\\b}\n", true),
+            "\\usepackage{a,\\b}\n"
+        );
+    }
+
+    fn sorted(preamble: &str) -> String {
+        let mut tokens = lex_tokens_owned(&format!("{preamble}\\begin{{document}}")).unwrap();
+        sort_packages(&mut tokens);
+        let formatted = write_tokens(&tokens);
+        formatted
+            .strip_suffix("\\begin{document}")
+            .unwrap()
+            .to_owned()
+    }
+
+    #[test]
+    fn sort_lines() {
+        assert_eq!(
+            sorted("\\usepackage{graphicx}\n\\usepackage[T1]{fontenc}\n\\usepackage{amsmath}\n"),
+            "\\usepackage{amsmath}\n\\usepackage[T1]{fontenc}\n\\usepackage{graphicx}\n"
+        );
+        // stable for the same packages, and keeping the indentation and the comments
+        assert_eq!(
+            sorted("  \\usepackage{b} % b\n\\usepackage[x]{A}\n\\RequirePackage[y]{a}\n"),
+            "\\usepackage[x]{A}\n\\RequirePackage[y]{a}\n  \\usepackage{b} % b\n"
+        );
+        // the groups are separated by the other lines
+        let src = "\\usepackage{c}\n\\usepackage{b}\n\n\\usepackage{a}\n% z\n\\usepackage{d}\n\\x\n\\usepackage{c}\n";
+        assert_eq!(
+            sorted(src),
+            src.replacen("{c}\n\\usepackage{b}", "{b}\n\\usepackage{c}", 1)
+        );
+        let untouched = "\\usepackage{b}\\usepackage{a}\n\\usepackage{{b}}\n\\usepackage{a}";
+        assert_eq!(sorted(untouched), untouched);
+    }
+
+    #[test]
+    fn sort_lists() {
+        assert_eq!(sorted("\\usepackage{c,a,b}\n"), "\\usepackage{a,b,c}\n");
+        assert_eq!(
+            sorted("\\usepackage{zz}\n\\usepackage[opt]{amssymb, amsmath}\n"),
+            "\\usepackage[opt]{amsmath, amssymb}\n\\usepackage{zz}\n"
+        );
+        let src = "\\usepackage{b,a}";
+        let mut tokens = lex_tokens_owned(src).unwrap();
+        sort_packages(&mut tokens);
+        assert_eq!(write_tokens(&tokens), src);
+    }
+
+    #[test]
+    fn fragment() {
+        let src = "\\usepackage{a}\\usepackage{b,c}";
+        let mut tokens = lex_tokens_owned(src).unwrap();
+        one_package_per_line(&mut tokens, NewlineKind::Lf, true);
+        assert_eq!(write_tokens(&tokens), src);
+    }
+}
@@ -0,0 +1,246 @@
+//! Normalize the spaces around punctuation marks.
+
+use crate::lexer::Token;
+
+use super::{Environments, NO_REFLOW_ENVIRONMENTS};
+
+/// Punctuation marks whose spacing is normalized.
+const PUNCTUATION: &[char] = &[',', '.', ';', ':', '!', '?'];
+
+/// Typographic rules for the spaces around punctuation marks.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum PunctuationSpacing {
+    /// No space before punctuation marks
+    En,
+    /// A space before `;`, `:`, `!` and `?`, and none before `,` and `.`
+    Fr,
+}
+
+impl PunctuationSpacing {
+    /// Whether a run of punctuation marks starting with `mark` is preceded by a space.
+    fn space_before(self, mark: char) -> bool {
+        self == PunctuationSpacing::Fr && matches!(mark, ';' | ':' | '!' | '?')
+    }
+}
+
+/// Where the text must not be changed.
+#[derive(Default)]
+pub(super) struct Skip {
+    environments: Environments,
+    math: usize,
+    /// Whether each open group is skipped, and whether it is a brace group.
+    groups: Vec<(bool, bool)>,
+    /// Whether the previous token is a command or one of its arguments.
+    argument: bool,
+}
+
+impl Skip {
+    pub(super) fn update<S: AsRef<str>>(&mut self, token: &Token<S>) {
+        self.environments.update(token, NO_REFLOW_ENVIRONMENTS);
+        let skipped = self.groups.last().is_some_and(|&(skip, _)| skip);
+        let mut argument = false;
+        match token {
+            Token::InlineMath | Token::TDisplayMath if self.math > 0 => self.math -= 1,
+            Token::InlineMath | Token::TDisplayMath | Token::BInlineMath | Token::BDisplayMath => {
+                self.math += 1
+            }
+            Token::EInlineMath | Token::EDisplayMath => self.math = self.math.saturating_sub(1),
+            Token::Command(_) | Token::BeginEnv(..) => argument = true,
+            Token::LBrace | Token::LBracket => {
+                let brace = matches!(token, Token::LBrace);
+                self.groups.push((skipped || self.argument, brace));
+            }
+            Token::RBracket => {
+                if let Some(&(skip, false)) = self.groups.last() {
+                    self.groups.pop();
+                    argument = skip && !self.groups.last().is_some_and(|&(skip, _)| skip);
+                }
+            }
+            Token::RBrace => {
+                while let Some((skip, brace)) = self.groups.pop() {
+                    if brace {
+                        argument = skip && !self.groups.last().is_some_and(|&(skip, _)| skip);
+                        break;
+                    }
+                }
+            }
+            _ => {}
+        }
+        self.argument = argument;
+    }
+
+    /// Whether the next token may be changed.
+    pub(super) fn active(&self) -> bool {
+        self.math == 0
+            && !self.environments.inside()
+            && !self.groups.last().is_some_and(|&(skip, _)| skip)
+    }
+}
+
+/// The last character of `token`, if it is text.
+fn last_char<S: AsRef<str>>(token: Option<&Token<S>>) -> Option<char> {
+    match token {
+        Some(Token::Text(text)) => text.as_ref().chars().last(),
+        _ => None,
+    }
+}
+
+/// Normalize the spaces around the punctuation marks `,.;:!?` of the text, following `rules`.
+///
+/// A run of punctuation marks after a word, such as `,` or `?!`, loses the spaces before
+/// it, or gets exactly one space with the French rules for `;:!?`. It is followed by one
+/// space if a word follows it directly, except for a `.` between two lowercase letters,
+/// as in `e.g.` or `example.com`, and between uppercase letters, as in `U.S.A.`.
+/// Numbers such as `3.14` and `10:30` are untouched, and so are math formulas, math and
+/// tabular environments, and the arguments of commands, such as labels and citation keys.
+pub fn punctuation_spacing<S>(tokens: &mut Vec<Token<S>>, rules: PunctuationSpacing)
+where
+    S: AsRef<str> + From<String>,
+{
+    let mut result: Vec<Token<S>> = Vec::with_capacity(tokens.len());
+    let mut skip = Skip::default();
+    for token in tokens.drain(..) {
+        let active = skip.active();
+        skip.update(&token);
+        match token {
+            Token::Text(text) if active => spaced_text(&mut result, text, rules),
+            token => result.push(token),
+        }
+    }
+    *tokens = result;
+}
+
+/// Push the tokens of `text` to `result`, with normalized spaces around its punctuation.
+fn spaced_text<S>(result: &mut Vec<Token<S>>, text: S, rules: PunctuationSpacing)
+where
+    S: AsRef<str> + From<String>,
+{
+    let s = text.as_ref();
+    let word_before =
+        |result: &[Token<S>]| last_char(result.last()).is_some_and(char::is_alphanumeric);
+    // after whitespace, the previous character is the last of the word before the whitespace
+    if s.starts_with(PUNCTUATION)
+        && matches!(result.last(), Some(Token::Whitespace(_)))
+        && word_before(&result[..result.len() - 1])
+        && !rules.space_before(s.chars().next().unwrap())
+    {
+        let run = s.find(|c| !PUNCTUATION.contains(&c)).unwrap_or(s.len());
+        if s[run..].chars().next().is_none_or(char::is_alphabetic) {
+            result.pop();
+        }
+    }
+
+    // the character before the text, also skipping the whitespace
+    let mut prev = match result.split_last() {
+        Some((Token::Whitespace(_), before)) => last_char(before.last()),
+        _ => last_char(result.last()),
+    };
+    let mut piece = String::new();
+    let mut chars = s.char_indices().peekable();
+    while let Some((i, c)) = chars.next() {
+        if c == '\\' {
+            // an escaped character, such as `\,`
+            piece.push(c);
+            if let Some((_, escaped)) = chars.next() {
+                piece.push(escaped);
+            }
+            prev = None;
+            continue;
+        }
+        if !PUNCTUATION.contains(&c) {
+            piece.push(c);
+            prev = Some(c);
+            continue;
+        }
+        let end = s[i..]
+            .find(|c| !PUNCTUATION.contains(&c))
+            .map_or(s.len(), |len| i + len);
+        while chars.peek().is_some_and(|&(j, _)| j < end) {
+            chars.next();
+        }
+        let next = s[end..].chars().next();
+        let after_letter = prev.is_some_and(char::is_alphabetic);
+
+        let spaced = piece.is_empty() && matches!(result.last(), Some(Token::Whitespace(_)));
+        if after_letter && next.is_none() && rules.space_before(c) && !spaced {
+            push_piece(result, &mut piece);
+            result.push(Token::Whitespace(" ".to_owned().into()));
+        }
+        piece.push_str(&s[i..end]);
+        if let Some(next) = next.filter(|_| after_letter) {
+            let abbreviation = end - i == 1
+                && c == '.'
+                && (next.is_lowercase() || prev.is_some_and(char::is_uppercase));
+            if next.is_alphabetic() && !abbreviation {
+                push_piece(result, &mut piece);
+                result.push(Token::Whitespace(" ".to_owned().into()));
+            }
+        }
+        prev = Some(c);
+    }
+    push_piece(result, &mut piece);
+}
+
+/// Push `piece` to `result` as text, if it is not empty.
+fn push_piece<S: From<String>>(result: &mut Vec<Token<S>>, piece: &mut String) {
+    if !piece.is_empty() {
+        result.push(Token::Text(std::mem::take(piece).into()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_tokens_owned;
+    use crate::writer::write_tokens;
+
+    fn spacing(input: &str, rules: PunctuationSpacing) -> String {
+        let mut tokens = lex_tokens_owned(input).unwrap();
+        punctuation_spacing(&mut tokens, rules);
+        write_tokens(&tokens)
+    }
+
+    #[test]
+    fn english() {
+        let en = |input| spacing(input, PunctuationSpacing::En);
+        assert_eq!(en("word ,next"), "word, next");
+        assert_eq!(en("one,two;three :four"), "one, two; three: four");
+        assert_eq!(en("Really ?! Yes ."), "Really?! Yes.");
+        assert_eq!(en("end.Next"), "end. Next");
+        assert_eq!(en("e.g. example.com U.S.A."), "e.g. example.com U.S.A.");
+        assert_eq!(en("3.14 at 10:30, and 1 , 2"), "3.14 at 10:30, and 1, 2");
+        assert_eq!(en("thin\\,space \\, here"), "thin\\,space \\, here");
+    }
+
+    #[test]
+    fn untouched() {
+        let en = |input| spacing(input, PunctuationSpacing::En);
+        for src in [
+            "$a ,b$ and \\(x ,y\\)",
+            "\\cite{a,b} \\ref{sec:intro} \\includegraphics[width=1,height=2]{a.png}",
+            "\\begin{tabular}{l,l}\na ,b\n\\end{tabular}",
+            "% comment ,here",
+            "\\verb|a ,b|",
+        ] {
+            assert_eq!(en(src), src);
+        }
+        assert_eq!(en("\\emph{x} word ,next"), "\\emph{x} word, next");
+        assert_eq!(en("{\\bf a ,b}"), "{\\bf a, b}");
+    }
+
+    #[test]
+    fn french() {
+        let fr = |input| spacing(input, PunctuationSpacing::Fr);
+        assert_eq!(
+            fr("Quoi? Oui; non: peut-être!"),
+            "Quoi ? Oui ; non : peut-être !"
+        );
+        assert_eq!(fr("Quoi ? Oui"), "Quoi ? Oui");
+        assert_eq!(fr("Oui ;non"), "Oui ; non");
+        assert_eq!(fr("un ,deux .Trois"), "un, deux. Trois");
+        assert_eq!(fr("http://example.com"), "http://example.com");
+    }
+}
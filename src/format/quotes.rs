@@ -0,0 +1,177 @@
+//! Write the straight double quotes of the text as TeX quotation marks.
+
+use crate::lexer::Token;
+
+use super::punctuation::Skip;
+
+/// Marks which may end a quotation, after its closing quote.
+const CLOSING_PUNCTUATION: &[char] = &['.', ',', ';', ':', '!', '?', ')', ']'];
+
+/// Marks which may start a quotation, before its opening quote.
+const OPENING_PUNCTUATION: &[char] = &['(', '['];
+
+/// What surrounds a quote, with `None` for a space or the boundary of the text.
+fn neighbor<S: AsRef<str>>(token: Option<&Token<S>>, last: bool) -> Option<char> {
+    match token? {
+        Token::Text(text) => {
+            let text = text.as_ref();
+            if last {
+                text.chars().last()
+            } else {
+                text.chars().next()
+            }
+        }
+        Token::Whitespace(_)
+        | Token::Newline(_)
+        | Token::Tie
+        | Token::LBrace
+        | Token::RBrace
+        | Token::Comment(_) => None,
+        // such as a command, which is not a space
+        _ => Some('\\'),
+    }
+}
+
+/// Whether `c` is a space, or the boundary of the text.
+fn spaced(c: Option<char>) -> bool {
+    c.is_none_or(char::is_whitespace)
+}
+
+/// Whether the quote between `before` and `after` opens a quotation, as in `"word`.
+fn opens(before: Option<char>, after: Option<char>) -> bool {
+    (spaced(before) || before.is_some_and(|c| OPENING_PUNCTUATION.contains(&c))) && !spaced(after)
+}
+
+/// Whether the quote between `before` and `after` closes a quotation, as in `word"`.
+fn closes(before: Option<char>, after: Option<char>) -> bool {
+    !spaced(before) && (spaced(after) || after.is_some_and(|c| CLOSING_PUNCTUATION.contains(&c)))
+}
+
+/// Whether `tokens[i]` starts a blank line.
+fn blank_line<S>(tokens: &[Token<S>], i: usize) -> bool {
+    matches!(tokens[i], Token::Newline(_))
+        && tokens[i + 1..]
+            .iter()
+            .find(|token| !matches!(token, Token::Whitespace(_)))
+            .is_some_and(|token| matches!(token, Token::Newline(_)))
+}
+
+/// Replace the pairs of straight double quotes of the text, as in `"word"`, with the TeX
+/// quotation marks ``` ``word'' ```.
+///
+/// A quote opens a quotation after a space or `(`, and before a word, and closes it after
+/// a word, and before a space or a punctuation mark. Only the quotes opening and closing
+/// a quotation in the same paragraph are replaced, so that the other uses of `"`, such as
+/// the shorthands of babel, are untouched, and so are the apostrophes and the single
+/// quotes. Math formulas, math and tabular environments and the arguments of commands
+/// are untouched, and so is verbatim content.
+pub fn normalize_quotes<S>(tokens: &mut [Token<S>])
+where
+    S: AsRef<str> + From<String>,
+{
+    // the token and byte index of the quotes opening and closing a quotation
+    let mut quotes = Vec::new();
+    let mut open = None;
+    let mut skip = Skip::default();
+    for i in 0..tokens.len() {
+        let active = skip.active();
+        skip.update(&tokens[i]);
+        if blank_line(tokens, i) {
+            open = None;
+        }
+        let Token::Text(text) = &tokens[i] else {
+            continue;
+        };
+        if !active {
+            continue;
+        }
+        let text = text.as_ref();
+        for (byte, _) in text.match_indices('"') {
+            let before = match text[..byte].chars().last() {
+                Some(c) => Some(c),
+                None => i.checked_sub(1).and_then(|j| neighbor(tokens.get(j), true)),
+            };
+            let after = match text[byte + 1..].chars().next() {
+                Some(c) => Some(c),
+                None => neighbor(tokens.get(i + 1), false),
+            };
+            if opens(before, after) {
+                open = Some((i, byte));
+            } else if closes(before, after) {
+                if let Some(start) = open.take() {
+                    quotes.extend([(start, "``"), ((i, byte), "''")]);
+                }
+            }
+        }
+    }
+
+    let mut quotes = quotes.into_iter().peekable();
+    while let Some(&((i, _), _)) = quotes.peek() {
+        let Token::Text(text) = &tokens[i] else {
+            unreachable!("quotes are in text")
+        };
+        let text = text.as_ref();
+        let mut result = String::with_capacity(text.len() + 2);
+        let mut copied = 0;
+        while let Some(((_, byte), mark)) = quotes.next_if(|&((j, _), _)| j == i) {
+            result.push_str(&text[copied..byte]);
+            result.push_str(mark);
+            copied = byte + 1;
+        }
+        result.push_str(&text[copied..]);
+        tokens[i] = Token::Text(result.into());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_tokens_owned;
+    use crate::writer::write_tokens;
+
+    fn quotes(input: &str) -> String {
+        let mut tokens = lex_tokens_owned(input).unwrap();
+        normalize_quotes(&mut tokens);
+        write_tokens(&tokens)
+    }
+
+    #[test]
+    fn quotations() {
+        assert_eq!(quotes("\"hello\""), "``hello''");
+        assert_eq!(
+            quotes("He said \"good  morning,\" and left (\"bye\")."),
+            "He said ``good  morning,'' and left (``bye'')."
+        );
+        assert_eq!(quotes("\"$x$ is\" it"), "``$x$ is'' it");
+        assert_eq!(
+            quotes("\"one\nline\" and\n\"two\"\n"),
+            "``one\nline'' and\n``two''\n"
+        );
+        assert_eq!(quotes("~\"a\"~"), "~``a''~");
+    }
+
+    #[test]
+    fn untouched() {
+        assert_eq!(
+            quotes("don't, it's 'quoted' `too'"),
+            "don't, it's 'quoted' `too'"
+        );
+        assert_eq!(quotes("$\"$ and $\"$"), "$\"$ and $\"$");
+        assert_eq!(quotes("$\"a\"$ \\[\"b\"\\]"), "$\"a\"$ \\[\"b\"\\]");
+        assert_eq!(
+            quotes("\\verb|\"a\"| \\url{\"a\"}"),
+            "\\verb|\"a\"| \\url{\"a\"}"
+        );
+        assert_eq!(
+            quotes("\\begin{tabular}{l}\n\"a\"\n\\end{tabular}"),
+            "\\begin{tabular}{l}\n\"a\"\n\\end{tabular}"
+        );
+        assert_eq!(quotes("a \" b"), "a \" b");
+        assert_eq!(quotes("gr\"o\"s"), "gr\"o\"s");
+        assert_eq!(quotes("\"a and \"o"), "\"a and \"o");
+        // a quotation does not span paragraphs
+        assert_eq!(quotes("\"a\n\nb\""), "\"a\n\nb\"");
+        // 5" and 3"
+        assert_eq!(quotes("5\" and 3\""), "5\" and 3\"");
+    }
+}
@@ -0,0 +1,656 @@
+//! Reflow paragraphs to a maximum line width.
+
+use std::collections::BTreeMap;
+
+use super::{is_math_size_command, Environments, NO_REFLOW_ENVIRONMENTS};
+use crate::lexer::{NewlineKind, Token};
+
+/// Abbreviations that do not end a sentence, despite the final period.
+const ABBREVIATIONS: &[&str] = &[
+    "al.", "approx.", "cf.", "ch.", "dr.", "e.g.", "eq.", "eqs.", "fig.", "figs.", "i.e.", "mr.",
+    "mrs.", "ms.", "no.", "prof.", "resp.", "sec.", "st.", "vs.",
+];
+
+/// How the words of a paragraph are distributed on lines.
+#[derive(Clone, Copy)]
+enum Wrap {
+    /// Fill lines up to a maximum width, with a tab taking the given number of columns.
+    Width { width: usize, tab_width: usize },
+    /// Put each sentence on its own line.
+    Sentence,
+}
+
+/// Whether `word` ends a sentence.
+fn ends_sentence<S: AsRef<str>>(word: &[Token<S>]) -> bool {
+    let text = match word.last() {
+        Some(Token::Text(text)) => text.as_ref(),
+        _ => return false,
+    };
+    let text = text.trim_end_matches([')', '\'', '"']);
+    // `\!` is a negative thin space
+    if !text.ends_with(['.', '!', '?']) || text.ends_with("\\!") {
+        return false;
+    }
+    // the abbreviation is at the end of the last text in the word
+    let last = text
+        .rsplit(|c: char| !c.is_alphanumeric() && c != '.')
+        .next();
+    let last = last.unwrap_or(text);
+    let initial = last.chars().count() == 2 && last.starts_with(|c: char| c.is_uppercase());
+    !initial
+        && !ABBREVIATIONS
+            .iter()
+            .any(|abbr| abbr.eq_ignore_ascii_case(last))
+}
+
+/// Width of the source text of `token`, in display columns.
+///
+/// Wide characters such as `京` take two columns, combining marks take none, and a tab
+/// takes `tab_width` columns.
+pub(crate) fn width<S: AsRef<str>>(token: &Token<S>, tab_width: usize) -> usize {
+    struct Columns {
+        columns: usize,
+        tab_width: usize,
+    }
+
+    impl std::fmt::Write for Columns {
+        fn write_str(&mut self, s: &str) -> std::fmt::Result {
+            self.columns += s
+                .chars()
+                .map(|c| match c {
+                    '\t' => self.tab_width,
+                    c => unicode_width::UnicodeWidthChar::width(c).unwrap_or(0),
+                })
+                .sum::<usize>();
+            Ok(())
+        }
+    }
+
+    let mut columns = Columns {
+        columns: 0,
+        tab_width,
+    };
+    std::fmt::Write::write_fmt(&mut columns, format_args!("{token}")).unwrap();
+    columns.columns
+}
+
+/// Nesting of braces and math, used to find the places where a line can be broken.
+#[derive(Default)]
+struct Nesting {
+    braces: isize,
+    math: bool,
+}
+
+impl Nesting {
+    fn update<S>(&mut self, token: &Token<S>) {
+        match token {
+            Token::LBrace => self.braces += 1,
+            Token::RBrace => self.braces -= 1,
+            Token::InlineMath | Token::TDisplayMath => self.math = !self.math,
+            Token::BInlineMath | Token::BDisplayMath => self.math = true,
+            Token::EInlineMath | Token::EDisplayMath => self.math = false,
+            _ => {}
+        }
+    }
+
+    fn is_top_level(&self) -> bool {
+        self.braces == 0 && !self.math
+    }
+}
+
+/// A line of the source, delimited by newlines outside of groups and math.
+enum Line {
+    /// A line containing only whitespace.
+    Blank,
+    /// A line that must be left untouched.
+    Fixed,
+    /// A line of text that can be reflowed.
+    Prose {
+        /// The line starts a new paragraph, because it starts with a command.
+        starts_with_command: bool,
+        /// The line ends the paragraph, because it ends with `\\`, with `\par` or with a
+        /// comment, which eats the newline.
+        ends_paragraph: bool,
+    },
+}
+
+fn classify<S: AsRef<str>>(line: &[Token<S>], in_environment: bool) -> Line {
+    let mut nesting = Nesting::default();
+    let mut has_text = false;
+    let mut comment = false;
+    for token in line {
+        match token {
+            // the comment is the last token of the line
+            Token::Comment(_) if nesting.is_top_level() => comment = true,
+            Token::Newline(_)
+            | Token::Comment(_)
+            | Token::BeginEnv(..)
+            | Token::EndEnv(..)
+            | Token::Verbatim(_)
+            | Token::BDisplayMath
+            | Token::EDisplayMath
+            | Token::TDisplayMath => return Line::Fixed,
+            Token::Text(_) if nesting.is_top_level() => has_text = true,
+            _ => {}
+        }
+        nesting.update(token);
+        if nesting.braces < 0 {
+            return Line::Fixed;
+        }
+    }
+    let mut words = line.iter().filter(|t| !matches!(t, Token::Whitespace(_)));
+    match words.next() {
+        None => Line::Blank,
+        Some(_) if in_environment || !has_text || !nesting.is_top_level() => Line::Fixed,
+        Some(first) => Line::Prose {
+            starts_with_command: matches!(first, Token::Command(_)),
+            ends_paragraph: comment
+                || match words.last().unwrap_or(first) {
+                    Token::Endline | Token::EndlineSpaced(_) => true,
+                    token => is_par(token),
+                },
+        },
+    }
+}
+
+/// Split the tokens at the newlines outside of groups and math.
+fn split_lines<S>(tokens: &[Token<S>]) -> Vec<&[Token<S>]> {
+    let mut lines = Vec::new();
+    let mut nesting = Nesting::default();
+    let mut start = 0;
+    for (i, token) in tokens.iter().enumerate() {
+        if matches!(token, Token::Newline(_)) && nesting.is_top_level() {
+            lines.push(&tokens[start..i]);
+            start = i + 1;
+        }
+        nesting.update(token);
+    }
+    lines.push(&tokens[start..]);
+    lines
+}
+
+/// Whether `token` is the command `\par`.
+fn is_par<S: AsRef<str>>(token: &Token<S>) -> bool {
+    matches!(token, Token::Command(name) if name.as_ref() == "par")
+}
+
+/// Split a line of prose after each `\par` outside of groups and math that is followed
+/// by text, dropping the spaces after it.
+fn split_par<S: AsRef<str>>(line: &[Token<S>]) -> Vec<&[Token<S>]> {
+    let mut segments = Vec::new();
+    let mut nesting = Nesting::default();
+    let mut start = 0;
+    for (i, token) in line.iter().enumerate() {
+        nesting.update(token);
+        if !(is_par(token) && nesting.is_top_level()) {
+            continue;
+        }
+        // a comment stays after the `\par`
+        match line[i + 1..]
+            .iter()
+            .position(|token| !matches!(token, Token::Whitespace(_)))
+        {
+            Some(offset) if !matches!(line[i + 1 + offset], Token::Comment(_)) => {
+                segments.push(&line[start..=i]);
+                start = i + 1 + offset;
+            }
+            _ => {}
+        }
+    }
+    segments.push(&line[start..]);
+    segments
+}
+
+/// The arguments of the command being read, so that its line is not broken before them.
+#[derive(Default)]
+struct Arguments {
+    /// The last tokens are a command and its arguments, so that `[` and `{` continue them.
+    open: bool,
+    /// Inside `[...]` following a command.
+    optional: bool,
+    /// The number of required arguments still expected, as listed in `command_args`.
+    remaining: usize,
+    /// The last tokens are a command sizing a delimiter, such as `\left`, and spaces.
+    delimiter: bool,
+}
+
+impl Arguments {
+    /// Update the state with `token`, found at the top level, or closing a group if
+    /// `closes_group`.
+    fn update<S: AsRef<str>>(
+        &mut self,
+        token: &Token<S>,
+        closes_group: bool,
+        command_args: &BTreeMap<String, usize>,
+    ) {
+        match token {
+            Token::Command(name) if !self.optional && is_math_size_command(name.as_ref()) => {
+                *self = Arguments {
+                    delimiter: true,
+                    ..Arguments::default()
+                };
+            }
+            Token::Command(name) if !self.optional => {
+                *self = Arguments {
+                    open: true,
+                    optional: false,
+                    remaining: command_args.get(name.as_ref()).copied().unwrap_or(0),
+                    delimiter: false,
+                };
+            }
+            Token::LBracket if self.open => self.optional = true,
+            Token::RBracket if self.optional => self.optional = false,
+            Token::RBrace if closes_group && self.open && !self.optional => {
+                self.remaining = self.remaining.saturating_sub(1);
+            }
+            Token::LBrace | Token::Whitespace(_) if self.open => {}
+            Token::Whitespace(_) if self.delimiter => {}
+            _ if self.optional => {}
+            _ => *self = Arguments::default(),
+        }
+    }
+
+    /// Whether the whitespace before `next` is inside the arguments of the command.
+    fn continue_before<S>(&self, next: Option<&Token<S>>) -> bool {
+        self.optional
+            || (self.remaining > 0 && matches!(next, Some(Token::LBrace)))
+            || (self.delimiter && !matches!(next, None | Some(Token::Comment(_))))
+    }
+}
+
+/// Rewrap the paragraph made of `lines`, indenting each line with `indent`.
+///
+/// The spaces in the optional arguments of a command, those before the required arguments
+/// of the commands in `command_args`, and those between a command such as `\left` and its
+/// delimiter do not separate words.
+fn wrap_paragraph<'a, S>(
+    indent: Option<&Token<S>>,
+    lines: &[&[Token<S>]],
+    wrap: Wrap,
+    command_args: &BTreeMap<String, usize>,
+) -> Vec<Vec<Token<S>>>
+where
+    S: AsRef<str> + Clone + From<&'a str>,
+{
+    let tab_width = match wrap {
+        Wrap::Width { tab_width, .. } => tab_width,
+        Wrap::Sentence => 1,
+    };
+    let indent_width = indent.map_or(0, |indent| self::width(indent, tab_width));
+
+    // the unbreakable sequences of tokens
+    let mut words: Vec<Vec<Token<S>>> = Vec::new();
+    for line in lines {
+        let mut nesting = Nesting::default();
+        let mut arguments = Arguments::default();
+        let mut word = Vec::new();
+        let mut space = None;
+        for (i, token) in line.iter().enumerate() {
+            let top_level = nesting.is_top_level();
+            let next = line[i + 1..]
+                .iter()
+                .find(|token| !matches!(token, Token::Whitespace(_)));
+            if matches!(token, Token::Whitespace(_))
+                && top_level
+                && (word.is_empty() || !arguments.continue_before(next))
+            {
+                arguments = Arguments::default();
+                if !word.is_empty() {
+                    words.push(std::mem::take(&mut word));
+                }
+                space = Some(token);
+            } else if let (Token::Comment(_), true, Some(last)) =
+                (token, word.is_empty(), words.last_mut())
+            {
+                // a comment stays after the word before it, with the same spaces
+                last.extend(space.cloned());
+                last.push(token.clone());
+            } else {
+                nesting.update(token);
+                if top_level || nesting.is_top_level() {
+                    arguments.update(token, !top_level, command_args);
+                }
+                word.push(token.clone());
+            }
+        }
+        if !word.is_empty() {
+            words.push(word);
+        }
+    }
+
+    let mut result = Vec::new();
+    let mut line: Vec<Token<S>> = Vec::new();
+    let mut column = 0;
+    let mut end_of_sentence = false;
+    for word in words {
+        let word_width: usize = word.iter().map(|token| self::width(token, tab_width)).sum();
+        let fits = match wrap {
+            Wrap::Width { width, .. } => column + 1 + word_width <= width,
+            Wrap::Sentence => !end_of_sentence,
+        };
+        if !line.is_empty() && !fits {
+            result.push(std::mem::take(&mut line));
+        }
+        end_of_sentence = ends_sentence(&word);
+        if line.is_empty() {
+            line.extend(indent.cloned());
+            column = indent_width;
+        } else {
+            line.push(Token::Whitespace(S::from(" ")));
+            column += 1;
+        }
+        column += word_width;
+        line.extend(word);
+    }
+    result.push(line);
+    result
+}
+
+/// Reflow the paragraphs of text so that no line exceeds `width` columns, if possible.
+///
+/// Lines are broken only at whitespace outside of groups and math, never at a tie `~`.
+/// Paragraphs are delimited by blank lines, environments, display math, lines of comments,
+/// and by lines consisting only of commands.
+/// A line starting with a command starts a new paragraph and a line ending with `\\`
+/// or with a comment ends it, and the comment stays after the word before it.
+/// A `\par` ends the paragraph as a blank line does, and the text after it on the same line
+/// starts a new paragraph with the indentation of that line.
+/// The content of math and tabular environments is not reflowed.
+/// A word that is longer than `width` is left alone on its line, and a tab in the
+/// indentation or in the text takes `tab_width` columns.
+///
+/// A command stays on the same line as its arguments: those that immediately follow it,
+/// and also those after spaces for the commands that take the number of arguments
+/// given in `command_args`.
+pub fn reflow<'a, S>(
+    tokens: &[Token<S>],
+    width: usize,
+    tab_width: usize,
+    command_args: &BTreeMap<String, usize>,
+) -> Vec<Token<S>>
+where
+    S: AsRef<str> + Clone + From<&'a str>,
+{
+    rewrap(tokens, Wrap::Width { width, tab_width }, command_args)
+}
+
+/// Put each sentence of the paragraphs of text on its own line.
+///
+/// A sentence ends with `.`, `!` or `?` followed by whitespace, except for common
+/// abbreviations such as "e.g." and "Fig." and for initials.
+/// Paragraphs and the arguments of commands are detected as in [`reflow`].
+pub fn sentence_wrap<'a, S>(
+    tokens: &[Token<S>],
+    command_args: &BTreeMap<String, usize>,
+) -> Vec<Token<S>>
+where
+    S: AsRef<str> + Clone + From<&'a str>,
+{
+    rewrap(tokens, Wrap::Sentence, command_args)
+}
+
+/// Rewrap the paragraphs of text.
+fn rewrap<'a, S>(
+    tokens: &[Token<S>],
+    wrap: Wrap,
+    command_args: &BTreeMap<String, usize>,
+) -> Vec<Token<S>>
+where
+    S: AsRef<str> + Clone + From<&'a str>,
+{
+    let newline = Token::Newline(NewlineKind::detect(tokens).unwrap_or_default());
+    let mut result = Vec::with_capacity(tokens.len());
+    let mut environments = Environments::default();
+    let mut paragraph = Vec::new();
+    let mut indent = None;
+
+    let mut first = true;
+    let mut emit = |lines: Vec<Vec<Token<S>>>| {
+        for line in lines {
+            if !std::mem::take(&mut first) {
+                result.push(newline.clone());
+            }
+            result.extend(line);
+        }
+    };
+
+    for line in split_lines(tokens) {
+        match classify(line, environments.inside()) {
+            Line::Prose {
+                starts_with_command,
+                ends_paragraph,
+            } => {
+                if starts_with_command && !paragraph.is_empty() {
+                    emit(wrap_paragraph(indent, &paragraph, wrap, command_args));
+                    paragraph.clear();
+                }
+                let mut segments = split_par(line).into_iter().peekable();
+                while let Some(segment) = segments.next() {
+                    if paragraph.is_empty() {
+                        indent = line.first().filter(|t| matches!(t, Token::Whitespace(_)));
+                    }
+                    paragraph.push(segment);
+                    if ends_paragraph || segments.peek().is_some() {
+                        emit(wrap_paragraph(indent, &paragraph, wrap, command_args));
+                        paragraph.clear();
+                    }
+                }
+            }
+            Line::Blank | Line::Fixed => {
+                if !paragraph.is_empty() {
+                    emit(wrap_paragraph(indent, &paragraph, wrap, command_args));
+                    paragraph.clear();
+                }
+                emit(vec![line.to_vec()]);
+            }
+        }
+        for token in line {
+            environments.update(token, NO_REFLOW_ENVIRONMENTS);
+        }
+    }
+    if !paragraph.is_empty() {
+        emit(wrap_paragraph(indent, &paragraph, wrap, command_args));
+    }
+
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::BTreeMap;
+
+    use crate::lexer::lex_tokens;
+
+    fn reflow(input: &str, width: usize) -> String {
+        let tokens = lex_tokens(input).unwrap();
+        super::reflow(&tokens, width, 8, &BTreeMap::new())
+            .iter()
+            .map(|t| t.to_string())
+            .collect()
+    }
+
+    const LOREM: &str = "Lorem ipsum dolor sit amet, consectetur adipiscing elit,\nsed do eiusmod tempor incididunt ut labore et dolore magna aliqua.\n";
+
+    #[test]
+    fn width_20() {
+        assert_eq!(
+            reflow(LOREM, 20),
+            "Lorem ipsum dolor\nsit amet,\nconsectetur\nadipiscing elit, sed\ndo eiusmod tempor\nincididunt ut labore\net dolore magna\naliqua.\n"
+        );
+    }
+
+    #[test]
+    fn width_40() {
+        assert_eq!(
+            reflow(LOREM, 40),
+            "Lorem ipsum dolor sit amet, consectetur\nadipiscing elit, sed do eiusmod tempor\nincididunt ut labore et dolore magna\naliqua.\n"
+        );
+    }
+
+    #[test]
+    fn idempotent() {
+        for width in [10, 20, 40, 80] {
+            let once = reflow(LOREM, width);
+            assert_eq!(reflow(&once, width), once);
+        }
+    }
+
+    #[test]
+    fn paragraphs() {
+        assert_eq!(
+            reflow("aaa bbb\nccc\n\n  ddd eee\n  fff", 8),
+            "aaa bbb\nccc\n\n  ddd\n  eee\n  fff"
+        );
+        assert_eq!(reflow("aaa\n\\[ x \\]\nbbb\n", 80), "aaa\n\\[ x \\]\nbbb\n");
+        assert_eq!(
+            reflow("\\section{A}\naaa\nbbb\n\\item ccc\nddd \\\\\neee\n", 80),
+            "\\section{A}\naaa bbb\n\\item ccc ddd \\\\\neee\n"
+        );
+    }
+
+    #[test]
+    fn unbreakable() {
+        assert_eq!(
+            reflow("a \\textbf{b c d} $e + f$ g\n", 4),
+            "a\n\\textbf{b c d}\n$e + f$\ng\n"
+        );
+        assert_eq!(
+            reflow("short averyveryverylongword short\n", 10),
+            "short\naveryveryverylongword\nshort\n"
+        );
+        assert_eq!(reflow("a {b\nc} d\n", 1), "a {b\nc} d\n");
+        assert_eq!(
+            reflow("see Fig.~1 and Ref.~\\cite{x}\n", 3),
+            "see\nFig.~1\nand\nRef.~\\cite{x}\n"
+        );
+    }
+
+    #[test]
+    fn arguments() {
+        assert_eq!(
+            reflow("some \\emph{hello} text\n", 10),
+            "some\n\\emph{hello}\ntext\n"
+        );
+        assert_eq!(
+            reflow("see \\cite[p. 5]{x} now\n", 5),
+            "see\n\\cite[p. 5]{x}\nnow\n"
+        );
+        assert_eq!(
+            reflow("a \\href {u} {t} b\n", 1),
+            "a\n\\href\n{u}\n{t}\nb\n"
+        );
+        let tokens = lex_tokens("a \\href {u} {t} {b}\n").unwrap();
+        let command_args = BTreeMap::from([("href".to_owned(), 2)]);
+        let reflowed: String = super::reflow(&tokens, 1, 8, &command_args)
+            .iter()
+            .map(|t| t.to_string())
+            .collect();
+        assert_eq!(reflowed, "a\n\\href {u} {t}\n{b}\n");
+    }
+
+    #[test]
+    fn delimiters() {
+        let src = "a $\\left(x\\right)$ b\n";
+        assert_eq!(reflow(src, 1), "a\n$\\left(x\\right)$\nb\n");
+        // outside of math as well, as in the arguments of a macro
+        assert_eq!(
+            reflow("a \\left( x \\bigl \\{ y \\Bigr] z\n", 1),
+            "a\n\\left(\nx\n\\bigl \\{\ny\n\\Bigr]\nz\n"
+        );
+        assert_eq!(reflow("a \\right % c\nb\n", 1), "a\n\\right % c\nb\n");
+    }
+
+    #[test]
+    fn environments() {
+        let src = "\\begin{align}\n  a &= b \\\\\n  c &= d\n\\end{align}\n";
+        assert_eq!(reflow(src, 3), src);
+        let src = "\\begin{itemize}\n\\item a b\nc\n\\end{itemize}\n";
+        assert_eq!(
+            reflow(src, 80),
+            "\\begin{itemize}\n\\item a b c\n\\end{itemize}\n"
+        );
+    }
+
+    #[test]
+    fn comments() {
+        let src = "aaa % c\nbbb\n";
+        assert_eq!(reflow(src, 80), src);
+        assert_eq!(
+            reflow("aaa\nsome long text   % note\nnext line", 14),
+            "aaa some long\ntext   % note\nnext line"
+        );
+        assert_eq!(
+            reflow("some long text% note\nnext", 80),
+            "some long text% note\nnext"
+        );
+        let src = "% only a comment\naaa bbb\n{a % c\n}\n";
+        assert_eq!(reflow(src, 5), src.replace("aaa bbb", "aaa\nbbb"));
+    }
+
+    #[test]
+    fn tabs() {
+        let reflow = |input, tab_width| {
+            let tokens = lex_tokens(input).unwrap();
+            let reflowed = super::reflow(&tokens, 12, tab_width, &BTreeMap::new());
+            reflowed.iter().map(|t| t.to_string()).collect::<String>()
+        };
+        // the indentation takes 8 columns, or 4
+        assert_eq!(reflow("\taaa bbb\n", 8), "\taaa\n\tbbb\n");
+        assert_eq!(reflow("\taaa bbb\n", 4), "\taaa bbb\n");
+    }
+
+    #[test]
+    fn unicode() {
+        // 7 columns, but 11 bytes
+        assert_eq!(reflow("été été\n", 7), "été été\n");
+        assert_eq!(reflow("été été\n", 6), "été\nété\n");
+        // wide characters take 2 columns each
+        assert_eq!(reflow("京都 京都\n", 9), "京都 京都\n");
+        assert_eq!(reflow("京都 京都\n", 8), "京都\n京都\n");
+        // combining marks take no columns
+        let combined = "e\u{301}e\u{301} e\u{301}e\u{301}\n";
+        assert_eq!(reflow(combined, 5), combined);
+        assert_eq!(super::width(&crate::lexer::Token::Text("e\u{301}京"), 8), 3);
+    }
+
+    #[test]
+    fn par() {
+        assert_eq!(reflow("a \\par b\n", 80), "a \\par\nb\n");
+        // two paragraphs, each keeping the indentation of its first line
+        assert_eq!(reflow("  a \\par b\nc\n", 80), "  a \\par\n  b c\n");
+        assert_eq!(
+            reflow("some text \\par more\ntext.\\par\n  and more\n", 80),
+            "some text \\par\nmore text.\\par\n  and more\n"
+        );
+        assert_eq!(reflow("a \\par % c\nb\n", 80), "a \\par % c\nb\n");
+        assert_eq!(reflow("{a \\par b}\n", 80), "{a \\par b}\n");
+        // not the commands starting with `par`
+        assert_eq!(reflow("a \\parbox{x} b\n", 80), "a \\parbox{x} b\n");
+        assert_eq!(sentence_wrap("A b \\par c d.\n"), "A b \\par\nc d.\n");
+    }
+
+    fn sentence_wrap(input: &str) -> String {
+        let tokens = lex_tokens(input).unwrap();
+        super::sentence_wrap(&tokens, &BTreeMap::new())
+            .iter()
+            .map(|t| t.to_string())
+            .collect()
+    }
+
+    #[test]
+    fn sentences() {
+        assert_eq!(
+            sentence_wrap("Dr. Smith went home. He slept.\n"),
+            "Dr. Smith went home.\nHe slept.\n"
+        );
+        assert_eq!(
+            sentence_wrap("One! Two?\nThree, see Fig.~1 and\ne.g. J. Doe (or \\cite{x}).  Four.\n"),
+            "One!\nTwo?\nThree, see Fig.~1 and e.g. J. Doe (or \\cite{x}).\nFour.\n"
+        );
+        assert_eq!(
+            sentence_wrap("  A \\emph{b.} c.\n\n$x.$ y.\n"),
+            "  A \\emph{b.} c.\n\n$x.$ y.\n"
+        );
+        assert_eq!(sentence_wrap("a\\! b. c\n"), "a\\! b.\nc\n");
+    }
+}
@@ -0,0 +1,1051 @@
+//! Format a whole (La)TeX source with the configured passes.
+
+use std::borrow::Cow;
+use std::collections::BTreeMap;
+
+use crate::format::{IndentStyle, MathDelimiters, PunctuationSpacing};
+use crate::lexer::NewlineKind;
+use crate::pipeline::{Pipeline, Trace};
+use crate::timing::{self, Phase};
+use crate::{check, format, lexer, writer};
+
+/// Options controlling [`format_str`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FormatOptions {
+    /// Maximum line width.
+    pub width: usize,
+    /// Number of spaces per indentation level.
+    pub indent_width: usize,
+    /// Indent with tabs instead of spaces.
+    pub use_tabs: bool,
+    /// Rewrite the existing indentation in this style, keeping its depth, instead of
+    /// indenting the lines by their nesting depth, if any.
+    pub indent_style: Option<IndentStyle>,
+    /// Number of columns taken by a tab of the input, when measuring the width of lines.
+    pub tab_width: usize,
+    /// Put each sentence on its own line, instead of reflowing to `width`.
+    pub sentence_wrap: bool,
+    /// Align the `&` separators of tables into columns.
+    pub align_tables: bool,
+    /// Put exactly one space between `%` and the text of comments.
+    pub comment_space: bool,
+    /// Rewrite the delimiters of math formulas in this style, if any.
+    pub math_delimiters: Option<MathDelimiters>,
+    /// Normalize the spaces around punctuation marks with these rules, if any.
+    pub punctuation_spacing: Option<PunctuationSpacing>,
+    /// Split the commands loading lists of packages, such as `\usepackage{a,b}`.
+    pub split_packages: bool,
+    /// Sort alphabetically the consecutive commands loading packages, and their lists.
+    pub sort_packages: bool,
+    /// Remove the braces around single words of the text, as in `a{b}c`.
+    pub remove_redundant_braces: bool,
+    /// Write the straight double quotes of the text as TeX quotation marks, as in ``` ``a'' ```.
+    pub normalize_quotes: bool,
+    /// Maximum number of consecutive blank lines, so that 0 joins all the paragraphs.
+    pub max_consecutive_newlines: usize,
+    /// Line ending of the output.
+    pub line_ending: LineEnding,
+    /// Line ending which ends the output, replacing the trailing newlines of the input.
+    pub final_newline: FinalNewline,
+    /// Put a comma after the last field of BibTeX entries.
+    pub bib_trailing_comma: bool,
+    /// Format plain TeX, where `\begin` and `\end` are ordinary commands, instead of LaTeX.
+    pub plain_tex: bool,
+    /// Language of the inputs, instead of the one detected from the extension of their
+    /// paths, if any.
+    pub lang: Option<Lang>,
+    /// Environments whose body is copied as it is, such as `verbatim`.
+    pub verbatim_environments: Vec<String>,
+    /// Environments whose body is copied as it is, while their `\begin` and `\end` are
+    /// indented, such as `tikzpicture`.
+    pub opaque_environments: Vec<String>,
+    /// Commands citing a list of keys, such as `\cite`, whose spaces are removed.
+    pub citation_commands: Vec<String>,
+    /// Number of required arguments of commands, which stay on the line of the command
+    /// when reflowing even if spaces separate them.
+    pub command_args: BTreeMap<String, usize>,
+}
+
+impl Default for FormatOptions {
+    /// The defaults of the command line interface.
+    fn default() -> Self {
+        FormatOptions {
+            width: 80,
+            indent_width: 2,
+            use_tabs: false,
+            indent_style: None,
+            tab_width: 8,
+            sentence_wrap: false,
+            align_tables: false,
+            comment_space: false,
+            math_delimiters: None,
+            punctuation_spacing: None,
+            split_packages: false,
+            sort_packages: false,
+            remove_redundant_braces: false,
+            normalize_quotes: false,
+            max_consecutive_newlines: 1,
+            line_ending: LineEnding::Auto,
+            final_newline: FinalNewline::Auto,
+            bib_trailing_comma: false,
+            plain_tex: false,
+            lang: None,
+            verbatim_environments: lexer::VERBATIM_ENVIRONMENTS
+                .iter()
+                .map(|&name| name.to_owned())
+                .collect(),
+            opaque_environments: lexer::OPAQUE_ENVIRONMENTS
+                .iter()
+                .map(|&name| name.to_owned())
+                .collect(),
+            citation_commands: format::CITATION_COMMANDS
+                .iter()
+                .map(|&name| name.to_owned())
+                .collect(),
+            command_args: BTreeMap::new(),
+        }
+    }
+}
+
+/// Line ending of the formatted output.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, clap::ArgEnum, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum LineEnding {
+    /// The line ending of the first line of the input
+    #[default]
+    Auto,
+    /// `'\n'`
+    Lf,
+    /// `"\r\n"`
+    Crlf,
+}
+
+/// Language of the inputs.
+#[derive(
+    Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum Lang {
+    /// Plain TeX, where `\begin` and `\end` are ordinary commands
+    Tex,
+    /// LaTeX, with environments
+    Latex,
+    /// BibTeX
+    Bib,
+}
+
+/// End of the formatted output.
+#[derive(
+    Clone, Copy, Debug, Default, PartialEq, Eq, clap::ArgEnum, serde::Deserialize, serde::Serialize,
+)]
+#[serde(rename_all = "kebab-case")]
+pub enum FinalNewline {
+    /// Exactly one line ending, the same as the other lines
+    #[default]
+    Auto,
+    /// Exactly one `'\n'`
+    Lf,
+    /// Exactly one `"\r\n"`
+    Crlf,
+    /// The trailing newlines left by the formatting, which keeps at most one blank line
+    Keep,
+}
+
+impl FormatOptions {
+    /// Create a builder starting from the default options.
+    pub fn builder() -> FormatOptionsBuilder {
+        FormatOptionsBuilder::default()
+    }
+}
+
+/// Builder of [`FormatOptions`], validating them.
+///
+/// # Examples
+///
+/// ```rust
+/// use texfmt::FormatOptions;
+/// let options = FormatOptions::builder().width(100).use_tabs(true).build().unwrap();
+/// assert_eq!(options.width, 100);
+/// assert!(FormatOptions::builder().width(0).build().is_err());
+/// ```
+#[derive(Clone, Debug, Default)]
+pub struct FormatOptionsBuilder {
+    options: FormatOptions,
+}
+
+impl FormatOptionsBuilder {
+    /// Set the maximum line width.
+    pub fn width(mut self, width: usize) -> Self {
+        self.options.width = width;
+        self
+    }
+
+    /// Set the number of spaces per indentation level.
+    pub fn indent_width(mut self, indent_width: usize) -> Self {
+        self.options.indent_width = indent_width;
+        self
+    }
+
+    /// Indent with tabs instead of spaces.
+    pub fn use_tabs(mut self, use_tabs: bool) -> Self {
+        self.options.use_tabs = use_tabs;
+        self
+    }
+
+    /// Set the style in which the existing indentation is rewritten, if any.
+    pub fn indent_style(mut self, indent_style: Option<IndentStyle>) -> Self {
+        self.options.indent_style = indent_style;
+        self
+    }
+
+    /// Set the number of columns taken by a tab when measuring the width of lines.
+    pub fn tab_width(mut self, tab_width: usize) -> Self {
+        self.options.tab_width = tab_width;
+        self
+    }
+
+    /// Put each sentence on its own line, instead of reflowing to the width.
+    pub fn sentence_wrap(mut self, sentence_wrap: bool) -> Self {
+        self.options.sentence_wrap = sentence_wrap;
+        self
+    }
+
+    /// Align the `&` separators of tables into columns.
+    pub fn align_tables(mut self, align_tables: bool) -> Self {
+        self.options.align_tables = align_tables;
+        self
+    }
+
+    /// Put exactly one space between `%` and the text of comments.
+    pub fn comment_space(mut self, comment_space: bool) -> Self {
+        self.options.comment_space = comment_space;
+        self
+    }
+
+    /// Rewrite the delimiters of math formulas in the given style, or leave them alone.
+    pub fn math_delimiters(mut self, math_delimiters: Option<MathDelimiters>) -> Self {
+        self.options.math_delimiters = math_delimiters;
+        self
+    }
+
+    /// Normalize the spaces around punctuation marks with the given rules, or leave them alone.
+    pub fn punctuation_spacing(mut self, punctuation_spacing: Option<PunctuationSpacing>) -> Self {
+        self.options.punctuation_spacing = punctuation_spacing;
+        self
+    }
+
+    /// Split the commands loading lists of packages into one command per package.
+    pub fn split_packages(mut self, split_packages: bool) -> Self {
+        self.options.split_packages = split_packages;
+        self
+    }
+
+    /// Sort alphabetically the consecutive commands loading packages, and their lists.
+    pub fn sort_packages(mut self, sort_packages: bool) -> Self {
+        self.options.sort_packages = sort_packages;
+        self
+    }
+
+    /// Remove the braces around single words of the text, as in `a{b}c`.
+    pub fn remove_redundant_braces(mut self, remove_redundant_braces: bool) -> Self {
+        self.options.remove_redundant_braces = remove_redundant_braces;
+        self
+    }
+
+    /// Write the straight double quotes of the text as TeX quotation marks.
+    pub fn normalize_quotes(mut self, normalize_quotes: bool) -> Self {
+        self.options.normalize_quotes = normalize_quotes;
+        self
+    }
+
+    /// Set the maximum number of consecutive blank lines.
+    pub fn max_consecutive_newlines(mut self, max_consecutive_newlines: usize) -> Self {
+        self.options.max_consecutive_newlines = max_consecutive_newlines;
+        self
+    }
+
+    /// Set the line ending of the output.
+    pub fn line_ending(mut self, line_ending: LineEnding) -> Self {
+        self.options.line_ending = line_ending;
+        self
+    }
+
+    /// Set how the output ends.
+    pub fn final_newline(mut self, final_newline: FinalNewline) -> Self {
+        self.options.final_newline = final_newline;
+        self
+    }
+
+    /// Put a comma after the last field of BibTeX entries.
+    pub fn bib_trailing_comma(mut self, bib_trailing_comma: bool) -> Self {
+        self.options.bib_trailing_comma = bib_trailing_comma;
+        self
+    }
+
+    /// Format plain TeX instead of LaTeX.
+    pub fn plain_tex(mut self, plain_tex: bool) -> Self {
+        self.options.plain_tex = plain_tex;
+        self
+    }
+
+    /// Set the language of the inputs, if any, which is also plain TeX or not.
+    pub fn lang(mut self, lang: Option<Lang>) -> Self {
+        self.options.lang = lang;
+        if let Some(lang) = lang {
+            self.options.plain_tex = lang == Lang::Tex;
+        }
+        self
+    }
+
+    /// Set the environments whose body is copied as it is.
+    pub fn verbatim_environments(mut self, verbatim_environments: Vec<String>) -> Self {
+        self.options.verbatim_environments = verbatim_environments;
+        self
+    }
+
+    /// Set the environments whose body is copied as it is, but whose `\begin` and `\end`
+    /// are indented.
+    pub fn opaque_environments(mut self, opaque_environments: Vec<String>) -> Self {
+        self.options.opaque_environments = opaque_environments;
+        self
+    }
+
+    /// Set the commands citing a list of keys.
+    pub fn citation_commands(mut self, citation_commands: Vec<String>) -> Self {
+        self.options.citation_commands = citation_commands;
+        self
+    }
+
+    /// Set the number of required arguments of commands.
+    pub fn command_args(mut self, command_args: BTreeMap<String, usize>) -> Self {
+        self.options.command_args = command_args;
+        self
+    }
+
+    /// Validate the options.
+    pub fn build(self) -> Result<FormatOptions, OptionsError> {
+        if self.options.width == 0 {
+            return Err(OptionsError::ZeroWidth);
+        }
+        if self.options.tab_width == 0 {
+            return Err(OptionsError::ZeroTabWidth);
+        }
+        Ok(self.options)
+    }
+}
+
+/// Error returned by [`FormatOptionsBuilder::build`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum OptionsError {
+    /// The maximum line width is zero.
+    ZeroWidth,
+    /// The width of tabs is zero.
+    ZeroTabWidth,
+}
+
+impl std::fmt::Display for OptionsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OptionsError::ZeroWidth => f.write_str("the line width must be positive"),
+            OptionsError::ZeroTabWidth => f.write_str("the tab width must be positive"),
+        }
+    }
+}
+
+impl std::error::Error for OptionsError {}
+
+/// Error returned by [`format_str`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FormatError {
+    /// The input cannot be tokenized.
+    Lex(lexer::LexError),
+    /// The braces of the input are unbalanced.
+    Braces(check::BraceError),
+}
+
+impl std::fmt::Display for FormatError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            FormatError::Lex(e) => e.fmt(f),
+            FormatError::Braces(e) => e.fmt(f),
+        }
+    }
+}
+
+// the message is the one of the wrapped error, so it is not repeated as the source
+impl std::error::Error for FormatError {}
+
+impl From<lexer::LexError> for FormatError {
+    fn from(e: lexer::LexError) -> Self {
+        FormatError::Lex(e)
+    }
+}
+
+impl From<check::BraceError> for FormatError {
+    fn from(e: check::BraceError) -> Self {
+        FormatError::Braces(e)
+    }
+}
+
+/// Format `input` according to `options`.
+///
+/// The lines from a `% texfmt: off` comment to the next `% texfmt: on` comment, included,
+/// are left untouched, and so are all the following lines if there is none.
+/// The output ends with the single line ending chosen by [`FormatOptions::final_newline`],
+/// unless it is empty or it ends inside such a region.
+///
+/// # Examples
+///
+/// ```rust
+/// use texfmt::{format_str, FormatOptions};
+/// let options = FormatOptions::default();
+/// let src = "\\begin{itemize}\n\\item   one  \n\n\n\\item two\n\\end{itemize}\n";
+/// let formatted = format_str(src, &options).unwrap();
+/// assert_eq!(formatted, "\\begin{itemize}\n  \\item one\n\n  \\item two\n\\end{itemize}\n");
+/// assert_eq!(format_str(&formatted, &options).unwrap(), formatted);
+/// ```
+pub fn format_str(input: &str, options: &FormatOptions) -> Result<String, FormatError> {
+    format_with(input, options, &Pipeline::default(), None)
+}
+
+/// Format `input` according to `options` with the passes of `pipeline`, adding what they
+/// did to `trace`.
+pub(crate) fn format_with(
+    input: &str,
+    options: &FormatOptions,
+    pipeline: &Pipeline,
+    trace: Option<&mut Trace>,
+) -> Result<String, FormatError> {
+    let tokens = lex(input, options)?;
+    let mut result = format_guarded(input, &tokens, 0..tokens.len(), options, pipeline, trace);
+    if last_directive(&tokens) != Some(OFF_DIRECTIVE) {
+        let detected = NewlineKind::detect(tokens.iter().map(|(token, _)| token));
+        let newline = newline_kind(options.line_ending, detected);
+        end_with_newline(&mut result, options.final_newline, newline);
+    }
+    Ok(result)
+}
+
+/// The line ending of the output in the style `line_ending`, if the first line ending of
+/// the input is `detected`.
+pub(crate) fn newline_kind(line_ending: LineEnding, detected: Option<NewlineKind>) -> NewlineKind {
+    match line_ending {
+        LineEnding::Auto => detected.unwrap_or_default(),
+        LineEnding::Lf => NewlineKind::Lf,
+        LineEnding::Crlf => NewlineKind::CrLf,
+    }
+}
+
+/// Replace the trailing newlines of `output` with a single line ending in the style `style`,
+/// where `newline` is the line ending of the other lines.
+///
+/// An empty output stays empty, and so does an output made only of newlines.
+pub(crate) fn end_with_newline(output: &mut String, style: FinalNewline, newline: NewlineKind) {
+    let newline = match style {
+        FinalNewline::Auto => newline,
+        FinalNewline::Lf => NewlineKind::Lf,
+        FinalNewline::Crlf => NewlineKind::CrLf,
+        FinalNewline::Keep => return,
+    };
+    output.truncate(output.trim_end_matches(['\n', '\r']).len());
+    if !output.is_empty() {
+        output.push_str(newline.as_str());
+    }
+}
+
+/// Comment switching off the formatting of the following lines.
+const OFF_DIRECTIVE: &str = "texfmt: off";
+
+/// Comment switching the formatting back on after the end of its line.
+const ON_DIRECTIVE: &str = "texfmt: on";
+
+/// Whether `token` is a comment with the text `directive`, ignoring the surrounding spaces.
+fn is_directive(token: &lexer::Token<&str>, directive: &str) -> bool {
+    matches!(token, lexer::Token::Comment(text) if text.trim() == directive)
+}
+
+/// The last of the [`OFF_DIRECTIVE`] and [`ON_DIRECTIVE`] comments in `tokens`, if any.
+fn last_directive(tokens: &[(lexer::Token<&str>, lexer::Span)]) -> Option<&'static str> {
+    tokens.iter().rev().find_map(|(token, _)| {
+        [OFF_DIRECTIVE, ON_DIRECTIVE]
+            .into_iter()
+            .find(|directive| is_directive(token, directive))
+    })
+}
+
+/// Format the tokens in `range`, which is made of whole lines of `input`, with the passes of
+/// `pipeline`, except for the regions guarded by the [`OFF_DIRECTIVE`] and [`ON_DIRECTIVE`]
+/// comments.
+///
+/// A guarded region is made of the lines from the one of `% texfmt: off` to the one of the
+/// following `% texfmt: on`, or to the end of `range`, and it is copied byte-for-byte.
+fn format_guarded(
+    input: &str,
+    tokens: &[(lexer::Token<&str>, lexer::Span)],
+    range: std::ops::Range<usize>,
+    options: &FormatOptions,
+    pipeline: &Pipeline,
+    mut trace: Option<&mut Trace>,
+) -> String {
+    let token_end = |i: usize| tokens.get(i + 1).map_or(input.len(), |(_, s)| s.byte);
+    let is_newline = |i: usize| matches!(tokens[i].0, lexer::Token::Newline(_));
+    let mut format = |chunk: std::ops::Range<usize>| {
+        let context: Vec<_> = tokens[..chunk.start]
+            .iter()
+            .map(|(t, _)| t.clone())
+            .collect();
+        let mut tokens = tokens[chunk]
+            .iter()
+            .map(|(t, _)| t.clone().map(Cow::Borrowed))
+            .collect();
+        pipeline.run_traced(&mut tokens, options, &context, trace.as_deref_mut());
+        timing::time(Phase::Write, || writer::write_tokens(&tokens))
+    };
+
+    // the end of the line of the first `% texfmt: on` after `from`, or of the range
+    let guard_end = |from: usize| {
+        let on = (from..range.end).find(|&j| is_directive(&tokens[j].0, ON_DIRECTIVE));
+        on.map_or(range.end, |on| {
+            (on..range.end)
+                .find(|&j| is_newline(j))
+                .map_or(range.end, |j| j + 1)
+        })
+    };
+
+    let mut result = String::new();
+    // start of the tokens still to format
+    let mut start = range.start;
+    // the range may start inside a guarded region
+    if last_directive(&tokens[..range.start]) == Some(OFF_DIRECTIVE) && start < range.end {
+        start = guard_end(start);
+        result.push_str(&input[tokens[range.start].1.byte..token_end(start - 1)]);
+    }
+    let mut i = start;
+    while i < range.end {
+        if !is_directive(&tokens[i].0, OFF_DIRECTIVE) {
+            i += 1;
+            continue;
+        }
+        // the guarded region starts with the line of `% texfmt: off`
+        let off = (start..i)
+            .rev()
+            .find(|&j| is_newline(j))
+            .map_or(start, |j| j + 1);
+        let end = guard_end(i + 1);
+        if start < off {
+            result.push_str(&format(start..off));
+        }
+        result.push_str(&input[tokens[off].1.byte..token_end(end - 1)]);
+        start = end;
+        i = end;
+    }
+    if start < range.end {
+        result.push_str(&format(start..range.end));
+    }
+    result
+}
+
+/// Format only the lines of `input` overlapping the byte range `start..end`.
+///
+/// The range is extended to whole lines and tokens, so that a command is never split,
+/// and the rest of the input is left untouched. The formatted lines are indented
+/// according to the environments and groups enclosing them.
+///
+/// # Examples
+///
+/// ```rust
+/// use texfmt::{format_range, FormatOptions};
+/// let src = "a  b\nc  d\ne  f\n";
+/// let formatted = format_range(src, 6, 7, &FormatOptions::default()).unwrap();
+/// assert_eq!(formatted, "a  b\nc d\ne  f\n");
+/// ```
+pub fn format_range(
+    input: &str,
+    start: usize,
+    end: usize,
+    options: &FormatOptions,
+) -> Result<String, FormatError> {
+    let tokens = lex(input, options)?;
+    if tokens.is_empty() {
+        return Ok(input.to_owned());
+    }
+    let token_end = |i: usize| tokens.get(i + 1).map_or(input.len(), |(_, s)| s.byte);
+    let is_newline = |i: usize| matches!(tokens[i].0, lexer::Token::Newline(_));
+    // the tokens containing the first and the last byte of the range
+    let first = (0..tokens.len())
+        .find(|&i| token_end(i) > start)
+        .unwrap_or(tokens.len() - 1);
+    let last = (first..tokens.len())
+        .find(|&i| token_end(i) >= end.max(start + 1))
+        .unwrap_or(tokens.len() - 1);
+    // extended to whole lines
+    let first = (0..first)
+        .rev()
+        .find(|&i| is_newline(i))
+        .map_or(0, |i| i + 1);
+    let last = (last..tokens.len())
+        .find(|&i| is_newline(i))
+        .unwrap_or(tokens.len() - 1)
+        + 1;
+
+    let region_start = tokens[first].1.byte;
+    let region_end = token_end(last - 1);
+    let mut result = input[..region_start].to_owned();
+    result.push_str(&format_guarded(
+        input,
+        &tokens,
+        first..last,
+        options,
+        &Pipeline::default(),
+        None,
+    ));
+    result.push_str(&input[region_end..]);
+    Ok(result)
+}
+
+/// Format only the paragraphs of `input` overlapping the given ranges of lines.
+///
+/// The lines are numbered from 1, and each range is extended to the paragraphs, delimited
+/// by blank lines, containing its first and last lines, so that they can be reflowed.
+/// The overlapping ranges are merged, and the lines outside of them are left untouched.
+///
+/// # Examples
+///
+/// ```rust
+/// use texfmt::{format_lines, FormatOptions};
+/// let src = "a  b\nc  d\n\ne  f\n";
+/// let formatted = format_lines(src, &[2..=2], &FormatOptions::default()).unwrap();
+/// assert_eq!(formatted, "a b c d\n\ne  f\n");
+/// ```
+pub fn format_lines(
+    input: &str,
+    ranges: &[std::ops::RangeInclusive<usize>],
+    options: &FormatOptions,
+) -> Result<String, FormatError> {
+    let lines: Vec<&str> = input.split_inclusive('\n').collect();
+    let blank = |i: usize| lines[i].trim().is_empty();
+    let mut paragraphs: Vec<(usize, usize)> = ranges
+        .iter()
+        .filter(|range| (*range.start()).max(1) <= lines.len() && range.start() <= range.end())
+        .map(|range| {
+            // the indices of the first and the last lines, extended to whole paragraphs
+            let mut first = range.start().saturating_sub(1);
+            let mut last = range.end().saturating_sub(1).min(lines.len() - 1);
+            while first > 0 && !blank(first) && !blank(first - 1) {
+                first -= 1;
+            }
+            while last + 1 < lines.len() && !blank(last) && !blank(last + 1) {
+                last += 1;
+            }
+            (first, last)
+        })
+        .collect();
+    paragraphs.sort_unstable();
+
+    let mut merged: Vec<(usize, usize)> = Vec::with_capacity(paragraphs.len());
+    for (first, last) in paragraphs {
+        match merged.last_mut() {
+            Some((_, end)) if first <= *end + 1 => *end = (*end).max(last),
+            _ => merged.push((first, last)),
+        }
+    }
+
+    let line_start = |i: usize| lines[..i].iter().map(|line| line.len()).sum::<usize>();
+    let mut result = input.to_owned();
+    // from the end, so that the formatting does not move the lines still to be formatted
+    for &(first, last) in merged.iter().rev() {
+        let start = line_start(first);
+        let end = start
+            + lines[first..=last]
+                .iter()
+                .map(|line| line.len())
+                .sum::<usize>();
+        let end = input[..end].trim_end_matches(['\n', '\r']).len().max(start);
+        result = format_range(&result, start, end, options)?;
+    }
+    Ok(result)
+}
+
+/// Tokenize `input`, checking that its braces are balanced.
+fn lex<'a>(
+    input: &'a str,
+    options: &FormatOptions,
+) -> Result<Vec<(lexer::Token<&'a str>, lexer::Span)>, FormatError> {
+    let lexer = lexer::Lexer::new(input)
+        .verbatim_environments(&options.verbatim_environments)
+        .opaque_environments(&options.opaque_environments)
+        .environments(!options.plain_tex);
+    let tokens = timing::time(Phase::Lex, || lexer.spanned())?;
+    timing::time(Phase::Parse, || check::check_braces(&tokens))?;
+    Ok(tokens)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn defaults() {
+        let options = FormatOptions::default();
+        assert_eq!(options.width, 80);
+        assert_eq!(options.indent_width, 2);
+        assert!(!options.use_tabs);
+        assert_eq!(options.indent_style, None);
+        assert_eq!(options.tab_width, 8);
+        assert!(!options.sentence_wrap);
+        assert!(!options.align_tables);
+        assert!(!options.comment_space);
+        assert_eq!(options.math_delimiters, None);
+        assert_eq!(options.punctuation_spacing, None);
+        assert!(!options.split_packages);
+        assert!(!options.sort_packages);
+        assert!(!options.remove_redundant_braces);
+        assert!(!options.normalize_quotes);
+        assert_eq!(options.max_consecutive_newlines, 1);
+        assert_eq!(options.line_ending, LineEnding::Auto);
+        assert!(!options.bib_trailing_comma);
+        assert_eq!(options.lang, None);
+        assert!(options
+            .verbatim_environments
+            .contains(&"lstlisting".to_owned()));
+        assert_eq!(options.opaque_environments, ["pgfpicture", "tikzpicture"]);
+        assert!(options.citation_commands.contains(&"citep".to_owned()));
+        assert_eq!(FormatOptions::builder().build(), Ok(options));
+    }
+
+    #[test]
+    fn builder() {
+        let options = FormatOptions::builder()
+            .width(100)
+            .use_tabs(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            options,
+            FormatOptions {
+                width: 100,
+                use_tabs: true,
+                ..FormatOptions::default()
+            }
+        );
+        assert_eq!(
+            FormatOptions::builder().width(0).build(),
+            Err(OptionsError::ZeroWidth)
+        );
+        assert_eq!(
+            FormatOptions::builder().tab_width(0).build(),
+            Err(OptionsError::ZeroTabWidth)
+        );
+    }
+
+    #[test]
+    fn line_ending() {
+        let format = |input, line_ending| {
+            let options = FormatOptions::builder()
+                .line_ending(line_ending)
+                .build()
+                .unwrap();
+            format_str(input, &options).unwrap()
+        };
+        let crlf = "\\begin{itemize}\r\n  \\item a\r\n\r\n  \\item b\r\n\\end{itemize}\r\n";
+        let lf = crlf.replace("\r\n", "\n");
+        assert_eq!(format(crlf, LineEnding::Auto), crlf);
+        assert_eq!(format(crlf, LineEnding::Lf), lf);
+        assert_eq!(format(&lf, LineEnding::Auto), lf);
+        assert_eq!(format(&lf, LineEnding::Crlf), crlf);
+        assert_eq!(format("a\r\n\nb\n", LineEnding::Auto), "a\r\n\r\nb\r\n");
+        let options = FormatOptions::builder().width(3).build().unwrap();
+        assert_eq!(format_str("a b c\r\n", &options).unwrap(), "a b\r\nc\r\n");
+    }
+
+    #[test]
+    fn items() {
+        let options = FormatOptions::default();
+        let formatted = "\\begin{itemize}\n  \\item a\n  \\item b\n\\end{itemize}\n";
+        assert_eq!(
+            format_str("\\begin{itemize}\\item a\\item b\\end{itemize}\n", &options).unwrap(),
+            formatted
+        );
+        assert_eq!(format_str(formatted, &options).unwrap(), formatted);
+    }
+
+    #[test]
+    fn opaque_environments() {
+        let body = "[scale=2]\n\\draw  (0,0) --   (1,1);  \n\n\n    \\node at (0,0) {$x$};\n";
+        let src = format!("\\begin{{itemize}}\n\\item\n\\begin{{tikzpicture}}{body}\\end{{tikzpicture}}\n\\end{{itemize}}\n");
+        assert_eq!(
+            format_str(&src, &FormatOptions::default()).unwrap(),
+            format!("\\begin{{itemize}}\n  \\item\n  \\begin{{tikzpicture}}{body}  \\end{{tikzpicture}}\n\\end{{itemize}}\n")
+        );
+        let options = FormatOptions::builder()
+            .opaque_environments(Vec::new())
+            .build()
+            .unwrap();
+        assert!(!format_str(&src, &options).unwrap().contains(body));
+    }
+
+    #[test]
+    fn citation_commands() {
+        let src = "See \\citep[p.~5]{a, b} and \\mycite{c, d}.\n";
+        assert_eq!(
+            format_str(src, &FormatOptions::default()).unwrap(),
+            "See \\citep[p.~5]{a,b} and \\mycite{c, d}.\n"
+        );
+        let options = FormatOptions::builder()
+            .citation_commands(vec!["mycite".to_owned()])
+            .build()
+            .unwrap();
+        assert_eq!(
+            format_str(src, &options).unwrap(),
+            "See \\citep[p.~5]{a, b} and \\mycite{c,d}.\n"
+        );
+    }
+
+    #[test]
+    fn environment_names() {
+        let options = FormatOptions::default();
+        assert_eq!(
+            format_str(
+                "\\begin { itemize }\n\\item a\n\\end  {itemize }\n",
+                &options
+            )
+            .unwrap(),
+            "\\begin{itemize}\n  \\item a\n\\end{itemize}\n"
+        );
+        // the names are case-sensitive
+        assert_eq!(
+            format_str("\\begin{ Equation }\nx\n\\end{Equation}\n", &options).unwrap(),
+            "\\begin{Equation}\n  x\n\\end{Equation}\n"
+        );
+    }
+
+    #[test]
+    fn escapes() {
+        let src =
+            "Costs \\$5 \\& 10\\% off, see \\{x\\}.\nThe \\%, \\$ and \\& stay\\! % comment\n";
+        let normalized = FormatOptions::builder()
+            .comment_space(true)
+            .math_delimiters(Some(MathDelimiters::Amsmath))
+            .punctuation_spacing(Some(PunctuationSpacing::Fr))
+            .sentence_wrap(true)
+            .build()
+            .unwrap();
+        for options in [FormatOptions::default(), normalized] {
+            let formatted = format_str(src, &options).unwrap();
+            assert_eq!(format_str(&formatted, &options).unwrap(), formatted);
+            for escape in [
+                "\\$5", "\\&", "10\\%", "\\{x\\}", "\\%,", "\\$ and", "stay\\!",
+            ] {
+                assert!(formatted.contains(escape), "{escape} in {formatted:?}");
+            }
+            // the escaped `%` never starts a comment
+            let tokens = lexer::lex_tokens(&formatted).unwrap();
+            let comments = tokens
+                .iter()
+                .filter(|t| matches!(t, lexer::Token::Comment(_)));
+            assert_eq!(comments.count(), 1);
+        }
+        let narrow = FormatOptions::builder().width(8).build().unwrap();
+        assert_eq!(
+            format_str("\\{a\\} \\$1 \\% \\&\n", &narrow).unwrap(),
+            "\\{a\\}\n\\$1 \\%\n\\&\n"
+        );
+    }
+
+    #[test]
+    fn comment_newline() {
+        // the comment eats the newline, so `b` follows `a` without a space
+        let src = "Some text a% c\n   b and more text.\n";
+        let sentences = FormatOptions::builder()
+            .sentence_wrap(true)
+            .build()
+            .unwrap();
+        for options in [FormatOptions::default(), sentences] {
+            assert_eq!(
+                format_str(src, &options).unwrap(),
+                "Some text a% c\nb and more text.\n"
+            );
+        }
+        let narrow = FormatOptions::builder()
+            .width(6)
+            .comment_space(true)
+            .build()
+            .unwrap();
+        assert_eq!(
+            format_str(src, &narrow).unwrap(),
+            "Some\ntext\na% c\nb and\nmore\ntext.\n"
+        );
+        // while here `b` is part of the comment
+        let src = "Some text a % c b and more text.\n";
+        assert_eq!(
+            format_str(src, &narrow).unwrap(),
+            "Some\ntext\na % c b and more text.\n"
+        );
+    }
+
+    #[test]
+    fn max_consecutive_newlines() {
+        let src = "a\n\n\n\nb\n\n\\begin{verbatim}\nx\n\n\n\ny\n\\end{verbatim}\n% texfmt: off\nc\n\n\n\nd\n% texfmt: on\n\n\n\ne\n";
+        let format = |max| {
+            let options = FormatOptions::builder()
+                .max_consecutive_newlines(max)
+                .build()
+                .unwrap();
+            format_str(src, &options).unwrap()
+        };
+        let verbatim = "\\begin{verbatim}\nx\n\n\n\ny\n\\end{verbatim}\n";
+        let guarded = "% texfmt: off\nc\n\n\n\nd\n% texfmt: on\n";
+        assert_eq!(format(0), format!("a b\n{verbatim}{guarded}e\n"));
+        assert_eq!(format(1), format!("a\n\nb\n\n{verbatim}{guarded}\ne\n"));
+        assert_eq!(format(2), format!("a\n\n\nb\n\n{verbatim}{guarded}\n\ne\n"));
+        assert_eq!(
+            format(1),
+            format_str(src, &FormatOptions::default()).unwrap()
+        );
+    }
+
+    #[test]
+    fn final_newline() {
+        let options = FormatOptions::default();
+        for src in ["a  b", "a  b\n", "a  b\n\n\n"] {
+            assert_eq!(format_str(src, &options).unwrap(), "a b\n");
+        }
+        assert_eq!(format_str("a\r\n\r\n", &options).unwrap(), "a\r\n");
+        assert_eq!(format_str("\n\n", &options).unwrap(), "");
+
+        let crlf = FormatOptions::builder()
+            .final_newline(FinalNewline::Crlf)
+            .build()
+            .unwrap();
+        assert_eq!(format_str("a\nb\n\n\n", &crlf).unwrap(), "a b\r\n");
+        let keep = FormatOptions::builder()
+            .final_newline(FinalNewline::Keep)
+            .build()
+            .unwrap();
+        assert_eq!(format_str("a", &keep).unwrap(), "a");
+        assert_eq!(format_str("a\n", &keep).unwrap(), "a\n");
+        assert_eq!(format_str("a\n\n\n", &keep).unwrap(), "a\n\n");
+        // nor inside a region left untouched
+        let src = "% texfmt: off\na  b\n\n\n";
+        assert_eq!(format_str(src, &options).unwrap(), src);
+    }
+
+    #[test]
+    fn display_math() {
+        let options = FormatOptions::builder().width(20).build().unwrap();
+        assert_eq!(
+            format_str("text \\[ a+b \\] text\n", &options).unwrap(),
+            "text\n\\[\n  a+b\n\\]\ntext\n"
+        );
+        // the math is never wrapped, but the block is indented
+        assert_eq!(
+            format_str(
+                "\\begin{itemize}\n\\item see $$ a + b + c + d + e + f + g $$ here\n\\end{itemize}\n",
+                &options
+            )
+            .unwrap(),
+            "\\begin{itemize}\n  \\item see\n  $$\n    a + b + c + d + e + f + g\n  $$\n  here\n\\end{itemize}\n"
+        );
+    }
+
+    #[test]
+    fn disabled() {
+        let options = FormatOptions::default();
+        let block = "\\iffalse\n\\ifnum1<2   x  \\fi\n  {   \\begin{x}\n\\fi";
+        assert_eq!(
+            format_str(
+                &format!("\\begin{{itemize}}\n\\item a\n{block}\n\\end{{itemize}}\n"),
+                &options
+            )
+            .unwrap(),
+            format!("\\begin{{itemize}}\n  \\item a\n  {block}\n\\end{{itemize}}\n")
+        );
+        let src = "\\begin{comment}\n  a   {\n\\end{comment}\n";
+        assert_eq!(format_str(src, &options).unwrap(), src);
+    }
+
+    #[test]
+    fn guarded() {
+        let options = FormatOptions::builder().align_tables(true).build().unwrap();
+        let table = "% texfmt: off\n\\begin{tabular}{ll}\n  a & bbb \\\\\n  cc&d\n\\end{tabular}\n  %texfmt: on  \n";
+        assert_eq!(
+            format_str(&format!("Some   text.\n{table}More   text.\n"), &options).unwrap(),
+            format!("Some text.\n{table}More text.\n")
+        );
+        // without `% texfmt: on`, until the end
+        let src = "Some   text.\n\n\\begin{itemize}\n\\item   a % texfmt: off\n\\item   b\n";
+        assert_eq!(
+            format_str(src, &options).unwrap(),
+            src.replacen("Some   text", "Some text", 1)
+        );
+        // from inside a guarded region
+        let src = "a  b\n% texfmt: off\nc  d\n% texfmt: on\ne  f\n";
+        let start = src.find('c').unwrap();
+        assert_eq!(format_range(src, start, start, &options).unwrap(), src);
+        assert_eq!(
+            format_lines(src, &[3..=3], &options).unwrap(),
+            "a b\n% texfmt: off\nc  d\n% texfmt: on\ne f\n"
+        );
+    }
+
+    #[test]
+    fn range() {
+        let src = "First   paragraph,  left  alone.\n\nSecond   paragraph,   long enough to be reflowed.\n\nThird   paragraph.  \n";
+        let options = FormatOptions::builder().width(20).build().unwrap();
+        let start = src.find("paragraph,   long").unwrap();
+        assert_eq!(
+            format_range(src, start, start + 5, &options).unwrap(),
+            "First   paragraph,  left  alone.\n\nSecond paragraph,\nlong enough to be\nreflowed.\n\nThird   paragraph.  \n"
+        );
+        let second = src.find("Second").unwrap();
+        let third = src.find("Third").unwrap();
+        assert_eq!(
+            format_range(src, second, third, &options).unwrap(),
+            format_range(src, second + 1, third - 2, &options).unwrap()
+        );
+        assert_eq!(
+            format_range(src, 0, 0, &options).unwrap(),
+            format_range(src, 3, 3, &options).unwrap()
+        );
+        assert_eq!(
+            format_range(src, src.len(), src.len(), &options).unwrap(),
+            "First   paragraph,  left  alone.\n\nSecond   paragraph,   long enough to be reflowed.\n\nThird paragraph.\n"
+        );
+    }
+
+    #[test]
+    fn range_context() {
+        let src = "\\begin{itemize}\n\\item   a\n\\item   {b\nc}\n\\end{itemize}\n";
+        let start = src.find("c}").unwrap();
+        assert_eq!(
+            format_range(src, start, start + 1, &FormatOptions::default()).unwrap(),
+            "\\begin{itemize}\n\\item   a\n\\item   {b\n    c}\n\\end{itemize}\n"
+        );
+        let src = "\\begin{align}\n  a  &= b \\\\\nc  &= d\n\\end{align}\n";
+        let start = src.find('c').unwrap();
+        assert_eq!(
+            format_range(src, start, start, &FormatOptions::default()).unwrap(),
+            src.replace("\nc", "\n  c")
+        );
+    }
+
+    #[test]
+    fn lines() {
+        let src = "One  \n\nTwo  a\nb  \n\nThree  \nFour  \n";
+        let options = FormatOptions::default();
+        assert_eq!(
+            format_lines(src, &[4..=4], &options).unwrap(),
+            "One  \n\nTwo a b\n\nThree  \nFour  \n"
+        );
+        assert_eq!(
+            format_lines(src, &[6..=6, 1..=1, 3..=4], &options).unwrap(),
+            "One\n\nTwo a b\n\nThree Four\n"
+        );
+        assert_eq!(
+            format_lines(src, &[2..=2, 9..=10], &options).unwrap(),
+            "One  \n\nTwo  a\nb  \n\nThree  \nFour  \n"
+        );
+    }
+}
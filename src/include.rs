@@ -0,0 +1,61 @@
+//! Find the files included by a (La)TeX source with `\input` and `\include`.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use std::path::Path;
+//! use texfmt::{include::{included_files, resolve}, lexer::lex_tokens};
+//! let tokens = lex_tokens(r"\input{intro}\include{chapters/one.tex}").unwrap();
+//! assert_eq!(included_files(&tokens), ["intro", "chapters/one.tex"]);
+//! assert_eq!(resolve(Path::new("book"), "intro"), Path::new("book/intro.tex"));
+//! ```
+
+use std::path::{Path, PathBuf};
+
+use crate::check::argument;
+use crate::lexer::Token;
+
+/// Commands including another file, whose name is their argument.
+pub const INCLUDE_COMMANDS: &[&str] = &["input", "include"];
+
+/// The names of the files included by the [`INCLUDE_COMMANDS`] in `tokens`, in order.
+///
+/// The names are those written in the source, without `.tex` if it was omitted, and the
+/// arguments containing a parameter of a macro, such as `\input{#1}`, are skipped.
+pub fn included_files<S: AsRef<str>>(tokens: &[Token<S>]) -> Vec<String> {
+    tokens
+        .iter()
+        .enumerate()
+        .filter(|(_, token)| {
+            matches!(token, Token::Command(name) if INCLUDE_COMMANDS.contains(&name.as_ref()))
+        })
+        .filter_map(|(i, _)| argument(&tokens[i + 1..]))
+        .map(|name| name.trim().to_owned())
+        .filter(|name| !name.is_empty())
+        .collect()
+}
+
+/// The path of the file included as `name`, relative to the directory `base`, adding
+/// the extension `.tex` if `name` has none, as LaTeX does.
+pub fn resolve(base: &Path, name: &str) -> PathBuf {
+    let path = base.join(name);
+    match path.extension() {
+        Some(_) => path,
+        None => path.with_extension("tex"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_tokens;
+
+    #[test]
+    fn includes() {
+        let src = "\\input{a}\n\\include { b }\n\\input{#1}\\inputenc{c}\\input d\n\\begin{verbatim}\\input{e}\\end{verbatim}";
+        let tokens = lex_tokens(src).unwrap();
+        assert_eq!(included_files(&tokens), ["a", "b"]);
+        assert_eq!(resolve(Path::new(""), "a.b/c"), Path::new("a.b/c.tex"));
+        assert_eq!(resolve(Path::new("x"), "c.sty"), Path::new("x/c.sty"));
+    }
+}
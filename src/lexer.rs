@@ -8,34 +8,93 @@
 //! use texfmt::lexer::{lex_tokens, Token};
 //! assert_eq!(
 //!     lex_tokens(r"\cmd{arg}"),
-//!     Ok((
-//!         "",
-//!         vec![Token::Command("cmd"), Token::LBrace, Token::Text("arg"), Token::RBrace]
-//!     ))
+//!     Ok(vec![Token::Command("cmd"), Token::LBrace, Token::Text("arg"), Token::RBrace])
+//! );
+//! assert_eq!(
+//!     lex_tokens("a\n  b}\\").unwrap_err().to_string(),
+//!     "unexpected character '\\\\' at line 2, column 5"
 //! );
 //! ```
+//!
+//! The lexer is built with `nom`, which stays an implementation detail: the failures are
+//! reported as [`LexError`].
 
 use nom::{
     branch::alt,
-    bytes::complete::tag,
-    character::complete::{alpha1, char, line_ending, none_of, not_line_ending, one_of, space1},
-    combinator::{map, recognize},
-    multi::{many0, many1},
-    sequence::preceded,
+    bytes::complete::{tag, take_till},
+    character::complete::{
+        alpha1, anychar, char, none_of, not_line_ending, one_of, space0, space1,
+    },
+    combinator::{consumed, map, not, opt, recognize, verify},
+    multi::many1_count,
+    sequence::{delimited, pair, preceded, terminated},
     IResult,
 };
 
 /// (La)TeX tokens.
-#[derive(Clone, Debug, Eq, PartialEq)]
+///
+/// They serialize as objects like `{"kind": "Command", "text": "cmd"}`,
+/// where `text` is the payload, if any.
+#[derive(Clone, Debug, Eq, PartialEq, serde::Serialize)]
+#[serde(tag = "kind", content = "text")]
 pub enum Token<S> {
     /// `\command`
     Command(S),
-    /// `% comment`
+    /// `\begin{env}`, with the environment name trimmed, and the source text of the whole
+    /// token if it has spaces around the name, as in `\begin { env }`.
+    #[serde(serialize_with = "serialize_environment")]
+    BeginEnv(S, Option<S>),
+    /// `\end{env}`, with the environment name trimmed, and the source text of the whole
+    /// token if it has spaces around the name, as in `\end { env }`.
+    #[serde(serialize_with = "serialize_environment")]
+    EndEnv(S, Option<S>),
+    /// `% comment`, without the end of the line.
+    ///
+    /// A comment is always followed by a [`Token::Newline`], unless it ends the input.
+    /// TeX drops that newline together with the indentation of the next line, so `a%\nb`
+    /// means `ab`: the formatter keeps the comment and its newline together, never joins
+    /// the next line to the comment and never turns the newline into a space.
     Comment(S),
-    /// Regular text (none of the other tokens).
+    /// Regular text (none of the other tokens), emitted byte-for-byte.
+    ///
+    /// It is as long as possible, so two text tokens are never adjacent.
+    /// It keeps the escaped specials such as `\%`, `\$`, `\{`, `\}` and `\&` as they are
+    /// written, with their backslash, so a pass building new text must keep them escaped.
     Text(S),
     /// `\\`
     Endline,
+    /// `\\*`, `\\[length]` or `\\*[length]`, holding what follows `\\`.
+    EndlineSpaced(S),
+    /// `~`, a non-breaking space.
+    ///
+    /// The escaped tilde accent `\~` is part of [`Token::Text`] instead.
+    Tie,
+    /// `&`, the alignment tab.
+    ///
+    /// The escaped ampersand `\&` is part of [`Token::Text`] instead.
+    Ampersand,
+    /// `#1` to `#9`, a parameter in the body of a macro definition.
+    ///
+    /// The doubled `##` of nested definitions, the escaped `\#` and a `#` not followed by a
+    /// digit are part of [`Token::Text`] instead.
+    Param(u8),
+    /// `^`, the superscript operator.
+    ///
+    /// It is only a token in math mode, and part of [`Token::Text`] elsewhere, as in
+    /// `snake_case^`. The escaped circumflex accent `\^` is always part of the text.
+    Superscript,
+    /// `_`, the subscript operator.
+    ///
+    /// It is only a token in math mode, and part of [`Token::Text`] elsewhere, as in
+    /// `snake_case`. The escaped underscore `\_` is always part of the text.
+    Subscript,
+    /// Verbatim content, emitted byte-for-byte.
+    ///
+    /// This is either a whole `\verb|...|` command, including its delimiters,
+    /// the body of a `verbatim` environment, or a whole `\iffalse ... \fi` block.
+    /// The body of an opaque environment such as `tikzpicture` stops before the line of
+    /// its `\end`, if this starts a line.
+    Verbatim(S),
 
     // math
     /// `\[`
@@ -43,15 +102,23 @@ pub enum Token<S> {
     /// `\]`
     EDisplayMath,
     /// `$$`
+    ///
+    /// Two adjacent dollars are always this token, even where TeX would read the `$` closing
+    /// a formula and the `$` opening the next one: `$a$$b$` is `$`, `a`, `$$`, `b` and `$`,
+    /// where the formulas are not closed. The two formulas are written `$a$ $b$`.
     TDisplayMath,
     /// `$`
     InlineMath,
+    /// `\(`
+    BInlineMath,
+    /// `\)`
+    EInlineMath,
 
     // space
     /// `' '` or `'\t'`
     Whitespace(S),
     /// `'\n'` or `"\r\n"`
-    Newline,
+    Newline(NewlineKind),
 
     // delimiters
     /// `{`
@@ -64,12 +131,320 @@ pub enum Token<S> {
     RBracket,
 }
 
+/// Serialize the environment tokens with the name of the environment alone, like the
+/// other tokens with their payload.
+fn serialize_environment<S, T>(name: &S, _: &Option<S>, serializer: T) -> Result<T::Ok, T::Error>
+where
+    S: serde::Serialize,
+    T: serde::Serializer,
+{
+    name.serialize(serializer)
+}
+
+/// Line ending of a [`Token::Newline`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, Hash, serde::Serialize)]
+pub enum NewlineKind {
+    /// `'\n'`
+    #[default]
+    #[serde(rename = "\n")]
+    Lf,
+    /// `"\r\n"`
+    #[serde(rename = "\r\n")]
+    CrLf,
+}
+
+impl NewlineKind {
+    /// The source text of the line ending.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            NewlineKind::Lf => "\n",
+            NewlineKind::CrLf => "\r\n",
+        }
+    }
+
+    /// The line ending of the first newline in `tokens`, if any.
+    pub fn detect<'a, S: 'a>(tokens: impl IntoIterator<Item = &'a Token<S>>) -> Option<Self> {
+        tokens.into_iter().find_map(|token| match token {
+            Token::Newline(kind) => Some(*kind),
+            _ => None,
+        })
+    }
+}
+
+/// Kind of a [`Token`], without its payload.
+///
+/// The variants have the same names as those of [`Token`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TokenKind {
+    Command,
+    BeginEnv,
+    EndEnv,
+    Comment,
+    Text,
+    Endline,
+    EndlineSpaced,
+    Tie,
+    Ampersand,
+    Param,
+    Superscript,
+    Subscript,
+    Verbatim,
+    BDisplayMath,
+    EDisplayMath,
+    TDisplayMath,
+    InlineMath,
+    BInlineMath,
+    EInlineMath,
+    Whitespace,
+    Newline,
+    LBrace,
+    RBrace,
+    LBracket,
+    RBracket,
+}
+
+impl TokenKind {
+    /// Whether the token is whitespace or a newline.
+    pub fn is_whitespace(self) -> bool {
+        matches!(self, TokenKind::Whitespace | TokenKind::Newline)
+    }
+
+    /// Whether the token opens or closes a math formula, such as `$` or `\[`.
+    pub fn is_math(self) -> bool {
+        matches!(
+            self,
+            TokenKind::BDisplayMath
+                | TokenKind::EDisplayMath
+                | TokenKind::TDisplayMath
+                | TokenKind::InlineMath
+                | TokenKind::BInlineMath
+                | TokenKind::EInlineMath
+        )
+    }
+
+    /// Whether the token is a brace or a bracket.
+    pub fn is_delimiter(self) -> bool {
+        matches!(
+            self,
+            TokenKind::LBrace | TokenKind::RBrace | TokenKind::LBracket | TokenKind::RBracket
+        )
+    }
+}
+
+impl<S> Token<S> {
+    /// The kind of the token.
+    pub fn kind(&self) -> TokenKind {
+        match self {
+            Token::Command(_) => TokenKind::Command,
+            Token::BeginEnv(..) => TokenKind::BeginEnv,
+            Token::EndEnv(..) => TokenKind::EndEnv,
+            Token::Comment(_) => TokenKind::Comment,
+            Token::Text(_) => TokenKind::Text,
+            Token::Endline => TokenKind::Endline,
+            Token::EndlineSpaced(_) => TokenKind::EndlineSpaced,
+            Token::Tie => TokenKind::Tie,
+            Token::Ampersand => TokenKind::Ampersand,
+            Token::Param(_) => TokenKind::Param,
+            Token::Superscript => TokenKind::Superscript,
+            Token::Subscript => TokenKind::Subscript,
+            Token::Verbatim(_) => TokenKind::Verbatim,
+            Token::BDisplayMath => TokenKind::BDisplayMath,
+            Token::EDisplayMath => TokenKind::EDisplayMath,
+            Token::TDisplayMath => TokenKind::TDisplayMath,
+            Token::InlineMath => TokenKind::InlineMath,
+            Token::BInlineMath => TokenKind::BInlineMath,
+            Token::EInlineMath => TokenKind::EInlineMath,
+            Token::Whitespace(_) => TokenKind::Whitespace,
+            Token::Newline(_) => TokenKind::Newline,
+            Token::LBrace => TokenKind::LBrace,
+            Token::RBrace => TokenKind::RBrace,
+            Token::LBracket => TokenKind::LBracket,
+            Token::RBracket => TokenKind::RBracket,
+        }
+    }
+
+    /// Whether the token is whitespace or a newline.
+    pub fn is_whitespace(&self) -> bool {
+        self.kind().is_whitespace()
+    }
+
+    /// Whether the token opens or closes a math formula, such as `$` or `\[`.
+    pub fn is_math(&self) -> bool {
+        self.kind().is_math()
+    }
+
+    /// Whether the token is a brace or a bracket.
+    pub fn is_delimiter(&self) -> bool {
+        self.kind().is_delimiter()
+    }
+
+    /// Convert the payload of the token, if any.
+    pub fn map<T>(self, mut f: impl FnMut(S) -> T) -> Token<T> {
+        match self {
+            Token::Command(s) => Token::Command(f(s)),
+            Token::BeginEnv(s, source) => Token::BeginEnv(f(s), source.map(f)),
+            Token::EndEnv(s, source) => Token::EndEnv(f(s), source.map(f)),
+            Token::Comment(s) => Token::Comment(f(s)),
+            Token::Text(s) => Token::Text(f(s)),
+            Token::Endline => Token::Endline,
+            Token::EndlineSpaced(s) => Token::EndlineSpaced(f(s)),
+            Token::Tie => Token::Tie,
+            Token::Ampersand => Token::Ampersand,
+            Token::Param(n) => Token::Param(n),
+            Token::Superscript => Token::Superscript,
+            Token::Subscript => Token::Subscript,
+            Token::Verbatim(s) => Token::Verbatim(f(s)),
+            Token::BDisplayMath => Token::BDisplayMath,
+            Token::EDisplayMath => Token::EDisplayMath,
+            Token::TDisplayMath => Token::TDisplayMath,
+            Token::InlineMath => Token::InlineMath,
+            Token::BInlineMath => Token::BInlineMath,
+            Token::EInlineMath => Token::EInlineMath,
+            Token::Whitespace(s) => Token::Whitespace(f(s)),
+            Token::Newline(kind) => Token::Newline(kind),
+            Token::LBrace => Token::LBrace,
+            Token::RBrace => Token::RBrace,
+            Token::LBracket => Token::LBracket,
+            Token::RBracket => Token::RBracket,
+        }
+    }
+}
+
+impl<S: AsRef<str>> Token<S> {
+    /// Convert into a token that owns its payload.
+    pub fn into_owned(self) -> Token<String> {
+        self.map(|s| s.as_ref().to_owned())
+    }
+
+    /// Create a token that owns a copy of the payload.
+    pub fn to_owned(&self) -> Token<String> {
+        self.as_ref().into_owned()
+    }
+
+    /// Borrow the payload of the token.
+    pub fn as_ref(&self) -> Token<&str> {
+        match self {
+            Token::Command(s) => Token::Command(s.as_ref()),
+            Token::BeginEnv(s, source) => {
+                Token::BeginEnv(s.as_ref(), source.as_ref().map(AsRef::as_ref))
+            }
+            Token::EndEnv(s, source) => {
+                Token::EndEnv(s.as_ref(), source.as_ref().map(AsRef::as_ref))
+            }
+            Token::Comment(s) => Token::Comment(s.as_ref()),
+            Token::Text(s) => Token::Text(s.as_ref()),
+            Token::Endline => Token::Endline,
+            Token::EndlineSpaced(s) => Token::EndlineSpaced(s.as_ref()),
+            Token::Tie => Token::Tie,
+            Token::Ampersand => Token::Ampersand,
+            Token::Param(n) => Token::Param(*n),
+            Token::Superscript => Token::Superscript,
+            Token::Subscript => Token::Subscript,
+            Token::Verbatim(s) => Token::Verbatim(s.as_ref()),
+            Token::BDisplayMath => Token::BDisplayMath,
+            Token::EDisplayMath => Token::EDisplayMath,
+            Token::TDisplayMath => Token::TDisplayMath,
+            Token::InlineMath => Token::InlineMath,
+            Token::BInlineMath => Token::BInlineMath,
+            Token::EInlineMath => Token::EInlineMath,
+            Token::Whitespace(s) => Token::Whitespace(s.as_ref()),
+            Token::Newline(kind) => Token::Newline(*kind),
+            Token::LBrace => Token::LBrace,
+            Token::RBrace => Token::RBrace,
+            Token::LBracket => Token::LBracket,
+            Token::RBracket => Token::RBracket,
+        }
+    }
+
+    /// Whether the token is `\bgroup`, which opens a group like a brace.
+    pub fn begins_implicit_group(&self) -> bool {
+        matches!(self, Token::Command(name) if name.as_ref() == "bgroup")
+    }
+
+    /// Whether the token is `\egroup`, which closes a group like a brace.
+    pub fn ends_implicit_group(&self) -> bool {
+        matches!(self, Token::Command(name) if name.as_ref() == "egroup")
+    }
+
+    /// Whether the token is `\begingroup`, which opens a group only closed by `\endgroup`.
+    pub fn begins_semisimple_group(&self) -> bool {
+        matches!(self, Token::Command(name) if name.as_ref() == "begingroup")
+    }
+
+    /// Whether the token is `\endgroup`, which only closes a group opened by `\begingroup`.
+    pub fn ends_semisimple_group(&self) -> bool {
+        matches!(self, Token::Command(name) if name.as_ref() == "endgroup")
+    }
+}
+
+impl<S: AsRef<str>> std::fmt::Display for Token<S> {
+    /// Write the source text of the token.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Token::Command(s) => write!(f, "\\{}", s.as_ref()),
+            Token::BeginEnv(_, Some(source)) | Token::EndEnv(_, Some(source)) => {
+                f.write_str(source.as_ref())
+            }
+            Token::BeginEnv(s, None) => write!(f, "\\begin{{{}}}", s.as_ref()),
+            Token::EndEnv(s, None) => write!(f, "\\end{{{}}}", s.as_ref()),
+            Token::Comment(s) => write!(f, "%{}", s.as_ref()),
+            Token::Text(s) | Token::Verbatim(s) | Token::Whitespace(s) => f.write_str(s.as_ref()),
+            Token::Endline => f.write_str(r"\\"),
+            Token::EndlineSpaced(s) => write!(f, "\\\\{}", s.as_ref()),
+            Token::Tie => f.write_str("~"),
+            Token::Ampersand => f.write_str("&"),
+            Token::Param(n) => write!(f, "#{n}"),
+            Token::Superscript => f.write_str("^"),
+            Token::Subscript => f.write_str("_"),
+            Token::BDisplayMath => f.write_str(r"\["),
+            Token::EDisplayMath => f.write_str(r"\]"),
+            Token::TDisplayMath => f.write_str("$$"),
+            Token::InlineMath => f.write_str("$"),
+            Token::BInlineMath => f.write_str(r"\("),
+            Token::EInlineMath => f.write_str(r"\)"),
+            Token::Newline(kind) => f.write_str(kind.as_str()),
+            Token::LBrace => f.write_str("{"),
+            Token::RBrace => f.write_str("}"),
+            Token::LBracket => f.write_str("["),
+            Token::RBracket => f.write_str("]"),
+        }
+    }
+}
+
 type LexResult<'a> = IResult<&'a str, Token<&'a str>>;
 
 fn lex_command(input: &str) -> LexResult<'_> {
     map(preceded(char('\\'), alpha1), Token::Command)(input)
 }
 
+fn lex_environment(input: &str) -> LexResult<'_> {
+    let name = || {
+        delimited(
+            preceded(char('{'), space0),
+            recognize(many1_count(none_of("{}\\%$ \t\r\n"))),
+            preceded(space0, char('}')),
+        )
+    };
+    // the spaces after `\begin` and `\end` are skipped, like after any command
+    let (rest, (source, (begin, name))) = consumed(alt((
+        map(preceded(pair(tag(r"\begin"), space0), name()), |name| {
+            (true, name)
+        }),
+        map(preceded(pair(tag(r"\end"), space0), name()), |name| {
+            (false, name)
+        }),
+    )))(input)?;
+    // the source text is only kept if it differs from the one written by `Display`
+    let spaced = source.len() != name.len() + if begin { 8 } else { 6 };
+    let source = spaced.then_some(source);
+    let token = if begin {
+        Token::BeginEnv(name, source)
+    } else {
+        Token::EndEnv(name, source)
+    };
+    Ok((rest, token))
+}
+
 fn lex_comment(input: &str) -> LexResult<'_> {
     map(preceded(char('%'), not_line_ending), Token::Comment)(input)
 }
@@ -80,11 +455,230 @@ fn lex_math(input: &str) -> LexResult<'_> {
         map(tag(r"\]"), |_| Token::EDisplayMath),
         map(tag(r"$$"), |_| Token::TDisplayMath),
         map(tag(r"$"), |_| Token::InlineMath),
+        map(tag(r"\("), |_| Token::BInlineMath),
+        map(tag(r"\)"), |_| Token::EInlineMath),
     ))(input)
 }
 
 fn lex_endline(input: &str) -> LexResult<'_> {
-    map(tag(r"\\"), |_| Token::Endline)(input)
+    let spacing = recognize(pair(
+        opt(char('*')),
+        opt(delimited(
+            char('['),
+            take_till(|c| c == ']' || c == '\n'),
+            char(']'),
+        )),
+    ));
+    map(preceded(tag(r"\\"), spacing), |s: &str| {
+        if s.is_empty() {
+            Token::Endline
+        } else {
+            Token::EndlineSpaced(s)
+        }
+    })(input)
+}
+
+fn lex_tie(input: &str) -> LexResult<'_> {
+    map(char('~'), |_| Token::Tie)(input)
+}
+
+fn lex_ampersand(input: &str) -> LexResult<'_> {
+    map(char('&'), |_| Token::Ampersand)(input)
+}
+
+fn lex_param(input: &str) -> LexResult<'_> {
+    map(preceded(char('#'), one_of("123456789")), |n| {
+        Token::Param(n as u8 - b'0')
+    })(input)
+}
+
+fn lex_script(input: &str) -> LexResult<'_> {
+    alt((
+        map(char('^'), |_| Token::Superscript),
+        map(char('_'), |_| Token::Subscript),
+    ))(input)
+}
+
+// verbatim
+
+/// Environments whose body is not tokenized, unless configured otherwise.
+pub const VERBATIM_ENVIRONMENTS: &[&str] = &[
+    "alltt",
+    "comment",
+    "lstlisting",
+    "minted",
+    "verbatim",
+    "Verbatim",
+];
+
+/// Environments whose body is not tokenized, except for the indentation of their `\end`.
+pub const OPAQUE_ENVIRONMENTS: &[&str] = &["pgfpicture", "tikzpicture"];
+
+/// Commands starting with `if` which are not conditionals closed by `\fi`, because they
+/// take their branches as arguments.
+const NOT_CONDITIONALS: &[&str] = &[
+    "ifblank",
+    "ifbool",
+    "ifboolexpr",
+    "ifcsdef",
+    "ifcsempty",
+    "ifcsundef",
+    "ifcsvoid",
+    "ifdef",
+    "ifdefempty",
+    "ifdefequal",
+    "ifdefmacro",
+    "ifdefstring",
+    "ifdefvoid",
+    "ifdimcomp",
+    "ifnumcomp",
+    "ifnumequal",
+    "ifnumgreater",
+    "ifnumless",
+    "ifnumodd",
+    "ifstrempty",
+    "ifstrequal",
+    "ifthenelse",
+    "iftoggle",
+    "ifundef",
+];
+
+fn lex_verbatim(input: &str) -> LexResult<'_> {
+    map(
+        recognize(|input| {
+            let (input, _) = preceded(tag(r"\verb"), opt(char('*')))(input)?;
+            let (input, delim) =
+                verify(anychar, |c: &char| !c.is_alphabetic() && !c.is_whitespace())(input)?;
+            let (input, _) = take_till(|c| c == delim || c == '\n')(input)?;
+            char(delim)(input)
+        }),
+        Token::Verbatim,
+    )(input)
+}
+
+/// Find the end of the `\fi` closing a conditional, whose body starts `input`.
+///
+/// The nested conditionals, such as `\ifnum`, are skipped with their `\fi`, except the
+/// ones defined by `\newif`, and so are the comments.
+fn find_fi(input: &str) -> Option<usize> {
+    let bytes = input.as_bytes();
+    let mut depth = 0usize;
+    let mut after_newif = false;
+    let mut i = 0;
+    while i < bytes.len() {
+        match bytes[i] {
+            b'%' => i = input[i..].find('\n').map_or(bytes.len(), |len| i + len),
+            b'\\' => {
+                let letters = bytes[i + 1..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_alphabetic())
+                    .count();
+                let name = &input[i + 1..i + 1 + letters];
+                i += 1 + match letters {
+                    // a control symbol, such as `\\` or `\%`
+                    0 => input[i + 1..].chars().next().map_or(0, char::len_utf8),
+                    _ => letters,
+                };
+                if name == "fi" {
+                    match depth.checked_sub(1) {
+                        Some(outer) => depth = outer,
+                        None => return Some(i),
+                    }
+                } else if name.starts_with("if") && !after_newif {
+                    depth += usize::from(!NOT_CONDITIONALS.contains(&name));
+                }
+                after_newif = name == "newif";
+            }
+            b => {
+                after_newif &= b.is_ascii_whitespace();
+                i += 1;
+            }
+        }
+    }
+    None
+}
+
+/// A whole `\iffalse ... \fi` block, up to its matching `\fi`, which is never expanded.
+fn lex_iffalse(input: &str) -> LexResult<'_> {
+    let (body, _) = terminated(tag(r"\iffalse"), not(alpha1))(input)?;
+    match find_fi(body) {
+        Some(len) => {
+            let len = input.len() - body.len() + len;
+            Ok((&input[len..], Token::Verbatim(&input[..len])))
+        }
+        None => Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::TakeUntil,
+        ))),
+    }
+}
+
+/// Find the `\end{name}` closing the verbatim environment `name`, with optional spaces
+/// around the name.
+fn find_verbatim_end(input: &str, name: &str) -> Option<usize> {
+    input.match_indices(r"\end{").find_map(|(i, end)| {
+        let rest = input[i + end.len()..].trim_start_matches([' ', '\t']);
+        let rest = rest.strip_prefix(name)?.trim_start_matches([' ', '\t']);
+        rest.starts_with('}').then_some(i)
+    })
+}
+
+/// Capture the body of the verbatim environment `name`, up to the `\end{name}`.
+///
+/// If `opaque`, the body stops before the newline and the indentation of a `\end{name}`
+/// starting a line, so that they are tokenized. An unterminated environment extends to
+/// the end of the input.
+fn lex_verbatim_body<'a>(input: &'a str, name: &str, opaque: bool) -> LexResult<'a> {
+    let mut len = find_verbatim_end(input, name).unwrap_or(input.len());
+    if opaque && len < input.len() {
+        if let Some(newline) = input[..len].rfind('\n') {
+            if input[newline + 1..len]
+                .trim_start_matches([' ', '\t'])
+                .is_empty()
+            {
+                len = input[..newline]
+                    .strip_suffix('\r')
+                    .map_or(newline, str::len);
+            }
+        }
+    }
+    if len == 0 {
+        return Err(nom::Err::Error(nom::error::Error::new(
+            input,
+            nom::error::ErrorKind::TakeUntil,
+        )));
+    }
+    Ok((&input[len..], Token::Verbatim(&input[..len])))
+}
+
+/// Name of the verbatim environment among `names`, or of the opaque one among `opaque`,
+/// opened by `token`, if any, and whether it is opaque.
+fn verbatim_environment<'a>(
+    token: &Token<&'a str>,
+    names: &[String],
+    opaque: &[String],
+) -> Option<(&'a str, bool)> {
+    match token {
+        Token::BeginEnv(name, _) if names.iter().any(|n| n == name) => Some((name, false)),
+        Token::BeginEnv(name, _) if opaque.iter().any(|n| n == name) => Some((name, true)),
+        _ => None,
+    }
+}
+
+/// The default [`VERBATIM_ENVIRONMENTS`], owned.
+fn default_verbatim_environments() -> Vec<String> {
+    VERBATIM_ENVIRONMENTS
+        .iter()
+        .map(|&name| name.to_owned())
+        .collect()
+}
+
+/// The default [`OPAQUE_ENVIRONMENTS`], owned.
+fn default_opaque_environments() -> Vec<String> {
+    OPAQUE_ENVIRONMENTS
+        .iter()
+        .map(|&name| name.to_owned())
+        .collect()
 }
 
 // space
@@ -94,7 +688,10 @@ fn lex_whitespace(input: &str) -> LexResult<'_> {
 }
 
 fn lex_newline(input: &str) -> LexResult<'_> {
-    map(line_ending, |_| Token::Newline)(input)
+    alt((
+        map(tag("\r\n"), |_| Token::Newline(NewlineKind::CrLf)),
+        map(char('\n'), |_| Token::Newline(NewlineKind::Lf)),
+    ))(input)
 }
 
 // delimiters
@@ -110,37 +707,630 @@ fn lex_delimiter(input: &str) -> LexResult<'_> {
 
 // text
 
-fn lex_text(input: &str) -> LexResult<'_> {
+/// Text made of anything but the `special` characters, which can be escaped.
+fn text<'a>(special: &'static str) -> impl FnMut(&'a str) -> LexResult<'a> {
     map(
         // verify(
         //     escaped(none_of("\\%{}$ \t\n"), '\\', one_of("%{}$&,;! ")),
         //     |s: &str| !s.is_empty(),
         // ),
-        recognize(many1(alt((
-            none_of("\\%{}$ \t\n"),
-            preceded(char('\\'), one_of("%{}$&,;! ")),
+        recognize(many1_count(alt((
+            none_of(special),
+            preceded(char('\\'), one_of("%{}$&#^_,;!~ ")),
+            map(tag("##"), |_| '#'),
+            terminated(char('#'), not(one_of("123456789"))),
+            terminated(char('\r'), not(char('\n'))),
         )))),
         Token::Text,
-    )(input)
+    )
 }
 
-/// Identiy the first token in the input (La)TeX string.
-pub fn lex_token(input: &str) -> LexResult<'_> {
-    alt((
-        lex_command,
-        lex_comment,
-        lex_endline,
-        lex_math,
-        lex_whitespace,
-        lex_newline,
-        lex_delimiter,
-        lex_text,
-    ))(input)
+fn lex_text(input: &str) -> LexResult<'_> {
+    text("\\%{}[]~&#^_$ \t\r\n")(input)
 }
 
-/// Tokenize the input (La)TeX string.
-pub fn lex_tokens(input: &str) -> IResult<&str, Vec<Token<&str>>> {
-    many0(lex_token)(input)
+/// Text outside of math mode, where `^` and `_` are ordinary characters.
+fn lex_plain_text(input: &str) -> LexResult<'_> {
+    text("\\%{}[]~&#$ \t\r\n")(input)
+}
+
+/// Identify the first token in the input (La)TeX string, in math mode or not.
+///
+/// Outside of math mode, `^` and `_` are ordinary characters. [`Lexer`] keeps track of
+/// the mode.
+///
+/// A double backslash `\\` is always a [`Token::Endline`], or a [`Token::EndlineSpaced`],
+/// and never part of a command or of text: `\\\\` is two endlines and `\\cmd` is an endline
+/// followed by the text `cmd`.
+///
+/// Only the tokens which can start with the first character of `input` are tried.
+fn lex_token_in(input: &str, math: bool) -> LexResult<'_> {
+    let text: for<'a> fn(&'a str) -> LexResult<'a> = if math { lex_text } else { lex_plain_text };
+    match input.as_bytes().first() {
+        Some(b'\\') => alt((
+            lex_verbatim,
+            // before the other tokens starting with a backslash
+            lex_endline,
+            lex_iffalse,
+            lex_environment,
+            lex_math,
+            lex_command,
+            text,
+        ))(input),
+        Some(b'$') => lex_math(input),
+        Some(b'%') => lex_comment(input),
+        Some(b'~') => lex_tie(input),
+        Some(b'&') => lex_ampersand(input),
+        Some(b'#') => alt((lex_param, text))(input),
+        // outside of math mode, they are text
+        Some(b'^' | b'_') if math => lex_script(input),
+        Some(b' ' | b'\t') => lex_whitespace(input),
+        Some(b'\r' | b'\n') => alt((lex_newline, text))(input),
+        Some(b'{' | b'}' | b'[' | b']') => lex_delimiter(input),
+        _ => text(input),
+    }
+}
+
+/// Identify the first token in the input (La)TeX string, where `\begin` and `\end` are
+/// ordinary commands unless `environments` is set.
+fn lex_token_with(input: &str, math: bool, environments: bool) -> LexResult<'_> {
+    if !environments && lex_environment(input).is_ok() {
+        return lex_command(input);
+    }
+    lex_token_in(input, math)
+}
+
+// mode
+
+/// Environments typesetting their body in math mode.
+const MATH_ENVIRONMENTS: &[&str] = &[
+    "align",
+    "align*",
+    "alignat",
+    "alignat*",
+    "displaymath",
+    "eqnarray",
+    "eqnarray*",
+    "equation",
+    "equation*",
+    "flalign",
+    "flalign*",
+    "gather",
+    "gather*",
+    "math",
+    "multline",
+    "multline*",
+];
+
+/// Token entering a new mode.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Opener {
+    /// `$`
+    Dollar,
+    /// `$$`
+    DoubleDollar,
+    /// `\(`
+    Parens,
+    /// `\[`
+    Brackets,
+    /// `\begin{env}`
+    Environment,
+}
+
+/// Stack of the open modes, each with whether it is math mode.
+#[derive(Clone, Debug, Default)]
+struct Modes {
+    open: Vec<(Opener, bool)>,
+}
+
+impl Modes {
+    /// Whether the lexer is in math mode.
+    fn math(&self) -> bool {
+        self.open.last().is_some_and(|&(_, math)| math)
+    }
+
+    /// Enter or leave a mode after `token`.
+    fn update<S: AsRef<str>>(&mut self, token: &Token<S>) {
+        match token {
+            Token::InlineMath => self.toggle(Opener::Dollar),
+            Token::TDisplayMath => self.toggle(Opener::DoubleDollar),
+            Token::BInlineMath => self.open.push((Opener::Parens, true)),
+            Token::EInlineMath => self.close(Opener::Parens),
+            Token::BDisplayMath => self.open.push((Opener::Brackets, true)),
+            Token::EDisplayMath => self.close(Opener::Brackets),
+            Token::BeginEnv(name, _) => {
+                let math = self.math() || MATH_ENVIRONMENTS.contains(&name.as_ref());
+                self.open.push((Opener::Environment, math));
+            }
+            Token::EndEnv(..) => self.close(Opener::Environment),
+            _ => {}
+        }
+    }
+
+    /// Leave the mode opened by `opener` if it is the current one, or else enter it.
+    fn toggle(&mut self, opener: Opener) {
+        if matches!(self.open.last(), Some(&(last, _)) if last == opener) {
+            self.open.pop();
+        } else {
+            self.open.push((opener, true));
+        }
+    }
+
+    /// Leave the innermost mode opened by `opener`, and the modes left open inside of it.
+    ///
+    /// A closer without a matching opener is ignored.
+    fn close(&mut self, opener: Opener) {
+        if let Some(i) = self.open.iter().rposition(|&(open, _)| open == opener) {
+            self.open.truncate(i);
+        }
+    }
+}
+
+/// Stateful lexer tracking whether the source is in math mode.
+///
+/// The superscript `^` and subscript `_` are tokens only in math mode, which is entered by
+/// `$`, `$$`, `\(`, `\[` and math environments such as `equation`, and they are text
+/// otherwise. The content of `\text{...}` inside math is still considered in math mode.
+///
+/// # Examples
+///
+/// ```rust
+/// use texfmt::lexer::{Lexer, Token};
+/// let mut lexer = Lexer::new("a_b $a_b$");
+/// assert_eq!(lexer.next_token(), Some(Token::Text("a_b")));
+/// assert_eq!(lexer.next_token(), Some(Token::Whitespace(" ")));
+/// assert_eq!(lexer.next_token(), Some(Token::InlineMath));
+/// assert!(lexer.in_math());
+/// assert_eq!(lexer.next_token(), Some(Token::Text("a")));
+/// assert_eq!(lexer.next_token(), Some(Token::Subscript));
+/// ```
+#[derive(Clone, Debug)]
+pub struct Lexer<'a> {
+    source: &'a str,
+    /// Start of the input still to tokenize.
+    pos: usize,
+    modes: Modes,
+    /// Verbatim environment whose body comes next, and whether it is opaque.
+    verbatim: Option<(&'a str, bool)>,
+    verbatim_environments: Vec<String>,
+    opaque_environments: Vec<String>,
+    /// Whether `\begin` and `\end` delimit environments.
+    environments: bool,
+}
+
+impl<'a> Lexer<'a> {
+    /// Tokenize `source` from its start, with the default verbatim and opaque environments.
+    pub fn new(source: &'a str) -> Self {
+        Lexer {
+            source,
+            pos: 0,
+            modes: Modes::default(),
+            verbatim: None,
+            verbatim_environments: default_verbatim_environments(),
+            opaque_environments: default_opaque_environments(),
+            environments: true,
+        }
+    }
+
+    /// Set the environments whose body is not tokenized, instead of [`VERBATIM_ENVIRONMENTS`].
+    pub fn verbatim_environments<S: Into<String>>(
+        mut self,
+        names: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.verbatim_environments = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the environments whose body is not tokenized, except for the indentation of
+    /// their `\end`, instead of [`OPAQUE_ENVIRONMENTS`].
+    pub fn opaque_environments<S: Into<String>>(
+        mut self,
+        names: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.opaque_environments = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Tokenize `\begin{env}` and `\end{env}` as [`Token::BeginEnv`] and [`Token::EndEnv`],
+    /// as by default, or as ordinary commands, as in plain TeX.
+    pub fn environments(mut self, environments: bool) -> Self {
+        self.environments = environments;
+        self
+    }
+
+    /// The input still to tokenize.
+    pub fn rest(&self) -> &'a str {
+        &self.source[self.pos..]
+    }
+
+    /// Byte offset of the next token.
+    pub fn offset(&self) -> usize {
+        self.pos
+    }
+
+    /// Whether the next token is in math mode.
+    pub fn in_math(&self) -> bool {
+        self.modes.math()
+    }
+
+    /// Tokenize the next token.
+    ///
+    /// Returns `None` at the end of the input, or if the rest cannot be tokenized.
+    pub fn next_token(&mut self) -> Option<Token<&'a str>> {
+        let input = self.rest();
+        let (rest, token) = match self.verbatim.take() {
+            Some((name, opaque)) => lex_verbatim_body(input, name, opaque)
+                .or_else(|_| lex_token_with(input, self.in_math(), self.environments))
+                .ok()?,
+            None => lex_token_with(input, self.in_math(), self.environments).ok()?,
+        };
+        self.verbatim = verbatim_environment(
+            &token,
+            &self.verbatim_environments,
+            &self.opaque_environments,
+        );
+        self.modes.update(&token);
+        self.pos = self.source.len() - rest.len();
+        Some(token)
+    }
+
+    /// Tokenize the rest of the input, recording where each token starts.
+    ///
+    /// It is an error if some input cannot be tokenized.
+    pub fn spanned(mut self) -> Result<Vec<(Token<&'a str>, Span)>, LexError> {
+        let mut span = Span::default();
+        span.advance(&self.source[..self.pos]);
+        let mut tokens = Vec::new();
+        loop {
+            let byte = self.pos;
+            match self.next_token() {
+                Some(token) => {
+                    span.advance(&self.source[span.byte..byte]);
+                    tokens.push((token, span));
+                }
+                None => break,
+            }
+        }
+        self.finish().map(|()| tokens)
+    }
+
+    /// An error if some input is left, which cannot be tokenized.
+    pub(crate) fn finish(&self) -> Result<(), LexError> {
+        if self.rest().is_empty() {
+            Ok(())
+        } else {
+            Err(LexError::new(self.source, self.pos))
+        }
+    }
+}
+
+impl<'a> Iterator for Lexer<'a> {
+    type Item = Token<&'a str>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        self.next_token()
+    }
+}
+
+/// Position of a token in the source.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct Span {
+    /// Line number, starting from 1.
+    pub line: usize,
+    /// Column number in characters, starting from 1.
+    pub col: usize,
+    /// Byte offset, starting from 0.
+    pub byte: usize,
+}
+
+impl Default for Span {
+    fn default() -> Self {
+        Span {
+            line: 1,
+            col: 1,
+            byte: 0,
+        }
+    }
+}
+
+impl Span {
+    /// Move the position past `text`.
+    fn advance(&mut self, text: &str) {
+        for c in text.chars() {
+            if c == '\n' {
+                self.line += 1;
+                self.col = 1;
+            } else {
+                self.col += 1;
+            }
+        }
+        self.byte += text.len();
+    }
+}
+
+/// Tokenize the whole input (La)TeX string.
+///
+/// The body of a verbatim environment is captured as a single [`Token::Verbatim`].
+/// It is an error if some input cannot be tokenized, while [`Lexer`] and [`tokens`] yield
+/// the tokens before it.
+pub fn lex_tokens(input: &str) -> Result<Vec<Token<&str>>, LexError> {
+    let mut lexer = Lexer::new(input);
+    let tokens = lexer.by_ref().collect();
+    lexer.finish().map(|()| tokens)
+}
+
+/// Tokenize the whole input (La)TeX string, recording where each token starts.
+pub fn lex_tokens_spanned(input: &str) -> Result<Vec<(Token<&str>, Span)>, LexError> {
+    Lexer::new(input).spanned()
+}
+
+/// Tokenize the whole input (La)TeX string into owned tokens.
+pub fn lex_tokens_owned(input: &str) -> Result<Vec<Token<String>>, LexError> {
+    let tokens = lex_tokens(input)?;
+    Ok(tokens.into_iter().map(Token::into_owned).collect())
+}
+
+/// Error of the lexer: the input cannot be tokenized from this position onwards.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct LexError {
+    /// Position of the first character which cannot be tokenized.
+    pub span: Span,
+    /// The character which cannot be tokenized.
+    pub found: char,
+}
+
+impl LexError {
+    /// The error at the byte offset `byte` of `source`, which is not at its end.
+    fn new(source: &str, byte: usize) -> Self {
+        let mut span = Span::default();
+        span.advance(&source[..byte]);
+        LexError {
+            span,
+            found: source[byte..].chars().next().unwrap_or_default(),
+        }
+    }
+}
+
+impl std::fmt::Display for LexError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "unexpected character {:?} at line {}, column {}",
+            self.found, self.span.line, self.span.col
+        )
+    }
+}
+
+impl std::error::Error for LexError {}
+
+/// Lazy iterator over the tokens of a (La)TeX string, created by [`tokens`].
+///
+/// Unlike [`Lexer`], it yields an error if some input cannot be tokenized, and then stops.
+#[derive(Clone, Debug)]
+pub struct Tokens<'a> {
+    lexer: Lexer<'a>,
+    failed: bool,
+}
+
+impl<'a> From<Lexer<'a>> for Tokens<'a> {
+    fn from(lexer: Lexer<'a>) -> Self {
+        Tokens {
+            lexer,
+            failed: false,
+        }
+    }
+}
+
+impl<'a> Iterator for Tokens<'a> {
+    type Item = Result<Token<&'a str>, LexError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        match self.lexer.next_token() {
+            Some(token) => Some(Ok(token)),
+            None if self.lexer.rest().is_empty() => None,
+            None => {
+                self.failed = true;
+                Some(Err(LexError::new(self.lexer.source, self.lexer.offset())))
+            }
+        }
+    }
+}
+
+impl std::iter::FusedIterator for Tokens<'_> {}
+
+/// Tokenize the input (La)TeX string lazily, one token at a time.
+///
+/// # Examples
+///
+/// ```rust
+/// use texfmt::lexer::{tokens, LexError, Token};
+/// let first_env = tokens(r"a \begin{x} \'").find_map(|token| match token {
+///     Ok(Token::BeginEnv(name, _)) => Some(name),
+///     _ => None,
+/// });
+/// assert_eq!(first_env, Some("x"));
+/// assert_eq!(tokens(r"\'").next().unwrap().unwrap_err().span.byte, 0);
+/// ```
+pub fn tokens(input: &str) -> Tokens<'_> {
+    Lexer::new(input).into()
+}
+
+/// Iterator over the tokens of a (La)TeX source read from a [`BufRead`](std::io::BufRead).
+///
+/// The source is read one line at a time, so that only the current line is kept in memory,
+/// or the whole body of a verbatim environment.
+/// Yields the same tokens as [`lex_tokens`], and an error of kind
+/// [`InvalidData`](std::io::ErrorKind::InvalidData) if some input cannot be tokenized.
+///
+/// # Examples
+///
+/// ```rust
+/// use texfmt::lexer::{NewlineKind, Token, TokenIterator};
+/// let tokens: Vec<_> = TokenIterator::new("a\n\\b".as_bytes())
+///     .collect::<std::io::Result<_>>()
+///     .unwrap();
+/// assert_eq!(
+///     tokens,
+///     [Token::Text("a".to_owned()), Token::Newline(NewlineKind::Lf), Token::Command("b".to_owned())]
+/// );
+/// ```
+#[derive(Debug)]
+pub struct TokenIterator<R> {
+    reader: R,
+    buffer: String,
+    /// Start of the input still to tokenize in `buffer`.
+    pos: usize,
+    /// Length of the input before `buffer`.
+    offset: usize,
+    eof: bool,
+    /// Verbatim environment whose body comes next, and whether it is opaque.
+    verbatim: Option<(String, bool)>,
+    verbatim_environments: Vec<String>,
+    opaque_environments: Vec<String>,
+    /// Whether `\begin` and `\end` delimit environments.
+    environments: bool,
+    modes: Modes,
+    failed: bool,
+}
+
+impl<R: std::io::BufRead> TokenIterator<R> {
+    /// Tokenize the text read from `reader`, with the default environments.
+    pub fn new(reader: R) -> Self {
+        TokenIterator {
+            reader,
+            buffer: String::new(),
+            pos: 0,
+            offset: 0,
+            eof: false,
+            verbatim: None,
+            verbatim_environments: default_verbatim_environments(),
+            opaque_environments: default_opaque_environments(),
+            environments: true,
+            modes: Modes::default(),
+            failed: false,
+        }
+    }
+
+    /// Set the environments whose body is not tokenized, instead of [`VERBATIM_ENVIRONMENTS`].
+    pub fn verbatim_environments<S: Into<String>>(
+        mut self,
+        names: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.verbatim_environments = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Set the environments whose body is not tokenized, except for the indentation of
+    /// their `\end`, instead of [`OPAQUE_ENVIRONMENTS`].
+    pub fn opaque_environments<S: Into<String>>(
+        mut self,
+        names: impl IntoIterator<Item = S>,
+    ) -> Self {
+        self.opaque_environments = names.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Tokenize `\begin{env}` and `\end{env}` as [`Token::BeginEnv`] and [`Token::EndEnv`],
+    /// as by default, or as ordinary commands, as in plain TeX.
+    pub fn environments(mut self, environments: bool) -> Self {
+        self.environments = environments;
+        self
+    }
+
+    /// Discard the tokenized input and read one more line.
+    fn refill(&mut self) -> std::io::Result<()> {
+        self.buffer.drain(..self.pos);
+        self.offset += self.pos;
+        self.pos = 0;
+        let mut line = Vec::new();
+        if self.reader.read_until(b'\n', &mut line)? == 0 {
+            self.eof = true;
+        }
+        match std::str::from_utf8(&line) {
+            Ok(line) => self.buffer.push_str(line),
+            Err(e) => {
+                return Err(std::io::Error::new(
+                    std::io::ErrorKind::InvalidData,
+                    format!(
+                        "the input is not valid UTF-8 at byte {}",
+                        self.offset + self.buffer.len() + e.valid_up_to()
+                    ),
+                ))
+            }
+        }
+        Ok(())
+    }
+
+    /// Tokenize the next token in the buffer, which ends at the end of a line or of the input.
+    ///
+    /// Returns `None` if more input is needed.
+    fn lex_buffered(&mut self) -> Option<std::io::Result<Token<String>>> {
+        let input = &self.buffer[self.pos..];
+        if let Some((name, opaque)) = self.verbatim.take() {
+            if !self.eof && find_verbatim_end(input, &name).is_none() {
+                self.verbatim = Some((name, opaque));
+                return None;
+            }
+            if let Ok((rest, token)) = lex_verbatim_body(input, &name, opaque) {
+                self.pos = self.buffer.len() - rest.len();
+                return Some(Ok(token.into_owned()));
+            }
+        }
+        if input.is_empty() {
+            return None;
+        }
+        // an `\iffalse` block is a single token only once its `\fi` is read
+        let iffalse = input
+            .strip_prefix(r"\iffalse")
+            .is_some_and(|rest| !rest.starts_with(|c: char| c.is_ascii_alphabetic()));
+        if iffalse && !self.eof && lex_iffalse(input).is_err() {
+            return None;
+        }
+        match lex_token_with(input, self.modes.math(), self.environments) {
+            Ok((rest, token)) => {
+                self.verbatim = verbatim_environment(
+                    &token,
+                    &self.verbatim_environments,
+                    &self.opaque_environments,
+                )
+                .map(|(name, opaque)| (name.to_owned(), opaque));
+                self.modes.update(&token);
+                let token = token.into_owned();
+                self.pos = self.buffer.len() - rest.len();
+                Some(Ok(token))
+            }
+            Err(_) => Some(Err(std::io::Error::new(
+                std::io::ErrorKind::InvalidData,
+                format!("cannot tokenize input at byte {}", self.offset + self.pos),
+            ))),
+        }
+    }
+}
+
+impl<R: std::io::BufRead> Iterator for TokenIterator<R> {
+    type Item = std::io::Result<Token<String>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        while !self.failed {
+            match self.lex_buffered() {
+                Some(result) => {
+                    self.failed = result.is_err();
+                    return Some(result);
+                }
+                None if self.eof => return None,
+                None => {
+                    if let Err(e) = self.refill() {
+                        self.failed = true;
+                        return Some(Err(e));
+                    }
+                }
+            }
+        }
+        None
+    }
 }
 
 #[cfg(test)]
@@ -163,12 +1353,114 @@ mod tests {
         );
     }
 
+    #[test]
+    fn environment() {
+        assert_eq!(
+            lex_environment(r"\begin{align*}x"),
+            Ok(("x", Token::BeginEnv("align*", None)))
+        );
+        assert_eq!(
+            lex_environment(r"\end{align*}"),
+            Ok(("", Token::EndEnv("align*", None)))
+        );
+        assert_eq!(
+            lex_environment("\\begin{ equation\t}"),
+            Ok((
+                "",
+                Token::BeginEnv("equation", Some("\\begin{ equation\t}"))
+            ))
+        );
+        assert_eq!(
+            lex_environment("\\begin { itemize }"),
+            Ok(("", Token::BeginEnv("itemize", Some("\\begin { itemize }"))))
+        );
+        assert_eq!(
+            lex_environment("\\end\t{Equation }"),
+            Ok(("", Token::EndEnv("Equation", Some("\\end\t{Equation }"))))
+        );
+        assert!(lex_environment(r"\begin{}").is_err());
+        assert!(lex_environment(r"\beginning").is_err());
+        assert_eq!(
+            lex_tokens(r"\begingroup\begin x"),
+            Ok(vec![
+                Token::Command("begingroup"),
+                Token::Command("begin"),
+                Token::Whitespace(" "),
+                Token::Text("x"),
+            ])
+        );
+        // in plain TeX
+        let plain: Vec<_> = Lexer::new("\\begin{verbatim}$x$\\end{verbatim}")
+            .environments(false)
+            .collect();
+        assert_eq!(
+            plain,
+            [
+                Token::Command("begin"),
+                Token::LBrace,
+                Token::Text("verbatim"),
+                Token::RBrace,
+                Token::InlineMath,
+                Token::Text("x"),
+                Token::InlineMath,
+                Token::Command("end"),
+                Token::LBrace,
+                Token::Text("verbatim"),
+                Token::RBrace,
+            ]
+        );
+    }
+
+    #[test]
+    fn maximal_text() {
+        assert_eq!(lex_tokens(r"foo\&bar"), Ok(vec![Token::Text(r"foo\&bar")]));
+        assert_eq!(
+            lex_tokens(r"a\,b\%c\{d\}e\$f\#g##h\_i\^j\~k"),
+            Ok(vec![Token::Text(r"a\,b\%c\{d\}e\$f\#g##h\_i\^j\~k")])
+        );
+        assert_eq!(
+            lex_tokens("x_y^z #a\ry"),
+            Ok(vec![
+                Token::Text("x_y^z"),
+                Token::Whitespace(" "),
+                Token::Text("#a\ry"),
+            ])
+        );
+    }
+
     #[test]
     fn math() {
         assert_eq!(lex_math(r"\[1+2\]"), Ok((r"1+2\]", Token::BDisplayMath)));
         assert_eq!(lex_math(r"\]asd"), Ok((r"asd", Token::EDisplayMath)));
         assert_eq!(lex_math(r"$$1+2$$"), Ok((r"1+2$$", Token::TDisplayMath)));
         assert_eq!(lex_math(r"$1+2$"), Ok((r"1+2$", Token::InlineMath)));
+        assert_eq!(lex_math(r"\(1+2\)"), Ok((r"1+2\)", Token::BInlineMath)));
+        assert_eq!(lex_math(r"\)asd"), Ok((r"asd", Token::EInlineMath)));
+    }
+
+    #[test]
+    fn dollars() {
+        use Token::*;
+        assert_eq!(
+            lex_tokens("$a$$b$"),
+            Ok(vec![
+                InlineMath,
+                Text("a"),
+                TDisplayMath,
+                Text("b"),
+                InlineMath
+            ])
+        );
+        assert_eq!(
+            lex_tokens("$$a$$"),
+            Ok(vec![TDisplayMath, Text("a"), TDisplayMath])
+        );
+        assert_eq!(lex_tokens("$$$"), Ok(vec![TDisplayMath, InlineMath]));
+        // the `$$` is display math, inside the inline math
+        let mut lexer = Lexer::new("$a$$b");
+        let modes: Vec<bool> =
+            std::iter::from_fn(|| lexer.next_token().map(|_| lexer.in_math())).collect();
+        assert_eq!(modes, [true, true, true, true]);
     }
 
     #[test]
@@ -181,6 +1473,353 @@ mod tests {
             lex_comment("% hello world\n"),
             Ok(("\n", Token::Comment(" hello world")))
         );
+        // the newline after the comment is a token of its own, while in `a % c b` the
+        // comment runs to the end of the line
+        assert_eq!(
+            lex_tokens("a% c\n  b"),
+            Ok(vec![
+                Token::Text("a"),
+                Token::Comment(" c"),
+                Token::Newline(NewlineKind::Lf),
+                Token::Whitespace("  "),
+                Token::Text("b"),
+            ])
+        );
+        assert_eq!(
+            lex_tokens("a % c b"),
+            Ok(vec![
+                Token::Text("a"),
+                Token::Whitespace(" "),
+                Token::Comment(" c b"),
+            ])
+        );
+    }
+
+    #[test]
+    fn endline() {
+        assert_eq!(lex_endline("\\\\\n"), Ok(("\n", Token::Endline)));
+        assert_eq!(lex_endline(r"\\*x"), Ok(("x", Token::EndlineSpaced("*"))));
+        assert_eq!(
+            lex_endline(r"\\[2ex] x"),
+            Ok((" x", Token::EndlineSpaced("[2ex]")))
+        );
+        assert_eq!(
+            lex_endline(r"\\*[1.5ex]"),
+            Ok(("", Token::EndlineSpaced("*[1.5ex]")))
+        );
+        assert_eq!(lex_endline("\\\\[2ex\n]"), Ok(("[2ex\n]", Token::Endline)));
+        assert_eq!(Token::EndlineSpaced("[2ex]").to_string(), r"\\[2ex]");
+
+        assert_eq!(lex_tokens(r"\\").unwrap(), [Token::Endline]);
+        assert_eq!(
+            lex_tokens(r"\\\\").unwrap(),
+            [Token::Endline, Token::Endline]
+        );
+        assert_eq!(
+            lex_tokens(r"\\text").unwrap(),
+            [Token::Endline, Token::Text("text")]
+        );
+        assert_eq!(
+            lex_tokens(r"\\\cmd\\\\\[").unwrap(),
+            [
+                Token::Endline,
+                Token::Command("cmd"),
+                Token::Endline,
+                Token::Endline,
+                Token::BDisplayMath
+            ]
+        );
+        assert_eq!(
+            lex_tokens(r"a\\begin{x}").unwrap(),
+            [
+                Token::Text("a"),
+                Token::Endline,
+                Token::Text("begin"),
+                Token::LBrace,
+                Token::Text("x"),
+                Token::RBrace
+            ]
+        );
+        assert_eq!(
+            Lexer::new(r"$a\\b$\\*c").collect::<Vec<_>>(),
+            [
+                Token::InlineMath,
+                Token::Text("a"),
+                Token::Endline,
+                Token::Text("b"),
+                Token::InlineMath,
+                Token::EndlineSpaced("*"),
+                Token::Text("c")
+            ]
+        );
+        // no command has an empty name
+        for input in [r"\\", r"\\\", r"\\ ", "\\\\\n"] {
+            assert!(
+                !Lexer::new(input).any(|t| matches!(t, Token::Command(""))),
+                "{input:?}"
+            );
+        }
+    }
+
+    #[test]
+    fn tie() {
+        assert_eq!(
+            lex_tokens("Fig.~1"),
+            Ok(vec![Token::Text("Fig."), Token::Tie, Token::Text("1")])
+        );
+        assert_eq!(lex_tokens(r"\~n"), Ok(vec![Token::Text(r"\~n")]));
+    }
+
+    #[test]
+    fn ampersand() {
+        assert_eq!(
+            lex_tokens("a & b"),
+            Ok(vec![
+                Token::Text("a"),
+                Token::Whitespace(" "),
+                Token::Ampersand,
+                Token::Whitespace(" "),
+                Token::Text("b"),
+            ])
+        );
+        assert_eq!(
+            lex_tokens(r"a \& b"),
+            Ok(vec![
+                Token::Text("a"),
+                Token::Whitespace(" "),
+                Token::Text(r"\&"),
+                Token::Whitespace(" "),
+                Token::Text("b"),
+            ])
+        );
+    }
+
+    #[test]
+    fn param() {
+        assert_eq!(
+            lex_tokens("#1#2"),
+            Ok(vec![Token::Param(1), Token::Param(2)])
+        );
+        assert_eq!(lex_tokens("##"), Ok(vec![Token::Text("##")]));
+        assert_eq!(
+            lex_tokens(r"##1 \# #0#"),
+            Ok(vec![
+                Token::Text("##1"),
+                Token::Whitespace(" "),
+                Token::Text(r"\#"),
+                Token::Whitespace(" "),
+                Token::Text("#0#"),
+            ])
+        );
+        assert_eq!(
+            lex_tokens(r"\newcommand{\x}[2]{#1+#2}"),
+            Ok(vec![
+                Token::Command("newcommand"),
+                Token::LBrace,
+                Token::Command("x"),
+                Token::RBrace,
+                Token::LBracket,
+                Token::Text("2"),
+                Token::RBracket,
+                Token::LBrace,
+                Token::Param(1),
+                Token::Text("+"),
+                Token::Param(2),
+                Token::RBrace,
+            ])
+        );
+        assert_eq!(Token::<&str>::Param(3).to_string(), "#3");
+    }
+
+    #[test]
+    fn script() {
+        assert_eq!(
+            lex_tokens("\\(x^2_i\\)"),
+            Ok(vec![
+                Token::BInlineMath,
+                Token::Text("x"),
+                Token::Superscript,
+                Token::Text("2"),
+                Token::Subscript,
+                Token::Text("i"),
+                Token::EInlineMath,
+            ])
+        );
+        assert_eq!(lex_script("^2"), Ok(("2", Token::Superscript)));
+        assert_eq!(lex_script("_i"), Ok(("i", Token::Subscript)));
+        assert_eq!(
+            lex_tokens(r"$a_{ij}^\prime$"),
+            Ok(vec![
+                Token::InlineMath,
+                Token::Text("a"),
+                Token::Subscript,
+                Token::LBrace,
+                Token::Text("ij"),
+                Token::RBrace,
+                Token::Superscript,
+                Token::Command("prime"),
+                Token::InlineMath,
+            ])
+        );
+        assert_eq!(
+            lex_tokens(r"snake\_case \^o"),
+            Ok(vec![
+                Token::Text(r"snake\_case"),
+                Token::Whitespace(" "),
+                Token::Text(r"\^o"),
+            ])
+        );
+    }
+
+    #[test]
+    fn modes() {
+        let lex = |input| Lexer::new(input).collect::<Vec<_>>();
+        assert_eq!(lex("a_b"), [Token::Text("a_b")]);
+        assert_eq!(
+            lex("$a_b$"),
+            [
+                Token::InlineMath,
+                Token::Text("a"),
+                Token::Subscript,
+                Token::Text("b"),
+                Token::InlineMath,
+            ]
+        );
+        assert_eq!(
+            lex("$a$b_c"),
+            [
+                Token::InlineMath,
+                Token::Text("a"),
+                Token::InlineMath,
+                Token::Text("b_c"),
+            ]
+        );
+        assert_eq!(
+            lex("\\begin{equation}x^2\\end{equation}^"),
+            [
+                Token::BeginEnv("equation", None),
+                Token::Text("x"),
+                Token::Superscript,
+                Token::Text("2"),
+                Token::EndEnv("equation", None),
+                Token::Text("^"),
+            ]
+        );
+        assert_eq!(
+            lex("\\[\\begin{cases}a_b\\end{cases}\\]_"),
+            [
+                Token::BDisplayMath,
+                Token::BeginEnv("cases", None),
+                Token::Text("a"),
+                Token::Subscript,
+                Token::Text("b"),
+                Token::EndEnv("cases", None),
+                Token::EDisplayMath,
+                Token::Text("_"),
+            ]
+        );
+        // unmatched closers are ignored
+        assert_eq!(lex("\\)a_b"), [Token::EInlineMath, Token::Text("a_b")]);
+        assert_eq!(
+            lex_tokens_owned("a_b = $a_b$").unwrap(),
+            TokenIterator::new("a_b = $a_b$".as_bytes())
+                .map(|t| t.unwrap())
+                .collect::<Vec<_>>()
+        );
+    }
+
+    #[test]
+    fn lazy_tokens() {
+        let mut iter = tokens("\\a\\b");
+        assert_eq!(iter.next(), Some(Ok(Token::Command("a"))));
+        assert_eq!(iter.next(), Some(Ok(Token::Command("b"))));
+        assert_eq!(iter.next(), None);
+        assert_eq!(iter.next(), None);
+
+        // only the first token is lexed
+        let mut iter = tokens("\\a\\b");
+        assert_eq!(
+            iter.by_ref().take(1).collect::<Vec<_>>(),
+            [Ok(Token::Command("a"))]
+        );
+        assert_eq!(iter.lexer.rest(), "\\b");
+
+        let iter = tokens("a \\'b");
+        assert_eq!(
+            iter.collect::<Vec<_>>(),
+            [
+                Ok(Token::Text("a")),
+                Ok(Token::Whitespace(" ")),
+                Err(LexError {
+                    span: Span {
+                        line: 1,
+                        col: 3,
+                        byte: 2
+                    },
+                    found: '\\'
+                })
+            ]
+        );
+        assert_eq!(tokens("").next(), None);
+    }
+
+    #[test]
+    fn errors() {
+        let error = |input| lex_tokens(input).unwrap_err().to_string();
+        assert_eq!(
+            error(r"a \'b"),
+            r"unexpected character '\\' at line 1, column 3"
+        );
+        assert_eq!(
+            error("\\section{Intro}\n\n  \u{e9}t\u{e9} \\"),
+            r"unexpected character '\\' at line 3, column 7"
+        );
+        assert_eq!(lex_tokens_spanned("\\[x\n\\").unwrap_err().span.byte, 4);
+    }
+
+    #[test]
+    fn newline() {
+        assert_eq!(lex_newline("\n"), Ok(("", Token::Newline(NewlineKind::Lf))));
+        assert_eq!(
+            lex_newline("\r\nx"),
+            Ok(("x", Token::Newline(NewlineKind::CrLf)))
+        );
+        let src = "a\r\n\r\nb\n";
+        let tokens = lex_tokens(src).unwrap();
+        assert_eq!(
+            tokens.iter().map(|t| t.to_string()).collect::<String>(),
+            src
+        );
+        assert_eq!(NewlineKind::detect(&tokens), Some(NewlineKind::CrLf));
+        assert_eq!(NewlineKind::detect::<&str>(&[]), None);
+    }
+
+    #[test]
+    fn kind() {
+        let kinds = |src| {
+            let tokens = lex_tokens(src).unwrap();
+            tokens.iter().map(Token::kind).collect::<Vec<_>>()
+        };
+        assert_eq!(
+            kinds("\\x{$a$}\n"),
+            [
+                TokenKind::Command,
+                TokenKind::LBrace,
+                TokenKind::InlineMath,
+                TokenKind::Text,
+                TokenKind::InlineMath,
+                TokenKind::RBrace,
+                TokenKind::Newline,
+            ]
+        );
+        assert!(Token::Whitespace(" ").is_whitespace());
+        assert!(Token::<&str>::Newline(NewlineKind::CrLf).is_whitespace());
+        assert!(!Token::Text(" ").is_whitespace());
+        assert!(Token::<&str>::BDisplayMath.is_math());
+        assert!(!Token::<&str>::Superscript.is_math());
+        assert!(Token::<&str>::RBracket.is_delimiter());
+        assert!(!Token::<&str>::Tie.is_delimiter());
+        assert!(!Token::Command("{").is_delimiter());
     }
 
     #[test]
@@ -193,14 +1832,228 @@ mod tests {
         );
     }
 
+    #[test]
+    fn escaped_specials() {
+        for special in [
+            "\\#", "\\_", "\\~", "\\^", "\\%", "\\&", "\\$", "\\{", "\\}", "\\ ",
+        ] {
+            let src = format!("a{special}b");
+            assert_eq!(lex_text(&src), Ok(("", Token::Text(src.as_str()))));
+            assert_eq!(lex_plain_text(&src), Ok(("", Token::Text(src.as_str()))));
+        }
+        // accents are text followed by a group
+        assert_eq!(
+            lex_tokens(r"\~{}\^{n}").unwrap(),
+            [
+                Token::Text(r"\~"),
+                Token::LBrace,
+                Token::RBrace,
+                Token::Text(r"\^"),
+                Token::LBrace,
+                Token::Text("n"),
+                Token::RBrace,
+            ]
+        );
+        assert_eq!(lex_tokens(r"\#1").unwrap(), [Token::Text(r"\#1")]);
+    }
+
+    #[test]
+    fn verbatim() {
+        assert_eq!(
+            lex_verbatim(r"\verb|$x$| text"),
+            Ok((" text", Token::Verbatim(r"\verb|$x$|")))
+        );
+        assert_eq!(
+            lex_verbatim(r"\verb*+{a} %b+"),
+            Ok(("", Token::Verbatim(r"\verb*+{a} %b+")))
+        );
+        assert!(lex_verbatim(r"\verbatim").is_err());
+        assert!(lex_verbatim("\\verb|unterminated\n|").is_err());
+    }
+
+    #[test]
+    fn verbatim_env() {
+        assert_eq!(
+            lex_tokens("\\begin{verbatim}\n{ $x$ % not a comment\n}\n\\end{verbatim}\n"),
+            Ok(vec![
+                Token::BeginEnv("verbatim", None),
+                Token::Verbatim("\n{ $x$ % not a comment\n}\n"),
+                Token::EndEnv("verbatim", None),
+                Token::Newline(NewlineKind::Lf),
+            ])
+        );
+        assert_eq!(
+            lex_tokens(r"\begin{verbatim}\end{verbatim}"),
+            Ok(vec![
+                Token::BeginEnv("verbatim", None),
+                Token::EndEnv("verbatim", None)
+            ])
+        );
+        assert_eq!(
+            lex_tokens(r"\begin{verbatim}\x{"),
+            Ok(vec![
+                Token::BeginEnv("verbatim", None),
+                Token::Verbatim(r"\x{")
+            ])
+        );
+    }
+
+    #[test]
+    fn listing_env() {
+        let src = "\\begin{lstlisting}\n\\end{foo} $ % {\n  \\end{ lstlisting }\n";
+        assert_eq!(
+            lex_tokens(src),
+            Ok(vec![
+                Token::BeginEnv("lstlisting", None),
+                Token::Verbatim("\n\\end{foo} $ % {\n  "),
+                Token::EndEnv("lstlisting", Some("\\end{ lstlisting }")),
+                Token::Newline(NewlineKind::Lf),
+            ])
+        );
+        let streamed: Vec<_> = TokenIterator::new(src.as_bytes())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(streamed, lex_tokens_owned(src).unwrap());
+
+        let custom = |src| {
+            Lexer::new(src)
+                .verbatim_environments(["code"])
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(
+            custom(r"\begin{code}%\end{code}"),
+            [
+                Token::BeginEnv("code", None),
+                Token::Verbatim("%"),
+                Token::EndEnv("code", None)
+            ]
+        );
+        assert_eq!(
+            custom(r"\begin{verbatim}%\end{verbatim}"),
+            [
+                Token::BeginEnv("verbatim", None),
+                Token::Comment(r"\end{verbatim}")
+            ]
+        );
+    }
+
+    #[test]
+    fn opaque_env() {
+        let src =
+            "\\begin{tikzpicture}[x=1cm]\r\n\\draw (0,0) -- (1,1); % {\r\n  \\end{tikzpicture}";
+        assert_eq!(
+            lex_tokens(src),
+            Ok(vec![
+                Token::BeginEnv("tikzpicture", None),
+                Token::Verbatim("[x=1cm]\r\n\\draw (0,0) -- (1,1); % {"),
+                Token::Newline(NewlineKind::CrLf),
+                Token::Whitespace("  "),
+                Token::EndEnv("tikzpicture", None),
+            ])
+        );
+        let streamed: Vec<_> = TokenIterator::new(src.as_bytes())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(streamed, lex_tokens_owned(src).unwrap());
+        // `\end` not starting a line, or right after `\begin`
+        assert_eq!(
+            lex_tokens("\\begin{tikzpicture}\n{ \\end{tikzpicture}"),
+            Ok(vec![
+                Token::BeginEnv("tikzpicture", None),
+                Token::Verbatim("\n{ "),
+                Token::EndEnv("tikzpicture", None),
+            ])
+        );
+        assert_eq!(
+            lex_tokens("\\begin{tikzpicture}\n\\end{tikzpicture}"),
+            Ok(vec![
+                Token::BeginEnv("tikzpicture", None),
+                Token::Newline(NewlineKind::Lf),
+                Token::EndEnv("tikzpicture", None),
+            ])
+        );
+        let tokens: Vec<_> = Lexer::new("\\begin{tikzpicture}{\\end{tikzpicture}")
+            .opaque_environments(["circuitikz"])
+            .collect();
+        assert_eq!(tokens[1], Token::LBrace);
+    }
+
+    #[test]
+    fn iffalse() {
+        let block = "\\iffalse\n  \\ifnum1<2 { \\else $ \\fi\n% \\fi\n  \\newif\\ifdraft \\ifdefined\\x\\fi\n\\fi";
+        let src = format!("a{block} b");
+        assert_eq!(
+            lex_tokens(&src),
+            Ok(vec![
+                Token::Text("a"),
+                Token::Verbatim(block),
+                Token::Whitespace(" "),
+                Token::Text("b")
+            ])
+        );
+        let streamed: Vec<_> = TokenIterator::new(src.as_bytes())
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(streamed, lex_tokens_owned(&src).unwrap());
+
+        // without their `\fi`, and in other commands, the conditionals are commands
+        assert_eq!(
+            lex_tokens(r"\iffalse{\ifx}").unwrap(),
+            [
+                Token::Command("iffalse"),
+                Token::LBrace,
+                Token::Command("ifx"),
+                Token::RBrace
+            ]
+        );
+        assert_eq!(
+            lex_tokens(r"\iffalsetrue\fi").unwrap(),
+            [Token::Command("iffalsetrue"), Token::Command("fi")]
+        );
+        assert_eq!(
+            lex_tokens(r"\iffalse\ifthenelse{a}{b}{c}\fi").unwrap(),
+            [Token::Verbatim(r"\iffalse\ifthenelse{a}{b}{c}\fi")]
+        );
+        assert_eq!(
+            lex_tokens("\\begin{comment}\n{\n\\end{comment}").unwrap(),
+            [
+                Token::BeginEnv("comment", None),
+                Token::Verbatim("\n{\n"),
+                Token::EndEnv("comment", None)
+            ]
+        );
+    }
+
     #[test]
     fn tokens_0() {
         assert_eq!(
             lex_tokens(r"\[1+2\]"),
-            Ok((
-                "",
-                vec![Token::BDisplayMath, Token::Text("1+2"), Token::EDisplayMath]
-            ))
+            Ok(vec![
+                Token::BDisplayMath,
+                Token::Text("1+2"),
+                Token::EDisplayMath
+            ])
+        );
+    }
+
+    #[test]
+    fn inline_math() {
+        assert_eq!(
+            lex_tokens(r"\(a+b\)"),
+            Ok(vec![
+                Token::BInlineMath,
+                Token::Text("a+b"),
+                Token::EInlineMath
+            ])
+        );
+        assert_eq!(
+            lex_tokens(r"\[\(\)\]"),
+            Ok(vec![
+                Token::BDisplayMath,
+                Token::BInlineMath,
+                Token::EInlineMath,
+                Token::EDisplayMath
+            ])
         );
     }
 
@@ -208,27 +2061,24 @@ mod tests {
     fn tokens_1() {
         assert_eq!(
             lex_tokens("\\cmd{arg} some\ttext \\{\\%\\} \t\n \\end % comment"),
-            Ok((
-                "",
-                vec![
-                    Token::Command("cmd"),
-                    Token::LBrace,
-                    Token::Text("arg"),
-                    Token::RBrace,
-                    Token::Whitespace(" "),
-                    Token::Text("some"),
-                    Token::Whitespace("\t"),
-                    Token::Text("text"),
-                    Token::Whitespace(" "),
-                    Token::Text("\\{\\%\\}"),
-                    Token::Whitespace(" \t"),
-                    Token::Newline,
-                    Token::Whitespace(" "),
-                    Token::Command("end"),
-                    Token::Whitespace(" "),
-                    Token::Comment(" comment"),
-                ]
-            ))
+            Ok(vec![
+                Token::Command("cmd"),
+                Token::LBrace,
+                Token::Text("arg"),
+                Token::RBrace,
+                Token::Whitespace(" "),
+                Token::Text("some"),
+                Token::Whitespace("\t"),
+                Token::Text("text"),
+                Token::Whitespace(" "),
+                Token::Text("\\{\\%\\}"),
+                Token::Whitespace(" \t"),
+                Token::Newline(NewlineKind::Lf),
+                Token::Whitespace(" "),
+                Token::Command("end"),
+                Token::Whitespace(" "),
+                Token::Comment(" comment"),
+            ])
         );
     }
 
@@ -236,26 +2086,85 @@ mod tests {
     fn tokens_2() {
         assert_eq!(
             lex_tokens(r"\cmd{arg}$1+2$\\$$1+2$$ \[1+2\]"),
-            Ok((
-                "",
-                vec![
-                    Token::Command("cmd"),
-                    Token::LBrace,
-                    Token::Text("arg"),
-                    Token::RBrace,
-                    Token::InlineMath,
-                    Token::Text("1+2"),
-                    Token::InlineMath,
-                    Token::Endline,
-                    Token::TDisplayMath,
-                    Token::Text("1+2"),
-                    Token::TDisplayMath,
-                    Token::Whitespace(" "),
-                    Token::BDisplayMath,
-                    Token::Text("1+2"),
-                    Token::EDisplayMath,
-                ]
+            Ok(vec![
+                Token::Command("cmd"),
+                Token::LBrace,
+                Token::Text("arg"),
+                Token::RBrace,
+                Token::InlineMath,
+                Token::Text("1+2"),
+                Token::InlineMath,
+                Token::Endline,
+                Token::TDisplayMath,
+                Token::Text("1+2"),
+                Token::TDisplayMath,
+                Token::Whitespace(" "),
+                Token::BDisplayMath,
+                Token::Text("1+2"),
+                Token::EDisplayMath,
+            ])
+        );
+    }
+
+    #[test]
+    fn spanned() {
+        let tokens = lex_tokens_spanned("a\n\n  é \\cmd{x}\r\n\\end").unwrap();
+        assert_eq!(tokens[0], (Token::Text("a"), Span::default()));
+        assert_eq!(
+            tokens[6],
+            (
+                Token::Command("cmd"),
+                Span {
+                    line: 3,
+                    col: 5,
+                    byte: 8
+                }
+            )
+        );
+        assert_eq!(
+            tokens.last(),
+            Some(&(
+                Token::Command("end"),
+                Span {
+                    line: 4,
+                    col: 1,
+                    byte: 17
+                }
             ))
         );
     }
+
+    #[test]
+    fn owned() {
+        let src = "\\begin{x}\\cmd{arg} $1+2$ % comment\n\\verb|y|\\end{x}";
+        let tokens = lex_tokens(src).unwrap();
+        let owned = lex_tokens_owned(src).unwrap();
+        assert_eq!(
+            owned,
+            tokens.iter().map(Token::to_owned).collect::<Vec<_>>()
+        );
+        assert_eq!(
+            owned,
+            tokens
+                .into_iter()
+                .map(Token::into_owned)
+                .collect::<Vec<_>>()
+        );
+        assert_eq!(
+            owned.iter().map(Token::as_ref).collect::<Vec<_>>(),
+            lex_tokens(src).unwrap()
+        );
+        assert!(lex_tokens_owned(r"a\").is_err());
+    }
+
+    #[test]
+    fn display() {
+        let src =
+            "\\begin{x}\\cmd{arg}[o] $1+2$ \\(a\\)\\[b\\]$$c$$\\\\ % comment\n\\verb|y|\\end{x}";
+        let tokens = lex_tokens(src).unwrap();
+        assert_eq!(
+            tokens.iter().map(|t| t.to_string()).collect::<String>(),
+            src
+        );
+    }
 }
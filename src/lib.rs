@@ -2,7 +2,25 @@
 
 #![cfg_attr(docsrs, feature(doc_auto_cfg))]
 
+pub mod bib;
+pub mod check;
+pub mod config;
+pub mod edit;
+pub mod format;
+pub mod formatter;
+pub mod include;
 pub mod lexer;
+pub mod lsp;
+pub mod parse;
+pub mod pipeline;
+pub mod stats;
+pub mod timing;
+pub mod writer;
+
+pub use formatter::{
+    format_lines, format_range, format_str, FinalNewline, FormatError, FormatOptions,
+    FormatOptionsBuilder, Lang, LineEnding, OptionsError,
+};
 
 #[cfg(test)]
 mod tests {}
@@ -0,0 +1,423 @@
+//! Language server, speaking the Language Server Protocol over a pair of streams.
+//!
+//! Only the requests needed by a formatter are implemented: the documents are kept in sync
+//! with `textDocument/didOpen`, `didChange` and `didClose`, and they are formatted by
+//! `textDocument/formatting` and `textDocument/rangeFormatting`, which answer with the
+//! edits turning the content of the document into the formatted one.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use texfmt::FormatOptions;
+//! let request = r#"{"jsonrpc": "2.0", "id": 1, "method": "shutdown"}"#;
+//! let input = format!("Content-Length: {}\r\n\r\n{request}", request.len());
+//! let mut output = Vec::new();
+//! texfmt::lsp::serve(input.as_bytes(), &mut output, |_| {
+//!     Ok::<_, String>(FormatOptions::default())
+//! })
+//! .unwrap();
+//! let response = r#"{"id":1,"jsonrpc":"2.0","result":null}"#;
+//! assert_eq!(
+//!     String::from_utf8(output).unwrap(),
+//!     format!("Content-Length: {}\r\n\r\n{response}", response.len())
+//! );
+//! ```
+
+use std::collections::HashMap;
+use std::io::{BufRead, Write};
+use std::path::{Path, PathBuf};
+
+use serde_json::{json, Value};
+
+use crate::FormatOptions;
+
+/// Error code of JSON-RPC for a method which is not implemented.
+const METHOD_NOT_FOUND: i64 = -32601;
+/// Error code of JSON-RPC for invalid parameters.
+const INVALID_PARAMS: i64 = -32602;
+/// Error code of LSP for a request which failed, such as the formatting of invalid input.
+const REQUEST_FAILED: i64 = -32803;
+
+/// Error answered to a request.
+struct ResponseError {
+    code: i64,
+    message: String,
+}
+
+impl ResponseError {
+    fn invalid_params(message: impl Into<String>) -> Self {
+        ResponseError {
+            code: INVALID_PARAMS,
+            message: message.into(),
+        }
+    }
+
+    fn failed(message: impl std::fmt::Display) -> Self {
+        ResponseError {
+            code: REQUEST_FAILED,
+            message: message.to_string(),
+        }
+    }
+}
+
+/// An open document.
+struct Document {
+    text: String,
+    /// Whether it is a BibTeX database, from its language or its extension.
+    bib: bool,
+}
+
+/// State of the server.
+struct Server<F> {
+    documents: HashMap<String, Document>,
+    /// Options used to format the document at a path, or without a path.
+    options: F,
+}
+
+/// Read a message from `input`, returning `None` at the end of the input.
+fn read_message(input: &mut impl BufRead) -> std::io::Result<Option<Value>> {
+    let invalid = |message: String| std::io::Error::new(std::io::ErrorKind::InvalidData, message);
+    let mut length = None;
+    let mut line = String::new();
+    loop {
+        line.clear();
+        if input.read_line(&mut line)? == 0 {
+            return match length {
+                None => Ok(None),
+                Some(_) => Err(std::io::ErrorKind::UnexpectedEof.into()),
+            };
+        }
+        let header = line.trim_end();
+        if header.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = header.split_once(':') {
+            if name.eq_ignore_ascii_case("Content-Length") {
+                let value = value.trim();
+                length = Some(
+                    value
+                        .parse::<usize>()
+                        .map_err(|e| invalid(format!("invalid Content-Length {value:?}: {e}")))?,
+                );
+            }
+        }
+    }
+    let length = length.ok_or_else(|| invalid("missing Content-Length".to_owned()))?;
+    let mut content = vec![0; length];
+    input.read_exact(&mut content)?;
+    serde_json::from_slice(&content)
+        .map(Some)
+        .map_err(|e| invalid(format!("invalid message: {e}")))
+}
+
+/// Write `message` to `output`.
+fn write_message(output: &mut impl Write, message: &Value) -> std::io::Result<()> {
+    let content = message.to_string();
+    write!(output, "Content-Length: {}\r\n\r\n{content}", content.len())?;
+    output.flush()
+}
+
+/// Serve the messages read from `input`, writing the responses to `output`, until the
+/// `exit` notification or the end of the input.
+///
+/// The documents are formatted with the options returned by `options` for their path,
+/// which is `None` if their URI is not a `file:` URI. The content of the documents is
+/// synchronized in full, so the server never handles incremental changes.
+pub fn serve<R, W, F, E>(mut input: R, mut output: W, options: F) -> std::io::Result<()>
+where
+    R: BufRead,
+    W: Write,
+    F: Fn(Option<&Path>) -> Result<FormatOptions, E>,
+    E: std::fmt::Display,
+{
+    let mut server = Server {
+        documents: HashMap::new(),
+        options,
+    };
+    while let Some(message) = read_message(&mut input)? {
+        let method = message["method"].as_str().unwrap_or_default();
+        if method == "exit" {
+            break;
+        }
+        let Some(id) = message.get("id") else {
+            server.notification(method, &message["params"]);
+            continue;
+        };
+        if message.get("method").is_none() {
+            // a response to a request of the server, which sends none
+            continue;
+        }
+        let response = match server.request(method, &message["params"]) {
+            Ok(result) => json!({"jsonrpc": "2.0", "id": id, "result": result}),
+            Err(e) => json!({
+                "jsonrpc": "2.0",
+                "id": id,
+                "error": {"code": e.code, "message": e.message},
+            }),
+        };
+        write_message(&mut output, &response)?;
+    }
+    Ok(())
+}
+
+impl<F, E> Server<F>
+where
+    F: Fn(Option<&Path>) -> Result<FormatOptions, E>,
+    E: std::fmt::Display,
+{
+    fn notification(&mut self, method: &str, params: &Value) {
+        let uri = params["textDocument"]["uri"].as_str();
+        match (method, uri) {
+            ("textDocument/didOpen", Some(uri)) => {
+                let document = &params["textDocument"];
+                let bib = document["languageId"] == "bibtex" || uri.ends_with(".bib");
+                let text = document["text"].as_str().unwrap_or_default().to_owned();
+                self.documents
+                    .insert(uri.to_owned(), Document { text, bib });
+            }
+            ("textDocument/didChange", Some(uri)) => {
+                let text = params["contentChanges"]
+                    .as_array()
+                    .and_then(|changes| changes.last())
+                    .and_then(|change| change["text"].as_str());
+                if let (Some(document), Some(text)) = (self.documents.get_mut(uri), text) {
+                    document.text = text.to_owned();
+                }
+            }
+            ("textDocument/didClose", Some(uri)) => {
+                self.documents.remove(uri);
+            }
+            _ => {}
+        }
+    }
+
+    fn request(&mut self, method: &str, params: &Value) -> Result<Value, ResponseError> {
+        match method {
+            "initialize" => Ok(json!({
+                "capabilities": {
+                    // the whole content of the documents is sent at each change
+                    "textDocumentSync": 1,
+                    "documentFormattingProvider": true,
+                    "documentRangeFormattingProvider": true,
+                },
+                "serverInfo": {"name": "texfmt", "version": env!("CARGO_PKG_VERSION")},
+            })),
+            "shutdown" => Ok(Value::Null),
+            "textDocument/formatting" | "textDocument/rangeFormatting" => {
+                let uri = params["textDocument"]["uri"]
+                    .as_str()
+                    .ok_or_else(|| ResponseError::invalid_params("missing document URI"))?;
+                let document = self.documents.get(uri).ok_or_else(|| {
+                    ResponseError::invalid_params(format!("unknown document {uri}"))
+                })?;
+                let path = file_path(uri);
+                let options = (self.options)(path.as_deref()).map_err(ResponseError::failed)?;
+                let text = &document.text;
+                let formatted = if method == "textDocument/formatting" {
+                    if document.bib {
+                        crate::bib::format_bib(text, &options).map_err(ResponseError::failed)?
+                    } else {
+                        crate::format_str(text, &options).map_err(ResponseError::failed)?
+                    }
+                } else {
+                    if document.bib {
+                        return Err(ResponseError::failed(
+                            "BibTeX databases cannot be formatted by range",
+                        ));
+                    }
+                    let range = &params["range"];
+                    let (start, end) = (offset(text, &range["start"]), offset(text, &range["end"]));
+                    crate::format_range(text, start, end.max(start), &options)
+                        .map_err(ResponseError::failed)?
+                };
+                Ok(Value::Array(text_edits(text, &formatted)))
+            }
+            _ => Err(ResponseError {
+                code: METHOD_NOT_FOUND,
+                message: format!("unknown method {method:?}"),
+            }),
+        }
+    }
+}
+
+/// The path of a `file:` URI, decoding its escaped characters.
+fn file_path(uri: &str) -> Option<PathBuf> {
+    let path = uri.strip_prefix("file://")?;
+    let mut bytes = Vec::with_capacity(path.len());
+    let mut rest = path.as_bytes();
+    while let Some((&byte, tail)) = rest.split_first() {
+        let escaped = tail
+            .get(..2)
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match escaped {
+            Some(escaped) if byte == b'%' => {
+                bytes.push(escaped);
+                rest = &tail[2..];
+            }
+            _ => {
+                bytes.push(byte);
+                rest = tail;
+            }
+        }
+    }
+    String::from_utf8(bytes).ok().map(PathBuf::from)
+}
+
+/// The byte offset in `text` of an LSP position, whose character counts UTF-16 code units.
+///
+/// A position past the end of its line is at the end of the line, and a position past the
+/// end of the text is at the end of the text.
+fn offset(text: &str, position: &Value) -> usize {
+    let line = position["line"].as_u64().unwrap_or(0) as usize;
+    let character = position["character"].as_u64().unwrap_or(0) as usize;
+    let Some(start) = text
+        .split_inclusive('\n')
+        .scan(0, |start, line| {
+            let line_start = *start;
+            *start += line.len();
+            Some(line_start)
+        })
+        .nth(line)
+    else {
+        return text.len();
+    };
+    let content = text[start..]
+        .split_inclusive('\n')
+        .next()
+        .unwrap_or_default()
+        .trim_end_matches(['\n', '\r']);
+    let mut units = 0;
+    for (i, c) in content.char_indices() {
+        if units >= character {
+            return start + i;
+        }
+        units += c.len_utf16();
+    }
+    start + content.len()
+}
+
+/// The edits of `diff_edits` as LSP `TextEdit`s.
+fn text_edits(original: &str, formatted: &str) -> Vec<Value> {
+    crate::edit::diff_edits(original, formatted)
+        .into_iter()
+        .map(|edit| json!({"range": {"start": edit.start, "end": edit.end}, "newText": edit.text}))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The messages written by the server for the messages `input`.
+    fn serve(input: &[Value]) -> Vec<Value> {
+        let mut transport = Vec::new();
+        for message in input {
+            write_message(&mut transport, message).unwrap();
+        }
+        let mut output = Vec::new();
+        super::serve(transport.as_slice(), &mut output, |_| {
+            FormatOptions::builder().width(20).build()
+        })
+        .unwrap();
+        let mut output = output.as_slice();
+        std::iter::from_fn(|| read_message(&mut output).unwrap()).collect()
+    }
+
+    #[test]
+    fn formatting() {
+        let uri = "file:///tmp/a%20b.tex";
+        let text = "Unchanged.\n\nSome  words,   long enough to be reflowed.\n\nUnchanged.\n";
+        let responses = serve(&[
+            json!({"jsonrpc": "2.0", "id": 1, "method": "initialize", "params": {}}),
+            json!({"jsonrpc": "2.0", "method": "initialized", "params": {}}),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": {"textDocument": {"uri": uri, "languageId": "latex", "version": 1, "text": "x"}},
+            }),
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didChange",
+                "params": {
+                    "textDocument": {"uri": uri, "version": 2},
+                    "contentChanges": [{"text": text}],
+                },
+            }),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": "textDocument/formatting",
+                "params": {"textDocument": {"uri": uri}, "options": {"tabSize": 2, "insertSpaces": true}},
+            }),
+            json!({"jsonrpc": "2.0", "id": 3, "method": "shutdown"}),
+            json!({"jsonrpc": "2.0", "method": "exit"}),
+            json!({"jsonrpc": "2.0", "id": 4, "method": "shutdown"}),
+        ]);
+        assert_eq!(responses.len(), 3);
+        assert_eq!(
+            responses[0]["result"]["capabilities"]["textDocumentSync"],
+            1
+        );
+        assert_eq!(
+            responses[1],
+            json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "result": [{
+                    "range": {"start": {"line": 2, "character": 5}, "end": {"line": 2, "character": 33}},
+                    "newText": "words, long\nenough to be\n",
+                }],
+            })
+        );
+        assert_eq!(
+            responses[2],
+            json!({"jsonrpc": "2.0", "id": 3, "result": null})
+        );
+    }
+
+    #[test]
+    fn errors() {
+        let responses = serve(&[
+            json!({
+                "jsonrpc": "2.0",
+                "method": "textDocument/didOpen",
+                "params": {"textDocument": {"uri": "file:///a.tex", "text": "{"}},
+            }),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 1,
+                "method": "textDocument/formatting",
+                "params": {"textDocument": {"uri": "file:///a.tex"}},
+            }),
+            json!({
+                "jsonrpc": "2.0",
+                "id": 2,
+                "method": "textDocument/formatting",
+                "params": {"textDocument": {"uri": "file:///b.tex"}},
+            }),
+            json!({"jsonrpc": "2.0", "id": 3, "method": "unknown"}),
+        ]);
+        let codes: Vec<_> = responses
+            .iter()
+            .map(|r| r["error"]["code"].clone())
+            .collect();
+        assert_eq!(codes, [REQUEST_FAILED, INVALID_PARAMS, METHOD_NOT_FOUND]);
+    }
+
+    #[test]
+    fn positions() {
+        let text = "a\u{e9}\u{1f600}b\r\nc";
+        let position = |line, character| json!({"line": line, "character": character});
+        assert_eq!(offset(text, &position(0, 2)), 3);
+        assert_eq!(offset(text, &position(0, 4)), 7);
+        assert_eq!(offset(text, &position(0, 9)), 8);
+        assert_eq!(offset(text, &position(1, 0)), 10);
+        assert_eq!(offset(text, &position(5, 0)), text.len());
+        assert_eq!(
+            file_path("file:///a%20b.tex"),
+            Some(PathBuf::from("/a b.tex"))
+        );
+        assert_eq!(file_path("untitled:1"), None);
+    }
+}
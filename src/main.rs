@@ -1,62 +1,1223 @@
 use anyhow::Context;
-use clap::Parser;
+use clap::{CommandFactory, Parser};
 use should_color::{clap_color, resolve, ColorChoice};
 
+/// Description of the exit statuses, shown by `--help`.
+const EXIT_STATUS_HELP: &str = "EXIT STATUS:
+    0    Success, and no input would change
+    1    Some input would change, with --check or --diff,
+         or has inconsistent references, with --check-refs
+    2    Invalid command line options or configuration
+    3    An input or output cannot be read or written
+    4    An input cannot be tokenized or parsed";
+
 #[derive(Debug, Parser)]
-#[clap(version, color = clap_color())]
+#[clap(version, about, color = clap_color(), after_help = EXIT_STATUS_HELP)]
+#[clap(disable_help_subcommand = true)]
+#[clap(group(clap::ArgGroup::new("inputs").args(&["input", "files-from"]).multiple(true)))]
 struct Cli {
-    /// Input file
-    input: Option<std::path::PathBuf>,
+    #[clap(subcommand)]
+    command: Option<Command>,
+
+    /// Input files [default: stdin]
+    input: Vec<std::path::PathBuf>,
+
+    /// Read the input from stdin, as without input files, which cannot be given as well
+    #[clap(long, conflicts_with = "inputs")]
+    stdin: bool,
+
+    /// Also format the files listed in this file, one per line, or in stdin if `-`
+    ///
+    /// Blank lines and lines starting with `#` are skipped.
+    #[clap(long, value_name = "PATH")]
+    files_from: Option<std::path::PathBuf>,
+
+    /// Also format the files included by the inputs with \input and \include, recursively
+    ///
+    /// The included files are resolved relative to the directory of the input from which
+    /// they are reached, or to --include-root, and `.tex` is added to the names without
+    /// an extension.
+    #[clap(long, requires = "inputs")]
+    follow_includes: bool,
+
+    /// Directory relative to which the included files are resolved
+    #[clap(long, value_name = "DIR", requires = "follow-includes")]
+    include_root: Option<std::path::PathBuf>,
 
     /// Output file
     #[clap(short, long)]
     output: Option<std::path::PathBuf>,
 
-    /// Flag
+    /// Check that the input is formatted, without writing anything
+    ///
+    /// The names of the inputs that would be reformatted are printed to stdout.
+    /// Exits with status 0 if the input is already formatted and 1 otherwise.
+    #[clap(long, conflicts_with = "output")]
+    check: bool,
+
+    /// Print a unified diff of the changes, without writing anything
+    ///
+    /// Exits with status 0 if there are no changes and 1 otherwise.
+    #[clap(long, conflicts_with_all = &["output", "check"])]
+    diff: bool,
+
+    /// Print the tokens of the input, without formatting it, like --emit tokens
+    #[clap(long, conflicts_with_all = &["output", "check", "diff", "emit"])]
+    dump_tokens: bool,
+
+    /// What to print for each input [default: content]
+    ///
+    /// Other forms than the formatted content cannot be written with --check, --diff,
+    /// --in-place, --output or --output-dir.
+    #[clap(long, value_name = "FORM", arg_enum)]
+    emit: Option<Emit>,
+
+    /// Format of what is printed [default: json for the tokens and the edits, text otherwise]
+    #[clap(
+        long,
+        value_name = "FORMAT",
+        arg_enum,
+        conflicts_with_all = &["output", "check", "diff", "in-place", "output-dir"]
+    )]
+    format: Option<OutputFormat>,
+
+    /// Format the input files in place
+    #[clap(
+        short,
+        long,
+        requires = "inputs",
+        conflicts_with_all = &["output", "check", "diff", "dump-tokens"]
+    )]
+    in_place: bool,
+
+    /// With --in-place, copy each changed file to the file with the suffix SUFFIX before
+    /// overwriting it
+    ///
+    /// Without a value, the suffix is `.bak`, so that the original of doc.tex is kept in
+    /// doc.tex.bak. An existing backup file is overwritten.
+    #[clap(
+        long,
+        value_name = "SUFFIX",
+        requires = "in-place",
+        min_values = 0,
+        require_equals = true,
+        forbid_empty_values = true,
+        default_missing_value = ".bak"
+    )]
+    backup: Option<String>,
+
+    /// Write the formatted inputs into this directory, preserving their relative paths
+    ///
+    /// The inputs must be inside the current directory, and the directories are created
+    /// as needed.
+    #[clap(
+        long,
+        value_name = "DIR",
+        requires = "inputs",
+        conflicts_with_all = &["output", "check", "diff", "dump-tokens", "in-place"]
+    )]
+    output_dir: Option<std::path::PathBuf>,
+
+    /// Keep the byte order mark at the start of the inputs, which is removed otherwise
+    #[clap(long)]
+    keep_bom: bool,
+
+    /// Only format the lines from START to END, counted from 1 and included
+    ///
+    /// The lines are extended to whole paragraphs, and the rest of the input is left
+    /// untouched. This option can be repeated, and the ranges are merged.
+    /// It cannot be used with BibTeX inputs.
+    #[clap(long, value_name = "START:END", multiple_occurrences = true)]
+    range: Vec<LineRange>,
+
+    /// Print the configuration used for the input, after applying the options, and exit
+    ///
+    /// The configuration is the one found for the first input, or for --stdin-filepath,
+    /// and it is printed as the content of a configuration file.
+    #[clap(long, conflicts_with_all = &["check", "diff", "dump-tokens", "in-place", "output-dir"])]
+    print_config: bool,
+
+    /// Report the undefined references and the unused labels on stderr, without formatting
+    ///
+    /// The labels are defined with \label, and referenced with \ref, \eqref, \cref and
+    /// similar commands. The exit status is 1 if some problem is found.
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "output", "check", "diff", "dump-tokens", "emit", "in-place", "output-dir",
+            "print-config",
+        ]
+    )]
+    check_refs: bool,
+
+    /// Report on stderr what each formatting pass did, without writing the formatted inputs
+    ///
+    /// The enabled passes are listed in order, each with the number of tokens it changed.
+    #[clap(
+        long,
+        conflicts_with_all = &[
+            "output", "check", "diff", "dump-tokens", "emit", "in-place", "output-dir",
+            "print-config", "check-refs", "range",
+        ]
+    )]
+    show_passes: bool,
+
+    /// Stop at the first input that cannot be processed
+    ///
+    /// The inputs are processed in order of path, so the inputs following the first
+    /// failure are not processed, except those already being processed in parallel.
+    #[clap(long)]
+    fail_fast: bool,
+
+    /// Format of the summary of the run [default: text]
+    ///
+    /// The text summary is a line written to stderr after multiple inputs, unless --quiet.
+    /// The JSON summary is an object written to stdout after any input, even with --quiet,
+    /// with the number of `formatted` and `unchanged` inputs, the `changed` paths,
+    /// and the `errors` with their `path` and `message`.
+    #[clap(long, value_name = "FORMAT", arg_enum)]
+    summary_format: Option<SummaryFormat>,
+
+    /// Write the summary of the run to this file instead
+    #[clap(long, value_name = "PATH")]
+    report_file: Option<std::path::PathBuf>,
+
+    /// Number of inputs processed in parallel [default: number of CPUs]
+    #[clap(short, long, value_name = "N")]
+    jobs: Option<usize>,
+
+    /// Path of the content read from stdin, which is neither read nor written
+    ///
+    /// It is used to find the configuration file and to detect the type of file.
+    #[clap(long, value_name = "PATH", conflicts_with = "inputs")]
+    stdin_filepath: Option<std::path::PathBuf>,
+
+    /// Language of the inputs [default: detected from the extension]
+    ///
+    /// The `.bib` files are BibTeX and all the others LaTeX, which this option overrides,
+    /// for example for stdin without --stdin-filepath.
+    #[clap(long, arg_enum)]
+    lang: Option<texfmt::Lang>,
+
+    /// Configuration file [default: the nearest .texfmt.toml]
+    ///
+    /// Without this option, the configuration is searched in the directory of each input,
+    /// or the current directory for stdin, and then in their ancestors.
+    /// The command line options take precedence over the configuration.
+    #[clap(long, value_name = "PATH")]
+    config: Option<std::path::PathBuf>,
+
+    /// Maximum line width [default: 80]
+    #[clap(long, value_name = "N")]
+    width: Option<usize>,
+
+    /// Put each sentence on its own line, instead of reflowing to a maximum width
+    #[clap(long, conflicts_with = "width")]
+    sentence_wrap: bool,
+
+    /// Number of spaces per indentation level [default: 2]
+    #[clap(long, value_name = "N")]
+    indent_width: Option<usize>,
+
+    /// Indent with tabs instead of spaces
+    #[clap(long, conflicts_with = "indent-width")]
+    use_tabs: bool,
+
+    /// Rewrite the existing indentation with spaces or tabs, keeping its depth
+    ///
+    /// Instead of indenting the lines by their nesting depth, the whitespace at their start
+    /// is converted, where a tab is --indent-width columns wide.
+    #[clap(long, value_name = "STYLE", arg_enum, conflicts_with = "use-tabs")]
+    indent_style: Option<texfmt::format::IndentStyle>,
+
+    /// Number of columns taken by a tab of the input, when measuring lines [default: 8]
+    ///
+    /// Unlike --indent-width and --use-tabs, this does not affect the output.
+    #[clap(long, value_name = "N")]
+    tab_width: Option<usize>,
+
+    /// Align the `&` separators of tables into columns
+    #[clap(long)]
+    align_tables: bool,
+
+    /// Put exactly one space between `%` and the text of comments
+    #[clap(long)]
+    comment_space: bool,
+
+    /// Rewrite the delimiters of math formulas in this style
+    #[clap(long, value_name = "STYLE", arg_enum)]
+    math_delimiters: Option<texfmt::format::MathDelimiters>,
+
+    /// Normalize the spaces around punctuation marks with the rules of this language
+    ///
+    /// This is the language of the text, unlike --lang, which is the language of the input.
+    /// Without a value, the English rules are used.
+    #[clap(
+        long,
+        value_name = "LANG",
+        arg_enum,
+        min_values = 0,
+        require_equals = true,
+        default_missing_value = "en"
+    )]
+    punctuation_spacing: Option<texfmt::format::PunctuationSpacing>,
+
+    /// Split the commands loading lists of packages, such as \usepackage{a,b}
+    #[clap(long)]
+    split_packages: bool,
+
+    /// Sort alphabetically the consecutive lines loading packages, and the lists of packages
+    ///
+    /// The lines separated by a blank line or a comment are sorted separately.
+    /// Beware that some packages must be loaded after others.
+    #[clap(long)]
+    sort_packages: bool,
+
+    /// Remove the braces around single words of the text, as in `a{b}c`
+    ///
+    /// The braces after commands, in math and tabular environments, and those preventing a
+    /// ligature, as in `f{f}`, are kept.
+    #[clap(long)]
+    remove_redundant_braces: bool,
+
+    /// Write the pairs of straight double quotes of the text, as in "a", as ``a''
+    ///
+    /// The apostrophes, the single quotes and the quotes without a pair in the paragraph are
+    /// kept, and so are those in math, in tabular environments and in the arguments of
+    /// commands.
+    #[clap(long)]
+    normalize_quotes: bool,
+
+    /// Maximum number of consecutive blank lines [default: 1]
+    ///
+    /// With 0, the blank lines are removed, which joins the paragraphs. The blank lines of
+    /// verbatim environments and of the regions between `% texfmt: off` and `% texfmt: on`
+    /// are kept.
+    #[clap(long, value_name = "N")]
+    max_consecutive_newlines: Option<usize>,
+
+    /// Line ending of the output [default: auto]
+    ///
+    /// With `auto`, the line ending of the first line of the input is used throughout.
+    #[clap(long, value_name = "STYLE", arg_enum)]
+    line_ending: Option<texfmt::LineEnding>,
+
+    /// End of the output [default: auto]
+    ///
+    /// The trailing newlines are replaced by exactly one line ending, the same as the other
+    /// lines with `auto`, unless the output is empty. With `keep`, they are left as they are.
+    #[clap(long, value_name = "STYLE", arg_enum)]
+    final_newline: Option<texfmt::FinalNewline>,
+
+    /// Leave the trailing newlines as they are, as `--final-newline keep`
+    #[clap(long, conflicts_with = "final-newline")]
+    no_final_newline: bool,
+
+    /// Put a comma after the last field of BibTeX entries
+    #[clap(long)]
+    bib_trailing_comma: bool,
+
+    /// Only log errors
     #[clap(short, long)]
+    quiet: bool,
+
+    /// Log more messages, up to -vvv
+    #[clap(short, long, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Log all messages, like -vvv
+    #[clap(short, long, conflicts_with = "quiet")]
     debug: bool,
 
+    /// Print the time spent in each phase of the formatting to stderr at the end
+    ///
+    /// The times are added up over all the inputs, and over the threads of --jobs.
+    #[clap(long)]
+    verbose_timing: bool,
+
     /// Coloring
     #[clap(long, value_name = "WHEN", arg_enum, global = true)]
     color: Option<ColorChoice>,
 }
 
-static mut COLOR_STDOUT: bool = false;
-static mut COLOR_STDERR: bool = false;
+/// Subcommands, which replace the formatting of the inputs.
+#[derive(Debug, clap::Subcommand)]
+enum Command {
+    /// Run a language server, speaking the Language Server Protocol over stdin and stdout
+    ///
+    /// The server formats the documents opened by the editor, with the configuration and
+    /// the options given before the subcommand, as in `texfmt --width 100 lsp`.
+    Lsp,
+}
+
+/// Type of file, detected from the extension.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum FileType {
+    /// `.tex`, the packages `.sty` and the classes `.cls`, and unknown extensions
+    Tex,
+    /// `.bib`, which is not LaTeX and is formatted by [`texfmt::bib`]
+    Bib,
+}
+
+impl FileType {
+    fn from_path(path: &std::path::Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("bib") => FileType::Bib,
+            _ => FileType::Tex,
+        }
+    }
+}
+
+/// Range of lines given to `--range`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct LineRange(std::ops::RangeInclusive<usize>);
+
+impl std::str::FromStr for LineRange {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (start, end) = s
+            .split_once(':')
+            .ok_or_else(|| "expected START:END".to_owned())?;
+        let line = |n: &str| match n.trim().parse::<usize>() {
+            Ok(0) => Err("the lines are counted from 1".to_owned()),
+            Ok(n) => Ok(n),
+            Err(e) => Err(format!("invalid line {n:?}: {e}")),
+        };
+        let (start, end) = (line(start)?, line(end)?);
+        if start > end {
+            return Err(format!("the range ends at line {end}, before it starts"));
+        }
+        Ok(LineRange(start..=end))
+    }
+}
+
+/// What is printed for each input, as chosen by `--emit`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+enum Emit {
+    /// The formatted content
+    Content,
+    /// The tokens of the input
+    Tokens,
+    /// The syntax tree of the input
+    Ast,
+    /// The edits turning the input into the formatted content, as in the Language Server Protocol
+    Edits,
+}
+
+/// Format of what is printed, as chosen by `--format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+enum OutputFormat {
+    /// A JSON value, or a JSON array for a sequence
+    Json,
+    /// One JSON value per line
+    Ndjson,
+    /// Plain text
+    Text,
+}
+
+/// What is printed for each input, accounting for `--dump-tokens`.
+fn emit(cli: &Cli) -> Emit {
+    match cli.emit {
+        _ if cli.dump_tokens => Emit::Tokens,
+        Some(emit) => emit,
+        None => Emit::Content,
+    }
+}
+
+/// The format of what is printed for each input, with the default depending on [`emit`].
+fn output_format(cli: &Cli) -> OutputFormat {
+    match (cli.format, emit(cli)) {
+        (Some(format), _) => format,
+        (None, Emit::Tokens | Emit::Edits) => OutputFormat::Json,
+        (None, Emit::Content | Emit::Ast) => OutputFormat::Text,
+    }
+}
 
-fn main() -> anyhow::Result<()> {
+/// Exit status of the program, as listed in [`EXIT_STATUS_HELP`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Status {
+    Success = 0,
+    WouldChange = 1,
+    Usage = 2,
+    Io = 3,
+    Parse = 4,
+}
+
+impl Status {
+    /// Classify `error` by the first of its causes having a known type.
+    ///
+    /// Errors of unknown type are considered input/output errors.
+    fn of(error: &anyhow::Error) -> Self {
+        for cause in error.chain() {
+            if cause.is::<texfmt::FormatError>() || cause.is::<texfmt::bib::BibError>() {
+                return Status::Parse;
+            }
+            if cause.is::<texfmt::OptionsError>() {
+                return Status::Usage;
+            }
+            if let Some(texfmt::config::ConfigError::Parse(_)) = cause.downcast_ref() {
+                return Status::Usage;
+            }
+            if let Some(e) = cause.downcast_ref::<std::io::Error>() {
+                // the lexing errors of `TokenIterator` and the inputs which are not UTF-8
+                return match e.kind() {
+                    std::io::ErrorKind::InvalidData => Status::Parse,
+                    _ => Status::Io,
+                };
+            }
+        }
+        Status::Io
+    }
+}
+
+impl From<Status> for std::process::ExitCode {
+    fn from(status: Status) -> Self {
+        std::process::ExitCode::from(status as u8)
+    }
+}
+
+/// Whether the output to stdout and stderr is colored.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Colors {
+    stdout: bool,
+    stderr: bool,
+}
+
+impl Colors {
+    /// Resolve the choice from `--color`, the environment variables and the default value,
+    /// and check whether the streams are terminals.
+    fn resolve(choice: Option<ColorChoice>) -> Self {
+        let choice = resolve(choice).unwrap_or(ColorChoice::Auto);
+        Colors {
+            stdout: choice.for_stream(atty::Stream::Stdout),
+            stderr: choice.for_stream(atty::Stream::Stderr),
+        }
+    }
+}
+
+fn main() -> std::process::ExitCode {
     let cli = Cli::parse();
+    if cli.verbose_timing {
+        texfmt::timing::enable();
+    }
+    let result = run(&cli);
+    if cli.verbose_timing {
+        eprint!("{}", timing_table(&texfmt::timing::report()));
+    }
+    match result {
+        Ok(status) => status.into(),
+        Err(e) => {
+            eprintln!("Error: {e:?}");
+            Status::of(&e).into()
+        }
+    }
+}
+
+/// The table of the durations of the phases, with their total, for --verbose-timing.
+fn timing_table(durations: &[(texfmt::timing::Phase, std::time::Duration)]) -> String {
+    use std::fmt::Write;
 
-    // resolve from cli preference, environment variables, default value
-    let color_choice = resolve(cli.color).unwrap_or(ColorChoice::Auto);
-    // Safety: the program is single-threaded.
-    unsafe {
-        COLOR_STDOUT = color_choice.for_stream(atty::Stream::Stdout);
-        COLOR_STDERR = color_choice.for_stream(atty::Stream::Stderr);
+    let total: std::time::Duration = durations.iter().map(|(_, duration)| *duration).sum();
+    let mut table = String::new();
+    let rows = durations
+        .iter()
+        .map(|(phase, duration)| (phase.as_str(), *duration))
+        .chain([("total", total)]);
+    for (name, duration) in rows {
+        let millis = duration.as_secs_f64() * 1000.0;
+        // writing to a string cannot fail
+        let _ = writeln!(table, "{name:<8}{millis:>12.3} ms");
     }
+    table
+}
+
+fn run(cli: &Cli) -> anyhow::Result<Status> {
+    let colors = Colors::resolve(cli.color);
 
-    init_logger(&cli)?;
+    init_logger(cli, colors)?;
     cfg_log::debug!("{cli:?}");
+    cfg_log::debug!("{colors:?}");
 
-    // Safety: the program is single-threaded.
-    colored::control::set_override(unsafe { COLOR_STDOUT });
+    colored::control::set_override(colors.stdout);
 
-    if let Some(ref path) = cli.input {
-        let file = std::fs::File::open(path)
-            .context(format!("cannot open {path:?}"))
-            .log_err()?;
-        process_stream(std::io::BufReader::new(file))
-            .context("cannot process stream")
-            .log_err()?;
-    } else {
-        process_stream(std::io::stdin().lock())
+    if let Some(Command::Lsp) = cli.command {
+        texfmt::lsp::serve(std::io::stdin().lock(), std::io::stdout().lock(), |path| {
+            format_options(cli, path).map_err(|e| format!("{e:#}"))
+        })
+        .context("cannot run the language server")?;
+        return Ok(Status::Success);
+    }
+
+    if cli.print_config {
+        let filepath = cli.input.first().or(cli.stdin_filepath.as_ref());
+        let options = format_options(cli, filepath.map(|path| path.as_path()))?;
+        print!("{}", texfmt::config::Config::from(&options));
+        return Ok(Status::Success);
+    }
+
+    let mut inputs = cli.input.clone();
+    if let Some(ref list) = cli.files_from {
+        inputs.extend(
+            read_files_list(list).context(format!("cannot read the list of files {list:?}"))?,
+        );
+    }
+    if cli.follow_includes {
+        inputs = follow_includes(cli, inputs)?;
+    }
+
+    let emit = emit(cli);
+    if emit != Emit::Content
+        && (cli.check
+            || cli.diff
+            || cli.in_place
+            || cli.output.is_some()
+            || cli.output_dir.is_some())
+    {
+        Cli::command()
+            .error(
+                clap::ErrorKind::ArgumentConflict,
+                "--emit cannot be used with --check, --diff, --in-place, --output \
+                 or --output-dir, except to emit the content",
+            )
+            .exit();
+    }
+
+    if inputs.len() > 1
+        && !(cli.check
+            || cli.diff
+            || cli.in_place
+            || cli.output_dir.is_some()
+            || cli.check_refs
+            || cli.show_passes
+            || emit != Emit::Content)
+    {
+        Cli::command()
+            .error(
+                clap::ErrorKind::ArgumentConflict,
+                "multiple input files require --check, --diff, --in-place, --output-dir, \
+                 --check-refs, --show-passes or --emit",
+            )
+            .exit();
+    }
+
+    if let Some(ref dir) = cli.output_dir {
+        for path in &inputs {
+            if let Err(e) = output_path(dir, path) {
+                Cli::command()
+                    .error(clap::ErrorKind::ValueValidation, format!("{e:#}"))
+                    .exit();
+            }
+        }
+    }
+
+    if !cli.range.is_empty() {
+        let stdin = (cli.files_from.is_none() && inputs.is_empty())
+            .then_some(cli.stdin_filepath.as_deref());
+        let filepaths = inputs.iter().map(|path| Some(path.as_path())).chain(stdin);
+        for filepath in filepaths {
+            // the language may be set by the configuration of the input
+            let lang = format_options(cli, filepath)?.lang;
+            if file_type(lang, filepath) == FileType::Bib {
+                Cli::command()
+                    .error(
+                        clap::ErrorKind::ArgumentConflict,
+                        "--range cannot be used with BibTeX inputs",
+                    )
+                    .exit();
+            }
+        }
+    }
+
+    let mut summary = Summary {
+        check: cli.check || cli.diff || cli.check_refs,
+        ..Summary::default()
+    };
+    let mut first_error = None;
+    let from_stdin = cli.files_from.is_none() && inputs.is_empty();
+    // the text summary is only about the formatting
+    let summary_format = match cli.summary_format {
+        Some(format) => Some(format),
+        None if cli.check_refs || cli.show_passes || emit != Emit::Content => None,
+        None => Some(SummaryFormat::Text),
+    };
+
+    if from_stdin {
+        if atty::is(atty::Stream::Stdin) && !cli.quiet {
+            eprintln!("reading from stdin, press {EOF_KEYS} to finish");
+        }
+        let result = process_input(cli, None, &mut std::io::stdout().lock())
             .context("cannot process stdin")
-            .log_err()?;
+            .log_err();
+        match result {
+            Ok(changed) => summary.add(STDIN_NAME, changed),
+            Err(e) => {
+                summary.add_error(STDIN_NAME, &e);
+                first_error = Some(e);
+            }
+        }
+    } else {
+        use std::io::Write;
+
+        let mut inputs: Vec<_> = inputs.iter().map(|path| path.as_path()).collect();
+        inputs.sort();
+        start_progress(cli, colors, inputs.len());
+        let results = process_inputs(cli, &inputs);
+        if let Some(bar) = PROGRESS.get() {
+            bar.finish_and_clear();
+        }
+        for (path, output, result) in results? {
+            std::io::stdout().write_all(&output)?;
+            let path = path.display().to_string();
+            match result.log_err() {
+                Ok(changed) => summary.add(&path, changed),
+                Err(e) => {
+                    summary.add_error(&path, &e);
+                    first_error.get_or_insert(e);
+                    if cli.fail_fast {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+    if let Some(format) = summary_format {
+        summary.report(cli, format, inputs.len())?;
+    }
+
+    // the exit status is determined by the first input that failed, in sorted order
+    if let Some(e) = first_error {
+        if cli.fail_fast || from_stdin {
+            return Err(e);
+        }
+        return Err(e.context(format!(
+            "{} of {} inputs could not be processed",
+            summary.errors.len(),
+            inputs.len()
+        )));
+    }
+
+    if summary.formatted > 0 && summary.check {
+        Ok(Status::WouldChange)
+    } else {
+        Ok(Status::Success)
+    }
+}
+
+/// Format of the summary, chosen with `--summary-format`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ArgEnum)]
+enum SummaryFormat {
+    /// A line such as `2 files formatted, 3 unchanged`
+    Text,
+    /// A JSON object
+    Json,
+}
+
+/// Inputs by outcome, reported at the end of a batch.
+#[derive(Debug, Default, serde::Serialize)]
+struct Summary {
+    /// Whether the inputs are only checked, with `--check` or `--diff`.
+    #[serde(skip)]
+    check: bool,
+    /// Number of inputs which are, or would be, formatted.
+    formatted: usize,
+    unchanged: usize,
+    errors: Vec<InputError>,
+    /// Paths of the inputs which are, or would be, formatted.
+    changed: Vec<String>,
+}
+
+/// Input which cannot be processed.
+#[derive(Debug, serde::Serialize)]
+struct InputError {
+    path: String,
+    message: String,
+}
+
+impl Summary {
+    fn add(&mut self, path: &str, changed: bool) {
+        if changed {
+            self.formatted += 1;
+            self.changed.push(path.to_owned());
+        } else {
+            self.unchanged += 1;
+        }
+    }
+
+    fn add_error(&mut self, path: &str, error: &anyhow::Error) {
+        self.errors.push(InputError {
+            path: path.to_owned(),
+            message: format!("{error:#}"),
+        });
+    }
+
+    /// Write the summary in `format` to the `--report-file`, or to its default stream.
+    ///
+    /// The text summary is only written after multiple inputs, except to a report file.
+    fn report(&self, cli: &Cli, format: SummaryFormat, inputs: usize) -> anyhow::Result<()> {
+        let report = match format {
+            SummaryFormat::Text => format!("{self}\n"),
+            SummaryFormat::Json => serde_json::to_string(self)? + "\n",
+        };
+        if let Some(ref path) = cli.report_file {
+            std::fs::write(path, report).context(format!("cannot write {path:?}"))?;
+        } else if format == SummaryFormat::Json {
+            use std::io::Write;
+            std::io::stdout().write_all(report.as_bytes())?;
+        } else if inputs > 1 && !cli.quiet {
+            eprint!("{report}");
+        }
+        Ok(())
+    }
+}
+
+impl std::fmt::Display for Summary {
+    /// Write a line such as `2 files formatted, 3 unchanged, 1 error`.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let plural = |n: usize, word: &str| format!("{n} {word}{}", if n == 1 { "" } else { "s" });
+        if self.check {
+            write!(f, "would reformat {}", plural(self.formatted, "file"))?;
+        } else {
+            write!(f, "{} formatted", plural(self.formatted, "file"))?;
+        }
+        write!(f, ", {} unchanged", self.unchanged)?;
+        if !self.errors.is_empty() {
+            write!(f, ", {}", plural(self.errors.len(), "error"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Read the paths listed in the file at `list`, or in stdin if it is `-`.
+fn read_files_list(list: &std::path::Path) -> anyhow::Result<Vec<std::path::PathBuf>> {
+    let content = if list == std::path::Path::new("-") {
+        std::io::read_to_string(std::io::stdin())?
+    } else {
+        std::fs::read_to_string(list)?
+    };
+    Ok(content
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(std::path::PathBuf::from)
+        .collect())
+}
+
+/// The `roots` followed by the files that they include, recursively, each listed once.
+fn follow_includes(
+    cli: &Cli,
+    roots: Vec<std::path::PathBuf>,
+) -> anyhow::Result<Vec<std::path::PathBuf>> {
+    // the files still to read, with the directory relative to which their includes are resolved
+    let mut queue: std::collections::VecDeque<_> = roots
+        .into_iter()
+        .map(|path| {
+            let base = match cli.include_root {
+                Some(ref root) => root.clone(),
+                None => path.parent().unwrap_or(std::path::Path::new("")).to_owned(),
+            };
+            (path, base)
+        })
+        .collect();
+    let mut seen = std::collections::HashSet::new();
+    let mut result = Vec::new();
+    while let Some((path, base)) = queue.pop_front() {
+        // the same file can be reached through different paths, and the includes can be cyclic
+        let canonical = std::fs::canonicalize(&path).context(format!("cannot open {path:?}"))?;
+        if !seen.insert(canonical) {
+            continue;
+        }
+        let options = format_options(cli, Some(&path))?;
+        if file_type(options.lang, Some(&path)) != FileType::Bib {
+            let bytes = std::fs::read(&path).context(format!("cannot read {path:?}"))?;
+            let input = decode_utf8(bytes).context(format!("cannot process {path:?}"))?;
+            let tokens: Vec<_> = texfmt::lexer::Lexer::new(&input)
+                .verbatim_environments(&options.verbatim_environments)
+                .opaque_environments(&options.opaque_environments)
+                .environments(!options.plain_tex)
+                .collect();
+            for name in texfmt::include::included_files(&tokens) {
+                cfg_log::debug!("{path:?} includes {name:?}");
+                queue.push_back((texfmt::include::resolve(&base, &name), base.clone()));
+            }
+        }
+        result.push(path);
+    }
+    Ok(result)
+}
+
+/// Minimum number of input files for which the progress bar is shown.
+const PROGRESS_MIN_INPUTS: usize = 20;
+
+/// Progress bar of the input files, if shown.
+static PROGRESS: std::sync::OnceLock<indicatif::ProgressBar> = std::sync::OnceLock::new();
+
+/// Show the progress bar on stderr for the `inputs` files, if there are many of them and
+/// it is shown to a person.
+///
+/// It is not shown when stderr is not a colored terminal, with --quiet, when other
+/// messages are logged with --verbose or --debug, and with --check or --diff when
+/// stdout is not a terminal.
+fn start_progress(cli: &Cli, colors: Colors, inputs: usize) {
+    let interactive = colors.stderr
+        && atty::is(atty::Stream::Stderr)
+        && (!(cli.check || cli.diff) || atty::is(atty::Stream::Stdout));
+    if inputs < PROGRESS_MIN_INPUTS || !interactive || cli.quiet || cli.verbose > 0 || cli.debug {
+        return;
+    }
+    let style = indicatif::ProgressStyle::with_template("{bar:40.cyan/blue} {pos}/{len} files")
+        .expect("the template is valid");
+    let _ = PROGRESS.set(indicatif::ProgressBar::new(inputs as u64).with_style(style));
+}
+
+/// Count one more processed input file in the progress bar, if shown.
+fn advance_progress() {
+    if let Some(bar) = PROGRESS.get() {
+        bar.inc(1);
+    }
+}
+
+/// Process the input files in parallel, collecting what they print to stdout.
+///
+/// With `--fail-fast`, the inputs not yet started after a failure are skipped.
+/// The forms printed by `--emit`, other than the content, are instead printed directly,
+/// one input at a time.
+#[allow(clippy::type_complexity)]
+fn process_inputs<'a>(
+    cli: &Cli,
+    inputs: &[&'a std::path::Path],
+) -> anyhow::Result<Vec<(&'a std::path::Path, Vec<u8>, anyhow::Result<bool>)>> {
+    use rayon::prelude::*;
+
+    if emit(cli) != Emit::Content {
+        let mut stdout = std::io::stdout().lock();
+        return Ok(inputs
+            .iter()
+            .map(|&path| {
+                let result = process_input(cli, Some(path), &mut stdout);
+                advance_progress();
+                (path, Vec::new(), result)
+            })
+            .collect());
+    }
+
+    let pool = rayon::ThreadPoolBuilder::new()
+        .num_threads(cli.jobs.unwrap_or(0))
+        .thread_name(|i| format!("worker-{i}"))
+        .build()?;
+    let failed = std::sync::atomic::AtomicBool::new(false);
+    let results: Vec<_> = pool.install(|| {
+        inputs
+            .par_iter()
+            .map(|&path| {
+                if cli.fail_fast && failed.load(std::sync::atomic::Ordering::Relaxed) {
+                    return None;
+                }
+                let mut output = Vec::new();
+                let result = process_input(cli, Some(path), &mut output);
+                if result.is_err() {
+                    failed.store(true, std::sync::atomic::Ordering::Relaxed);
+                }
+                advance_progress();
+                Some((path, output, result))
+            })
+            .collect()
+    });
+    Ok(results.into_iter().flatten().collect())
+}
+
+/// The type of the input whose path is `filepath`, unless it is overridden by `lang`.
+fn file_type(lang: Option<texfmt::Lang>, filepath: Option<&std::path::Path>) -> FileType {
+    match lang {
+        Some(texfmt::Lang::Tex | texfmt::Lang::Latex) => FileType::Tex,
+        Some(texfmt::Lang::Bib) => FileType::Bib,
+        None => filepath.map_or(FileType::Tex, FileType::from_path),
     }
+}
+
+/// Name of stdin in the messages.
+const STDIN_NAME: &str = "<stdin>";
+
+/// Format the file at `path`, or stdin if `None`, and emit the result as requested by `cli`.
+///
+/// What would be printed to stdout is written to `out` instead.
+/// Returns whether the formatting changed the content.
+fn process_input(
+    cli: &Cli,
+    path: Option<&std::path::Path>,
+    out: &mut impl std::io::Write,
+) -> anyhow::Result<bool> {
+    // the path used for the configuration and the file type, even for stdin
+    let filepath = path.or(cli.stdin_filepath.as_deref());
+    let options = format_options(cli, filepath)?;
+
+    let name = match path {
+        Some(path) => path.display().to_string(),
+        None => STDIN_NAME.to_owned(),
+    };
 
+    if cli.check_refs {
+        return if let Some(path) = path {
+            let file = std::fs::File::open(path).context(format!("cannot open {path:?}"))?;
+            check_refs(file, &name, &options).context(format!("cannot process {path:?}"))
+        } else {
+            check_refs(std::io::stdin().lock(), &name, &options)
+        };
+    }
+
+    if cli.show_passes {
+        if file_type(options.lang, filepath) == FileType::Bib {
+            anyhow::bail!("--show-passes cannot be used with the BibTeX input {name}");
+        }
+        return if let Some(path) = path {
+            let file = std::fs::File::open(path).context(format!("cannot open {path:?}"))?;
+            show_passes(file, &name, &options).context(format!("cannot process {path:?}"))
+        } else {
+            show_passes(std::io::stdin().lock(), &name, &options)
+        };
+    }
+
+    let emit = emit(cli);
+    let format = output_format(cli);
+    if matches!(emit, Emit::Tokens | Emit::Ast) {
+        if let Some(path) = path {
+            let file = std::fs::File::open(path).context(format!("cannot open {path:?}"))?;
+            emit_tree(std::io::BufReader::new(file), &options, emit, format, out)
+                .context(format!("cannot process {path:?}"))?;
+        } else {
+            emit_tree(std::io::stdin().lock(), &options, emit, format, out)?;
+        }
+        return Ok(false);
+    }
+
+    let file_type = file_type(options.lang, filepath);
+    cfg_log::debug!("file type {file_type:?}");
+    let ranges: Vec<_> = cli.range.iter().map(|range| range.0.clone()).collect();
+    let formatted = if let Some(path) = path {
+        let file = std::fs::File::open(path).context(format!("cannot open {path:?}"))?;
+        process_stream(
+            std::io::BufReader::new(file),
+            file_type,
+            cli,
+            &options,
+            &ranges,
+        )
+        .context(format!("cannot process {path:?}"))?
+    } else {
+        process_stream(std::io::stdin().lock(), file_type, cli, &options, &ranges)?
+    };
+
+    if emit == Emit::Edits {
+        let edits = texfmt::edit::diff_edits(&formatted.original, &formatted.formatted);
+        write_sequence(out, &edits, format, |out, edit| {
+            let position = |p: texfmt::edit::Position| format!("{}:{}", p.line, p.character);
+            writeln!(
+                out,
+                "{}-{} {:?}",
+                position(edit.start),
+                position(edit.end),
+                edit.text
+            )
+        })?;
+        return Ok(formatted.is_changed());
+    }
+
+    if cli.check {
+        if formatted.is_changed() {
+            writeln!(out, "{name}")?;
+        }
+    } else if cli.diff {
+        write_diff(out, &name, &formatted)?;
+    } else if cli.in_place {
+        // `in_place` requires `input`
+        let path = path.unwrap();
+        if formatted.is_changed() {
+            write_atomically(path, &formatted.formatted, cli.backup.as_deref())
+                .context(format!("cannot write {path:?}"))?;
+        }
+    } else if let Some(ref dir) = cli.output_dir {
+        // `output_dir` requires `input`
+        let path = output_path(dir, path.unwrap())?;
+        create_output_parent(dir, &path)
+            .context(format!("cannot create the parent of {path:?}"))?;
+        write_atomically(&path, &formatted.formatted, None)
+            .context(format!("cannot write {path:?}"))?;
+    } else if let Some(ref path) = cli.output {
+        long_path(path)
+            .and_then(|long| std::fs::write(long, &formatted.formatted))
+            .context(format!("cannot write {path:?}"))?;
+    } else if format == OutputFormat::Text {
+        out.write_all(formatted.formatted.as_bytes())?;
+    } else {
+        serde_json::to_writer(&mut *out, &formatted.formatted)?;
+        writeln!(out)?;
+    }
+
+    Ok(formatted.is_changed())
+}
+
+/// Write the tokens read from `stream` to `out`, tokenized as configured in `options`,
+/// or their syntax tree if `emit` is [`Emit::Ast`]. A byte order mark is skipped.
+fn emit_tree(
+    mut stream: impl std::io::BufRead,
+    options: &texfmt::FormatOptions,
+    emit: Emit,
+    format: OutputFormat,
+    out: &mut impl std::io::Write,
+) -> anyhow::Result<()> {
+    skip_bom(&mut stream)?;
+    let tokens = texfmt::lexer::TokenIterator::new(stream)
+        .verbatim_environments(&options.verbatim_environments)
+        .opaque_environments(&options.opaque_environments)
+        .environments(!options.plain_tex);
+    if emit == Emit::Ast {
+        let tokens = tokens.collect::<Result<Vec<_>, _>>()?;
+        let nodes = texfmt::parse::parse(&tokens)?;
+        return write_sequence(out, &nodes, format, |out, node| writeln!(out, "{node:#?}"));
+    }
+    if format == OutputFormat::Text {
+        for token in tokens {
+            writeln!(out, "{:?}", token?)?;
+        }
+        return Ok(());
+    }
+
+    // the tokens are written while they are read, without keeping them in memory
+    let (start, separator, end) = match format {
+        OutputFormat::Ndjson => ("", "\n", "\n"),
+        _ => ("[", ",", "]\n"),
+    };
+    out.write_all(start.as_bytes())?;
+    let mut empty = true;
+    for token in tokens {
+        if !empty {
+            out.write_all(separator.as_bytes())?;
+        }
+        serde_json::to_writer(&mut *out, &token?)?;
+        empty = false;
+    }
+    if !(empty && format == OutputFormat::Ndjson) {
+        out.write_all(end.as_bytes())?;
+    }
     Ok(())
 }
 
+/// Write `items` to `out` in `format`, each with `write_text` for [`OutputFormat::Text`].
+fn write_sequence<T: serde::Serialize, W: std::io::Write>(
+    out: &mut W,
+    items: &[T],
+    format: OutputFormat,
+    write_text: impl Fn(&mut W, &T) -> std::io::Result<()>,
+) -> anyhow::Result<()> {
+    match format {
+        OutputFormat::Json => {
+            serde_json::to_writer(&mut *out, items)?;
+            writeln!(out)?;
+        }
+        OutputFormat::Ndjson => {
+            for item in items {
+                serde_json::to_writer(&mut *out, item)?;
+                writeln!(out)?;
+            }
+        }
+        OutputFormat::Text => {
+            for item in items {
+                write_text(out, item)?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Combine the configuration file for the input at `path`, or stdin if `None`, with `cli`.
+fn format_options(
+    cli: &Cli,
+    path: Option<&std::path::Path>,
+) -> anyhow::Result<texfmt::FormatOptions> {
+    use texfmt::config::Config;
+
+    let config_path = match cli.config {
+        Some(ref config) => Some(config.clone()),
+        None => {
+            let dir = match path.and_then(|path| path.parent()) {
+                Some(dir) if !dir.as_os_str().is_empty() => dir.to_owned(),
+                _ => std::env::current_dir()?,
+            };
+            Config::discover(&dir)
+        }
+    };
+    let config = match config_path {
+        Some(ref config_path) => {
+            cfg_log::debug!("loading configuration {config_path:?}");
+            Config::load(config_path).context(format!("cannot load {config_path:?}"))?
+        }
+        None => Config::default(),
+    };
+
+    let mut builder = config.apply(texfmt::FormatOptions::builder());
+    if let Some(width) = cli.width {
+        builder = builder.width(width);
+    }
+    if let Some(indent_width) = cli.indent_width {
+        builder = builder.indent_width(indent_width);
+    }
+    if cli.use_tabs {
+        builder = builder.use_tabs(true);
+    }
+    if cli.indent_style.is_some() {
+        builder = builder.indent_style(cli.indent_style);
+    }
+    if let Some(tab_width) = cli.tab_width {
+        builder = builder.tab_width(tab_width);
+    }
+    if cli.sentence_wrap {
+        builder = builder.sentence_wrap(true);
+    }
+    if cli.align_tables {
+        builder = builder.align_tables(true);
+    }
+    if cli.comment_space {
+        builder = builder.comment_space(true);
+    }
+    if cli.math_delimiters.is_some() {
+        builder = builder.math_delimiters(cli.math_delimiters);
+    }
+    if cli.punctuation_spacing.is_some() {
+        builder = builder.punctuation_spacing(cli.punctuation_spacing);
+    }
+    if cli.split_packages {
+        builder = builder.split_packages(true);
+    }
+    if cli.sort_packages {
+        builder = builder.sort_packages(true);
+    }
+    if cli.remove_redundant_braces {
+        builder = builder.remove_redundant_braces(true);
+    }
+    if cli.normalize_quotes {
+        builder = builder.normalize_quotes(true);
+    }
+    if let Some(max_consecutive_newlines) = cli.max_consecutive_newlines {
+        builder = builder.max_consecutive_newlines(max_consecutive_newlines);
+    }
+    if let Some(line_ending) = cli.line_ending {
+        builder = builder.line_ending(line_ending);
+    }
+    if let Some(final_newline) = cli.final_newline {
+        builder = builder.final_newline(final_newline);
+    }
+    if cli.no_final_newline {
+        builder = builder.final_newline(texfmt::FinalNewline::Keep);
+    }
+    if cli.bib_trailing_comma {
+        builder = builder.bib_trailing_comma(true);
+    }
+    if cli.lang.is_some() {
+        builder = builder.lang(cli.lang);
+    }
+    Ok(builder.build()?)
+}
+
 trait LogErr {
     fn log_err(self) -> Self;
 }
@@ -70,37 +1231,331 @@ impl<T, E: std::fmt::Debug> LogErr for Result<T, E> {
     }
 }
 
+/// Content of an input, together with its formatted version.
+struct Formatted {
+    original: String,
+    formatted: String,
+}
+
+impl Formatted {
+    fn is_changed(&self) -> bool {
+        self.original != self.formatted
+    }
+}
+
+/// Write a unified diff between the original and the formatted content.
+fn write_diff(
+    out: &mut impl std::io::Write,
+    name: &str,
+    formatted: &Formatted,
+) -> std::io::Result<()> {
+    use colored::Colorize;
+
+    let diff = similar::TextDiff::from_lines(&formatted.original, &formatted.formatted);
+    for (i, hunk) in diff.unified_diff().iter_hunks().enumerate() {
+        if i == 0 {
+            writeln!(out, "{}", format!("--- {name}").bold())?;
+            writeln!(out, "{}", format!("+++ {name}").bold())?;
+        }
+        writeln!(out, "{}", hunk.header().to_string().cyan())?;
+        for change in hunk.iter_changes() {
+            let line = format!("{}{}", change.tag(), change.value().trim_end_matches('\n'));
+            match change.tag() {
+                similar::ChangeTag::Delete => writeln!(out, "{}", line.red())?,
+                similar::ChangeTag::Insert => writeln!(out, "{}", line.green())?,
+                similar::ChangeTag::Equal => writeln!(out, "{line}")?,
+            }
+            if change.missing_newline() {
+                writeln!(out, "\\ No newline at end of file")?;
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Read and format the content of `stream`, which is of type `file_type`.
+///
+/// If `ranges` is not empty, only these lines are formatted.
+/// The whole stream is read in memory, because the passes need all the tokens at once:
+/// only `--dump-tokens` streams its input, with [`texfmt::lexer::TokenIterator`].
 #[logging_timer::stime]
-fn process_stream<S: std::io::BufRead + std::fmt::Debug>(mut stream: S) -> anyhow::Result<()> {
+fn process_stream<S: std::io::BufRead + std::fmt::Debug>(
+    stream: S,
+    file_type: FileType,
+    cli: &Cli,
+    options: &texfmt::FormatOptions,
+    ranges: &[std::ops::RangeInclusive<usize>],
+) -> anyhow::Result<Formatted> {
     cfg_log::debug!("processing stream {:?}", stream);
 
-    let mut content = String::new();
-    stream.read_to_string(&mut content)?;
+    let original = read_input(stream)?;
+    let (bom, input) = match original.strip_prefix(BOM) {
+        Some(input) => (cli.keep_bom, input),
+        None => (false, original.as_str()),
+    };
 
-    print!("{content}");
+    let mut formatted = match file_type {
+        FileType::Bib => texfmt::bib::format_bib(input, options)?,
+        _ if !ranges.is_empty() => texfmt::format_lines(input, ranges, options)?,
+        _ => texfmt::format_str(input, options)?,
+    };
+    if bom {
+        formatted.insert_str(0, BOM);
+    }
 
-    Ok(())
+    Ok(Formatted {
+        original,
+        formatted,
+    })
 }
 
-fn init_logger(cli: &Cli) -> anyhow::Result<()> {
-    simplelog::TermLogger::init(
-        if cli.debug {
-            simplelog::LevelFilter::Trace
+/// Report on stderr the problems found by [`texfmt::check::check_refs`] in the input
+/// named `name`, and return whether there are some.
+fn check_refs(
+    stream: impl std::io::Read,
+    name: &str,
+    options: &texfmt::FormatOptions,
+) -> anyhow::Result<bool> {
+    let input = read_input(stream)?;
+    let input = input.strip_prefix(BOM).unwrap_or(&input);
+    let lexer = texfmt::lexer::Lexer::new(input)
+        .verbatim_environments(&options.verbatim_environments)
+        .opaque_environments(&options.opaque_environments)
+        .environments(!options.plain_tex);
+    let tokens = lexer.spanned().map_err(texfmt::FormatError::Lex)?;
+    let errors = texfmt::check::check_refs(&tokens);
+    report(
+        &errors
+            .iter()
+            .map(|e| format!("{name}: {e}\n"))
+            .collect::<String>(),
+    );
+    Ok(!errors.is_empty())
+}
+
+/// Format the input named `name` without writing it, report on stderr what each pass did,
+/// and return whether the input changes.
+fn show_passes(
+    stream: impl std::io::Read,
+    name: &str,
+    options: &texfmt::FormatOptions,
+) -> anyhow::Result<bool> {
+    use std::fmt::Write;
+
+    let input = read_input(stream)?;
+    let input = input.strip_prefix(BOM).unwrap_or(&input);
+    let (formatted, trace) = texfmt::pipeline::Pipeline::default().trace_str(input, options)?;
+    let mut lines = format!("{name}\n");
+    for (pass, result) in &trace {
+        let n = result.affected;
+        if result.changed {
+            let s = if n == 1 { "" } else { "s" };
+            writeln!(lines, "  {pass}: changed {n} token{s}")?;
         } else {
-            simplelog::LevelFilter::Info
+            writeln!(lines, "  {pass}: unchanged")?;
+        }
+    }
+    report(&lines);
+    Ok(formatted != input)
+}
+
+/// Print `report` on stderr, above the progress bar if shown.
+///
+/// It is a single write, so that the reports of the inputs processed in parallel do not mix.
+fn report(report: &str) {
+    match PROGRESS.get() {
+        Some(bar) => bar.suspend(|| eprint!("{report}")),
+        None => eprint!("{report}"),
+    }
+}
+
+/// Read the whole `stream` as UTF-8 text, including its byte order mark, if any.
+fn read_input(mut stream: impl std::io::Read) -> anyhow::Result<String> {
+    let mut bytes = Vec::new();
+    stream.read_to_end(&mut bytes)?;
+    Ok(decode_utf8(bytes)?)
+}
+
+/// The keys ending the input typed in a terminal.
+#[cfg(windows)]
+const EOF_KEYS: &str = "Ctrl-Z and Enter";
+#[cfg(not(windows))]
+const EOF_KEYS: &str = "Ctrl-D";
+
+/// Byte order mark, which some editors write at the start of UTF-8 files.
+const BOM: &str = "\u{feff}";
+
+/// Decode `bytes` as UTF-8, with an error suggesting how to convert the other encodings.
+fn decode_utf8(bytes: Vec<u8>) -> std::io::Result<String> {
+    String::from_utf8(bytes).map_err(|e| {
+        let bytes = e.as_bytes();
+        let valid = &bytes[..e.utf8_error().valid_up_to()];
+        let line = valid.iter().filter(|&&b| b == b'\n').count() + 1;
+        let encoding = if bytes.starts_with(b"\xff\xfe") || bytes.starts_with(b"\xfe\xff") {
+            "UTF-16"
+        } else {
+            "LATIN1"
+        };
+        std::io::Error::new(
+            std::io::ErrorKind::InvalidData,
+            format!(
+                "the input is not valid UTF-8, because of the byte 0x{:02x} on line {line}; \
+                 if it is encoded in {encoding}, convert it with `iconv -f {encoding} -t UTF-8`",
+                bytes[valid.len()]
+            ),
+        )
+    })
+}
+
+/// Skip the byte order mark at the start of `stream`, if any.
+fn skip_bom(stream: &mut impl std::io::BufRead) -> std::io::Result<()> {
+    if stream.fill_buf()?.starts_with(BOM.as_bytes()) {
+        stream.consume(BOM.len());
+    }
+    Ok(())
+}
+
+/// The path in `dir` where the input at `path` is written by `--output-dir`.
+///
+/// The path is normalized lexically, so it fails if `path` is outside of the current
+/// directory, either because it is absolute or because of its `..` components.
+fn output_path(
+    dir: &std::path::Path,
+    path: &std::path::Path,
+) -> anyhow::Result<std::path::PathBuf> {
+    use std::path::Component;
+
+    let outside = || anyhow::anyhow!("{path:?} is outside of the current directory");
+    let current_dir;
+    let relative = if path.is_absolute() {
+        current_dir = std::env::current_dir()?;
+        path.strip_prefix(&current_dir).map_err(|_| outside())?
+    } else {
+        path
+    };
+    let mut result = std::path::PathBuf::new();
+    for component in relative.components() {
+        match component {
+            Component::Normal(name) => result.push(name),
+            Component::CurDir => {}
+            Component::ParentDir => {
+                if !result.pop() {
+                    return Err(outside());
+                }
+            }
+            Component::RootDir | Component::Prefix(_) => return Err(outside()),
+        }
+    }
+    if result.as_os_str().is_empty() {
+        anyhow::bail!("{path:?} is not a file");
+    }
+    Ok(dir.join(result))
+}
+
+/// Create the parent directories of `path`, checking that they do not lead outside of `dir`
+/// through symbolic links.
+fn create_output_parent(dir: &std::path::Path, path: &std::path::Path) -> anyhow::Result<()> {
+    // `path` is in `dir`, so it has a parent
+    let parent = path.parent().unwrap();
+    std::fs::create_dir_all(parent)?;
+    if !parent.canonicalize()?.starts_with(dir.canonicalize()?) {
+        anyhow::bail!("{parent:?} is outside of {dir:?}");
+    }
+    Ok(())
+}
+
+/// Replace the content of the file at `path`.
+///
+/// The content is first written to a temporary file in the same directory,
+/// which is then renamed over the original, so that the file is never left half-written.
+/// With a `backup` suffix, the original is copied beforehand to the path with the suffix,
+/// replacing any file there.
+fn write_atomically(
+    path: &std::path::Path,
+    content: &str,
+    backup: Option<&str>,
+) -> anyhow::Result<()> {
+    use std::io::Write;
+
+    // the temporary file is renamed, so it must be on the same volume even on Windows,
+    // where the rename does not accept long paths unless they are verbatim
+    let path = &long_path(path)?;
+    let dir = match path.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => std::path::Path::new("."),
+    };
+    let mut file = tempfile::NamedTempFile::new_in(dir)?;
+    file.write_all(content.as_bytes())?;
+    if let Ok(metadata) = std::fs::metadata(path) {
+        file.as_file().set_permissions(metadata.permissions())?;
+    }
+    if let Some(suffix) = backup {
+        let mut backup = path.as_os_str().to_owned();
+        backup.push(suffix);
+        std::fs::copy(path, &backup).context(format!("cannot back up to {backup:?}"))?;
+    }
+    file.persist(path)?;
+
+    Ok(())
+}
+
+/// The path which the system accepts for `path`, whatever its length.
+///
+/// On Windows, this is the absolute path with the verbatim prefix `\\?\`, which lifts the
+/// limit of 260 characters, and with backslashes, since the prefix does not allow slashes.
+#[cfg(windows)]
+fn long_path(path: &std::path::Path) -> std::io::Result<std::path::PathBuf> {
+    // `absolute` joins the current directory, turns the slashes into backslashes and
+    // resolves the `..` components, which verbatim paths do not allow either
+    let absolute = std::path::absolute(path)?;
+    let long = match absolute.to_str() {
+        // already verbatim, or a device
+        Some(s) if s.starts_with(r"\\?\") || s.starts_with(r"\\.\") => return Ok(absolute),
+        Some(s) => match s.strip_prefix(r"\\") {
+            Some(unc) => format!(r"\\?\UNC\{unc}"),
+            None => format!(r"\\?\{s}"),
         },
+        // not representable as a string, so left as it is
+        None => return Ok(absolute),
+    };
+    Ok(long.into())
+}
+
+/// The path which the system accepts for `path`, which is `path` itself except on Windows.
+#[cfg(not(windows))]
+fn long_path(path: &std::path::Path) -> std::io::Result<std::path::PathBuf> {
+    Ok(path.to_owned())
+}
+
+fn init_logger(cli: &Cli, colors: Colors) -> anyhow::Result<()> {
+    use simplelog::LevelFilter;
+
+    let level = if cli.debug {
+        LevelFilter::Trace
+    } else if cli.quiet {
+        LevelFilter::Error
+    } else {
+        match cli.verbose {
+            0 => LevelFilter::Warn,
+            1 => LevelFilter::Info,
+            2 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    };
+    simplelog::TermLogger::init(
+        level,
         simplelog::ConfigBuilder::new()
             .set_target_level(simplelog::LevelFilter::Error)
             .set_location_level(simplelog::LevelFilter::Debug)
-            .set_thread_level(simplelog::LevelFilter::Trace)
+            .set_thread_level(simplelog::LevelFilter::Debug)
+            .set_thread_mode(simplelog::ThreadLogMode::Names)
             .set_level_padding(simplelog::LevelPadding::Left)
             .set_time_format_rfc3339()
             .set_time_offset_to_local()
             .unwrap_or_else(|e| e)
             .build(),
         simplelog::TerminalMode::Stderr,
-        // Safety: the program is single-threaded.
-        if unsafe { COLOR_STDERR } {
+        if colors.stderr {
             simplelog::ColorChoice::Always
         } else {
             simplelog::ColorChoice::Never
@@ -0,0 +1,732 @@
+//! Parse a stream of [`Token`]s into a tree of [`Node`]s.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use texfmt::{lexer::lex_tokens, parse::{parse, Argument, Node}};
+//! let tokens = lex_tokens(r"\section{Intro}").unwrap();
+//! assert_eq!(
+//!     parse(&tokens).unwrap(),
+//!     [Node::Command {
+//!         name: "section",
+//!         args: vec![Argument::Required(vec![Node::Text("Intro")])],
+//!     }]
+//! );
+//! ```
+
+use crate::lexer::{NewlineKind, Token};
+
+/// Syntax tree node.
+///
+/// Every token of the source belongs to exactly one node, so that writing the nodes back
+/// with [`Display`](std::fmt::Display) reproduces the source.
+///
+/// They serialize as objects like `{"kind": "Group", "content": [...]}`, as [`Token`]s do.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "kind", content = "content")]
+pub enum Node<S> {
+    /// `\command`, followed by its arguments.
+    Command { name: S, args: Vec<Argument<S>> },
+    /// `\begin{name}...\end{name}`, with the source text of the `\begin` and of the `\end`
+    /// if they have spaces around the name, as in [`Token::BeginEnv`].
+    Environment {
+        name: S,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        begin: Option<S>,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        end: Option<S>,
+        body: Vec<Node<S>>,
+    },
+    /// `{...}`
+    Group(Vec<Node<S>>),
+    /// Math delimited according to `kind`.
+    Math { kind: MathKind, body: Vec<Node<S>> },
+    /// Regular text.
+    Text(S),
+    /// `% comment`
+    Comment(S),
+    /// Verbatim content, emitted byte-for-byte.
+    Verbatim(S),
+    /// `' '` or `'\t'`
+    Whitespace(S),
+    /// `'\n'` or `"\r\n"`
+    Newline(NewlineKind),
+    /// `\\`
+    Endline,
+    /// `\\*`, `\\[length]` or `\\*[length]`, holding what follows `\\`.
+    EndlineSpaced(S),
+    /// `~`
+    Tie,
+    /// `&`
+    Ampersand,
+    /// `#1` to `#9`
+    Param(u8),
+    /// `^`
+    Superscript,
+    /// `_`
+    Subscript,
+    /// `[` which does not open an optional argument.
+    LBracket,
+    /// `]` which does not close an optional argument.
+    RBracket,
+}
+
+/// A source split into its preamble and its body at `\begin{document}`.
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+pub struct Document<S> {
+    /// The nodes before `\begin{document}`, empty for a fragment.
+    pub preamble: Vec<Node<S>>,
+    /// The nodes inside the `document` environment, or all the nodes of a fragment.
+    pub body: Vec<Node<S>>,
+    /// The nodes after `\end{document}`, which LaTeX ignores, or `None` for a fragment.
+    pub trailer: Option<Vec<Node<S>>>,
+    /// The source text of `\begin{document}` if it has spaces around the name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub begin: Option<S>,
+    /// The source text of `\end{document}` if it has spaces around the name.
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub end: Option<S>,
+}
+
+impl<S> Document<S> {
+    /// Whether the source has no `document` environment, such as a file meant for `\input`.
+    pub fn is_fragment(&self) -> bool {
+        self.trailer.is_none()
+    }
+}
+
+/// Argument of a [`Node::Command`].
+#[derive(Clone, Debug, PartialEq, Eq, serde::Serialize)]
+#[serde(tag = "kind", content = "content")]
+pub enum Argument<S> {
+    /// `[...]`
+    Optional(Vec<Node<S>>),
+    /// `{...}`
+    Required(Vec<Node<S>>),
+}
+
+/// Delimiters of a [`Node::Math`].
+///
+/// The `$` and `$$`, which open and close the math alike, are paired in order: the first
+/// one opens the math, and the next one of the same kind at the same depth closes it.
+/// Since two adjacent dollars are always a `$$`, `$a$$b$` is an unclosed `$` containing
+/// an unclosed `$$`, instead of the two formulas of `$a$ $b$`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, serde::Serialize)]
+pub enum MathKind {
+    /// `$...$`
+    Dollar,
+    /// `$$...$$`
+    DoubleDollar,
+    /// `\(...\)`
+    Parens,
+    /// `\[...\]`
+    Brackets,
+}
+
+impl MathKind {
+    /// Whether the math is displayed on its own line, as with `$$` and `\[`.
+    pub fn is_display(self) -> bool {
+        matches!(self, MathKind::DoubleDollar | MathKind::Brackets)
+    }
+
+    fn delimiters(self) -> (&'static str, &'static str) {
+        match self {
+            MathKind::Dollar => ("$", "$"),
+            MathKind::DoubleDollar => ("$$", "$$"),
+            MathKind::Parens => (r"\(", r"\)"),
+            MathKind::Brackets => (r"\[", r"\]"),
+        }
+    }
+}
+
+/// Error returned by [`parse`].
+///
+/// Positions are indices into the token slice.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParseError {
+    /// A closing token without a matching opening token.
+    Unmatched { index: usize },
+    /// An opening token which is never closed.
+    Unclosed { index: usize },
+    /// `\end{found}` closing `\begin{expected}`.
+    MismatchedEnvironment {
+        index: usize,
+        expected: String,
+        found: String,
+    },
+    /// An opening token nested deeper than [`MAX_DEPTH`].
+    TooDeep { index: usize },
+}
+
+impl std::fmt::Display for ParseError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseError::Unmatched { index } => write!(f, "unmatched closing token {index}"),
+            ParseError::Unclosed { index } => write!(f, "unclosed opening token {index}"),
+            ParseError::MismatchedEnvironment {
+                index,
+                expected,
+                found,
+            } => write!(
+                f,
+                "token {index} closes environment `{found}` instead of `{expected}`"
+            ),
+            ParseError::TooDeep { index } => write!(
+                f,
+                "opening token {index} is nested deeper than {MAX_DEPTH} levels"
+            ),
+        }
+    }
+}
+
+impl std::error::Error for ParseError {}
+
+impl<S: AsRef<str>> std::fmt::Display for Node<S> {
+    /// Write the source text of the node.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        fn write_all<S: AsRef<str>>(
+            f: &mut std::fmt::Formatter<'_>,
+            nodes: &[Node<S>],
+        ) -> std::fmt::Result {
+            nodes.iter().try_for_each(|node| write!(f, "{node}"))
+        }
+
+        match self {
+            Node::Command { name, args } => {
+                write!(f, "\\{}", name.as_ref())?;
+                for arg in args {
+                    match arg {
+                        Argument::Optional(nodes) => {
+                            f.write_str("[")?;
+                            write_all(f, nodes)?;
+                            f.write_str("]")?;
+                        }
+                        Argument::Required(nodes) => {
+                            f.write_str("{")?;
+                            write_all(f, nodes)?;
+                            f.write_str("}")?;
+                        }
+                    }
+                }
+                Ok(())
+            }
+            Node::Environment {
+                name,
+                begin,
+                end,
+                body,
+            } => {
+                write_begin(f, name, begin)?;
+                write_all(f, body)?;
+                write_end(f, name, end)
+            }
+            Node::Group(nodes) => {
+                f.write_str("{")?;
+                write_all(f, nodes)?;
+                f.write_str("}")
+            }
+            Node::Math { kind, body } => {
+                let (open, close) = kind.delimiters();
+                f.write_str(open)?;
+                write_all(f, body)?;
+                f.write_str(close)
+            }
+            Node::Comment(s) => write!(f, "%{}", s.as_ref()),
+            Node::Text(s) | Node::Verbatim(s) | Node::Whitespace(s) => f.write_str(s.as_ref()),
+            Node::Newline(kind) => f.write_str(kind.as_str()),
+            Node::Endline => f.write_str(r"\\"),
+            Node::EndlineSpaced(s) => write!(f, "\\\\{}", s.as_ref()),
+            Node::Tie => f.write_str("~"),
+            Node::Ampersand => f.write_str("&"),
+            Node::Param(n) => write!(f, "#{n}"),
+            Node::Superscript => f.write_str("^"),
+            Node::Subscript => f.write_str("_"),
+            Node::LBracket => f.write_str("["),
+            Node::RBracket => f.write_str("]"),
+        }
+    }
+}
+
+/// Write `source`, or `\begin{name}` if it is `None`.
+fn write_begin<S: AsRef<str>>(
+    f: &mut std::fmt::Formatter<'_>,
+    name: impl AsRef<str>,
+    source: &Option<S>,
+) -> std::fmt::Result {
+    match source {
+        Some(source) => f.write_str(source.as_ref()),
+        None => write!(f, "\\begin{{{}}}", name.as_ref()),
+    }
+}
+
+/// Write `source`, or `\end{name}` if it is `None`.
+fn write_end<S: AsRef<str>>(
+    f: &mut std::fmt::Formatter<'_>,
+    name: impl AsRef<str>,
+    source: &Option<S>,
+) -> std::fmt::Result {
+    match source {
+        Some(source) => f.write_str(source.as_ref()),
+        None => write!(f, "\\end{{{}}}", name.as_ref()),
+    }
+}
+
+impl<S: AsRef<str>> std::fmt::Display for Document<S> {
+    /// Write the source text of the document.
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let write_all = |f: &mut std::fmt::Formatter<'_>, nodes: &[Node<S>]| {
+            nodes.iter().try_for_each(|node| write!(f, "{node}"))
+        };
+        write_all(f, &self.preamble)?;
+        match self.trailer {
+            Some(ref trailer) => {
+                write_begin(f, "document", &self.begin)?;
+                write_all(f, &self.body)?;
+                write_end(f, "document", &self.end)?;
+                write_all(f, trailer)
+            }
+            None => write_all(f, &self.body),
+        }
+    }
+}
+
+/// Maximum number of nested nodes, such as groups, environments and math, returned by
+/// [`parse`], which fails on deeper inputs.
+///
+/// The nodes are handled recursively, when parsing but also when dropping or printing them,
+/// so the limit keeps a pathological input from overflowing the stack.
+pub const MAX_DEPTH: usize = 128;
+
+/// Construct closing the nodes being parsed.
+#[derive(Clone, Copy)]
+enum Closer<'a> {
+    Eof,
+    RBrace,
+    RBracket,
+    EndEnv(&'a str),
+    Math(MathKind),
+}
+
+struct Parser<'t, S> {
+    tokens: &'t [Token<S>],
+    pos: usize,
+    /// Number of nodes being parsed, including the top level.
+    depth: usize,
+}
+
+impl<'t, S: AsRef<str> + Clone> Parser<'t, S> {
+    /// Parse nodes until `closer`, which is consumed, failing if they are nested too deep.
+    ///
+    /// The opening token is at `open`.
+    fn nodes(&mut self, closer: Closer<'_>, open: usize) -> Result<Vec<Node<S>>, ParseError> {
+        if self.depth > MAX_DEPTH {
+            return Err(ParseError::TooDeep { index: open });
+        }
+        self.depth += 1;
+        let nodes = self.nested_nodes(closer, open);
+        self.depth -= 1;
+        nodes
+    }
+
+    /// Parse nodes until `closer`, which is consumed, one level deeper.
+    fn nested_nodes(
+        &mut self,
+        closer: Closer<'_>,
+        open: usize,
+    ) -> Result<Vec<Node<S>>, ParseError> {
+        let mut nodes = Vec::new();
+        while let Some(token) = self.tokens.get(self.pos) {
+            let index = self.pos;
+            self.pos += 1;
+            let node = match token {
+                Token::Command(name) => Node::Command {
+                    name: name.clone(),
+                    args: self.arguments()?,
+                },
+                Token::BeginEnv(name, begin) => {
+                    let body = self.nodes(Closer::EndEnv(name.as_ref()), index)?;
+                    let Token::EndEnv(_, end) = &self.tokens[self.pos - 1] else {
+                        unreachable!("the environment is closed by its `\\end`")
+                    };
+                    Node::Environment {
+                        name: name.clone(),
+                        begin: begin.clone(),
+                        end: end.clone(),
+                        body,
+                    }
+                }
+                Token::EndEnv(name, _) => {
+                    return match closer {
+                        Closer::EndEnv(expected) if expected == name.as_ref() => Ok(nodes),
+                        Closer::EndEnv(expected) => Err(ParseError::MismatchedEnvironment {
+                            index,
+                            expected: expected.to_owned(),
+                            found: name.as_ref().to_owned(),
+                        }),
+                        _ => Err(ParseError::Unmatched { index }),
+                    };
+                }
+                Token::LBrace => Node::Group(self.nodes(Closer::RBrace, index)?),
+                Token::RBrace => {
+                    return match closer {
+                        Closer::RBrace => Ok(nodes),
+                        _ => Err(ParseError::Unmatched { index }),
+                    };
+                }
+                Token::RBracket if matches!(closer, Closer::RBracket) => return Ok(nodes),
+                Token::InlineMath | Token::TDisplayMath => {
+                    let kind = if matches!(token, Token::InlineMath) {
+                        MathKind::Dollar
+                    } else {
+                        MathKind::DoubleDollar
+                    };
+                    match closer {
+                        Closer::Math(open) if open == kind => return Ok(nodes),
+                        _ => Node::Math {
+                            kind,
+                            body: self.nodes(Closer::Math(kind), index)?,
+                        },
+                    }
+                }
+                Token::BInlineMath | Token::BDisplayMath => {
+                    let kind = if matches!(token, Token::BInlineMath) {
+                        MathKind::Parens
+                    } else {
+                        MathKind::Brackets
+                    };
+                    Node::Math {
+                        kind,
+                        body: self.nodes(Closer::Math(kind), index)?,
+                    }
+                }
+                Token::EInlineMath | Token::EDisplayMath => {
+                    let kind = if matches!(token, Token::EInlineMath) {
+                        MathKind::Parens
+                    } else {
+                        MathKind::Brackets
+                    };
+                    return match closer {
+                        Closer::Math(open) if open == kind => Ok(nodes),
+                        _ => Err(ParseError::Unmatched { index }),
+                    };
+                }
+                Token::Comment(s) => Node::Comment(s.clone()),
+                Token::Text(s) => Node::Text(s.clone()),
+                Token::Verbatim(s) => Node::Verbatim(s.clone()),
+                Token::Whitespace(s) => Node::Whitespace(s.clone()),
+                Token::Newline(kind) => Node::Newline(*kind),
+                Token::Endline => Node::Endline,
+                Token::EndlineSpaced(s) => Node::EndlineSpaced(s.clone()),
+                Token::Tie => Node::Tie,
+                Token::Ampersand => Node::Ampersand,
+                Token::Param(n) => Node::Param(*n),
+                Token::Superscript => Node::Superscript,
+                Token::Subscript => Node::Subscript,
+                Token::LBracket => Node::LBracket,
+                Token::RBracket => Node::RBracket,
+            };
+            nodes.push(node);
+        }
+        match closer {
+            Closer::Eof => Ok(nodes),
+            _ => Err(ParseError::Unclosed { index: open }),
+        }
+    }
+
+    /// Parse the arguments immediately following a command.
+    fn arguments(&mut self) -> Result<Vec<Argument<S>>, ParseError> {
+        let mut args = Vec::new();
+        loop {
+            let index = self.pos;
+            match self.tokens.get(index) {
+                Some(Token::LBrace) => {
+                    self.pos += 1;
+                    args.push(Argument::Required(self.nodes(Closer::RBrace, index)?));
+                }
+                Some(Token::LBracket) if self.closes_bracket() => {
+                    self.pos += 1;
+                    args.push(Argument::Optional(self.nodes(Closer::RBracket, index)?));
+                }
+                _ => return Ok(args),
+            }
+        }
+    }
+
+    /// Whether the `[` at the current position is closed by a `]` at the same depth.
+    fn closes_bracket(&self) -> bool {
+        let mut depth = 0usize;
+        for token in &self.tokens[self.pos + 1..] {
+            match token {
+                Token::LBrace => depth += 1,
+                Token::RBrace if depth == 0 => return false,
+                Token::RBrace => depth -= 1,
+                Token::RBracket if depth == 0 => return true,
+                _ => {}
+            }
+        }
+        false
+    }
+}
+
+/// Parse `tokens` into a sequence of nodes.
+///
+/// Returns an error if braces, math delimiters or environments are not properly nested, or
+/// if they are nested deeper than [`MAX_DEPTH`].
+pub fn parse<S: AsRef<str> + Clone>(tokens: &[Token<S>]) -> Result<Vec<Node<S>>, ParseError> {
+    Parser {
+        tokens,
+        pos: 0,
+        depth: 0,
+    }
+    .nodes(Closer::Eof, 0)
+}
+
+/// Parse `tokens` into a [`Document`], splitting it at the first `document` environment
+/// outside of any other node.
+///
+/// Without such an environment, all the nodes belong to the body of a fragment.
+pub fn parse_document<S: AsRef<str> + Clone>(
+    tokens: &[Token<S>],
+) -> Result<Document<S>, ParseError> {
+    let mut nodes = parse(tokens)?;
+    let position = nodes.iter().position(
+        |node| matches!(node, Node::Environment { name, .. } if name.as_ref() == "document"),
+    );
+    let Some(i) = position else {
+        return Ok(Document {
+            preamble: Vec::new(),
+            body: nodes,
+            trailer: None,
+            begin: None,
+            end: None,
+        });
+    };
+    let trailer = nodes.split_off(i + 1);
+    let Some(Node::Environment {
+        begin, end, body, ..
+    }) = nodes.pop()
+    else {
+        unreachable!("the node at the position is the document environment")
+    };
+    Ok(Document {
+        preamble: nodes,
+        body,
+        trailer: Some(trailer),
+        begin,
+        end,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_tokens;
+
+    fn parse(input: &str) -> Result<Vec<Node<&str>>, ParseError> {
+        let tokens = lex_tokens(input).unwrap();
+        super::parse(&tokens)
+    }
+
+    #[test]
+    fn section() {
+        assert_eq!(
+            parse(r"\section{Intro}"),
+            Ok(vec![Node::Command {
+                name: "section",
+                args: vec![Argument::Required(vec![Node::Text("Intro")])],
+            }])
+        );
+        assert_eq!(
+            parse(r"\section[A]{B} [x]"),
+            Ok(vec![
+                Node::Command {
+                    name: "section",
+                    args: vec![
+                        Argument::Optional(vec![Node::Text("A")]),
+                        Argument::Required(vec![Node::Text("B")]),
+                    ],
+                },
+                Node::Whitespace(" "),
+                Node::LBracket,
+                Node::Text("x"),
+                Node::RBracket,
+            ])
+        );
+        assert_eq!(
+            parse(r"\item[{a]}"),
+            Ok(vec![
+                Node::Command {
+                    name: "item",
+                    args: vec![]
+                },
+                Node::LBracket,
+                Node::Group(vec![Node::Text("a"), Node::RBracket]),
+            ])
+        );
+    }
+
+    #[test]
+    fn nested_environments() {
+        assert_eq!(
+            parse("\\begin{a}\n\\begin{b}$x$\\end{b}\\end{a}"),
+            Ok(vec![Node::Environment {
+                name: "a",
+                begin: None,
+                end: None,
+                body: vec![
+                    Node::Newline(NewlineKind::Lf),
+                    Node::Environment {
+                        name: "b",
+                        begin: None,
+                        end: None,
+                        body: vec![Node::Math {
+                            kind: MathKind::Dollar,
+                            body: vec![Node::Text("x")],
+                        }],
+                    },
+                ],
+            }])
+        );
+        assert_eq!(
+            parse("\\begin { a }x\\end{ a}"),
+            Ok(vec![Node::Environment {
+                name: "a",
+                begin: Some("\\begin { a }"),
+                end: Some("\\end{ a}"),
+                body: vec![Node::Text("x")],
+            }])
+        );
+    }
+
+    #[test]
+    fn math() {
+        let math = |kind, body| Node::Math { kind, body };
+        assert_eq!(
+            parse(r"$a$ \(b\) $$c$$ \[d\]"),
+            Ok(vec![
+                math(MathKind::Dollar, vec![Node::Text("a")]),
+                Node::Whitespace(" "),
+                math(MathKind::Parens, vec![Node::Text("b")]),
+                Node::Whitespace(" "),
+                math(MathKind::DoubleDollar, vec![Node::Text("c")]),
+                Node::Whitespace(" "),
+                math(MathKind::Brackets, vec![Node::Text("d")]),
+            ])
+        );
+        assert_eq!(
+            parse("$$a$$"),
+            Ok(vec![math(MathKind::DoubleDollar, vec![Node::Text("a")])])
+        );
+        // the `$` inside `$$` open and close inline math
+        assert_eq!(
+            parse(r"$$\text{$x$}$$"),
+            Ok(vec![math(
+                MathKind::DoubleDollar,
+                vec![Node::Command {
+                    name: "text",
+                    args: vec![Argument::Required(vec![math(
+                        MathKind::Dollar,
+                        vec![Node::Text("x")]
+                    )])],
+                }],
+            )])
+        );
+        let kinds = [
+            MathKind::Dollar,
+            MathKind::DoubleDollar,
+            MathKind::Parens,
+            MathKind::Brackets,
+        ];
+        assert_eq!(kinds.map(MathKind::is_display), [false, true, false, true]);
+    }
+
+    #[test]
+    fn errors() {
+        assert_eq!(parse("a}b"), Err(ParseError::Unmatched { index: 1 }));
+        assert_eq!(parse("{a{b}"), Err(ParseError::Unclosed { index: 0 }));
+        assert_eq!(
+            parse(r"\begin{b}x\end{a}"),
+            Err(ParseError::MismatchedEnvironment {
+                index: 2,
+                expected: "b".to_owned(),
+                found: "a".to_owned(),
+            })
+        );
+        assert_eq!(parse(r"\[x\)"), Err(ParseError::Unmatched { index: 2 }));
+        assert_eq!(parse("a $b"), Err(ParseError::Unclosed { index: 2 }));
+        assert_eq!(parse("$a$ $$b"), Err(ParseError::Unclosed { index: 4 }));
+        assert_eq!(parse("$a$$b$"), Err(ParseError::Unclosed { index: 4 }));
+        assert_eq!(parse(r"\end{a}"), Err(ParseError::Unmatched { index: 0 }));
+    }
+
+    #[test]
+    fn depth() {
+        let nested = |depth| "{".repeat(depth) + &"}".repeat(depth);
+        assert!(parse(&nested(MAX_DEPTH)).is_ok());
+        assert_eq!(
+            parse(&nested(MAX_DEPTH + 1)),
+            Err(ParseError::TooDeep { index: MAX_DEPTH })
+        );
+        assert_eq!(
+            parse(&nested(100_000)),
+            Err(ParseError::TooDeep { index: MAX_DEPTH })
+        );
+        let environments =
+            "\\begin{a}".repeat(MAX_DEPTH) + "$x^{y}$" + &"\\end{a}".repeat(MAX_DEPTH);
+        assert_eq!(
+            parse(&environments).unwrap_err().to_string(),
+            format!("opening token {MAX_DEPTH} is nested deeper than {MAX_DEPTH} levels")
+        );
+    }
+
+    #[test]
+    fn document() {
+        let src = "\\documentclass{article}\n\\begin{document}\nHi\n\\end{document}\n";
+        let tokens = lex_tokens(src).unwrap();
+        let document = parse_document(&tokens).unwrap();
+        assert!(!document.is_fragment());
+        assert_eq!(
+            document.preamble,
+            [
+                Node::Command {
+                    name: "documentclass",
+                    args: vec![Argument::Required(vec![Node::Text("article")])],
+                },
+                Node::Newline(NewlineKind::Lf),
+            ]
+        );
+        assert_eq!(
+            document.body,
+            [
+                Node::Newline(NewlineKind::Lf),
+                Node::Text("Hi"),
+                Node::Newline(NewlineKind::Lf),
+            ]
+        );
+        assert_eq!(document.trailer, Some(vec![Node::Newline(NewlineKind::Lf)]));
+        assert_eq!(document.to_string(), src);
+    }
+
+    #[test]
+    fn fragment() {
+        let src = "\\section{Intro}\n{\\begin{document}\\end{document}}\n";
+        let tokens = lex_tokens(src).unwrap();
+        let document = parse_document(&tokens).unwrap();
+        assert!(document.is_fragment());
+        assert!(document.preamble.is_empty());
+        assert_eq!(document.body, super::parse(&tokens).unwrap());
+        assert_eq!(document.to_string(), src);
+    }
+
+    #[test]
+    fn roundtrip() {
+        let src = "\\documentclass[a4paper]{article}\n% comment\n\\begin{document}\n\\[ {x}^2 \\] \\(y\\) $$z$$ \\\\[1ex]\n\\begin{verbatim}\n}\n\\end{verbatim}\n\\end{document}\n";
+        let nodes = parse(src).unwrap();
+        assert_eq!(nodes.iter().map(|n| n.to_string()).collect::<String>(), src);
+        let src = "\\begin {document}\n\\begin { a }x\\end{a}\n\\end{ document }\n";
+        let nodes = parse(src).unwrap();
+        assert_eq!(nodes.iter().map(|n| n.to_string()).collect::<String>(), src);
+        let tokens = lex_tokens(src).unwrap();
+        assert_eq!(parse_document(&tokens).unwrap().to_string(), src);
+    }
+}
@@ -0,0 +1,609 @@
+//! The ordered list of passes run by the formatter, which can be extended with custom passes.
+//!
+//! [`format_str`](crate::format_str) runs the [`Pipeline::default`], made of the
+//! [`BuiltinPass`]es. A custom [`Pass`] can be added anywhere in the pipeline, and the
+//! pipeline then formats the inputs with [`Pipeline::format_str`].
+//!
+//! # Examples
+//!
+//! ```rust
+//! use std::borrow::Cow;
+//! use texfmt::lexer::Token;
+//! use texfmt::pipeline::{BuiltinPass, Context, Pass, Pipeline};
+//! use texfmt::FormatOptions;
+//!
+//! /// Write `LaTeX` with the command `\LaTeX`.
+//! struct LatexLogo;
+//!
+//! impl Pass for LatexLogo {
+//!     fn name(&self) -> &str {
+//!         "latex-logo"
+//!     }
+//!
+//!     fn run(&self, tokens: &mut Vec<Token<Cow<'_, str>>>, _: &FormatOptions, _: &Context<'_>) {
+//!         for token in tokens.iter_mut() {
+//!             if matches!(token, Token::Text(text) if text == "LaTeX") {
+//!                 *token = Token::Command(Cow::Borrowed("LaTeX"));
+//!             }
+//!         }
+//!     }
+//! }
+//!
+//! let mut pipeline = Pipeline::default();
+//! pipeline.insert_before(BuiltinPass::Reflow.as_str(), LatexLogo);
+//! let formatted = pipeline.format_str("I  like LaTeX \n", &FormatOptions::default());
+//! assert_eq!(formatted.unwrap(), "I like \\LaTeX\n");
+//! ```
+
+use std::borrow::Cow;
+
+use crate::format;
+use crate::formatter::{self, FormatError, FormatOptions, LineEnding};
+use crate::lexer::{NewlineKind, Token};
+use crate::timing::{self, Phase};
+
+/// What a [`Pass`] knows about the surroundings of the tokens it rewrites.
+#[derive(Clone, Copy, Debug)]
+pub struct Context<'a> {
+    /// The tokens of the input before the ones being formatted, which end with a newline.
+    pub before: &'a [Token<&'a str>],
+    /// Line ending of the output.
+    pub newline: NewlineKind,
+    /// Whether the tokens are inside a math or tabular environment, whose content is laid
+    /// out by hand.
+    pub manual_layout: bool,
+}
+
+/// A formatting pass, rewriting the tokens of the input.
+///
+/// The tokens are made of whole lines, but they may only be a part of the input, such as
+/// the lines between two regions where the formatting is switched off: the [`Context`]
+/// holds the tokens before them.
+pub trait Pass {
+    /// The name of the pass, to find it in a [`Pipeline`].
+    fn name(&self) -> &str;
+
+    /// The phase of the formatting in which the duration of the pass is counted.
+    fn phase(&self) -> Phase {
+        Phase::Passes
+    }
+
+    /// Whether the pass runs with `options`, since it may be optional.
+    fn enabled(&self, options: &FormatOptions) -> bool {
+        let _ = options;
+        true
+    }
+
+    /// Rewrite `tokens`, according to `options`.
+    ///
+    /// The [`Pipeline`] only runs the pass if it is [`enabled`](Pass::enabled) with `options`.
+    fn run(
+        &self,
+        tokens: &mut Vec<Token<Cow<'_, str>>>,
+        options: &FormatOptions,
+        context: &Context<'_>,
+    );
+}
+
+/// The passes of texfmt, which are enabled by the [`FormatOptions`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BuiltinPass {
+    /// [`format::convert_newlines`]
+    ConvertNewlines,
+    /// [`format::normalize_environments`]
+    NormalizeEnvironments,
+    /// [`format::remove_redundant_braces`]
+    RemoveRedundantBraces,
+    /// [`format::normalize_spaces`]
+    NormalizeSpaces,
+    /// [`format::punctuation_spacing`]
+    PunctuationSpacing,
+    /// [`format::normalize_quotes`]
+    NormalizeQuotes,
+    /// [`format::break_items`]
+    BreakItems,
+    /// [`format::break_display_math`]
+    BreakDisplayMath,
+    /// [`format::one_package_per_line`]
+    SplitPackages,
+    /// [`format::sort_packages`]
+    SortPackages,
+    /// [`format::normalize_citations`]
+    NormalizeCitations,
+    /// [`format::normalize_comments`]
+    NormalizeComments,
+    /// [`format::convert_math_delimiters`]
+    ConvertMathDelimiters,
+    /// [`format::strip_trailing_whitespace`]
+    StripTrailingWhitespace,
+    /// [`format::collapse_blank_lines`]
+    CollapseBlankLines,
+    /// [`format::convert_indentation`]
+    ConvertIndentation,
+    /// [`format::indent`]
+    Indent,
+    /// [`format::align_tables`]
+    AlignTables,
+    /// [`format::reflow`], or [`format::sentence_wrap`]
+    Reflow,
+}
+
+impl BuiltinPass {
+    /// All the built-in passes, in the order of the [`Pipeline::default`].
+    pub const ALL: [BuiltinPass; 19] = [
+        BuiltinPass::ConvertNewlines,
+        BuiltinPass::NormalizeEnvironments,
+        BuiltinPass::RemoveRedundantBraces,
+        BuiltinPass::NormalizeSpaces,
+        BuiltinPass::PunctuationSpacing,
+        BuiltinPass::NormalizeQuotes,
+        BuiltinPass::BreakItems,
+        BuiltinPass::BreakDisplayMath,
+        BuiltinPass::SplitPackages,
+        BuiltinPass::SortPackages,
+        BuiltinPass::NormalizeCitations,
+        BuiltinPass::NormalizeComments,
+        BuiltinPass::ConvertMathDelimiters,
+        BuiltinPass::StripTrailingWhitespace,
+        BuiltinPass::CollapseBlankLines,
+        BuiltinPass::ConvertIndentation,
+        BuiltinPass::Indent,
+        BuiltinPass::AlignTables,
+        BuiltinPass::Reflow,
+    ];
+
+    /// The name of the pass.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            BuiltinPass::ConvertNewlines => "convert-newlines",
+            BuiltinPass::NormalizeEnvironments => "normalize-environments",
+            BuiltinPass::RemoveRedundantBraces => "remove-redundant-braces",
+            BuiltinPass::NormalizeSpaces => "normalize-spaces",
+            BuiltinPass::PunctuationSpacing => "punctuation-spacing",
+            BuiltinPass::NormalizeQuotes => "normalize-quotes",
+            BuiltinPass::BreakItems => "break-items",
+            BuiltinPass::BreakDisplayMath => "break-display-math",
+            BuiltinPass::SplitPackages => "split-packages",
+            BuiltinPass::SortPackages => "sort-packages",
+            BuiltinPass::NormalizeCitations => "normalize-citations",
+            BuiltinPass::NormalizeComments => "normalize-comments",
+            BuiltinPass::ConvertMathDelimiters => "convert-math-delimiters",
+            BuiltinPass::StripTrailingWhitespace => "strip-trailing-whitespace",
+            BuiltinPass::CollapseBlankLines => "collapse-blank-lines",
+            BuiltinPass::ConvertIndentation => "convert-indentation",
+            BuiltinPass::Indent => "indent",
+            BuiltinPass::AlignTables => "align-tables",
+            BuiltinPass::Reflow => "reflow",
+        }
+    }
+}
+
+impl std::fmt::Display for BuiltinPass {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl Pass for BuiltinPass {
+    fn name(&self) -> &str {
+        self.as_str()
+    }
+
+    fn phase(&self) -> Phase {
+        match self {
+            BuiltinPass::ConvertIndentation | BuiltinPass::Indent | BuiltinPass::AlignTables => {
+                Phase::Indent
+            }
+            BuiltinPass::Reflow => Phase::Reflow,
+            _ => Phase::Passes,
+        }
+    }
+
+    fn enabled(&self, options: &FormatOptions) -> bool {
+        match self {
+            BuiltinPass::RemoveRedundantBraces => options.remove_redundant_braces,
+            BuiltinPass::PunctuationSpacing => options.punctuation_spacing.is_some(),
+            BuiltinPass::NormalizeQuotes => options.normalize_quotes,
+            BuiltinPass::SortPackages => options.sort_packages,
+            BuiltinPass::NormalizeCitations => !options.citation_commands.is_empty(),
+            BuiltinPass::NormalizeComments => options.comment_space,
+            BuiltinPass::ConvertMathDelimiters => options.math_delimiters.is_some(),
+            BuiltinPass::ConvertIndentation => options.indent_style.is_some(),
+            BuiltinPass::Indent => options.indent_style.is_none(),
+            BuiltinPass::AlignTables => options.align_tables,
+            _ => true,
+        }
+    }
+
+    fn run(
+        &self,
+        tokens: &mut Vec<Token<Cow<'_, str>>>,
+        options: &FormatOptions,
+        context: &Context<'_>,
+    ) {
+        let (before, newline) = (context.before, context.newline);
+        match self {
+            BuiltinPass::ConvertNewlines => format::convert_newlines(tokens, newline),
+            BuiltinPass::NormalizeEnvironments => format::normalize_environments(tokens),
+            // the content of math and tabular environments is laid out by hand
+            _ if context.manual_layout && self.respects_manual_layout() => {}
+            BuiltinPass::RemoveRedundantBraces => {
+                format::remove_redundant_braces_after(tokens, before)
+            }
+            BuiltinPass::NormalizeSpaces => format::normalize_spaces(tokens),
+            BuiltinPass::PunctuationSpacing => {
+                if let Some(rules) = options.punctuation_spacing {
+                    format::punctuation_spacing(tokens, rules);
+                }
+            }
+            BuiltinPass::NormalizeQuotes => format::normalize_quotes(tokens),
+            BuiltinPass::BreakItems => format::break_items_after(tokens, newline, before),
+            BuiltinPass::BreakDisplayMath => {
+                format::break_display_math_after(tokens, newline, before)
+            }
+            BuiltinPass::SplitPackages => {
+                format::one_package_per_line_after(tokens, newline, options.split_packages, before)
+            }
+            BuiltinPass::SortPackages => format::sort_packages_after(tokens, before),
+            BuiltinPass::NormalizeCitations => {
+                format::normalize_citations(tokens, &options.citation_commands)
+            }
+            BuiltinPass::NormalizeComments => format::normalize_comments(tokens, true),
+            BuiltinPass::ConvertMathDelimiters => {
+                if let Some(style) = options.math_delimiters {
+                    format::convert_math_delimiters(tokens, style);
+                }
+            }
+            BuiltinPass::StripTrailingWhitespace => format::strip_trailing_whitespace(tokens),
+            BuiltinPass::CollapseBlankLines => {
+                format::collapse_blank_lines(tokens, options.max_consecutive_newlines)
+            }
+            BuiltinPass::ConvertIndentation => {
+                if let Some(style) = options.indent_style {
+                    format::convert_indentation(tokens, style, options.indent_width);
+                }
+            }
+            BuiltinPass::Indent => {
+                let unit = if options.use_tabs {
+                    "\t".to_owned()
+                } else {
+                    " ".repeat(options.indent_width)
+                };
+                format::indent_after(tokens, &unit, before);
+            }
+            BuiltinPass::AlignTables => format::align_tables(tokens, options.tab_width),
+            BuiltinPass::Reflow => {
+                *tokens = if options.sentence_wrap {
+                    format::sentence_wrap(tokens, &options.command_args)
+                } else {
+                    format::reflow(
+                        tokens,
+                        options.width,
+                        options.tab_width,
+                        &options.command_args,
+                    )
+                };
+            }
+        }
+    }
+}
+
+impl BuiltinPass {
+    /// Whether the pass leaves alone the content of math and tabular environments.
+    fn respects_manual_layout(self) -> bool {
+        matches!(
+            self,
+            BuiltinPass::RemoveRedundantBraces
+                | BuiltinPass::NormalizeSpaces
+                | BuiltinPass::PunctuationSpacing
+                | BuiltinPass::NormalizeQuotes
+                | BuiltinPass::BreakItems
+                | BuiltinPass::BreakDisplayMath
+                | BuiltinPass::SplitPackages
+                | BuiltinPass::SortPackages
+                | BuiltinPass::Reflow
+        )
+    }
+}
+
+/// What a pass did to the tokens, as traced by [`Pipeline::trace_str`].
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct PassResult {
+    /// Whether the pass changed the tokens.
+    pub changed: bool,
+    /// Number of tokens from the first to the last one changed by the pass, before or after
+    /// it, whichever is more.
+    pub affected: usize,
+}
+
+impl PassResult {
+    /// Compare the tokens before and after a pass.
+    fn compare<S: PartialEq>(before: &[Token<S>], after: &[Token<S>]) -> Self {
+        let prefix = before.iter().zip(after).take_while(|(a, b)| a == b).count();
+        let suffix = before[prefix..]
+            .iter()
+            .rev()
+            .zip(after[prefix..].iter().rev())
+            .take_while(|(a, b)| a == b)
+            .count();
+        let affected = before.len().max(after.len()) - prefix - suffix;
+        PassResult {
+            changed: affected > 0,
+            affected,
+        }
+    }
+
+    /// Add up the results of the same pass on two parts of the input.
+    fn merge(&mut self, other: PassResult) {
+        self.changed |= other.changed;
+        self.affected += other.affected;
+    }
+}
+
+/// The names of the passes run by a [`Pipeline`], in order, with what they did.
+pub type Trace = Vec<(String, PassResult)>;
+
+/// An ordered list of passes.
+pub struct Pipeline {
+    passes: Vec<Box<dyn Pass>>,
+}
+
+impl Default for Pipeline {
+    /// The pipeline of [`format_str`](crate::format_str), made of all the built-in passes.
+    fn default() -> Self {
+        let mut pipeline = Pipeline::new();
+        for pass in BuiltinPass::ALL {
+            pipeline.push(pass);
+        }
+        pipeline
+    }
+}
+
+impl std::fmt::Debug for Pipeline {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_list().entries(self.names()).finish()
+    }
+}
+
+impl Pipeline {
+    /// An empty pipeline, which only checks and copies the inputs.
+    pub fn new() -> Self {
+        Pipeline { passes: Vec::new() }
+    }
+
+    /// Add `pass` at the end of the pipeline.
+    pub fn push(&mut self, pass: impl Pass + 'static) -> &mut Self {
+        self.passes.push(Box::new(pass));
+        self
+    }
+
+    /// Add `pass` before the first pass named `name`, or at the end if there is none.
+    pub fn insert_before(&mut self, name: &str, pass: impl Pass + 'static) -> &mut Self {
+        let index = self.position(name).unwrap_or(self.passes.len());
+        self.passes.insert(index, Box::new(pass));
+        self
+    }
+
+    /// Remove the passes named `name`.
+    pub fn remove(&mut self, name: &str) -> &mut Self {
+        self.passes.retain(|pass| pass.name() != name);
+        self
+    }
+
+    /// The index of the first pass named `name`, if any.
+    pub fn position(&self, name: &str) -> Option<usize> {
+        self.passes.iter().position(|pass| pass.name() == name)
+    }
+
+    /// The names of the passes, in order.
+    pub fn names(&self) -> impl Iterator<Item = &str> {
+        self.passes.iter().map(|pass| pass.name())
+    }
+
+    /// Run the passes on `tokens`, which follow `before`, in order.
+    ///
+    /// The line ending of the output is the one of `before` if it has any, or else the one
+    /// chosen by [`FormatOptions::line_ending`].
+    pub fn run(
+        &self,
+        tokens: &mut Vec<Token<Cow<'_, str>>>,
+        options: &FormatOptions,
+        before: &[Token<&str>],
+    ) {
+        self.run_traced(tokens, options, before, None);
+    }
+
+    /// Run the passes on `tokens`, which follow `before`, and add what they did to `trace`.
+    pub(crate) fn run_traced(
+        &self,
+        tokens: &mut Vec<Token<Cow<'_, str>>>,
+        options: &FormatOptions,
+        before: &[Token<&str>],
+        mut trace: Option<&mut Trace>,
+    ) {
+        let newline = match NewlineKind::detect(before) {
+            Some(newline) if options.line_ending == LineEnding::Auto => newline,
+            _ => formatter::newline_kind(options.line_ending, NewlineKind::detect(tokens.iter())),
+        };
+        let context = Context {
+            before,
+            newline,
+            manual_layout: format::inside_no_reflow_environment(before),
+        };
+        let passes = self.passes.iter().filter(|pass| pass.enabled(options));
+        for (i, pass) in passes.enumerate() {
+            let Some(trace) = trace.as_deref_mut() else {
+                timing::time(pass.phase(), || pass.run(tokens, options, &context));
+                continue;
+            };
+            let original = tokens.clone();
+            timing::time(pass.phase(), || pass.run(tokens, options, &context));
+            let result = PassResult::compare(&original, tokens);
+            match trace.get_mut(i) {
+                Some((_, total)) => total.merge(result),
+                None => trace.push((pass.name().to_owned(), result)),
+            }
+        }
+    }
+
+    /// Format `input` according to `options` with the passes of the pipeline, as
+    /// [`format_str`](crate::format_str) does with the default pipeline.
+    pub fn format_str(&self, input: &str, options: &FormatOptions) -> Result<String, FormatError> {
+        formatter::format_with(input, options, self, None)
+    }
+
+    /// Format `input` like [`Pipeline::format_str`], and trace what each pass enabled by
+    /// `options` did.
+    ///
+    /// The results of a pass are added up over the parts of the input between the regions
+    /// where the formatting is switched off.
+    pub fn trace_str(
+        &self,
+        input: &str,
+        options: &FormatOptions,
+    ) -> Result<(String, Trace), FormatError> {
+        let mut trace = Trace::new();
+        let formatted = formatter::format_with(input, options, self, Some(&mut trace))?;
+        if trace.is_empty() {
+            // no part of the input was formatted
+            trace = self
+                .passes
+                .iter()
+                .filter(|pass| pass.enabled(options))
+                .map(|pass| (pass.name().to_owned(), PassResult::default()))
+                .collect();
+        }
+        Ok((formatted, trace))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Upper-case all the text.
+    struct Shout;
+
+    impl Pass for Shout {
+        fn name(&self) -> &str {
+            "shout"
+        }
+
+        fn run(&self, tokens: &mut Vec<Token<Cow<'_, str>>>, _: &FormatOptions, _: &Context<'_>) {
+            for token in tokens.iter_mut() {
+                if let Token::Text(text) = token {
+                    *text = Cow::Owned(text.to_uppercase());
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn default() {
+        let pipeline = Pipeline::default();
+        let names: Vec<_> = pipeline.names().collect();
+        let builtin: Vec<_> = BuiltinPass::ALL.iter().map(|pass| pass.as_str()).collect();
+        assert_eq!(names, builtin);
+        let src = "\\begin{itemize}\n\\item   one  \n\n\n\\item two\n\\end{itemize}\n";
+        let options = FormatOptions::default();
+        assert_eq!(
+            pipeline.format_str(src, &options),
+            crate::format_str(src, &options)
+        );
+    }
+
+    #[test]
+    fn custom() {
+        let options = FormatOptions::default();
+        let src = "\\begin{center}\nsome  text % note\n\\end{center}\n";
+        let mut pipeline = Pipeline::default();
+        pipeline.push(Shout);
+        assert_eq!(
+            pipeline.format_str(src, &options).unwrap(),
+            "\\begin{center}\n  SOME TEXT % note\n\\end{center}\n"
+        );
+        // only the custom pass
+        let mut pipeline = Pipeline::new();
+        pipeline.push(Shout);
+        assert_eq!(
+            pipeline.format_str(src, &options).unwrap(),
+            "\\begin{center}\nSOME  TEXT % note\n\\end{center}\n"
+        );
+        // the text is reflowed after being upper-cased
+        let options = FormatOptions::builder().width(8).build().unwrap();
+        let mut pipeline = Pipeline::default();
+        pipeline.insert_before("reflow", Shout);
+        assert_eq!(pipeline.position("shout"), Some(BuiltinPass::ALL.len() - 1));
+        assert_eq!(
+            pipeline.format_str("ab cd ef\n", &options).unwrap(),
+            "AB CD EF\n"
+        );
+        pipeline.remove("shout").remove("reflow");
+        assert_eq!(
+            pipeline.format_str("ab  cd ef gh\n", &options).unwrap(),
+            "ab cd ef gh\n"
+        );
+    }
+
+    #[test]
+    fn trace() {
+        let mut pipeline = Pipeline::default();
+        pipeline.insert_before("indent", Shout);
+        let options = FormatOptions::builder()
+            .sort_packages(true)
+            .build()
+            .unwrap();
+        let src = "a  b\n% texfmt: off\nc  d\n% texfmt: on\ne  f\n";
+        let (formatted, trace) = pipeline.trace_str(src, &options).unwrap();
+        assert_eq!(formatted, "A B\n% texfmt: off\nc  d\n% texfmt: on\nE F\n");
+        let names: Vec<_> = trace.iter().map(|(name, _)| name.as_str()).collect();
+        assert_eq!(
+            names,
+            [
+                "convert-newlines",
+                "normalize-environments",
+                "normalize-spaces",
+                "break-items",
+                "break-display-math",
+                "split-packages",
+                "sort-packages",
+                "normalize-citations",
+                "strip-trailing-whitespace",
+                "collapse-blank-lines",
+                "shout",
+                "indent",
+                "reflow",
+            ]
+        );
+        let result = |name| trace.iter().find(|(n, _)| n == name).unwrap().1;
+        assert_eq!(
+            result("normalize-spaces"),
+            PassResult {
+                changed: true,
+                affected: 2
+            }
+        );
+        assert_eq!(
+            result("shout"),
+            PassResult {
+                changed: true,
+                affected: 6
+            }
+        );
+        assert_eq!(result("reflow"), PassResult::default());
+        // the passes are listed even if nothing is formatted
+        let (_, trace) = pipeline.trace_str("% texfmt: off\n", &options).unwrap();
+        assert_eq!(trace.len(), names.len());
+    }
+
+    #[test]
+    fn guarded() {
+        let mut pipeline = Pipeline::new();
+        pipeline.push(Shout);
+        let src = "a\n% texfmt: off\nb\n% texfmt: on\nc\n";
+        assert_eq!(
+            pipeline.format_str(src, &FormatOptions::default()).unwrap(),
+            "A\n% texfmt: off\nb\n% texfmt: on\nC\n"
+        );
+    }
+}
@@ -0,0 +1,107 @@
+//! Count the tokens, the words and the formulas of a (La)TeX source.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use texfmt::lexer::TokenKind;
+//! let stats = texfmt::stats::token_stats("Let $x$ be \\emph{positive}. % TODO\n").unwrap();
+//! assert_eq!(stats.words, 3);
+//! assert_eq!(stats.math, 1);
+//! assert_eq!(stats.commands, 1);
+//! assert_eq!(stats.count(TokenKind::InlineMath), 2);
+//! ```
+
+use std::collections::HashMap;
+
+use crate::lexer::{LexError, Lexer, Token, TokenKind};
+
+/// Statistics of the tokens of a (La)TeX source, returned by [`token_stats`].
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct TokenStats {
+    /// Number of tokens.
+    pub tokens: usize,
+    /// Number of tokens of each kind, only for the kinds with some tokens.
+    pub kinds: HashMap<TokenKind, usize>,
+    /// Number of commands, such as `\emph`, without environments.
+    pub commands: usize,
+    /// Number of comments.
+    pub comments: usize,
+    /// Number of math formulas and math environments, not counting the nested ones.
+    pub math: usize,
+    /// Number of words of the text outside of math, including the arguments of commands.
+    ///
+    /// A word is made of text with a letter or a digit, up to a space, a tie `~`, a
+    /// command or a formula, so that `don't` and `a{b}c` are one word each.
+    pub words: usize,
+}
+
+impl TokenStats {
+    /// Number of tokens of `kind`.
+    pub fn count(&self, kind: TokenKind) -> usize {
+        self.kinds.get(&kind).copied().unwrap_or(0)
+    }
+}
+
+/// Count the tokens of `input`, by kind, with its words and its formulas.
+pub fn token_stats(input: &str) -> Result<TokenStats, LexError> {
+    let mut stats = TokenStats::default();
+    let mut lexer = Lexer::new(input);
+    let mut in_word = false;
+    loop {
+        let math = lexer.in_math();
+        let Some(token) = lexer.next_token() else {
+            break;
+        };
+        stats.tokens += 1;
+        *stats.kinds.entry(token.kind()).or_default() += 1;
+        if !math && lexer.in_math() {
+            stats.math += 1;
+        }
+        match token {
+            Token::Command(_) => stats.commands += 1,
+            Token::Comment(_) => stats.comments += 1,
+            _ => {}
+        }
+        in_word = match token {
+            Token::Text(text) if !math && !lexer.in_math() => {
+                let word = text.chars().any(char::is_alphanumeric);
+                if word && !in_word {
+                    stats.words += 1;
+                }
+                in_word || word
+            }
+            Token::LBrace | Token::RBrace => in_word,
+            _ => false,
+        };
+    }
+    lexer.finish().map(|()| stats)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn snippet() {
+        let src = "\\section{Intro}\n% a comment\nWe don't use $a_1$, \\[x\\] or\n\\begin{equation}\ny = $z$\n\\end{equation}\nin a{b}c~words.\n";
+        let stats = token_stats(src).unwrap();
+        assert_eq!(stats.tokens, 48);
+        assert_eq!(stats.commands, 1);
+        assert_eq!(stats.comments, 1);
+        assert_eq!(stats.math, 3);
+        // Intro, We, don't, use, or, in, abc, words
+        assert_eq!(stats.words, 8);
+        assert_eq!(stats.count(TokenKind::Newline), 7);
+        assert_eq!(stats.count(TokenKind::InlineMath), 4);
+        assert_eq!(stats.count(TokenKind::Subscript), 1);
+        assert_eq!(stats.count(TokenKind::Tie), 1);
+        assert_eq!(stats.count(TokenKind::Verbatim), 0);
+        assert_eq!(stats.kinds.values().sum::<usize>(), stats.tokens);
+    }
+
+    #[test]
+    fn empty() {
+        assert_eq!(token_stats(""), Ok(TokenStats::default()));
+        assert!(token_stats("a\\").is_err());
+    }
+}
@@ -0,0 +1,94 @@
+//! Measure the time spent in each phase of the formatting.
+//!
+//! The measurement is off by default, and then each phase only checks a flag. Once
+//! [`enable`]d, the durations of the phases are added up over all the inputs, and over all
+//! the threads, until they are read by [`report`].
+//!
+//! # Examples
+//!
+//! ```rust
+//! use texfmt::timing::{self, Phase};
+//! timing::enable();
+//! texfmt::format_str("Some text.\n", &texfmt::FormatOptions::default()).unwrap();
+//! let phases: Vec<Phase> = timing::report().into_iter().map(|(phase, _)| phase).collect();
+//! assert_eq!(phases, Phase::ALL);
+//! ```
+
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Phase of the formatting.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Phase {
+    /// Tokenizing the input.
+    Lex,
+    /// Checking the structure of the tokens, such as the balance of the braces.
+    Parse,
+    /// The passes rewriting the tokens, except for the indentation and the reflow.
+    Passes,
+    /// Indenting the lines, and aligning the tables.
+    Indent,
+    /// Reflowing the paragraphs, or wrapping them at the sentences.
+    Reflow,
+    /// Writing the tokens back to text.
+    Write,
+}
+
+impl Phase {
+    /// All the phases, in order of execution.
+    pub const ALL: [Phase; 6] = [
+        Phase::Lex,
+        Phase::Parse,
+        Phase::Passes,
+        Phase::Indent,
+        Phase::Reflow,
+        Phase::Write,
+    ];
+
+    /// The name of the phase.
+    pub fn as_str(self) -> &'static str {
+        match self {
+            Phase::Lex => "lex",
+            Phase::Parse => "parse",
+            Phase::Passes => "passes",
+            Phase::Indent => "indent",
+            Phase::Reflow => "reflow",
+            Phase::Write => "write",
+        }
+    }
+}
+
+impl std::fmt::Display for Phase {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// The total duration of each phase, in the order of [`Phase::ALL`].
+static DURATIONS: Mutex<[Duration; 6]> = Mutex::new([Duration::ZERO; 6]);
+
+/// Start measuring the phases.
+pub fn enable() {
+    ENABLED.store(true, Ordering::Relaxed);
+}
+
+/// The total duration of each phase since the last report, in order of execution.
+pub fn report() -> Vec<(Phase, Duration)> {
+    let durations = std::mem::take(&mut *DURATIONS.lock().unwrap_or_else(|e| e.into_inner()));
+    Phase::ALL.into_iter().zip(durations).collect()
+}
+
+/// Run `f`, counting its duration in `phase` if the measurement is enabled.
+pub(crate) fn time<T>(phase: Phase, f: impl FnOnce() -> T) -> T {
+    if !ENABLED.load(Ordering::Relaxed) {
+        return f();
+    }
+    let start = Instant::now();
+    let result = f();
+    let elapsed = start.elapsed();
+    DURATIONS.lock().unwrap_or_else(|e| e.into_inner())[phase as usize] += elapsed;
+    result
+}
@@ -0,0 +1,72 @@
+//! Serialize a stream of tokens back into a (La)TeX source.
+//!
+//! # Examples
+//!
+//! ```rust
+//! use texfmt::{lexer::lex_tokens, writer::write_tokens};
+//! let src = "\\section{Intro} % first\n$x^2$ \\\\[1ex]\n";
+//! let tokens = lex_tokens(src).unwrap();
+//! assert_eq!(write_tokens(&tokens), src);
+//! ```
+
+use crate::lexer::Token;
+
+/// Reconstruct the source text of `tokens`.
+///
+/// Each token is written with the delimiters it implies, such as the `\` of a command or the
+/// `%` of a comment, so this is the inverse of [`lex_tokens`](crate::lexer::lex_tokens):
+/// the source is reproduced exactly.
+/// Nothing is escaped: the text and the verbatim content are emitted byte-for-byte, and they
+/// already hold the backslashes of escapes such as `\%`, which never become comments.
+pub fn write_tokens<S: AsRef<str>>(tokens: &[Token<S>]) -> String {
+    let mut result = String::new();
+    write_tokens_to(&mut result, tokens).unwrap();
+    result
+}
+
+/// Write the source text of `tokens` to `out`.
+pub fn write_tokens_to<S: AsRef<str>>(
+    out: &mut impl std::fmt::Write,
+    tokens: &[Token<S>],
+) -> std::fmt::Result {
+    tokens.iter().try_for_each(|token| write!(out, "{token}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_tokens;
+
+    const CORPUS: &[&str] = &[
+        "",
+        "plain text",
+        "  indented\n\n\n\tlines \t\n",
+        "\\documentclass[a4paper,11pt]{article}\n\\usepackage{amsmath}\n",
+        "\\begin{itemize}\n  \\item one % comment\n  \\item[b)] two\n\\end{itemize}",
+        "%!TeX root=main.tex\n%%% rule %%%\n%",
+        "$a_{ij}^2$ and $$x$$, \\(y\\) or \\[z\\]",
+        "\\begin{align}\n  a &= b \\\\\n  c &= d \\\\*[2ex]\n\\end{align}",
+        "Fig.~1, \\$5 \\& 10\\% \\{braces\\} \\_ \\^o \\# [bracket]",
+        "\\newcommand{\\pair}[2]{(#1, #2)}\\def\\x{##}",
+        "\\verb|$x%| and \\verb*+{+",
+        "\\begin{verbatim}\n  {$ % raw\n\\end{verbatim}\n",
+        "windows\r\nline\r\nendings\r\n",
+        "snake_case^ outside math",
+        "\\begin { itemize }\n\\item a\n\\end\t{itemize }",
+    ];
+
+    #[test]
+    fn roundtrip() {
+        for src in CORPUS {
+            let tokens = lex_tokens(src).unwrap_or_else(|e| panic!("cannot tokenize {src:?}: {e}"));
+            assert_eq!(write_tokens(&tokens), *src);
+        }
+    }
+
+    #[test]
+    fn roundtrip_asset() {
+        let src = include_str!("../assets/src_01.tex");
+        let tokens = lex_tokens(src).unwrap();
+        assert_eq!(write_tokens(&tokens), src);
+    }
+}
@@ -0,0 +1,1033 @@
+use std::io::Write;
+use std::process::{Command, Output, Stdio};
+
+use assert2::*;
+
+/// Run `texfmt` with the given arguments, feeding `stdin` to it.
+fn texfmt(args: &[&str], stdin: &str) -> Output {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_texfmt"))
+        .args(args)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .spawn()
+        .expect("cannot spawn texfmt");
+    // texfmt may exit before reading its input
+    let _ = child.stdin.take().unwrap().write_all(stdin.as_bytes());
+    child.wait_with_output().unwrap()
+}
+
+#[test]
+fn check_formatted() {
+    let output = texfmt(&["--check"], "\\section{Intro}\nSome text.\n");
+    check!(output.status.code() == Some(0));
+    check!(output.stdout.is_empty());
+}
+
+#[test]
+fn in_place_unchanged() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("doc.tex");
+    std::fs::write(&path, "Some text.\n").unwrap();
+    let output = texfmt(&["-i", path.to_str().unwrap()], "");
+    check!(output.status.success());
+    check!(output.stdout.is_empty());
+    check!(std::fs::read_to_string(&path).unwrap() == "Some text.\n");
+}
+
+#[test]
+fn in_place_backup() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("doc.tex");
+    let path = path.to_str().unwrap();
+    std::fs::write(path, "Some   text.\n").unwrap();
+    std::fs::write(format!("{path}.bak"), "Stale backup.\n").unwrap();
+    let output = texfmt(&["-i", "--backup", path], "");
+    check!(output.status.success());
+    check!(std::fs::read_to_string(path).unwrap() == "Some text.\n");
+    check!(std::fs::read_to_string(format!("{path}.bak")).unwrap() == "Some   text.\n");
+    // a custom suffix, and no backup of an unchanged file
+    std::fs::write(path, "More   text.\n").unwrap();
+    let output = texfmt(&["-i", "--backup=.orig", path], "");
+    check!(output.status.success());
+    check!(std::fs::read_to_string(format!("{path}.orig")).unwrap() == "More   text.\n");
+    let output = texfmt(&["-i", "--backup=.new", path], "");
+    check!(output.status.success());
+    check!(!std::path::Path::new(&format!("{path}.new")).exists());
+    // no backup without --backup, which requires --in-place
+    let output = texfmt(&["-i", path], "");
+    check!(output.status.success());
+    check!(std::fs::read_dir(dir.path()).unwrap().count() == 3);
+    check!(!texfmt(&["--backup", path], "").status.success());
+}
+
+#[cfg(windows)]
+#[test]
+fn long_output_path() {
+    let dir = tempfile::tempdir().unwrap();
+    // more than 260 characters, with slashes
+    let parent = format!(
+        "{}/{}",
+        dir.path().display(),
+        ["d".repeat(100); 3].join("/")
+    );
+    std::fs::create_dir_all(&parent).unwrap();
+    let path = format!("{parent}/doc.tex");
+    check!(path.len() > 300);
+    let output = texfmt(&["--output", &path], "Some   text.\n");
+    check!(output.status.success());
+    check!(std::fs::read_to_string(&path).unwrap() == "Some text.\n");
+    let output = texfmt(&["-i", &path], "");
+    check!(output.status.success());
+    check!(std::fs::read_to_string(&path).unwrap() == "Some text.\n");
+}
+
+#[test]
+fn in_place_stdin() {
+    let output = texfmt(&["--in-place"], "Some text.\n");
+    check!(!output.status.success());
+    check!(output.stdout.is_empty());
+    check!(String::from_utf8_lossy(&output.stderr).contains("<INPUT|--files-from <PATH>>"));
+}
+
+#[test]
+fn explicit_stdin() {
+    let output = texfmt(&["--stdin"], "Some   text.\n");
+    check!(output.status.success());
+    check!(output.stdout == b"Some text.\n");
+    // no hint when stdin is not a terminal
+    check!(output.stderr.is_empty());
+    for args in [
+        &["--stdin", "doc.tex"][..],
+        &["--stdin", "--files-from", "-"],
+    ] {
+        let output = texfmt(args, "Some text.\n");
+        check!(output.status.code() == Some(2));
+        check!(output.stdout.is_empty());
+        check!(String::from_utf8_lossy(&output.stderr).contains("--stdin"));
+    }
+}
+
+#[test]
+fn multiple_inputs() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a.tex");
+    let b = dir.path().join("b.tex");
+    std::fs::write(&a, "Some text.\n").unwrap();
+    std::fs::write(&b, "Other text.\n").unwrap();
+    let output = texfmt(&["--check", a.to_str().unwrap(), b.to_str().unwrap()], "");
+    check!(output.status.success());
+    check!(output.stdout.is_empty());
+}
+
+#[test]
+fn files_from() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a.tex");
+    let b = dir.path().join("b.tex");
+    std::fs::write(&a, "Some text. \n").unwrap();
+    std::fs::write(&b, "Other text. \n").unwrap();
+    let list = format!("{}\n\n# skipped\n{}\n", a.display(), b.display());
+    let output = texfmt(&["--check", "--files-from", "-"], &list);
+    check!(output.status.code() == Some(1));
+    check!(
+        String::from_utf8_lossy(&output.stdout) == format!("{}\n{}\n", a.display(), b.display())
+    );
+
+    let path = dir.path().join("list.txt");
+    std::fs::write(&path, &list).unwrap();
+    let output = texfmt(&["-i", "--files-from", path.to_str().unwrap()], "");
+    check!(output.status.success());
+    check!(std::fs::read_to_string(&a).unwrap() == "Some text.\n");
+    check!(std::fs::read_to_string(&b).unwrap() == "Other text.\n");
+}
+
+#[test]
+fn summary() {
+    let dir = tempfile::tempdir().unwrap();
+    let formatted = dir.path().join("a.tex");
+    let unformatted = dir.path().join("b.tex");
+    let missing = dir.path().join("c.tex");
+    std::fs::write(&formatted, "Some text.\n").unwrap();
+    std::fs::write(&unformatted, "Other text. \n").unwrap();
+    let paths = [&formatted, &unformatted, &missing].map(|path| path.to_str().unwrap());
+    let stderr = |output: Output| String::from_utf8_lossy(&output.stderr).into_owned();
+
+    let output = texfmt(&[&["--check"], &paths[..]].concat(), "");
+    check!(stderr(output).contains("would reformat 1 file, 1 unchanged, 1 error\n"));
+    let output = texfmt(&[&["--check", "--quiet"], &paths[..]].concat(), "");
+    check!(!stderr(output).contains("unchanged"));
+    let output = texfmt(&[&["-i"], &paths[..2]].concat(), "");
+    check!(stderr(output) == "1 file formatted, 1 unchanged\n");
+    let output = texfmt(&[&["-i"], &paths[..2]].concat(), "");
+    check!(stderr(output) == "0 files formatted, 2 unchanged\n");
+}
+
+#[test]
+fn summary_json() {
+    let dir = tempfile::tempdir().unwrap();
+    let formatted = dir.path().join("a.tex");
+    let unformatted = dir.path().join("b.tex");
+    let missing = dir.path().join("c.tex");
+    std::fs::write(&formatted, "Some text.\n").unwrap();
+    std::fs::write(&unformatted, "Other text. \n").unwrap();
+    let paths = [&formatted, &unformatted, &missing].map(|path| path.to_str().unwrap());
+
+    let args = [
+        &["--check", "--quiet", "--summary-format", "json"],
+        &paths[..],
+    ]
+    .concat();
+    let output = texfmt(&args, "");
+    check!(output.status.code() == Some(3));
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let (changed, report) = stdout.split_once('\n').unwrap();
+    check!(changed == paths[1]);
+    let report: serde_json::Value = serde_json::from_str(report).unwrap();
+    check!(report["formatted"] == 1);
+    check!(report["unchanged"] == 1);
+    check!(report["changed"] == serde_json::json!([paths[1]]));
+    check!(report["errors"][0]["path"] == paths[2]);
+    let message = report["errors"][0]["message"].as_str().unwrap();
+    check!(message.starts_with("cannot open"));
+    check!(report["errors"].as_array().unwrap().len() == 1);
+
+    let file = dir.path().join("report.json");
+    let args = [
+        &[
+            "-i",
+            "--summary-format",
+            "json",
+            "--report-file",
+            file.to_str().unwrap(),
+        ],
+        &paths[..2],
+    ]
+    .concat();
+    let output = texfmt(&args, "");
+    check!(output.status.success());
+    check!(output.stdout.is_empty());
+    let report: serde_json::Value =
+        serde_json::from_str(&std::fs::read_to_string(&file).unwrap()).unwrap();
+    check!(report["changed"] == serde_json::json!([paths[1]]));
+}
+
+#[test]
+fn verbose_timing() {
+    let output = texfmt(&["--verbose-timing"], "Some   text.\n");
+    check!(output.status.success());
+    check!(output.stdout == b"Some text.\n");
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    let phases: Vec<_> = stderr
+        .lines()
+        .map(|line| line.split_whitespace().collect::<Vec<_>>())
+        .collect();
+    let names: Vec<_> = phases.iter().map(|row| row[0]).collect();
+    check!(names == ["lex", "parse", "passes", "indent", "reflow", "write", "total"]);
+    check!(phases
+        .iter()
+        .all(|row| row[2] == "ms" && row[1].parse::<f64>().is_ok()));
+
+    let output = texfmt(&[], "Some   text.\n");
+    check!(output.stderr.is_empty());
+}
+
+#[test]
+fn progress_not_terminal() {
+    let dir = tempfile::tempdir().unwrap();
+    let paths: Vec<_> = (0..25)
+        .map(|i| {
+            let path = dir.path().join(format!("{i}.tex"));
+            std::fs::write(&path, "Some   text.\n").unwrap();
+            path.to_str().unwrap().to_owned()
+        })
+        .collect();
+    let args = [
+        &["-i", "--color", "always"][..],
+        &paths.iter().map(String::as_str).collect::<Vec<_>>(),
+    ]
+    .concat();
+    let output = texfmt(&args, "");
+    check!(output.status.success());
+    // only the summary, without the bar
+    check!(output.stderr == b"25 files formatted, 0 unchanged\n");
+}
+
+#[test]
+fn multiple_inputs_missing() {
+    let dir = tempfile::tempdir().unwrap();
+    let a = dir.path().join("a.tex");
+    let missing = dir.path().join("missing.tex");
+    std::fs::write(&a, "Some text.\n").unwrap();
+    for args in [
+        &["-i", missing.to_str().unwrap(), a.to_str().unwrap()][..],
+        &[
+            "-i",
+            "--fail-fast",
+            missing.to_str().unwrap(),
+            a.to_str().unwrap(),
+        ],
+    ] {
+        let output = texfmt(args, "");
+        check!(!output.status.success());
+        let stderr = String::from_utf8_lossy(&output.stderr);
+        check!(stderr.contains("missing.tex"));
+        check!(!stderr.contains("a.tex"));
+    }
+    check!(std::fs::read_to_string(&a).unwrap() == "Some text.\n");
+}
+
+#[test]
+fn multiple_inputs_stdout() {
+    let output = texfmt(&["a.tex", "b.tex"], "");
+    check!(output.status.code() == Some(2));
+    check!(String::from_utf8_lossy(&output.stderr).contains("--in-place"));
+}
+
+#[test]
+fn diff_unchanged() {
+    let output = texfmt(&["--diff"], "\\section{Intro}\nSome text.\n");
+    check!(output.status.code() == Some(0));
+    check!(output.stdout.is_empty());
+}
+
+#[test]
+fn check_unformatted() {
+    let output = texfmt(&["--check"], "Some text. \n");
+    check!(output.status.code() == Some(1));
+    check!(output.stdout == b"<stdin>\n");
+}
+
+#[test]
+fn diff_changed() {
+    let output = texfmt(&["--diff", "--color=never"], "a\n\nb \n\nc\n");
+    check!(output.status.code() == Some(1));
+    check!(
+        String::from_utf8_lossy(&output.stdout)
+            == "--- <stdin>\n+++ <stdin>\n@@ -1,5 +1,5 @@\n a\n \n-b \n+b\n \n c\n"
+    );
+}
+
+#[test]
+fn color() {
+    let output = |color| texfmt(&["--diff", "-vv", color], "a \n");
+    let colored = |bytes: &[u8]| bytes.contains(&0x1b);
+    let always = output("--color=always");
+    check!(colored(&always.stdout));
+    check!(colored(&always.stderr));
+    let never = output("--color=never");
+    check!(!colored(&never.stdout));
+    check!(!colored(&never.stderr));
+    // the streams are pipes, not terminals
+    let auto = output("--color=auto");
+    check!(!colored(&auto.stdout));
+    check!(!colored(&auto.stderr));
+}
+
+#[test]
+fn in_place_changed() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("doc.tex");
+    std::fs::write(&path, "Some text. \n").unwrap();
+    let output = texfmt(&["-i", path.to_str().unwrap()], "");
+    check!(output.status.success());
+    check!(output.stdout.is_empty());
+    check!(std::fs::read_to_string(&path).unwrap() == "Some text.\n");
+}
+
+#[test]
+fn output_dir() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::create_dir(dir.path().join("chapters")).unwrap();
+    std::fs::write(dir.path().join("main.tex"), "Main. \n").unwrap();
+    std::fs::write(dir.path().join("chapters/intro.tex"), "Intro. \n").unwrap();
+    let texfmt = |args: &[&str]| {
+        Command::new(env!("CARGO_BIN_EXE_texfmt"))
+            .args(args)
+            .current_dir(dir.path())
+            .output()
+            .unwrap()
+    };
+
+    let output = texfmt(&["--output-dir", "out", "main.tex", "./chapters/intro.tex"]);
+    check!(output.status.success());
+    check!(output.stdout.is_empty());
+    let read = |path| std::fs::read_to_string(dir.path().join(path)).unwrap();
+    check!(read("out/main.tex") == "Main.\n");
+    check!(read("out/chapters/intro.tex") == "Intro.\n");
+    // the inputs are untouched
+    check!(read("main.tex") == "Main. \n");
+
+    let output = texfmt(&["--output-dir", "out", "chapters/../../main.tex"]);
+    check!(output.status.code() == Some(2));
+    check!(String::from_utf8_lossy(&output.stderr).contains("outside of the current directory"));
+    let output = texfmt(&["--output-dir", "out", "-i", "main.tex"]);
+    check!(output.status.code() == Some(2));
+}
+
+#[test]
+fn sentence_wrap() {
+    let output = texfmt(&["--sentence-wrap"], "Dr. Smith went home. He slept.\n");
+    check!(output.status.success());
+    check!(output.stdout == b"Dr. Smith went home.\nHe slept.\n");
+
+    let output = texfmt(&["--sentence-wrap", "--width", "40"], "");
+    check!(output.status.code() == Some(2));
+}
+
+#[test]
+fn unbalanced_braces() {
+    let output = texfmt(&[], "a\n{b}}\n");
+    check!(output.status.code() == Some(4));
+    check!(output.stdout.is_empty());
+    check!(String::from_utf8_lossy(&output.stderr).contains("unmatched `}` at line 2, column 4"));
+}
+
+#[test]
+fn lex_error() {
+    let output = texfmt(&[], "a\n  b \\'c\n");
+    check!(output.status.code() == Some(4));
+    check!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    check!(stderr.contains(r"unexpected character '\\' at line 2, column 5"));
+}
+
+#[test]
+fn exit_status() {
+    let dir = tempfile::tempdir().unwrap();
+    let missing = dir.path().join("missing.tex");
+    let bad = dir.path().join("bad.tex");
+    std::fs::write(&bad, "}\n").unwrap();
+
+    check!(texfmt(&["--width", "0"], "").status.code() == Some(2));
+    check!(texfmt(&["--unknown-option"], "").status.code() == Some(2));
+    let config = dir.path().join("bad.toml");
+    std::fs::write(&config, "widht = 60\n").unwrap();
+    check!(
+        texfmt(&["--config", config.to_str().unwrap()], "")
+            .status
+            .code()
+            == Some(2)
+    );
+    check!(texfmt(&[missing.to_str().unwrap()], "").status.code() == Some(3));
+    check!(texfmt(&[bad.to_str().unwrap()], "").status.code() == Some(4));
+    check!(texfmt(&["--lang", "bib"], "@book{key").status.code() == Some(4));
+    check!(texfmt(&["--dump-tokens"], "a\\'b").status.code() == Some(4));
+    // the first input in sorted order decides
+    let output = texfmt(
+        &["--check", missing.to_str().unwrap(), bad.to_str().unwrap()],
+        "",
+    );
+    check!(output.status.code() == Some(4));
+    check!(String::from_utf8_lossy(&output.stderr).contains("2 of 2 inputs"));
+}
+
+#[test]
+fn verbosity() {
+    let stderr = |args| String::from_utf8_lossy(&texfmt(args, "a\n").stderr).into_owned();
+    check!(stderr(&["-vv"]).contains("processing stream"));
+    check!(stderr(&["--debug"]).contains("processing stream"));
+    check!(!stderr(&["-v"]).contains("processing stream"));
+    check!(!stderr(&["--quiet"]).contains("processing stream"));
+
+    let output = texfmt(&["--quiet", "missing.tex"], "");
+    check!(output.status.code() == Some(3));
+    check!(String::from_utf8_lossy(&output.stderr).contains("missing.tex"));
+    check!(texfmt(&["-q", "-v"], "").status.code() == Some(2));
+}
+
+#[test]
+fn comment_space() {
+    let output = texfmt(&["--comment-space"], "%hello\n%!TeX root=main.tex\n");
+    check!(output.status.success());
+    check!(output.stdout == b"% hello\n%!TeX root=main.tex\n");
+    let magic = "%  !TeX program = lualatex\n%hello\n";
+    let output = texfmt(&["--comment-space"], magic);
+    check!(output.stdout == b"%  !TeX program = lualatex\n% hello\n");
+}
+
+#[test]
+fn tab_width() {
+    let src = "\\begin{itemize}\n\\item aaa bbb\n\\end{itemize}\n";
+    let output = texfmt(&["--use-tabs", "--width", "20"], src);
+    check!(output.stdout == b"\\begin{itemize}\n\t\\item aaa\n\tbbb\n\\end{itemize}\n");
+    let output = texfmt(&["--use-tabs", "--width", "20", "--tab-width", "4"], src);
+    check!(output.stdout == src.replace("\\item", "\t\\item").as_bytes());
+    check!(texfmt(&["--tab-width", "0"], src).status.code() == Some(2));
+}
+
+#[test]
+fn range() {
+    let src = "a  b\nc  d\n\n\\begin{itemize}\n\\item  e\n\n\\item  f\n\\end{itemize}\n\ng  h\n";
+    let output = texfmt(&["--range", "2:2", "--range", "7:7"], src);
+    check!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    check!(
+        stdout == "a b c d\n\n\\begin{itemize}\n\\item  e\n\n  \\item f\n\\end{itemize}\n\ng  h\n"
+    );
+    // the lines 3 to 6 and 9 to 10 are byte-identical
+    let (src_lines, lines): (Vec<_>, Vec<_>) = (src.lines().collect(), stdout.lines().collect());
+    check!(lines[1..5] == src_lines[2..6]);
+    check!(lines[7..] == src_lines[8..]);
+
+    check!(texfmt(&["--range", "3:2"], src).status.code() == Some(2));
+    check!(
+        texfmt(&["--range", "1:1", "--lang", "bib"], src)
+            .status
+            .code()
+            == Some(2)
+    );
+}
+
+#[test]
+fn math_delimiters() {
+    let output = texfmt(&["--math-delimiters", "amsmath"], "$a$ and\n$$\nb\n$$\n");
+    check!(output.status.success());
+    check!(output.stdout == b"\\(a\\) and\n\\[\n  b\n\\]\n");
+    let output = texfmt(&["--math-delimiters", "dollar"], "\\(a\\)\n");
+    check!(output.stdout == b"$a$\n");
+}
+
+#[test]
+fn final_newline() {
+    for src in ["a", "a\n", "a\n\n\n"] {
+        check!(texfmt(&[], src).stdout == b"a\n");
+    }
+    check!(texfmt(&["--final-newline", "crlf"], "a\n\n\n").stdout == b"a\r\n");
+    check!(texfmt(&["--no-final-newline"], "a").stdout == b"a");
+    let output = texfmt(&["--no-final-newline", "--final-newline", "lf"], "a");
+    check!(output.status.code() == Some(2));
+}
+
+#[test]
+fn indent_style() {
+    // the depth of the indentation is kept, even where it differs from the nesting depth
+    let tabs = "\\begin{itemize}\n\t\\item a\n\t\t\\item b\n\\end{itemize}\n\tc\n";
+    let spaces = "\\begin{itemize}\n    \\item a\n        \\item b\n\\end{itemize}\n    c\n";
+    let to_spaces = ["--indent-style", "space", "--indent-width", "4"];
+    check!(texfmt(&to_spaces, tabs).stdout == spaces.as_bytes());
+    let to_tabs = ["--indent-style", "tab", "--indent-width", "4"];
+    check!(texfmt(&to_tabs, spaces).stdout == tabs.as_bytes());
+    check!(texfmt(&["--indent-style", "space"], "\tx\n").stdout == b"  x\n");
+    let output = texfmt(&["--indent-style", "tab", "--use-tabs"], spaces);
+    check!(output.status.code() == Some(2));
+}
+
+#[test]
+fn max_consecutive_newlines() {
+    let src = "a\n\n\n\nb\n";
+    check!(texfmt(&[], src).stdout == b"a\n\nb\n");
+    check!(texfmt(&["--max-consecutive-newlines", "2"], src).stdout == b"a\n\n\nb\n");
+    check!(texfmt(&["--max-consecutive-newlines", "0"], src).stdout == b"a b\n");
+
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        dir.path().join(".texfmt.toml"),
+        "max-consecutive-newlines = 0\n",
+    )
+    .unwrap();
+    let path = dir.path().join("doc.tex");
+    let output = texfmt(&["--stdin-filepath", path.to_str().unwrap()], src);
+    check!(output.stdout == b"a b\n");
+}
+
+#[test]
+fn punctuation_spacing() {
+    let output = texfmt(&["--punctuation-spacing"], "word ,next\n");
+    check!(output.status.success());
+    check!(output.stdout == b"word, next\n");
+    let output = texfmt(&["--punctuation-spacing=fr"], "Quoi? Oui ,non\n");
+    check!(output.stdout == b"Quoi ? Oui, non\n");
+    check!(texfmt(&[], "word ,next\n").stdout == b"word ,next\n");
+}
+
+#[test]
+fn split_packages() {
+    let src = "\\usepackage[utf8]{inputenc}\\usepackage{a,b}\n\\begin{document}\n\\end{document}\n";
+    let output = texfmt(&[], src);
+    check!(output.status.success());
+    check!(String::from_utf8_lossy(&output.stdout)
+        .starts_with("\\usepackage[utf8]{inputenc}\n\\usepackage{a,b}\n"));
+    let output = texfmt(&["--split-packages"], src);
+    check!(String::from_utf8_lossy(&output.stdout)
+        .starts_with("\\usepackage[utf8]{inputenc}\n\\usepackage{a}\n\\usepackage{b}\n"));
+}
+
+#[test]
+fn sort_packages() {
+    let src = "\\usepackage{b}\n\\usepackage{c,a}\n\\begin{document}\n\\end{document}\n";
+    let output = texfmt(&["--sort-packages"], src);
+    check!(output.status.success());
+    check!(
+        String::from_utf8_lossy(&output.stdout).starts_with("\\usepackage{a,c}\n\\usepackage{b}\n")
+    );
+    check!(texfmt(&[], src).stdout == src.as_bytes());
+}
+
+#[test]
+fn show_passes() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("doc.tex");
+    let path = path.to_str().unwrap();
+    std::fs::write(path, "a  b\n").unwrap();
+    let output = texfmt(&["--show-passes", "--align-tables", path], "");
+    check!(output.status.success());
+    check!(output.stdout.is_empty());
+    check!(std::fs::read_to_string(path).unwrap() == "a  b\n");
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    let passes: Vec<&str> = stderr
+        .lines()
+        .filter_map(|line| line.strip_prefix("  "))
+        .map(|line| line.split(':').next().unwrap())
+        .collect();
+    check!(
+        passes
+            == [
+                "convert-newlines",
+                "normalize-environments",
+                "normalize-spaces",
+                "break-items",
+                "break-display-math",
+                "split-packages",
+                "normalize-citations",
+                "strip-trailing-whitespace",
+                "collapse-blank-lines",
+                "indent",
+                "align-tables",
+                "reflow",
+            ]
+    );
+    check!(stderr.starts_with(&format!("{path}\n")));
+    check!(stderr.contains("  normalize-spaces: changed 1 token\n"));
+    check!(stderr.contains("  reflow: unchanged\n"));
+}
+
+#[test]
+fn remove_redundant_braces() {
+    let src = "a{b}c \\textbf{b} $a{b}$\n";
+    let output = texfmt(&["--remove-redundant-braces"], src);
+    check!(output.status.success());
+    check!(output.stdout == b"abc \\textbf{b} $a{b}$\n");
+    check!(texfmt(&[], src).stdout == src.as_bytes());
+}
+
+#[test]
+fn normalize_quotes() {
+    let src = "He said \"hello\", but don't $\"$.\n";
+    let output = texfmt(&["--normalize-quotes"], src);
+    check!(output.status.success());
+    check!(output.stdout == b"He said ``hello'', but don't $\"$.\n");
+    check!(texfmt(&[], src).stdout == src.as_bytes());
+}
+
+#[test]
+fn line_ending() {
+    let output = texfmt(&[], "a  b\r\n\r\n\r\nc\r\n");
+    check!(output.status.success());
+    check!(output.stdout == b"a b\r\n\r\nc\r\n");
+    let output = texfmt(&["--line-ending", "lf"], "a\r\n\r\nb\r\n");
+    check!(output.stdout == b"a\n\nb\n");
+}
+
+#[test]
+fn align_tables() {
+    let output = texfmt(
+        &["--align-tables"],
+        "\\begin{tabular}{ll}\na & bb \\\\\nccc & d\n\\end{tabular}\n",
+    );
+    check!(output.status.success());
+    check!(output.stdout == b"\\begin{tabular}{ll}\n  a   & bb \\\\\n  ccc & d\n\\end{tabular}\n");
+}
+
+#[test]
+fn config_discovery() {
+    let dir = tempfile::tempdir().unwrap();
+    let nested = dir.path().join("chapters").join("intro");
+    std::fs::create_dir_all(&nested).unwrap();
+    std::fs::write(dir.path().join(".texfmt.toml"), "width = 12\n").unwrap();
+    let path = nested.join("doc.tex");
+    std::fs::write(&path, "one two three four\n").unwrap();
+
+    let output = texfmt(&[path.to_str().unwrap()], "");
+    check!(output.status.success());
+    check!(output.stdout == b"one two\nthree four\n");
+
+    // the command line takes precedence
+    let output = texfmt(&["--width", "14", path.to_str().unwrap()], "");
+    check!(output.status.success());
+    check!(output.stdout == b"one two three\nfour\n");
+}
+
+#[test]
+fn config_explicit() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("texfmt.toml");
+    std::fs::write(&config, "width = 8\n").unwrap();
+    let output = texfmt(&["--config", config.to_str().unwrap()], "one two three\n");
+    check!(output.status.success());
+    check!(output.stdout == b"one two\nthree\n");
+
+    let missing = dir.path().join("missing.toml");
+    let output = texfmt(&["--config", missing.to_str().unwrap()], "one\n");
+    check!(!output.status.success());
+    check!(String::from_utf8_lossy(&output.stderr).contains("missing.toml"));
+}
+
+#[test]
+fn print_config() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(
+        dir.path().join(".texfmt.toml"),
+        "width = 60\nuse-tabs = true\n",
+    )
+    .unwrap();
+    let filepath = dir.path().join("doc.tex");
+    let output = texfmt(
+        &[
+            "--print-config",
+            "--stdin-filepath",
+            filepath.to_str().unwrap(),
+            "--width",
+            "70",
+            "--math-delimiters",
+            "amsmath",
+        ],
+        "",
+    );
+    check!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    let lines: Vec<_> = stdout.lines().collect();
+    check!(lines.contains(&"width = 70"));
+    check!(lines.contains(&"use-tabs = true"));
+    check!(lines.contains(&"math-delimiters = \"amsmath\""));
+    check!(lines.contains(&"indent-width = 2"));
+    check!(lines.contains(&"plain-tex = false"));
+    check!(!lines.iter().any(|line| line.starts_with("lang")));
+
+    // the printed configuration formats like the options it comes from
+    let output = texfmt(&["--print-config", "--lang", "tex"], "");
+    let printed = dir.path().join("printed.toml");
+    std::fs::write(&printed, &output.stdout).unwrap();
+    let src = "\\begin{a}\nx\n\\end{a}\n";
+    let expected = texfmt(&["--lang", "tex"], src).stdout;
+    check!(expected == src.as_bytes());
+    check!(texfmt(&["--config", printed.to_str().unwrap()], src).stdout == expected);
+
+    let config = dir.path().join("other.toml");
+    std::fs::write(&config, "split-packages = true\n").unwrap();
+    let output = texfmt(
+        &["--print-config", "--config", config.to_str().unwrap()],
+        "",
+    );
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    check!(stdout.lines().any(|line| line == "split-packages = true"));
+    check!(stdout.lines().any(|line| line == "width = 80"));
+}
+
+#[test]
+fn verbatim_environments() {
+    let dir = tempfile::tempdir().unwrap();
+    let config = dir.path().join("texfmt.toml");
+    std::fs::write(&config, "verbatim-environments = [\"code\"]\n").unwrap();
+    let input = "\\begin{code}\na   b {\n\\end{code}\n";
+    let output = texfmt(&["--config", config.to_str().unwrap()], input);
+    check!(output.status.success());
+    check!(output.stdout == input.as_bytes());
+    let output = texfmt(&[], input);
+    check!(output.status.code() == Some(4));
+}
+
+#[test]
+fn dump_tokens() {
+    let output = texfmt(&["--dump-tokens"], "\\cmd{arg}");
+    check!(output.status.success());
+    check!(
+        String::from_utf8_lossy(&output.stdout)
+            == "[{\"kind\":\"Command\",\"text\":\"cmd\"},{\"kind\":\"LBrace\"},{\"kind\":\"Text\",\"text\":\"arg\"},{\"kind\":\"RBrace\"}]\n"
+    );
+
+    let output = texfmt(&["--dump-tokens", "--format", "ndjson"], "\\cmd{arg}");
+    check!(output.status.success());
+    check!(
+        String::from_utf8_lossy(&output.stdout)
+            == "{\"kind\":\"Command\",\"text\":\"cmd\"}\n{\"kind\":\"LBrace\"}\n{\"kind\":\"Text\",\"text\":\"arg\"}\n{\"kind\":\"RBrace\"}\n"
+    );
+}
+
+#[test]
+fn emit_tokens() {
+    let output = texfmt(&["--emit", "tokens"], "\\cmd{arg}");
+    check!(output.status.success());
+    check!(output.stdout == texfmt(&["--dump-tokens"], "\\cmd{arg}").stdout);
+
+    let output = texfmt(&["--emit", "tokens", "--format", "text"], "\\cmd{arg}");
+    check!(output.status.success());
+    check!(
+        String::from_utf8_lossy(&output.stdout)
+            == "Command(\"cmd\")\nLBrace\nText(\"arg\")\nRBrace\n"
+    );
+
+    let output = texfmt(&["--emit", "tokens", "--check"], "\\cmd{arg}");
+    check!(output.status.code() == Some(2));
+}
+
+#[test]
+fn emit_ast() {
+    let output = texfmt(&["--emit", "ast", "--format", "json"], "\\cmd{arg}");
+    check!(output.status.success());
+    check!(
+        String::from_utf8_lossy(&output.stdout)
+            == "[{\"kind\":\"Command\",\"content\":{\"name\":\"cmd\",\"args\":[{\"kind\":\"Required\",\"content\":[{\"kind\":\"Text\",\"content\":\"arg\"}]}]}}]\n"
+    );
+
+    let output = texfmt(&["--emit", "ast"], "{a}");
+    check!(output.status.success());
+    check!(
+        String::from_utf8_lossy(&output.stdout)
+            == "Group(\n    [\n        Text(\n            \"a\",\n        ),\n    ],\n)\n"
+    );
+
+    let output = texfmt(&["--emit", "ast"], "{a");
+    check!(!output.status.success());
+
+    // a deep nesting is an error, instead of a stack overflow
+    let deep = "{".repeat(100_000) + &"}".repeat(100_000);
+    let output = texfmt(&["--emit", "ast", "--format", "json"], &deep);
+    check!(output.status.code() == Some(3));
+    check!(String::from_utf8_lossy(&output.stderr).contains("nested deeper than 128 levels"));
+}
+
+#[test]
+fn bom() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("doc.tex");
+    std::fs::write(&path, "\u{feff}Some  text.\n").unwrap();
+    let path = path.to_str().unwrap();
+
+    let output = texfmt(&[path], "");
+    check!(output.status.success());
+    check!(output.stdout == b"Some text.\n");
+    let output = texfmt(&["--keep-bom", path], "");
+    check!(output.stdout == "\u{feff}Some text.\n".as_bytes());
+    let output = texfmt(&["--check", "--keep-bom", path], "");
+    check!(output.status.code() == Some(1));
+
+    let output = texfmt(&["--emit", "tokens", path], "");
+    check!(output.status.success());
+    check!(String::from_utf8_lossy(&output.stdout)
+        .starts_with("[{\"kind\":\"Text\",\"text\":\"Some\"}"));
+}
+
+#[test]
+fn latin1() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("doc.tex");
+    std::fs::write(&path, b"Caf\xe9.\n").unwrap();
+    let output = texfmt(&[path.to_str().unwrap()], "");
+    check!(output.status.code() == Some(4));
+    check!(output.stdout.is_empty());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    check!(stderr.contains("not valid UTF-8, because of the byte 0xe9 on line 1"));
+    check!(stderr.contains("iconv -f LATIN1 -t UTF-8"));
+}
+
+#[test]
+fn check_refs_undefined() {
+    let input = "\\section{A}\\label{sec:a}\nSee \\ref{sec:a} and \\ref{sec:b}.\n";
+    let output = texfmt(&["--check-refs"], input);
+    check!(output.status.code() == Some(1));
+    check!(output.stdout.is_empty());
+    check!(
+        String::from_utf8_lossy(&output.stderr)
+            == "<stdin>: undefined reference `sec:b` at line 2, column 21 (byte 45)\n"
+    );
+}
+
+#[test]
+fn check_refs_unused() {
+    let dir = tempfile::tempdir().unwrap();
+    let path = dir.path().join("doc.tex");
+    std::fs::write(&path, "\\label{a}\n\\label{b}\n\\cref{a}\n").unwrap();
+    let output = texfmt(&["--check-refs", path.to_str().unwrap()], "");
+    check!(output.status.code() == Some(1));
+    check!(
+        String::from_utf8_lossy(&output.stderr)
+            == format!(
+                "{}: unused label `b` at line 2, column 1 (byte 10)\n",
+                path.display()
+            )
+    );
+    check!(std::fs::read_to_string(&path).unwrap() == "\\label{a}\n\\label{b}\n\\cref{a}\n");
+
+    let output = texfmt(&["--check-refs"], "\\label{a} \\ref{a}\n");
+    check!(output.status.success());
+    check!(output.stderr.is_empty());
+}
+
+#[test]
+fn follow_includes() {
+    let dir = tempfile::tempdir().unwrap();
+    let chapters = dir.path().join("chapters");
+    std::fs::create_dir(&chapters).unwrap();
+    let main = dir.path().join("main.tex");
+    std::fs::write(
+        &main,
+        "\\input{chapters/one}\n\\include{chapters/two.tex}\nSome   text.\n",
+    )
+    .unwrap();
+    // the includes are resolved from the root, and the cycles are broken
+    std::fs::write(chapters.join("one.tex"), "One   text.\n\\input{main}\n").unwrap();
+    std::fs::write(chapters.join("two.tex"), "Two   text.\n").unwrap();
+
+    let output = texfmt(&["-i", "--follow-includes", main.to_str().unwrap()], "");
+    check!(output.status.success());
+    check!(std::fs::read_to_string(&main)
+        .unwrap()
+        .ends_with("Some text.\n"));
+    check!(
+        std::fs::read_to_string(chapters.join("one.tex")).unwrap() == "One text.\n\\input{main}\n"
+    );
+    check!(std::fs::read_to_string(chapters.join("two.tex")).unwrap() == "Two text.\n");
+
+    std::fs::write(chapters.join("one.tex"), "One text.\n").unwrap();
+    std::fs::write(chapters.join("two.tex"), "\\input{one}\n").unwrap();
+    let output = texfmt(
+        &[
+            "--check",
+            "--follow-includes",
+            "--include-root",
+            chapters.to_str().unwrap(),
+            chapters.join("two.tex").to_str().unwrap(),
+        ],
+        "",
+    );
+    check!(output.status.success());
+    let output = texfmt(
+        &["--check", "--follow-includes", main.to_str().unwrap()],
+        "",
+    );
+    check!(output.status.code() == Some(3));
+    check!(String::from_utf8_lossy(&output.stderr).contains("cannot open"));
+}
+
+#[test]
+fn parallel() {
+    let dir = tempfile::tempdir().unwrap();
+    let (serial, parallel) = (dir.path().join("serial"), dir.path().join("parallel"));
+    let mut inputs = Vec::new();
+    for d in [&serial, &parallel] {
+        std::fs::create_dir(d).unwrap();
+        for i in 0..50 {
+            let content = format!(
+                "File {i}.  \n\n\n\\begin{{itemize}}\n\\item {}\n\\end{{itemize}}\n",
+                "word ".repeat(i)
+            );
+            std::fs::write(d.join(format!("{i:02}.tex")), content).unwrap();
+        }
+    }
+    for i in (0..50).rev() {
+        inputs.push(format!("{i:02}.tex"));
+    }
+    let paths = |d: &std::path::Path| -> Vec<String> {
+        inputs
+            .iter()
+            .map(|name| d.join(name).to_str().unwrap().to_owned())
+            .collect()
+    };
+    let args = |d: &std::path::Path, extra: &[&'static str]| -> Vec<String> {
+        extra
+            .iter()
+            .map(|s| s.to_string())
+            .chain(paths(d))
+            .collect()
+    };
+    let run = |args: Vec<String>| texfmt(&args.iter().map(String::as_str).collect::<Vec<_>>(), "");
+
+    let one = run(args(&serial, &["--diff", "--jobs", "1"]));
+    let many = run(args(&serial, &["--diff", "--jobs", "8"]));
+    check!(one.status.code() == Some(1));
+    check!(!one.stdout.is_empty());
+    check!(one.stdout == many.stdout);
+    let stdout = String::from_utf8_lossy(&one.stdout);
+    let first = stdout.find("00.tex").unwrap();
+    check!(stdout.find("49.tex").unwrap() > first);
+
+    check!(run(args(&serial, &["-i", "--jobs", "1"])).status.success());
+    check!(run(args(&parallel, &["-i"])).status.success());
+    for name in &inputs {
+        check!(
+            std::fs::read_to_string(serial.join(name)).unwrap()
+                == std::fs::read_to_string(parallel.join(name)).unwrap()
+        );
+    }
+}
+
+#[test]
+fn stdin_filepath() {
+    let dir = tempfile::tempdir().unwrap();
+    std::fs::write(dir.path().join(".texfmt.toml"), "width = 12\n").unwrap();
+    let virtual_path = dir.path().join("chapters").join("doc.tex");
+    let output = texfmt(
+        &["--stdin-filepath", virtual_path.to_str().unwrap()],
+        "one two three four\n",
+    );
+    check!(output.status.success());
+    check!(output.stdout == b"one two\nthree four\n");
+    check!(!virtual_path.exists());
+
+    let output = texfmt(
+        &["--stdin-filepath", "refs.bib"],
+        "@book{x,  title = {A}}  \n",
+    );
+    check!(output.status.success());
+    check!(output.stdout == b"@book{x,\n  title = {A}\n}\n");
+}
+
+#[test]
+fn bib() {
+    let input = "@book{knuth84, author={Knuth}, title = {The \\TeX book}}\n";
+    let output = texfmt(&["--lang", "bib"], input);
+    check!(output.status.success());
+    check!(
+        output.stdout == b"@book{knuth84,\n  author = {Knuth},\n  title  = {The \\TeX book}\n}\n"
+    );
+    let output = texfmt(&["--lang", "bib", "--bib-trailing-comma"], input);
+    check!(
+        output.stdout == b"@book{knuth84,\n  author = {Knuth},\n  title  = {The \\TeX book},\n}\n"
+    );
+}
+
+#[test]
+fn lang() {
+    let bib = "@book{k, title={x}}\n";
+    // the language overrides the extension
+    let output = texfmt(&["--lang", "bib", "--stdin-filepath", "refs.txt"], bib);
+    check!(output.stdout == b"@book{k,\n  title = {x}\n}\n");
+    let output = texfmt(&["--lang", "latex", "--stdin-filepath", "refs.bib"], bib);
+    check!(output.stdout == bib.as_bytes());
+
+    let env = "\\begin{center}\nx\n\\end{center}\n";
+    check!(texfmt(&["--lang", "latex"], env).stdout == b"\\begin{center}\n  x\n\\end{center}\n");
+    check!(texfmt(&["--lang", "tex"], env).stdout == env.as_bytes());
+}
+
+#[test]
+fn lsp() {
+    let messages = [
+        r#"{"jsonrpc":"2.0","id":1,"method":"initialize","params":{}}"#,
+        r#"{"jsonrpc":"2.0","method":"textDocument/didOpen","params":{"textDocument":{"uri":"file:///a.tex","text":"a  b\n"}}}"#,
+        r#"{"jsonrpc":"2.0","id":2,"method":"textDocument/formatting","params":{"textDocument":{"uri":"file:///a.tex"}}}"#,
+        r#"{"jsonrpc":"2.0","method":"exit"}"#,
+    ];
+    let input: String = messages
+        .iter()
+        .map(|m| format!("Content-Length: {}\r\n\r\n{m}", m.len()))
+        .collect();
+    let output = texfmt(&["lsp"], &input);
+    check!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    check!(stdout.contains(r#""documentFormattingProvider":true"#));
+    check!(stdout.ends_with(
+        r#"{"id":2,"jsonrpc":"2.0","result":[{"newText":"","range":{"end":{"character":3,"line":0},"start":{"character":2,"line":0}}}]}"#
+    ));
+}
@@ -0,0 +1,203 @@
+//! Feed random inputs to the lexer and the formatter, which must never panic.
+//!
+//! The inputs are generated from a fixed seed, so that a failure can be reproduced.
+//! The targets under `fuzz/` run the same checks with `cargo fuzz`.
+
+use assert2::*;
+
+use texfmt::format::{MathDelimiters, PunctuationSpacing};
+use texfmt::lexer::{lex_tokens, Lexer, Token, TokenIterator};
+use texfmt::writer::write_tokens;
+use texfmt::{format_str, FormatOptions};
+
+/// Pieces of (La)TeX which are likely to confuse the lexer.
+const PIECES: &[&str] = &[
+    "\\",
+    "\\\\",
+    "{",
+    "}",
+    "[",
+    "]",
+    "$",
+    "$$",
+    "\\[",
+    "\\]",
+    "\\(",
+    "\\)",
+    "%",
+    "\n",
+    "\r",
+    "\r\n",
+    " ",
+    "\t",
+    "~",
+    "&",
+    "#",
+    "#1",
+    "##",
+    "^",
+    "_",
+    "\\,",
+    "\\%",
+    "\\{",
+    "\\'",
+    "a",
+    "word",
+    "1.5",
+    ",",
+    ".",
+    "\u{e9}",
+    "\u{1f600}",
+    "\\item",
+    "\\begin{",
+    "\\end{",
+    "\\begin{itemize}",
+    "\\end{itemize}",
+    "\\begin{verbatim}",
+    "\\end{verbatim}",
+    "\\begin{tabular}{ll}",
+    "\\end{tabular}",
+    "\\begin{document}",
+    "itemize}",
+    "verbatim}",
+    "\\verb|",
+    "\\verb",
+    "|",
+    "\\iffalse",
+    "\\ifnum",
+    "\\fi",
+    "\\usepackage",
+    "\\usepackage{a,b}",
+    "\\x",
+    "+",
+];
+
+/// A small xorshift generator, to avoid depending on a crate for randomness.
+struct Rng(u64);
+
+impl Rng {
+    fn next(&mut self) -> u64 {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        self.0
+    }
+
+    fn below(&mut self, n: usize) -> usize {
+        (self.next() % n as u64) as usize
+    }
+
+    /// A random input, made of pieces of (La)TeX or of arbitrary bytes.
+    fn input(&mut self) -> String {
+        let len = self.below(40);
+        if self.below(4) == 0 {
+            let bytes: Vec<u8> = (0..len).map(|_| self.next() as u8).collect();
+            return String::from_utf8_lossy(&bytes).into_owned();
+        }
+        (0..len).map(|_| PIECES[self.below(PIECES.len())]).collect()
+    }
+}
+
+/// Check the properties that hold for any input.
+fn check_input(input: &str) {
+    // the written tokens are tokenized again into the same tokens, even though the padding
+    // of the names of environments is dropped
+    let mut lexer = Lexer::new(input);
+    let tokens: Vec<_> = lexer.by_ref().collect();
+    let rest = lexer.rest();
+    check!(lex_tokens(input).is_ok() == rest.is_empty(), "{input:?}");
+    let written = write_tokens(&tokens);
+    check!(lex_tokens(&written) == Ok(tokens.clone()), "{input:?}");
+    // the text is as long as possible
+    check!(
+        !tokens
+            .windows(2)
+            .any(|pair| matches!(pair, [Token::Text(_), Token::Text(_)])),
+        "{input:?}"
+    );
+    // a comment runs to the end of the line
+    check!(
+        !tokens
+            .windows(2)
+            .any(|pair| matches!(pair, [Token::Comment(_), t] if !matches!(t, Token::Newline(_)))),
+        "{input:?}"
+    );
+
+    let streamed: Result<Vec<_>, _> = TokenIterator::new(input.as_bytes()).collect();
+    match streamed {
+        Ok(streamed) => {
+            let streamed: Vec<_> = streamed.iter().map(|token| token.as_ref()).collect();
+            check!(rest.is_empty(), "{input:?}");
+            check!(streamed == tokens, "{input:?}");
+        }
+        Err(_) => {
+            check!(!rest.is_empty(), "{input:?}");
+        }
+    }
+
+    // the formatting may fail, but cleanly
+    let _ = format_str(input, &FormatOptions::default());
+    let _ = format_str(input, &all_options());
+    let _ = texfmt::format_lines(input, &[2..=3], &all_options());
+    let _ = texfmt::bib::format_bib(input, &FormatOptions::default());
+}
+
+/// The options enabling all the optional passes, with a short width.
+fn all_options() -> FormatOptions {
+    FormatOptions::builder()
+        .width(10)
+        .align_tables(true)
+        .comment_space(true)
+        .math_delimiters(Some(MathDelimiters::Amsmath))
+        .punctuation_spacing(Some(PunctuationSpacing::Fr))
+        .split_packages(true)
+        .sort_packages(true)
+        .remove_redundant_braces(true)
+        .normalize_quotes(true)
+        .build()
+        .unwrap()
+}
+
+#[test]
+fn random_inputs() {
+    let mut rng = Rng(0x7e8f_6d5c_4b3a_2910);
+    for _ in 0..10_000 {
+        let input = rng.input();
+        check_input(&input);
+    }
+}
+
+#[test]
+fn adversarial_inputs() {
+    for input in [
+        "\\",
+        "a\\",
+        "$$$",
+        "$a$$b$$",
+        "\\[$\\]$",
+        "\\verb",
+        "\\verb|",
+        "\\iffalse",
+        "\\begin{verbatim}",
+        "\\begin{",
+        "\r",
+        "\\\u{e9}",
+        "%\u{1f600}",
+    ] {
+        check_input(input);
+    }
+    check_input(&"{".repeat(100_000));
+    check_input(&"$".repeat(100_001));
+    check_input(&"\\begin{itemize}\\item ".repeat(1_000));
+}
+
+#[test]
+fn seed_corpus() {
+    let corpus = concat!(env!("CARGO_MANIFEST_DIR"), "/fuzz/corpus");
+    for target in std::fs::read_dir(corpus).unwrap() {
+        for seed in std::fs::read_dir(target.unwrap().path()).unwrap() {
+            let input = std::fs::read(seed.unwrap().path()).unwrap();
+            check_input(&String::from_utf8_lossy(&input));
+        }
+    }
+}
@@ -0,0 +1,64 @@
+use assert2::*;
+
+use texfmt::format::{MathDelimiters, PunctuationSpacing};
+use texfmt::{format_str, FormatOptions};
+use texfmt::{lexer::lex_tokens, writer::write_tokens};
+
+/// The `.tex` files in `tests/assets`, with their content.
+fn assets() -> Vec<(std::path::PathBuf, String)> {
+    let dir = std::path::Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/assets");
+    let mut paths: Vec<_> = std::fs::read_dir(dir)
+        .unwrap()
+        .map(|entry| entry.unwrap().path())
+        .filter(|path| path.extension().is_some_and(|ext| ext == "tex"))
+        .collect();
+    paths.sort();
+    check!(!paths.is_empty());
+    paths
+        .into_iter()
+        .map(|path| {
+            let content = std::fs::read_to_string(&path).unwrap();
+            (path, content)
+        })
+        .collect()
+}
+
+/// The default options, and all the optional passes.
+fn all_options() -> Vec<FormatOptions> {
+    let builder = FormatOptions::builder()
+        .align_tables(true)
+        .comment_space(true)
+        .math_delimiters(Some(MathDelimiters::Amsmath))
+        .punctuation_spacing(Some(PunctuationSpacing::En))
+        .split_packages(true)
+        .sort_packages(true);
+    vec![
+        FormatOptions::default(),
+        builder.clone().build().unwrap(),
+        builder.clone().width(40).use_tabs(true).build().unwrap(),
+        builder.clone().sentence_wrap(true).build().unwrap(),
+        builder
+            .punctuation_spacing(Some(PunctuationSpacing::Fr))
+            .build()
+            .unwrap(),
+    ]
+}
+
+#[test]
+fn lossless() {
+    for (path, src) in assets() {
+        let tokens = lex_tokens(&src).unwrap_or_else(|e| panic!("cannot tokenize {path:?}: {e}"));
+        check!(write_tokens(&tokens) == src, "{path:?} does not round-trip");
+    }
+}
+
+#[test]
+fn idempotent() {
+    for (path, src) in assets() {
+        for options in all_options() {
+            let_assert!(Ok(once) = format_str(&src, &options));
+            let_assert!(Ok(twice) = format_str(&once, &options));
+            check!(once == twice, "{path:?} with {options:?}");
+        }
+    }
+}
@@ -6,8 +6,7 @@ use texfmt::lexer::lex_tokens;
 fn tokenize_all() {
     let src = include_str!("../assets/src_01.tex");
     let result = lex_tokens(src);
-    let_assert!(Ok((rest, tokens)) = result);
-    check!(rest.is_empty());
+    let_assert!(Ok(tokens) = result);
     check!(!tokens.is_empty());
-    check!(tokens.len() == 286);
+    check!(tokens.len() == 256);
 }
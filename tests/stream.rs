@@ -0,0 +1,43 @@
+use assert2::*;
+
+use texfmt::lexer::{lex_tokens, TokenIterator};
+
+#[test]
+fn stream_large_input() {
+    let chunk = include_str!("../assets/src_01.tex");
+    let src = chunk.repeat(10 * 1024 * 1024 / chunk.len() + 1);
+    check!(src.len() >= 10 * 1024 * 1024);
+
+    let tokens = lex_tokens(&src).unwrap();
+
+    let reader = std::io::BufReader::with_capacity(1024, src.as_bytes());
+    let streamed: Vec<_> = TokenIterator::new(reader)
+        .collect::<std::io::Result<_>>()
+        .unwrap();
+    check!(streamed.len() == tokens.len());
+    let mismatches = streamed
+        .iter()
+        .zip(&tokens)
+        .filter(|(streamed, token)| streamed.as_ref() != **token)
+        .count();
+    check!(mismatches == 0);
+}
+
+#[test]
+fn stream_verbatim() {
+    let src = "a\n\\begin{verbatim}\n  \\x{\n\n\\end{verbatim}\nb $$\n\\begin{verbatim}\nc";
+    let tokens = lex_tokens(src).unwrap();
+    let streamed: Vec<_> = TokenIterator::new(src.as_bytes())
+        .collect::<std::io::Result<_>>()
+        .unwrap();
+    check!(streamed.iter().map(|t| t.as_ref()).collect::<Vec<_>>() == tokens);
+}
+
+#[test]
+fn stream_error() {
+    let mut tokens = TokenIterator::new("ok\nbad \\'".as_bytes());
+    check!(tokens.by_ref().take(4).all(|t| t.is_ok()));
+    let_assert!(Some(Err(e)) = tokens.next());
+    check!(e.to_string() == "cannot tokenize input at byte 7");
+    check!(tokens.next().is_none());
+}